@@ -0,0 +1,281 @@
+use crate::data::ParsedLine;
+use crate::export::{parse_bounding_box, ExportFilter};
+use crate::mariadb::MariaDb;
+use crate::postgres::PostgresDb;
+use crate::query::{MessageQuery, PositionQuery, MAX_QUERY_LIMIT};
+use crate::retention::{cutoff, parse_duration};
+use crate::sqlite::SqliteDb;
+use crate::stations::StationState;
+use anyhow::Result;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::prelude::*;
+use log::error;
+use serde::Deserialize;
+
+/// Lines returned when the request does not give a `limit`
+pub const DEFAULT_QUERY_LIMIT: u32 = 100;
+
+/// Database the API reads from
+#[derive(Clone)]
+pub enum Store {
+    Sqlite(SqliteDb),
+    Mariadb(MariaDb),
+    Postgres(PostgresDb),
+}
+
+/// Run a query on the SQLite connection without blocking the runtime
+async fn blocking<T, F>(db: &SqliteDb, query: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&SqliteDb) -> Result<T> + Send + 'static,
+{
+    let db = db.clone();
+    tokio::task::spawn_blocking(move || query(&db)).await?
+}
+
+impl Store {
+    async fn station(&self, callsign: String) -> Result<Option<StationState>> {
+        match self {
+            Store::Sqlite(db) => blocking(db, move |db| db.station(&callsign)).await,
+            Store::Mariadb(db) => db.station(&callsign).await,
+            Store::Postgres(db) => db.station(&callsign).await,
+        }
+    }
+
+    async fn stations_heard_since(
+        &self,
+        since: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<StationState>> {
+        match self {
+            Store::Sqlite(db) => {
+                blocking(db, move |db| db.stations_heard_since(since, limit)).await
+            }
+            Store::Mariadb(db) => db.stations_heard_since(since, limit).await,
+            Store::Postgres(db) => db.stations_heard_since(since, limit).await,
+        }
+    }
+
+    async fn position_lines(&self, query: PositionQuery) -> Result<Vec<ParsedLine>> {
+        match self {
+            Store::Sqlite(db) => blocking(db, move |db| db.position_lines(&query)).await,
+            Store::Mariadb(db) => db.position_lines(&query).await,
+            Store::Postgres(db) => db.position_lines(&query).await,
+        }
+    }
+
+    async fn message_lines(&self, query: MessageQuery) -> Result<Vec<ParsedLine>> {
+        match self {
+            Store::Sqlite(db) => blocking(db, move |db| db.message_lines(&query)).await,
+            Store::Mariadb(db) => db.message_lines(&query).await,
+            Store::Postgres(db) => db.message_lines(&query).await,
+        }
+    }
+}
+
+/// Error answered as `{"error": "..."}`
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn bad_request(message: String) -> ApiError {
+        ApiError(StatusCode::BAD_REQUEST, message)
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        error!("API Error: {}", e);
+        ApiError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "database query failed".to_string(),
+        )
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+/// Query string parameters shared by the endpoints. Values are parsed by
+/// hand so mistakes are answered in the same JSON form as other errors.
+#[derive(Deserialize, Debug, Default)]
+struct Params {
+    /// How far back to look, e.g. `6h` (ignored with `start`)
+    since: Option<String>,
+    /// First receive time included, RFC 3339
+    start: Option<String>,
+    /// Receive time up to which lines are included, RFC 3339
+    end: Option<String>,
+    /// `WEST,SOUTH,EAST,NORTH` in degrees
+    bbox: Option<String>,
+    /// Callsign pattern where `*` and `?` are wildcards
+    callsign: Option<String>,
+    limit: Option<String>,
+}
+
+fn parse_time(key: &str, value: &str) -> Result<DateTime<Utc>, ApiError> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|x| x.with_timezone(&Utc))
+        .map_err(|e| ApiError::bad_request(format!("{}: invalid time `{}`: {}", key, value, e)))
+}
+
+impl Params {
+    fn start(&self) -> Result<Option<DateTime<Utc>>, ApiError> {
+        if let Some(start) = &self.start {
+            return parse_time("start", start).map(Some);
+        }
+        self.since
+            .as_deref()
+            .map(|x| {
+                parse_duration(x)
+                    .map(cutoff)
+                    .map_err(|e| ApiError::bad_request(format!("since: {}", e)))
+            })
+            .transpose()
+    }
+
+    fn end(&self) -> Result<Option<DateTime<Utc>>, ApiError> {
+        self.end
+            .as_deref()
+            .map(|x| parse_time("end", x))
+            .transpose()
+    }
+
+    fn limit(&self) -> Result<u32, ApiError> {
+        let Some(limit) = &self.limit else {
+            return Ok(DEFAULT_QUERY_LIMIT);
+        };
+        match limit.parse() {
+            Ok(x @ 1..=MAX_QUERY_LIMIT) => Ok(x),
+            _ => Err(ApiError::bad_request(format!(
+                "limit: must be between 1 and {}",
+                MAX_QUERY_LIMIT
+            ))),
+        }
+    }
+
+    fn filter(&self, callsign: Option<String>) -> Result<ExportFilter, ApiError> {
+        Ok(ExportFilter {
+            callsign,
+            start: self.start()?,
+            end: self.end()?,
+            bounding_box: self
+                .bbox
+                .as_deref()
+                .map(parse_bounding_box)
+                .transpose()
+                .map_err(|e| ApiError::bad_request(format!("bbox: {}", e)))?,
+        })
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// Stations heard within `since`, one hour by default, most recent first
+async fn stations(
+    State(store): State<Store>,
+    Query(params): Query<Params>,
+) -> ApiResult<Vec<StationState>> {
+    let since = match params.start()? {
+        Some(x) => x,
+        None => cutoff(std::time::Duration::from_secs(3600)),
+    };
+    let stations = store.stations_heard_since(since, params.limit()?).await?;
+    Ok(Json(stations))
+}
+
+async fn station(
+    State(store): State<Store>,
+    Path(callsign): Path<String>,
+) -> ApiResult<StationState> {
+    let callsign = callsign.to_uppercase();
+    match store.station(callsign.clone()).await? {
+        Some(x) => Ok(Json(x)),
+        None => Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("station {} has not been heard", callsign),
+        )),
+    }
+}
+
+async fn positions(
+    State(store): State<Store>,
+    Query(params): Query<Params>,
+) -> ApiResult<Vec<ParsedLine>> {
+    let query = PositionQuery {
+        filter: params.filter(params.callsign.clone())?,
+        weather: false,
+        limit: params.limit()?,
+    };
+    Ok(Json(store.position_lines(query).await?))
+}
+
+async fn station_messages(
+    State(store): State<Store>,
+    Path(callsign): Path<String>,
+    Query(params): Query<Params>,
+) -> ApiResult<Vec<ParsedLine>> {
+    let query = MessageQuery {
+        callsign: Some(callsign.to_uppercase()),
+        bulletins: false,
+        start: params.start()?,
+        end: params.end()?,
+        limit: params.limit()?,
+    };
+    Ok(Json(store.message_lines(query).await?))
+}
+
+async fn station_weather(
+    State(store): State<Store>,
+    Path(callsign): Path<String>,
+    Query(params): Query<Params>,
+) -> ApiResult<Vec<ParsedLine>> {
+    let query = PositionQuery {
+        filter: params.filter(Some(callsign.to_uppercase()))?,
+        weather: true,
+        limit: params.limit()?,
+    };
+    Ok(Json(store.position_lines(query).await?))
+}
+
+async fn bulletins(
+    State(store): State<Store>,
+    Query(params): Query<Params>,
+) -> ApiResult<Vec<ParsedLine>> {
+    let query = MessageQuery {
+        callsign: None,
+        bulletins: true,
+        start: params.start()?,
+        end: params.end()?,
+        limit: params.limit()?,
+    };
+    Ok(Json(store.message_lines(query).await?))
+}
+
+/// Read-only JSON routes over the stored data:
+///
+/// - `GET /api/stations?since=1h` stations heard recently
+/// - `GET /api/stations/{callsign}` state of one station
+/// - `GET /api/stations/{callsign}/messages` messages from or to a station
+/// - `GET /api/stations/{callsign}/weather` weather reports of a station
+/// - `GET /api/positions?bbox=W,S,E,N&callsign=K0HAX-*` positions and Mic-E reports
+/// - `GET /api/bulletins` messages addressed to `BLN*`
+///
+/// Lines are `ParsedLine` documents, newest first. `start`, `end` and
+/// `since` narrow the receive time and `limit` caps the number of results.
+pub fn router(store: Store) -> Router {
+    Router::new()
+        .route("/api/stations", get(stations))
+        .route("/api/stations/:callsign", get(station))
+        .route("/api/stations/:callsign/messages", get(station_messages))
+        .route("/api/stations/:callsign/weather", get(station_weather))
+        .route("/api/positions", get(positions))
+        .route("/api/bulletins", get(bulletins))
+        .with_state(store)
+}
@@ -0,0 +1,387 @@
+use crate::data::*;
+use crate::dedup::DuplicatePath;
+use anyhow::Result;
+use chrono::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+/// Record format of archive files
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum ArchiveFormat {
+    /// One JSON document per line, as serialized by serde
+    Ndjson,
+
+    /// One CSV file per packet type, with the columns of `csv_columns`
+    Csv,
+}
+
+impl ArchiveFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Ndjson => "ndjson",
+            ArchiveFormat::Csv => "csv",
+        }
+    }
+}
+
+/// Compression applied to written files
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Compression {
+    /// Plain text
+    None,
+
+    /// gzip, appended to as concatenated members
+    Gzip,
+
+    /// Zstandard, appended to as concatenated frames
+    Zstd,
+}
+
+impl Compression {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+}
+
+/// How often a new set of archive files is started
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Rotation {
+    /// Keep writing to the same files
+    None,
+
+    /// New files every hour, named `PREFIX-YYYYMMDDHH`
+    Hourly,
+
+    /// New files every day, named `PREFIX-YYYYMMDD`
+    Daily,
+}
+
+impl Rotation {
+    /// Name of the period a receive time falls in
+    pub(crate) fn period(&self, time: DateTime<Utc>) -> String {
+        match self {
+            Rotation::None => String::new(),
+            Rotation::Hourly => time.format("%Y%m%d%H").to_string(),
+            Rotation::Daily => time.format("%Y%m%d").to_string(),
+        }
+    }
+}
+
+/// A writer which compresses everything written to it
+pub enum CompressedWriter<W: Write> {
+    Plain(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn new(inner: W, compression: Compression) -> io::Result<CompressedWriter<W>> {
+        Ok(match compression {
+            Compression::None => CompressedWriter::Plain(inner),
+            Compression::Gzip => CompressedWriter::Gzip(flate2::write::GzEncoder::new(
+                inner,
+                flate2::Compression::default(),
+            )),
+            Compression::Zstd => CompressedWriter::Zstd(zstd::stream::write::Encoder::new(
+                inner,
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?),
+        })
+    }
+
+    /// Write the end of the compressed stream and return the inner writer
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            CompressedWriter::Plain(x) => Ok(x),
+            CompressedWriter::Gzip(x) => x.finish(),
+            CompressedWriter::Zstd(x) => x.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::Plain(x) => x.write(buf),
+            CompressedWriter::Gzip(x) => x.write(buf),
+            CompressedWriter::Zstd(x) => x.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Plain(x) => x.flush(),
+            CompressedWriter::Gzip(x) => x.flush(),
+            CompressedWriter::Zstd(x) => x.flush(),
+        }
+    }
+}
+
+/// Columns shared by the CSV files of every packet type
+const CSV_LINE_COLUMNS: &[&str] = &["id", "received_at", "from", "via"];
+
+/// Columns of the CSV file of a packet type, or of `paths`. New columns are
+/// only ever appended, so readers can rely on the position of a column.
+pub fn csv_columns(kind: &str) -> Vec<&'static str> {
+    let columns: &[&str] = match kind {
+        "position" => &[
+            "to",
+            "timestamp",
+            "messaging_supported",
+            "latitude",
+            "longitude",
+            "precision",
+            "symbol_table",
+            "symbol_code",
+            "comment",
+            "cst",
+        ],
+        "message" => &["to", "addressee", "text", "msg_id"],
+        "status" => &["to", "timestamp", "comment"],
+        "mic_e" => &[
+            "latitude",
+            "longitude",
+            "precision",
+            "message",
+            "speed",
+            "course",
+            "symbol_table",
+            "symbol_code",
+            "comment",
+            "current",
+        ],
+        "unknown" => &["raw", "error"],
+        "paths" => return vec!["id", "received_at", "via"],
+        _ => &[],
+    };
+    CSV_LINE_COLUMNS.iter().chain(columns).copied().collect()
+}
+
+/// A packet timestamp in APRS notation, `DDHHMMz` or `HHMMSSh`
+fn timestamp_text(timestamp: &Option<Timestamp>) -> String {
+    match timestamp {
+        Some(Timestamp::DDHHMM(d, h, m)) => format!("{:02}{:02}{:02}z", d, h, m),
+        Some(Timestamp::HHMMSS(h, m, s)) => format!("{:02}{:02}{:02}h", h, m, s),
+        Some(Timestamp::Unsupported(_)) | None => String::new(),
+    }
+}
+
+/// Values of a line in the order of `csv_columns(line.data.type_name())`
+pub fn csv_record(line: &ParsedLine) -> Vec<String> {
+    let mut record = vec![
+        line.id.hyphenated().to_string(),
+        line.received_at
+            .to_rfc3339_opts(SecondsFormat::Micros, true),
+        line.from.clone(),
+        line.via.join(","),
+    ];
+    match &line.data {
+        ParsedAprsData::Position(x) => record.extend([
+            x.to.clone(),
+            timestamp_text(&x.timestamp),
+            x.messaging_supported.to_string(),
+            x.latitude.to_string(),
+            x.longitude.to_string(),
+            x.precision.to_string(),
+            x.symbol_table.to_string(),
+            x.symbol_code.to_string(),
+            x.comment.clone(),
+            x.cst.clone(),
+        ]),
+        ParsedAprsData::Message(x) => record.extend([
+            x.to.clone(),
+            x.addressee.clone(),
+            x.text.clone(),
+            x.id.as_ref()
+                .map(|x| String::from_utf8_lossy(x).to_string())
+                .unwrap_or_default(),
+        ]),
+        ParsedAprsData::Status(x) => record.extend([
+            x.to.clone(),
+            timestamp_text(&x.timestamp),
+            x.comment.clone(),
+        ]),
+        ParsedAprsData::MicE(x) => record.extend([
+            x.latitude.to_string(),
+            x.longitude.to_string(),
+            x.precision.to_string(),
+            x.message.clone(),
+            x.speed.to_string(),
+            x.course.to_string(),
+            x.symbol_table.to_string(),
+            x.symbol_code.to_string(),
+            x.comment.clone(),
+            x.current.to_string(),
+        ]),
+        ParsedAprsData::Unknown(x) => record.extend([x.raw.clone(), x.error.clone()]),
+    }
+    record
+}
+
+fn path_csv_record(path: &DuplicatePath) -> Vec<String> {
+    vec![
+        path.id.hyphenated().to_string(),
+        path.received_at
+            .to_rfc3339_opts(SecondsFormat::Micros, true),
+        path.via.join(","),
+    ]
+}
+
+/// Where and how `ArchiveWriter` writes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveOptions {
+    pub directory: PathBuf,
+    /// Start of every file name
+    pub prefix: String,
+    pub format: ArchiveFormat,
+    pub compression: Compression,
+    pub rotation: Rotation,
+}
+
+type ArchiveStream = CompressedWriter<BufWriter<File>>;
+
+enum ArchiveFile {
+    Ndjson(ArchiveStream),
+    Csv(Box<csv::Writer<ArchiveStream>>),
+}
+
+impl ArchiveFile {
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ArchiveFile::Ndjson(x) => x.flush(),
+            ArchiveFile::Csv(x) => x.flush(),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        let stream = match self {
+            ArchiveFile::Ndjson(x) => x,
+            ArchiveFile::Csv(x) => x.into_inner().map_err(|e| e.into_error())?,
+        };
+        stream.finish()?.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes lines and duplicate paths to archive files, starting new files as
+/// the receive times of the lines pass into the next rotation period.
+///
+/// NDJSON lines go to `PREFIX-PERIOD.ndjson` and paths to
+/// `PREFIX-paths-PERIOD.ndjson`. CSV gets a `PREFIX-TYPE-PERIOD.csv` per
+/// packet type plus `paths`. Existing files are appended to, so a restart
+/// within a period continues its files.
+pub struct ArchiveWriter {
+    options: ArchiveOptions,
+    period: String,
+    files: BTreeMap<&'static str, ArchiveFile>,
+}
+
+impl ArchiveWriter {
+    pub fn new(options: ArchiveOptions) -> Result<ArchiveWriter> {
+        std::fs::create_dir_all(&options.directory)?;
+        Ok(ArchiveWriter {
+            options,
+            period: String::new(),
+            files: BTreeMap::new(),
+        })
+    }
+
+    pub fn write(&mut self, line: &ParsedLine) -> Result<()> {
+        let kind = match self.options.format {
+            ArchiveFormat::Ndjson => "",
+            ArchiveFormat::Csv => line.data.type_name(),
+        };
+        match self.file(kind, line.received_at)? {
+            ArchiveFile::Ndjson(x) => {
+                serde_json::to_writer(&mut *x, line)?;
+                x.write_all(b"\n")?;
+            }
+            ArchiveFile::Csv(x) => x.write_record(csv_record(line))?,
+        }
+        Ok(())
+    }
+
+    pub fn write_path(&mut self, path: &DuplicatePath) -> Result<()> {
+        match self.file("paths", path.received_at)? {
+            ArchiveFile::Ndjson(x) => {
+                serde_json::to_writer(&mut *x, path)?;
+                x.write_all(b"\n")?;
+            }
+            ArchiveFile::Csv(x) => x.write_record(path_csv_record(path))?,
+        }
+        Ok(())
+    }
+
+    /// Push buffered records to disk, as far as the compression allows
+    pub fn flush(&mut self) -> Result<()> {
+        for file in self.files.values_mut() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Complete and close all open files
+    pub fn finish(&mut self) -> Result<()> {
+        for (_, file) in std::mem::take(&mut self.files) {
+            file.finish()?;
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, kind: &'static str, time: DateTime<Utc>) -> Result<&mut ArchiveFile> {
+        let period = self.options.rotation.period(time);
+        if period != self.period {
+            self.finish()?;
+            self.period = period;
+        }
+        if !self.files.contains_key(kind) {
+            let file = self.open(kind)?;
+            self.files.insert(kind, file);
+        }
+        Ok(self.files.get_mut(kind).expect("opened above"))
+    }
+
+    fn open(&self, kind: &str) -> Result<ArchiveFile> {
+        let name = [self.options.prefix.as_str(), kind, self.period.as_str()]
+            .iter()
+            .filter(|x| !x.is_empty())
+            .copied()
+            .collect::<Vec<_>>()
+            .join("-");
+        let path = self.options.directory.join(format!(
+            "{}.{}{}",
+            name,
+            self.options.format.extension(),
+            self.options.compression.extension()
+        ));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let is_new = file.metadata()?.len() == 0;
+        let stream = CompressedWriter::new(BufWriter::new(file), self.options.compression)?;
+        Ok(match self.options.format {
+            ArchiveFormat::Ndjson => ArchiveFile::Ndjson(stream),
+            ArchiveFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(stream);
+                if is_new {
+                    writer.write_record(csv_columns(kind))?;
+                }
+                ArchiveFile::Csv(Box::new(writer))
+            }
+        })
+    }
+}
+
+impl Drop for ArchiveWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::error!("Could not close archive files: {}", e);
+        }
+    }
+}
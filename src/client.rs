@@ -1,79 +1,229 @@
 use crate::data::Handshake;
 use anyhow::anyhow;
 use futures_util::sink::SinkExt;
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::StreamExt;
+use log::{debug, info, warn};
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpStream;
-use tokio_util::codec::{Framed, LinesCodec};
+use tokio::sync::{Mutex, RwLock};
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+
+type Connection = Framed<TcpStream, LinesCodec>;
+
+/// Longest wait for the server to answer the login
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The answer of a server to a login, from its `# logresp` line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginResponse {
+    pub callsign: String,
+    /// Whether the passcode was accepted, which is needed to send packets
+    pub verified: bool,
+    /// Name of the server, when given
+    pub server: Option<String>,
+}
+
+impl LoginResponse {
+    /// Parse a line such as `# logresp N0CALL verified, server T2EXAMPLE`
+    pub fn parse(line: &str) -> Option<LoginResponse> {
+        let rest = line.strip_prefix("# logresp ")?;
+        let (callsign, rest) = rest.split_once(' ')?;
+        let (status, server) = match rest.split_once(',') {
+            Some((status, server)) => (status, server.trim().strip_prefix("server ")),
+            None => (rest, None),
+        };
+        let verified = match status.trim() {
+            "verified" => true,
+            "unverified" => false,
+            _ => return None,
+        };
+        Some(LoginResponse {
+            callsign: callsign.to_string(),
+            verified,
+            server: server.map(|x| x.trim().to_string()),
+        })
+    }
+}
+
+impl std::fmt::Display for LoginResponse {
+    /// The `# logresp` line, without its line ending
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self.verified {
+            true => "verified",
+            false => "unverified",
+        };
+        write!(f, "# logresp {} {}", self.callsign, status)?;
+        if let Some(server) = &self.server {
+            write!(f, ", server {}", server)?;
+        }
+        Ok(())
+    }
+}
 
 pub struct AprsClient {
-    addr: SocketAddr,
-    client: Arc<RwLock<Framed<tokio::net::TcpStream, LinesCodec>>>,
+    hostname: String,
+    port: u16,
+    callsign: String,
+    addr: std::sync::RwLock<SocketAddr>,
+    /// Reading half of the connection, held while waiting for a line
+    client: Arc<Mutex<SplitStream<Connection>>>,
+    /// Writing half, so packets can be sent while a read is waiting
+    writer: Mutex<SplitSink<Connection, String>>,
     error_count: Arc<RwLock<u64>>,
+    reconnects: AtomicU64,
+    login: std::sync::RwLock<LoginResponse>,
+}
+
+/// Wait for the `# logresp` line answering a login, skipping the banner
+async fn read_login_response(client: &mut Connection) -> anyhow::Result<LoginResponse> {
+    let wait = async {
+        loop {
+            match client.next().await {
+                Some(Ok(line)) => match LoginResponse::parse(&line) {
+                    Some(x) => return Ok(x),
+                    None => debug!("Before login response: {}", line),
+                },
+                Some(Err(e)) => return Err(anyhow!("{}", e)),
+                None => return Err(anyhow!("connection closed")),
+            }
+        }
+    };
+    match tokio::time::timeout(LOGIN_TIMEOUT, wait).await {
+        Ok(result) => result.map_err(|e| anyhow!("no login response: {}", e)),
+        Err(_) => Err(anyhow!("no login response within {:?}", LOGIN_TIMEOUT)),
+    }
+}
+
+/// Resolve the server, connect and log in
+async fn connect(
+    hostname: &str,
+    port: u16,
+    callsign: &str,
+) -> anyhow::Result<(SocketAddr, Connection, LoginResponse)> {
+    let addr = tokio::net::lookup_host(format!("{}:{}", hostname, port))
+        .await?
+        .next()
+        .ok_or(anyhow!("{} has no addresses", hostname))?;
+
+    // Create the event loop, and initiate the connection to the remote server
+    let conn = TcpStream::connect(&addr).await?;
+
+    let mut client = Framed::new(conn, LinesCodec::new_with_max_length(2048));
+    let handshake = Handshake::new(callsign.to_string());
+    client
+        .send(format!(
+            "user {} pass {}\r\n",
+            handshake.callsign, handshake.passcode
+        ))
+        .await?;
+    let login = read_login_response(&mut client).await?;
+    match login.verified {
+        true => info!("Logged in to {} as {}", addr, login.callsign),
+        false => warn!(
+            "Logged in to {} as {}, unverified, so packets cannot be sent",
+            addr, login.callsign
+        ),
+    }
+    Ok((addr, client, login))
 }
 
 impl AprsClient {
-    pub async fn new(hostname: &str, port: u16, callsign: &str) -> Self {
-        let addr = tokio::net::lookup_host(format!("{}:{}", hostname, port))
-            .await
-            .unwrap()
-            .next()
-            .unwrap();
-
-        // Create the event loop, and initiate the connection to the remote server
-        let conn = TcpStream::connect(&addr).await.unwrap();
-
-        let mut client = Framed::new(conn, LinesCodec::new_with_max_length(2048));
-        let handshake = Handshake::new(callsign.to_string());
-        client
-            .send(format!(
-                "user {} pass {}\r\n",
-                handshake.callsign, handshake.passcode
-            ))
-            .await
-            .unwrap();
+    /// Connect and log in, failing when the server cannot be reached. Once
+    /// connected, a lost connection is established again by `read_raw_line`.
+    pub async fn new(hostname: &str, port: u16, callsign: &str) -> anyhow::Result<Self> {
+        let (addr, client, login) = connect(hostname, port, callsign).await?;
+        let (writer, client) = client.split();
 
         let error_count: u64 = 0;
-        AprsClient {
-            addr: addr,
-            client: Arc::new(RwLock::new(client)),
+        Ok(AprsClient {
+            hostname: hostname.to_string(),
+            port,
+            callsign: callsign.to_string(),
+            addr: std::sync::RwLock::new(addr),
+            client: Arc::new(Mutex::new(client)),
+            writer: Mutex::new(writer),
             error_count: Arc::new(RwLock::new(error_count)),
-        }
+            reconnects: AtomicU64::new(0),
+            login: std::sync::RwLock::new(login),
+        })
+    }
+
+    /// The answer of the server to the latest login
+    pub fn login(&self) -> LoginResponse {
+        self.login.read().unwrap().clone()
+    }
+
+    pub fn hostname(&self) -> &str {
+        &self.hostname
     }
 
     pub fn get_addr(&self) -> SocketAddr {
-        self.addr
+        *self.addr.read().unwrap()
+    }
+
+    /// Number of times the connection was lost and established again
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
     }
 
     pub async fn read_line(&self) -> Result<crate::ParsedLine, Box<dyn std::error::Error>> {
+        // Lines which fail to decode are kept as `Unknown` so they can be stored
+        Ok(crate::parse_line_or_unknown(&self.read_raw_line().await?))
+    }
+
+    /// Read the next packet as received, skipping nothing but server comments.
+    /// A lost connection is established again before the error is returned,
+    /// and attempts continue every `utils::MAX_RECONNECT_DELAY` for as long as the
+    /// server stays unreachable.
+    pub async fn read_raw_line(&self) -> Result<String, Box<dyn std::error::Error>> {
         let client_handle = Arc::clone(&self.client);
-        let mut client_rw = client_handle.write().unwrap();
-        match client_rw.next().await {
+        let mut client_rw = client_handle.lock().await;
+        let lost = match client_rw.next().await {
             Some(Ok(x)) => match x.as_str().get(..1) {
-                Some("#") => {
-                    return Err(anyhow!("Server Comment: {}", x).into());
-                }
+                Some("#") => return Err(anyhow!("Server Comment: {}", x).into()),
                 _ => {
                     let error_count_handle = Arc::clone(&self.error_count);
-                    let mut error_count = error_count_handle.write().unwrap();
+                    let mut error_count = error_count_handle.write().await;
                     *error_count = 0;
-                    match crate::parse_line(&x) {
-                        Ok(y) => return Ok(y),
-                        Err(y) => return Err(anyhow!("An error: {}; skipped. | {}", y, x).into()),
-                    };
+                    return Ok(x);
                 }
             },
-            Some(Err(x)) => Err(anyhow!("{}", x).into()),
-            None => {
-                let error_count_handle = Arc::clone(&self.error_count);
-                let mut error_count = error_count_handle.write().unwrap();
-                *error_count = *error_count + 1;
-                if *error_count > 100 {
-                    panic!("client_rw returned None and error_count is > 100!");
-                }
-                Err(anyhow!("client_rw returned None!").into())
+            Some(Err(LinesCodecError::Io(x))) => anyhow!("Connection error: {}", x),
+            Some(Err(x)) => return Err(anyhow!("{}", x).into()),
+            None => anyhow!("client_rw returned None!"),
+        };
+        let error_count_handle = Arc::clone(&self.error_count);
+        let mut error_count = error_count_handle.write().await;
+        *error_count += 1;
+        // Back off exponentially while the server stays unreachable
+        let delay = crate::utils::reconnect_delay(*error_count);
+        warn!("{}, reconnecting in {:?}", lost, delay);
+        tokio::time::sleep(delay).await;
+        match connect(&self.hostname, self.port, &self.callsign).await {
+            Ok((addr, client, login)) => {
+                info!("Reconnected to {}", addr);
+                let (writer, client) = client.split();
+                *client_rw = client;
+                *self.writer.lock().await = writer;
+                *self.addr.write().unwrap() = addr;
+                *self.login.write().unwrap() = login;
+                self.reconnects.fetch_add(1, Ordering::Relaxed);
+                Err(lost.into())
             }
+            Err(e) => Err(anyhow!("{}, reconnecting failed: {}", lost, e).into()),
         }
     }
+
+    /// Send a packet such as `N0CALL>APRS,TCPIP*:>status` to the server,
+    /// which only passes it on when the login was verified. A lost
+    /// connection is left to `read_raw_line` to establish again.
+    pub async fn send_line(&self, line: &str) -> anyhow::Result<()> {
+        let mut writer = self.writer.lock().await;
+        writer.send(format!("{}\r", line)).await?;
+        Ok(())
+    }
 }
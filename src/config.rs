@@ -0,0 +1,278 @@
+use crate::retention::parse_duration;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Start of the environment variables overriding configuration keys. The rest
+/// of the name is the key in upper case with `.` replaced by `_`, e.g.
+/// `K0HAX_APRS_SERVER_PORT` for `server.port`.
+pub const ENV_PREFIX: &str = "K0HAX_APRS_";
+
+/// Settings of the daemon, read from a TOML file such as
+///
+/// ```toml
+/// sinks = []
+///
+/// [server]
+/// hostname = "rotate.aprs.net"
+/// port = 10152
+///
+/// [queue]
+/// workers = 3
+/// channel_size = 65534
+/// stats_interval = "60s"
+/// spill_dir = "spill"
+/// high_water = 80
+/// drain_timeout = "30s"
+///
+/// [sqlite]
+/// path = "aprs.sqlite"
+///
+/// [mariadb]
+/// password_file = "/etc/k0hax-aprs/mariadb.password"
+/// ```
+///
+/// Every key is optional and defaults to the value shown.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Sinks fed alongside the one given on the command line, each written
+    /// like a `--sink` option, e.g. `"archive /var/lib/aprs --overflow drop-newest"`
+    pub sinks: Vec<String>,
+    pub server: ServerConfig,
+    pub queue: QueueConfig,
+    pub sqlite: SqliteConfig,
+    pub mariadb: Credentials,
+    pub postgres: Credentials,
+    pub mqtt: Credentials,
+}
+
+/// APRS-IS server to connect to
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub hostname: String,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            hostname: "rotate.aprs.net".to_string(),
+            port: 10152,
+        }
+    }
+}
+
+/// Queues between the APRS-IS connection and the sinks
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Number of tasks writing to each database, unless a sink sets its own
+    pub workers: usize,
+    /// Number of lines queued for each sink, unless a sink sets its own
+    pub channel_size: usize,
+    /// How often the counters are printed, zero disables them
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub stats_interval: Duration,
+    /// Directory of the files of sinks which spill to disk
+    pub spill_dir: PathBuf,
+    /// Percentage of a queue above which a warning is logged
+    pub high_water: u8,
+    /// How long the sinks may take to store what is queued after a shutdown
+    /// signal, before the rest is given up
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub drain_timeout: Duration,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            workers: 3,
+            channel_size: 65534,
+            stats_interval: Duration::from_secs(60),
+            spill_dir: "spill".into(),
+            high_water: 80,
+            drain_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SqliteConfig {
+    pub path: String,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        SqliteConfig {
+            path: "aprs.sqlite".to_string(),
+        }
+    }
+}
+
+/// Database or broker password, given directly or as a file holding it.
+/// Without either a database password is asked for on the terminal.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Credentials {
+    /// Takes precedence over `password_file`
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+}
+
+impl Credentials {
+    /// The configured password, reading `password_file` when needed. `section`
+    /// names the table for error messages.
+    pub fn password(&self, section: &str) -> Result<Option<String>> {
+        if let Some(password) = &self.password {
+            return Ok(Some(password.clone()));
+        }
+        let Some(path) = &self.password_file else {
+            return Ok(None);
+        };
+        let password = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("{}.password_file: {}: {}", section, path.display(), e))?;
+        Ok(Some(password.trim_end_matches(['\r', '\n']).to_string()))
+    }
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_duration(&value).map_err(serde::de::Error::custom)
+}
+
+/// Keys which can be overridden from the environment
+pub const KEYS: &[&str] = &[
+    "server.hostname",
+    "server.port",
+    "queue.workers",
+    "queue.channel_size",
+    "queue.stats_interval",
+    "queue.spill_dir",
+    "queue.high_water",
+    "queue.drain_timeout",
+    "sqlite.path",
+    "mariadb.password",
+    "mariadb.password_file",
+    "postgres.password",
+    "postgres.password_file",
+    "mqtt.password",
+    "mqtt.password_file",
+];
+
+/// Name of the environment variable overriding a key
+pub fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase())
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid number `{}`", value))
+}
+
+impl Config {
+    /// Read the configuration file when one is given, apply environment
+    /// overrides and validate the result
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let mut config = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+                toml::from_str(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))?
+            }
+            None => Config::default(),
+        };
+        for key in KEYS {
+            let name = env_name(key);
+            if let Ok(value) = std::env::var(&name) {
+                config
+                    .set(key, &value)
+                    .map_err(|e| anyhow!("{} ({}): {}", name, key, e))?;
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Set a key from its text form
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "server.hostname" => self.server.hostname = value.to_string(),
+            "server.port" => self.server.port = parse_number(value)?,
+            "queue.workers" => self.queue.workers = parse_number(value)?,
+            "queue.channel_size" => self.queue.channel_size = parse_number(value)?,
+            "queue.stats_interval" => self.queue.stats_interval = parse_duration(value)?,
+            "queue.spill_dir" => self.queue.spill_dir = value.into(),
+            "queue.high_water" => self.queue.high_water = parse_number(value)?,
+            "queue.drain_timeout" => self.queue.drain_timeout = parse_duration(value)?,
+            "sqlite.path" => self.sqlite.path = value.to_string(),
+            "mariadb.password" => self.mariadb.password = Some(value.to_string()),
+            "mariadb.password_file" => self.mariadb.password_file = Some(value.into()),
+            "postgres.password" => self.postgres.password = Some(value.to_string()),
+            "postgres.password_file" => self.postgres.password_file = Some(value.into()),
+            "mqtt.password" => self.mqtt.password = Some(value.to_string()),
+            "mqtt.password_file" => self.mqtt.password_file = Some(value.into()),
+            _ => return Err(format!("unknown key `{}`", key)),
+        }
+        Ok(())
+    }
+
+    /// Keys or sections which differ in `other` and only take effect after a
+    /// restart. `queue.stats_interval` and `queue.drain_timeout` are applied
+    /// when the configuration is reloaded.
+    pub fn restart_needed(&self, other: &Config) -> Vec<&'static str> {
+        [
+            ("sinks", self.sinks != other.sinks),
+            ("server", self.server != other.server),
+            ("queue.workers", self.queue.workers != other.queue.workers),
+            (
+                "queue.channel_size",
+                self.queue.channel_size != other.queue.channel_size,
+            ),
+            (
+                "queue.spill_dir",
+                self.queue.spill_dir != other.queue.spill_dir,
+            ),
+            (
+                "queue.high_water",
+                self.queue.high_water != other.queue.high_water,
+            ),
+            ("sqlite", self.sqlite != other.sqlite),
+            ("mariadb", self.mariadb != other.mariadb),
+            ("postgres", self.postgres != other.postgres),
+            ("mqtt", self.mqtt != other.mqtt),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(key, _)| key)
+        .collect()
+    }
+
+    /// Check values which parse but cannot be used, naming the key
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |key: &str, problem: &str| Err(anyhow!("{}: {}", key, problem));
+        if self.server.hostname.trim().is_empty() {
+            return invalid("server.hostname", "must not be empty");
+        }
+        if self.server.port == 0 {
+            return invalid("server.port", "must not be 0");
+        }
+        if self.queue.workers == 0 {
+            return invalid("queue.workers", "must be at least 1");
+        }
+        if self.queue.channel_size == 0 {
+            return invalid("queue.channel_size", "must be at least 1");
+        }
+        if !(1..=100).contains(&self.queue.high_water) {
+            return invalid("queue.high_water", "must be between 1 and 100");
+        }
+        if self.sqlite.path.is_empty() {
+            return invalid("sqlite.path", "must not be empty");
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use crate::utils::generate_passcode;
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 /// Timestamp enum
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -77,6 +78,19 @@ impl Timestamp {
     }
 }
 
+impl Timestamp {
+    /// Timestamp of a line read back from the database. Only the resolved
+    /// time is stored, so whole minutes come back as `DDHHMM` and anything
+    /// else as `HHMMSS`.
+    pub fn from_stored(time: DateTime<Utc>) -> Timestamp {
+        if time.second() == 0 {
+            Timestamp::DDHHMM(time.day() as u8, time.hour() as u8, time.minute() as u8)
+        } else {
+            Timestamp::HHMMSS(time.hour() as u8, time.minute() as u8, time.second() as u8)
+        }
+    }
+}
+
 impl From<aprs_parser::Timestamp> for Timestamp {
     fn from(item: aprs_parser::Timestamp) -> Self {
         match item {
@@ -101,10 +115,10 @@ impl From<aprs_parser::AprsMessage> for ParsedAprsMessage {
         ParsedAprsMessage {
             to: format!("{}", item.to),
             addressee: std::str::from_utf8(&item.addressee)
-                .unwrap_or_else(|_| "<ERROR PARSING UTF8>")
+                .unwrap_or("<ERROR PARSING UTF8>")
                 .to_string(),
             text: std::str::from_utf8(&item.text)
-                .unwrap_or_else(|_| "<ERROR PARSING UTF8>")
+                .unwrap_or("<ERROR PARSING UTF8>")
                 .to_string(),
             id: item.id,
         }
@@ -130,10 +144,7 @@ impl From<aprs_parser::AprsPosition> for ParsedAprsPosition {
     fn from(item: aprs_parser::AprsPosition) -> Self {
         ParsedAprsPosition {
             to: format!("{}", item.to),
-            timestamp: match item.timestamp {
-                Some(x) => Some(Timestamp::from(x)),
-                None => None,
-            },
+            timestamp: item.timestamp.map(Timestamp::from),
             messaging_supported: item.messaging_supported,
             latitude: item.latitude.value(),
             longitude: item.longitude.value(),
@@ -141,7 +152,7 @@ impl From<aprs_parser::AprsPosition> for ParsedAprsPosition {
             symbol_table: item.symbol_table,
             symbol_code: item.symbol_code,
             comment: std::str::from_utf8(&item.comment)
-                .unwrap_or_else(|_| "<ERROR PARSING UTF8>")
+                .unwrap_or("<ERROR PARSING UTF8>")
                 .to_string(),
             cst: format!("{:?}", item.cst),
         }
@@ -160,12 +171,9 @@ impl From<aprs_parser::AprsStatus> for ParsedAprsStatus {
     fn from(item: aprs_parser::AprsStatus) -> Self {
         ParsedAprsStatus {
             to: format!("{}", item.to),
-            timestamp: match item.timestamp() {
-                Some(x) => Some(Timestamp::from(x.to_owned())),
-                None => None,
-            },
+            timestamp: item.timestamp().map(|x| Timestamp::from(x.to_owned())),
             comment: std::str::from_utf8(item.comment())
-                .unwrap_or_else(|_| "<ERROR PARSING UTF8>")
+                .unwrap_or("<ERROR PARSING UTF8>")
                 .to_string(),
         }
     }
@@ -217,13 +225,22 @@ impl From<aprs_parser::AprsMicE> for ParsedAprsMicE {
             symbol_table: std::char::from_u32(item.symbol_table as u32).unwrap(),
             symbol_code: std::char::from_u32(item.symbol_code as u32).unwrap(),
             comment: std::str::from_utf8(&item.comment)
-                .unwrap_or_else(|_| "<ERROR PARSING UTF8>")
+                .unwrap_or("<ERROR PARSING UTF8>")
                 .to_string(),
             current: item.current,
         }
     }
 }
 
+/// APRS packet which could not be decoded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ParsedAprsUnknown {
+    /// The line as received
+    pub raw: String,
+    /// Why the packet could not be decoded
+    pub error: String,
+}
+
 /// Parsed APRS Data
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ParsedAprsData {
@@ -231,7 +248,32 @@ pub enum ParsedAprsData {
     Message(ParsedAprsMessage),
     Status(ParsedAprsStatus),
     MicE(ParsedAprsMicE),
-    Unknown(String),
+    Unknown(ParsedAprsUnknown),
+}
+
+/// Names returned by `ParsedAprsData::type_name`
+pub const PACKET_TYPES: &[&str] = &["position", "message", "status", "mic_e", "unknown"];
+
+impl ParsedAprsData {
+    /// Name of the packet type, as stored in the `type` column of `main_data`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ParsedAprsData::Position(_) => "position",
+            ParsedAprsData::Message(_) => "message",
+            ParsedAprsData::Status(_) => "status",
+            ParsedAprsData::MicE(_) => "mic_e",
+            ParsedAprsData::Unknown(_) => "unknown",
+        }
+    }
+
+    /// Latitude and longitude of the packet, for positions and Mic-E
+    pub fn position(&self) -> Option<(f64, f64)> {
+        match self {
+            ParsedAprsData::Position(x) => Some((x.latitude, x.longitude)),
+            ParsedAprsData::MicE(x) => Some((x.latitude, x.longitude)),
+            _ => None,
+        }
+    }
 }
 
 impl From<aprs_parser::AprsData> for ParsedAprsData {
@@ -245,7 +287,10 @@ impl From<aprs_parser::AprsData> for ParsedAprsData {
             }
             aprs_parser::AprsData::Status(x) => ParsedAprsData::Status(ParsedAprsStatus::from(x)),
             aprs_parser::AprsData::MicE(x) => ParsedAprsData::MicE(ParsedAprsMicE::from(x)),
-            aprs_parser::AprsData::Unknown(x) => ParsedAprsData::Unknown(format!("{:?}", x)),
+            aprs_parser::AprsData::Unknown(x) => ParsedAprsData::Unknown(ParsedAprsUnknown {
+                raw: String::new(),
+                error: format!("Unsupported data type (to {})", x),
+            }),
         }
     }
 }
@@ -253,11 +298,44 @@ impl From<aprs_parser::AprsData> for ParsedAprsData {
 /// Parsed APRS Line
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ParsedLine {
+    /// Id the line is stored under
+    pub id: Uuid,
+    /// When the line was received from APRS-IS
+    pub received_at: DateTime<Utc>,
     pub from: String,
     pub via: Vec<String>,
     pub data: ParsedAprsData,
 }
 
+impl ParsedLine {
+    /// Wrap a line which failed to decode.
+    ///
+    /// The source callsign is taken from the text before `>` when there is
+    /// one, so undecodable packets can still be attributed to a station.
+    pub fn undecoded(raw: &str, error: String) -> ParsedLine {
+        let from = match raw.split_once('>') {
+            Some((from, _)) if !from.is_empty() && from.len() <= 32 => from.to_string(),
+            _ => String::new(),
+        };
+        ParsedLine {
+            id: Uuid::new_v4(),
+            received_at: Utc::now(),
+            from,
+            via: Vec::new(),
+            data: ParsedAprsData::Unknown(ParsedAprsUnknown {
+                raw: raw.to_string(),
+                error,
+            }),
+        }
+    }
+
+    /// The source callsign, `None` for an undecodable line without one.
+    /// Such lines are stored with a NULL source.
+    pub fn source(&self) -> Option<&str> {
+        Some(self.from.as_str()).filter(|x| !x.is_empty())
+    }
+}
+
 /// Handshake message sent from a client to a server when it first connects,
 /// identifying the client.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -0,0 +1,207 @@
+use crate::data::*;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+/// Default time a packet is remembered for duplicate suppression
+pub const DEFAULT_DEDUP_WINDOW: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Another path which delivered an already seen packet
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DuplicatePath {
+    /// Id of the first copy of the packet
+    pub id: Uuid,
+    pub via: Vec<String>,
+    /// When this copy was received from APRS-IS
+    pub received_at: DateTime<Utc>,
+}
+
+/// Result of checking a line against the dedup window
+#[derive(Debug, Clone, PartialEq)]
+pub enum Deduped {
+    /// First copy of the packet within the window
+    New(ParsedLine),
+    /// Copy of a packet seen earlier within the window
+    Duplicate(DuplicatePath),
+}
+
+/// Identity of a packet: source, destination and payload, without the path
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DedupKey {
+    from: String,
+    payload: String,
+}
+
+impl DedupKey {
+    fn new(line: &ParsedLine) -> DedupKey {
+        let payload = match &line.data {
+            // Drop the path from `SRC>DEST,PATH:info` so every copy matches
+            ParsedAprsData::Unknown(x) => match x.raw.split_once(':') {
+                Some((header, info)) => {
+                    let dest = header
+                        .split_once('>')
+                        .map(|(_, x)| x.split(',').next().unwrap_or(""))
+                        .unwrap_or(header);
+                    format!("{}:{}", dest, info)
+                }
+                None => x.raw.clone(),
+            },
+            // The decoded data holds the destination and everything from the payload
+            x => serde_json::to_string(x).unwrap_or_default(),
+        };
+        DedupKey {
+            from: line.from.clone(),
+            payload,
+        }
+    }
+}
+
+/// Suppresses copies of a packet which arrive through other igates or
+/// digipeater paths within a time window of the first copy.
+pub struct Deduplicator {
+    window: std::time::Duration,
+    seen: HashMap<DedupKey, (Uuid, DateTime<Utc>)>,
+    // First sightings in arrival order, used to expire `seen`
+    expiry: VecDeque<(DateTime<Utc>, DedupKey)>,
+}
+
+impl Deduplicator {
+    pub fn new(window: std::time::Duration) -> Deduplicator {
+        Deduplicator {
+            window,
+            seen: HashMap::new(),
+            expiry: VecDeque::new(),
+        }
+    }
+
+    /// Check a line, remembering it when it is the first copy of its packet
+    pub fn check(&mut self, line: ParsedLine) -> Deduped {
+        self.expire(line.received_at);
+        let key = DedupKey::new(&line);
+        if let Some((id, _)) = self.seen.get(&key) {
+            return Deduped::Duplicate(DuplicatePath {
+                id: *id,
+                via: line.via,
+                received_at: line.received_at,
+            });
+        }
+        self.seen.insert(key.clone(), (line.id, line.received_at));
+        self.expiry.push_back((line.received_at, key));
+        Deduped::New(line)
+    }
+
+    fn expire(&mut self, now: DateTime<Utc>) {
+        while let Some((first_seen, _)) = self.expiry.front() {
+            // Lines arriving out of order have a negative age and expire nothing
+            match now.signed_duration_since(*first_seen).to_std() {
+                Ok(age) if age >= self.window => {}
+                _ => break,
+            }
+            if let Some((_, key)) = self.expiry.pop_front() {
+                self.seen.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::parse_line_or_unknown;
+
+    fn line(raw: &str, seconds: i64) -> ParsedLine {
+        let mut line = parse_line_or_unknown(raw);
+        line.received_at = Utc.timestamp_opt(1_700_000_000 + seconds, 0).unwrap();
+        line
+    }
+
+    fn deduplicator() -> Deduplicator {
+        Deduplicator::new(std::time::Duration::from_secs(30))
+    }
+
+    #[test]
+    fn copy_via_other_path_is_duplicate() {
+        let mut dedup = deduplicator();
+        let first = line("N0CALL>APRS,WIDE1-1,qAR,IGATE1:>status", 0);
+        let id = first.id;
+        assert!(matches!(dedup.check(first), Deduped::New(_)));
+        match dedup.check(line("N0CALL>APRS,WIDE2-1,qAR,IGATE2:>status", 5)) {
+            Deduped::Duplicate(path) => {
+                assert_eq!(path.id, id);
+                assert!(path.via.contains(&"IGATE2".to_string()));
+            }
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn other_source_or_payload_is_new() {
+        let mut dedup = deduplicator();
+        dedup.check(line("N0CALL>APRS:>status", 0));
+        assert!(matches!(
+            dedup.check(line("N1CALL>APRS:>status", 1)),
+            Deduped::New(_)
+        ));
+        assert!(matches!(
+            dedup.check(line("N0CALL>APRS:>other", 2)),
+            Deduped::New(_)
+        ));
+    }
+
+    #[test]
+    fn copies_of_undecodable_packets_ignore_the_path() {
+        let mut dedup = deduplicator();
+        dedup.check(line("N0CALL>APRS,qAR,IGATE1:}garbage", 0));
+        assert!(matches!(
+            dedup.check(line("N0CALL>APRS,qAR,IGATE2:}garbage", 1)),
+            Deduped::Duplicate(_)
+        ));
+        assert!(matches!(
+            dedup.check(line("N0CALL>APZZZ,qAR,IGATE2:}garbage", 2)),
+            Deduped::New(_)
+        ));
+    }
+
+    #[test]
+    fn packet_is_forgotten_after_the_window() {
+        let mut dedup = deduplicator();
+        dedup.check(line("N0CALL>APRS:>status", 0));
+        assert!(matches!(
+            dedup.check(line("N0CALL>APRS:>status", 29)),
+            Deduped::Duplicate(_)
+        ));
+        // The window counts from the first copy, not the latest
+        assert!(matches!(
+            dedup.check(line("N0CALL>APRS:>status", 30)),
+            Deduped::New(_)
+        ));
+    }
+
+    #[test]
+    fn late_line_expires_nothing() {
+        let mut dedup = deduplicator();
+        dedup.check(line("N0CALL>APRS:>status", 100));
+        dedup.check(line("N1CALL>APRS:>status", 60));
+        assert!(matches!(
+            dedup.check(line("N0CALL>APRS:>status", 110)),
+            Deduped::Duplicate(_)
+        ));
+    }
+
+    #[test]
+    fn entries_expire_in_arrival_order() {
+        let mut dedup = deduplicator();
+        dedup.check(line("N0CALL>APRS:>a", 0));
+        dedup.check(line("N0CALL>APRS:>b", 20));
+        dedup.check(line("N0CALL>APRS:>c", 40));
+        assert_eq!(dedup.seen.len(), 2);
+        assert!(matches!(
+            dedup.check(line("N0CALL>APRS:>b", 45)),
+            Deduped::Duplicate(_)
+        ));
+        dedup.check(line("N0CALL>APRS:>d", 55));
+        assert_eq!(dedup.seen.len(), 2);
+        assert_eq!(dedup.expiry.len(), 2);
+    }
+}
@@ -0,0 +1,416 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// Area given as `WEST,SOUTH,EAST,NORTH` in degrees, the order of a GeoJSON
+/// `bbox`. Areas crossing the antimeridian are not supported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl BoundingBox {
+    pub const WORLD: BoundingBox = BoundingBox {
+        west: -180.0,
+        south: -90.0,
+        east: 180.0,
+        north: 90.0,
+    };
+
+    /// Whether a point lies inside the box, edges included
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        (self.south..=self.north).contains(&latitude)
+            && (self.west..=self.east).contains(&longitude)
+    }
+}
+
+/// Parse a `WEST,SOUTH,EAST,NORTH` bounding box, e.g. `-97.5,43.5,-89.5,49.4`
+pub fn parse_bounding_box(value: &str) -> Result<BoundingBox, String> {
+    let corners: Vec<f64> = value
+        .split(',')
+        .map(|x| x.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid number in `{}`", value))?;
+    let [west, south, east, north] = corners[..] else {
+        return Err(format!("expected WEST,SOUTH,EAST,NORTH, got `{}`", value));
+    };
+    if !(-90.0..=90.0).contains(&south) || !(-90.0..=90.0).contains(&north) || south > north {
+        return Err(format!("invalid latitudes in `{}`", value));
+    }
+    if !(-180.0..=180.0).contains(&west) || !(-180.0..=180.0).contains(&east) || west > east {
+        return Err(format!("invalid longitudes in `{}`", value));
+    }
+    Ok(BoundingBox {
+        west,
+        south,
+        east,
+        north,
+    })
+}
+
+/// Which stored positions to export
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExportFilter {
+    /// Callsign pattern where `*` matches any run of characters and `?` a
+    /// single one, e.g. `K0HAX-*`
+    pub callsign: Option<String>,
+    /// First receive time included
+    pub start: Option<DateTime<Utc>>,
+    /// Receive time up to which positions are included, exclusive
+    pub end: Option<DateTime<Utc>>,
+    pub bounding_box: Option<BoundingBox>,
+}
+
+impl ExportFilter {
+    /// The callsign pattern as a SQL `LIKE` pattern which uses `!` as its
+    /// escape character
+    pub fn callsign_like(&self) -> String {
+        let Some(pattern) = &self.callsign else {
+            return "%".to_string();
+        };
+        let mut like = String::with_capacity(pattern.len());
+        for c in pattern.chars() {
+            match c {
+                '*' => like.push('%'),
+                '?' => like.push('_'),
+                '%' | '_' | '!' => {
+                    like.push('!');
+                    like.push(c);
+                }
+                c => like.push(c),
+            }
+        }
+        like
+    }
+
+    /// The bounding box, or the whole world when there is none
+    pub fn bounds(&self) -> BoundingBox {
+        self.bounding_box.unwrap_or(BoundingBox::WORLD)
+    }
+}
+
+/// A stored position with what is needed to draw it on a map
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PositionRecord {
+    pub callsign: String,
+    /// When the packet was received
+    pub time: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub symbol_table: char,
+    pub symbol_code: char,
+    pub comment: String,
+}
+
+/// Columns of a position as read back by the backends, in the order
+/// callsign, time, latitude, longitude, symbol_table, symbol_code and comment.
+/// The time is RFC 3339.
+pub type PositionColumns = (String, String, f64, f64, String, String, String);
+
+impl PositionRecord {
+    pub fn from_columns(columns: PositionColumns) -> anyhow::Result<PositionRecord> {
+        let (callsign, time, latitude, longitude, symbol_table, symbol_code, comment) = columns;
+        Ok(PositionRecord {
+            callsign,
+            time: DateTime::parse_from_rfc3339(&time)?.with_timezone(&Utc),
+            latitude,
+            longitude,
+            symbol_table: symbol_table.chars().next().unwrap_or(' '),
+            symbol_code: symbol_code.chars().next().unwrap_or(' '),
+            comment,
+        })
+    }
+}
+
+/// Positions grouped by station, each in receive order
+fn by_station(positions: &[PositionRecord]) -> BTreeMap<&str, Vec<&PositionRecord>> {
+    let mut stations: BTreeMap<&str, Vec<&PositionRecord>> = BTreeMap::new();
+    for position in positions {
+        stations
+            .entry(&position.callsign)
+            .or_default()
+            .push(position);
+    }
+    for track in stations.values_mut() {
+        track.sort_by_key(|x| x.time);
+    }
+    stations
+}
+
+fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Not allowed in XML 1.0, and APRS comments do contain them
+            c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn xml_time(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Write GPX 1.1 with one track per station and a waypoint at its last
+/// position, carrying the APRS symbol as `sym`
+pub fn write_gpx<W: Write>(out: &mut W, positions: &[PositionRecord]) -> io::Result<()> {
+    let stations = by_station(positions);
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<gpx version="1.1" creator="k0hax-aprs" xmlns="http://www.topografix.com/GPX/1/1">"#
+    )?;
+    // GPX wants every waypoint before the first track
+    for (callsign, track) in &stations {
+        let Some(last) = track.last() else {
+            continue;
+        };
+        writeln!(
+            out,
+            r#"  <wpt lat="{}" lon="{}">"#,
+            last.latitude, last.longitude
+        )?;
+        writeln!(out, "    <time>{}</time>", xml_time(&last.time))?;
+        writeln!(out, "    <name>{}</name>", xml_escape(callsign))?;
+        if !last.comment.is_empty() {
+            writeln!(out, "    <desc>{}</desc>", xml_escape(&last.comment))?;
+        }
+        writeln!(
+            out,
+            "    <sym>{}</sym>",
+            xml_escape(&format!("{}{}", last.symbol_table, last.symbol_code))
+        )?;
+        writeln!(out, "  </wpt>")?;
+    }
+    for (callsign, track) in &stations {
+        writeln!(out, "  <trk>")?;
+        writeln!(out, "    <name>{}</name>", xml_escape(callsign))?;
+        writeln!(out, "    <trkseg>")?;
+        for point in track {
+            writeln!(
+                out,
+                r#"      <trkpt lat="{}" lon="{}"><time>{}</time></trkpt>"#,
+                point.latitude,
+                point.longitude,
+                xml_time(&point.time)
+            )?;
+        }
+        writeln!(out, "    </trkseg>")?;
+        writeln!(out, "  </trk>")?;
+    }
+    writeln!(out, "</gpx>")
+}
+
+/// Id of the KML style of an APRS symbol, e.g. `sym-2f-3e` for `/>`
+fn kml_style_id(symbol_table: char, symbol_code: char) -> String {
+    format!("sym-{:02x}-{:02x}", symbol_table as u32, symbol_code as u32)
+}
+
+/// Write KML with a folder per station holding a timestamped placemark for
+/// every position and a track line.
+///
+/// Each APRS symbol gets a shared style. When `icon_url` is given its
+/// `{table}` and `{code}` are replaced by the hex codes of the symbol
+/// characters to build the icon link, e.g.
+/// `https://example.org/aprs/{table}/{code}.png`.
+pub fn write_kml<W: Write>(
+    out: &mut W,
+    positions: &[PositionRecord],
+    icon_url: Option<&str>,
+) -> io::Result<()> {
+    let stations = by_station(positions);
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#)?;
+    writeln!(out, "<Document>")?;
+    writeln!(out, "  <name>APRS positions</name>")?;
+    let mut symbols: Vec<(char, char)> = positions
+        .iter()
+        .map(|x| (x.symbol_table, x.symbol_code))
+        .collect();
+    symbols.sort_unstable();
+    symbols.dedup();
+    for (table, code) in symbols {
+        writeln!(out, r#"  <Style id="{}">"#, kml_style_id(table, code))?;
+        writeln!(out, "    <IconStyle>")?;
+        if let Some(icon_url) = icon_url {
+            let href = icon_url
+                .replace("{table}", &format!("{:02x}", table as u32))
+                .replace("{code}", &format!("{:02x}", code as u32));
+            writeln!(out, "      <Icon><href>{}</href></Icon>", xml_escape(&href))?;
+        }
+        writeln!(out, "    </IconStyle>")?;
+        writeln!(out, "  </Style>")?;
+    }
+    for (callsign, track) in &stations {
+        writeln!(out, "  <Folder>")?;
+        writeln!(out, "    <name>{}</name>", xml_escape(callsign))?;
+        for point in track {
+            writeln!(out, "    <Placemark>")?;
+            writeln!(out, "      <name>{}</name>", xml_escape(callsign))?;
+            if !point.comment.is_empty() {
+                writeln!(
+                    out,
+                    "      <description>{}</description>",
+                    xml_escape(&point.comment)
+                )?;
+            }
+            writeln!(
+                out,
+                "      <TimeStamp><when>{}</when></TimeStamp>",
+                xml_time(&point.time)
+            )?;
+            writeln!(
+                out,
+                "      <styleUrl>#{}</styleUrl>",
+                kml_style_id(point.symbol_table, point.symbol_code)
+            )?;
+            writeln!(
+                out,
+                "      <Point><coordinates>{},{}</coordinates></Point>",
+                point.longitude, point.latitude
+            )?;
+            writeln!(out, "    </Placemark>")?;
+        }
+        if track.len() > 1 {
+            writeln!(out, "    <Placemark>")?;
+            writeln!(out, "      <name>{} track</name>", xml_escape(callsign))?;
+            write!(out, "      <LineString><coordinates>")?;
+            for point in track {
+                write!(out, "{},{} ", point.longitude, point.latitude)?;
+            }
+            writeln!(out, "</coordinates></LineString>")?;
+            writeln!(out, "    </Placemark>")?;
+        }
+        writeln!(out, "  </Folder>")?;
+    }
+    writeln!(out, "</Document>")?;
+    writeln!(out, "</kml>")
+}
+
+/// Write a GeoJSON FeatureCollection with a point feature for every position
+pub fn write_geojson<W: Write>(out: &mut W, positions: &[PositionRecord]) -> io::Result<()> {
+    let features: Vec<serde_json::Value> = positions
+        .iter()
+        .map(|x| {
+            serde_json::json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [x.longitude, x.latitude],
+                },
+                "properties": {
+                    "callsign": x.callsign,
+                    "time": x.time,
+                    "symbol_table": x.symbol_table,
+                    "symbol_code": x.symbol_code,
+                    "comment": x.comment,
+                },
+            })
+        })
+        .collect();
+    let collection = serde_json::json!({
+        "type": "FeatureCollection",
+        "features": features,
+    });
+    serde_json::to_writer(&mut *out, &collection)?;
+    writeln!(out)
+}
+
+/// Write one JSON document per position
+pub fn write_ndjson<W: Write>(out: &mut W, positions: &[PositionRecord]) -> io::Result<()> {
+    for position in positions {
+        serde_json::to_writer(&mut *out, position)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Write CSV with a header row and the fields of `PositionRecord` as columns
+pub fn write_csv<W: Write>(out: &mut W, positions: &[PositionRecord]) -> io::Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    for position in positions {
+        writer.serialize(position)?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(callsign: &str) -> ExportFilter {
+        ExportFilter {
+            callsign: Some(callsign.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bounding_box_is_parsed_in_geojson_order() {
+        assert_eq!(
+            parse_bounding_box("-97.5, 43.5,-89.5,49.4"),
+            Ok(BoundingBox {
+                west: -97.5,
+                south: 43.5,
+                east: -89.5,
+                north: 49.4,
+            })
+        );
+        let bounds = parse_bounding_box("-180,-90,180,90").unwrap();
+        assert_eq!(bounds, BoundingBox::WORLD);
+        assert!(bounds.contains(90.0, -180.0));
+    }
+
+    #[test]
+    fn invalid_bounding_boxes_are_rejected() {
+        for value in [
+            "",
+            "1,2,3",
+            "1,2,3,4,5",
+            "a,2,3,4",
+            // South above north, west beyond east
+            "0,10,1,5",
+            "10,0,5,1",
+            "0,-91,1,0",
+            "-181,0,0,1",
+        ] {
+            assert!(parse_bounding_box(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn callsign_pattern_becomes_like_pattern() {
+        assert_eq!(ExportFilter::default().callsign_like(), "%");
+        assert_eq!(filter("K0HAX-*").callsign_like(), "K0HAX-%");
+        assert_eq!(filter("K0HAX-?").callsign_like(), "K0HAX-_");
+    }
+
+    #[test]
+    fn like_wildcards_and_escape_are_escaped() {
+        assert_eq!(filter("A%B_C!D").callsign_like(), "A!%B!_C!!D");
+    }
+
+    #[test]
+    fn xml_special_characters_are_escaped() {
+        assert_eq!(
+            xml_escape(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn control_characters_are_dropped_from_xml() {
+        assert_eq!(xml_escape("a\u{0}b\u{1b}c\td\r\n"), "abc\td\r\n");
+    }
+}
@@ -1,7 +1,33 @@
+pub mod api;
+pub mod archive;
 pub mod client;
+pub mod config;
 pub mod data;
+pub mod dedup;
+pub mod export;
+pub mod live;
+pub mod mariadb;
+pub mod metrics;
+pub mod migrations;
+pub mod mock;
+pub mod mqtt;
+pub mod output;
+pub mod postgres;
+pub mod query;
+pub mod queue;
+pub mod rawlog;
+pub mod reprocess;
+pub mod retention;
+pub mod server;
+pub mod spool;
+pub mod sqlite;
+pub mod stations;
+pub mod systemd;
+pub mod track;
 pub mod utils;
 
 pub use crate::client::*;
 pub use crate::data::*;
+pub use crate::dedup::*;
+pub use crate::stations::*;
 pub use crate::utils::*;
@@ -0,0 +1,218 @@
+use crate::data::*;
+use crate::export::{parse_bounding_box, BoundingBox};
+use crate::metrics::METRICS;
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use log::{debug, warn};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Lines a client of the live feed or the APRS-IS server may fall behind
+/// before it is disconnected
+pub const CLIENT_BUFFER: usize = 1024;
+
+/// Time a client gets to accept one message or line before it is disconnected
+pub(crate) const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A line with its JSON form, serialized once for all clients
+struct FeedItem {
+    line: ParsedLine,
+    json: String,
+}
+
+/// Broadcasts parsed lines to the WebSocket clients of `router`.
+///
+/// Every client reads from its own position in a shared ring of
+/// `buffer` lines, so publishing never waits and a client which falls
+/// further behind than that is dropped.
+#[derive(Clone)]
+pub struct LiveFeed {
+    tx: broadcast::Sender<Arc<FeedItem>>,
+}
+
+impl LiveFeed {
+    pub fn new(buffer: usize) -> LiveFeed {
+        let (tx, _) = broadcast::channel(buffer);
+        LiveFeed { tx }
+    }
+
+    /// Send a line to every client, doing nothing when there are none
+    pub fn publish(&self, line: &ParsedLine) {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+        let json = match serde_json::to_string(line) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Could not serialize line for the live feed: {}", e);
+                return;
+            }
+        };
+        let _ = self.tx.send(Arc::new(FeedItem {
+            line: line.clone(),
+            json,
+        }));
+    }
+}
+
+/// Which lines a client receives
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LiveFilter {
+    /// Names from `PACKET_TYPES`, any type when empty
+    pub types: Vec<String>,
+    /// Start of the source callsign, ignoring case, any station when empty
+    pub callsign_prefixes: Vec<String>,
+    /// Only lines with a position inside this box
+    pub bounding_box: Option<BoundingBox>,
+}
+
+impl LiveFilter {
+    pub fn matches(&self, line: &ParsedLine) -> bool {
+        if !self.types.is_empty() && !self.types.iter().any(|x| x == line.data.type_name()) {
+            return false;
+        }
+        if !self.callsign_prefixes.is_empty() {
+            let from = line.from.to_uppercase();
+            if !self.callsign_prefixes.iter().any(|x| from.starts_with(x)) {
+                return false;
+            }
+        }
+        match (&self.bounding_box, line.data.position()) {
+            (None, _) => true,
+            (Some(bounds), Some((latitude, longitude))) => bounds.contains(latitude, longitude),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Filters given when connecting, each a comma separated list
+#[derive(Deserialize, Debug, Default)]
+struct Params {
+    #[serde(rename = "type")]
+    types: Option<String>,
+    callsign: Option<String>,
+    bbox: Option<String>,
+}
+
+fn split_list(value: &Option<String>) -> Vec<String> {
+    value
+        .iter()
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+impl Params {
+    fn filter(&self) -> Result<LiveFilter, String> {
+        let types = split_list(&self.types);
+        if let Some(x) = types.iter().find(|x| !PACKET_TYPES.contains(&x.as_str())) {
+            return Err(format!(
+                "type: unknown packet type `{}` (use {})",
+                x,
+                PACKET_TYPES.join(", ")
+            ));
+        }
+        Ok(LiveFilter {
+            types,
+            callsign_prefixes: split_list(&self.callsign)
+                .into_iter()
+                .map(|x| x.to_uppercase())
+                .collect(),
+            bounding_box: self
+                .bbox
+                .as_deref()
+                .map(parse_bounding_box)
+                .transpose()
+                .map_err(|e| format!("bbox: {}", e))?,
+        })
+    }
+}
+
+async fn subscribe(
+    State(feed): State<LiveFeed>,
+    Query(params): Query<Params>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let filter = match params.filter() {
+        Ok(x) => x,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "error": e })),
+            )
+                .into_response()
+        }
+    };
+    let rx = feed.tx.subscribe();
+    ws.on_upgrade(move |socket| serve_client(socket, rx, filter))
+}
+
+async fn serve_client(
+    mut socket: WebSocket,
+    mut rx: broadcast::Receiver<Arc<FeedItem>>,
+    filter: LiveFilter,
+) {
+    METRICS.live_clients.inc();
+    debug!("Live client connected with {:?}", filter);
+    loop {
+        tokio::select! {
+            item = rx.recv() => match item {
+                Ok(item) => {
+                    if !filter.matches(&item.line) {
+                        continue;
+                    }
+                    let sent = tokio::time::timeout(
+                        SEND_TIMEOUT,
+                        socket.send(Message::Text(item.json.clone())),
+                    )
+                    .await;
+                    match sent {
+                        Ok(Ok(())) => {}
+                        Ok(Err(_)) => break,
+                        Err(_) => {
+                            warn!("Dropping live client which stopped reading");
+                            METRICS.live_dropped.inc();
+                            break;
+                        }
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Dropping live client {} lines behind", missed);
+                    METRICS.live_dropped.inc();
+                    let _ = socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "client too slow".into(),
+                        })))
+                        .await;
+                    break;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            // Clients only listen; anything they send besides a close is ignored
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    METRICS.live_clients.dec();
+    debug!("Live client disconnected");
+}
+
+/// Route serving the feed as a WebSocket at `GET /live`. Each text message
+/// is one `ParsedLine` as JSON. Clients may narrow the feed when connecting,
+/// e.g. `/live?type=position,mic_e&callsign=K0HAX,N0&bbox=-97.5,43.5,-89.5,49.4`,
+/// where `callsign` lists prefixes.
+pub fn router(feed: LiveFeed) -> Router {
+    Router::new()
+        .route("/live", get(subscribe))
+        .with_state(feed)
+}
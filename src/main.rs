@@ -1,19 +1,22 @@
 use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
-use ctrlc;
-use log::{error, info};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::sync::{watch, Mutex, RwLock};
 use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
 
 use std::error::Error;
 
 // Error handling
 use anyhow::Result;
+use chrono::prelude::*;
 
-mod sqlite;
-use sqlite::SqliteDb;
-
-mod mariadb;
+use libk0hax_aprs::sqlite::SqliteDb;
+use libk0hax_aprs::{
+    api, archive, config, export, live, mariadb, metrics, migrations, mqtt, output, postgres,
+    queue, rawlog, retention, server, spool, sqlite, systemd, track,
+};
 
 /// Timestamp enum for logging
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -56,8 +59,17 @@ pub enum LogTimestamp {
 #[derive(Parser, Debug)]
 #[clap(version, about, verbatim_doc_comment)]
 struct Cli {
-    /// Callsign to connect using
-    callsign: String,
+    /// Callsign to connect using (not needed for subcommands which exit or with `--replay`)
+    callsign: Option<String>,
+
+    /// TOML configuration file [default: $K0HAX_APRS_CONFIG]
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<std::path::PathBuf>,
+
+    /// Serve Prometheus metrics at /metrics on this address, e.g. `127.0.0.1:9100`,
+    /// the live WebSocket feed at /live and the JSON API at /api when saving to a database
+    #[arg(long, value_name = "ADDR")]
+    http_listen: Option<std::net::SocketAddr>,
 
     /// Increase message verbosity
     #[arg(short, long, action = clap::ArgAction::Count)]
@@ -71,18 +83,416 @@ struct Cli {
     #[arg(short, long, default_value_t = LogTimestamp::none, value_enum)]
     timestamp: LogTimestamp,
 
+    /// Seconds a packet is remembered to suppress copies arriving via other paths (0 disables)
+    #[arg(long, default_value_t = libk0hax_aprs::dedup::DEFAULT_DEDUP_WINDOW.as_secs())]
+    dedup_window: u64,
+
+    #[command(flatten)]
+    maintenance: MaintenanceSettings,
+
+    #[command(flatten)]
+    source: SourceSettings,
+
+    #[command(flatten)]
+    aprs_is: AprsIsSettings,
+
+    #[command(flatten)]
+    queue: SinkQueueSettings,
+
+    /// Also feed this sink, written as the arguments of a saving subcommand
+    /// with its own queue options, e.g. `--sink "archive /var/lib/aprs --overflow drop-newest"`
+    /// (repeatable, added to the `sinks` of the configuration)
+    #[arg(long = "sink", value_name = "SPEC", value_parser = parse_sink_spec)]
+    sinks: Vec<SinkSpec>,
+
     /// Database Mode
     #[command(subcommand)]
     database_mode: DatabaseMode,
 }
 
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Subcommand, Debug)]
+#[derive(Clone, PartialEq, Subcommand, Debug)]
 enum DatabaseMode {
     /// Save data in Sqlite3
     Sqlite3,
 
     /// Save data in MariaDB
     Mariadb(MariaDbSettings),
+
+    /// Save data in PostgreSQL with PostGIS
+    Postgres(PostgresSettings),
+
+    /// Upgrade a database schema to the version of this binary and exit
+    Migrate(MigrateSettings),
+
+    /// Decode stored unknown packets again with the current parser and exit
+    Reprocess(ReprocessSettings),
+
+    /// Print the track of a station with movement statistics and exit
+    Track(TrackSettings),
+
+    /// Write stored positions as GPX, KML, GeoJSON, NDJSON or CSV and exit
+    Export(ExportSettings),
+
+    /// Save data in rotating NDJSON or CSV files instead of a database
+    Archive(ArchiveSettings),
+
+    /// Print packets to standard output instead of saving them
+    Stdout(StdoutSettings),
+
+    /// Publish packets as JSON to an MQTT broker instead of saving them
+    Mqtt(MqttSettings),
+
+    /// Serve the JSON API of a database on --http-listen without connecting to APRS-IS
+    Serve(ServeSettings),
+}
+
+impl DatabaseMode {
+    /// Name of the subcommand, used to name its sink
+    fn name(&self) -> &'static str {
+        match self {
+            DatabaseMode::Sqlite3 => "sqlite3",
+            DatabaseMode::Mariadb(_) => "mariadb",
+            DatabaseMode::Postgres(_) => "postgres",
+            DatabaseMode::Migrate(_) => "migrate",
+            DatabaseMode::Reprocess(_) => "reprocess",
+            DatabaseMode::Track(_) => "track",
+            DatabaseMode::Export(_) => "export",
+            DatabaseMode::Archive(_) => "archive",
+            DatabaseMode::Stdout(_) => "stdout",
+            DatabaseMode::Mqtt(_) => "mqtt",
+            DatabaseMode::Serve(_) => "serve",
+        }
+    }
+
+    /// Whether the subcommand consumes the feed rather than running and exiting
+    fn is_sink(&self) -> bool {
+        matches!(
+            self,
+            DatabaseMode::Sqlite3
+                | DatabaseMode::Mariadb(_)
+                | DatabaseMode::Postgres(_)
+                | DatabaseMode::Archive(_)
+                | DatabaseMode::Stdout(_)
+                | DatabaseMode::Mqtt(_)
+        )
+    }
+}
+
+/// Queue of one sink, defaulting to the `queue` section of the configuration
+#[derive(Args, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct SinkQueueSettings {
+    /// Tasks writing to the database [default: queue.workers]
+    #[arg(long, global = true, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    workers: Option<usize>,
+
+    /// Lines queued for the sink [default: queue.channel_size]
+    #[arg(long, global = true, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    queue_size: Option<usize>,
+
+    /// What happens to lines while the queue is full
+    #[arg(long, global = true, value_enum, default_value_t = queue::OverflowPolicy::Block)]
+    overflow: queue::OverflowPolicy,
+}
+
+/// A sink given with `--sink` or in the configuration
+#[derive(Parser, Clone, PartialEq, Debug)]
+#[command(name = "--sink", no_binary_name = true)]
+struct SinkSpec {
+    #[command(flatten)]
+    queue: SinkQueueSettings,
+
+    #[command(subcommand)]
+    mode: DatabaseMode,
+}
+
+fn parse_sink_spec(value: &str) -> Result<SinkSpec, String> {
+    let words = shlex::split(value).ok_or_else(|| format!("unbalanced quotes in `{}`", value))?;
+    let spec = SinkSpec::try_parse_from(words).map_err(|e| {
+        // Only the problem and usage, the outer error adds the rest
+        let message = e.render().to_string();
+        let message = message
+            .split("\n\nFor more information")
+            .next()
+            .unwrap_or("");
+        message.trim_start_matches("error: ").trim_end().to_string()
+    })?;
+    if !spec.mode.is_sink() {
+        return Err(format!("`{}` cannot be used as a sink", spec.mode.name()));
+    }
+    Ok(spec)
+}
+
+#[derive(Args, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct MigrateSettings {
+    /// Only print the current and latest schema versions
+    #[clap(long, action=ArgAction::SetTrue)]
+    status: bool,
+
+    /// Database to migrate
+    #[command(subcommand)]
+    database: DatabaseTarget,
+}
+
+#[derive(Args, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct ReprocessSettings {
+    /// Database holding the packets to decode again
+    #[command(subcommand)]
+    database: DatabaseTarget,
+}
+
+#[derive(Args, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct TrackSettings {
+    /// Station to build the track of
+    station: String,
+
+    /// Start the track this long ago, e.g. `12h` (ignored with --start)
+    #[arg(long, value_name = "AGE", default_value = "1d", value_parser = retention::parse_duration)]
+    since: Duration,
+
+    /// Start the track at this RFC 3339 time
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    start: Option<DateTime<Utc>>,
+
+    /// End the track at this RFC 3339 time instead of now
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    end: Option<DateTime<Utc>>,
+
+    /// Silence after which a new segment is started
+    #[arg(long, value_name = "INTERVAL", default_value = "30m", value_parser = retention::parse_duration)]
+    max_gap: Duration,
+
+    /// Reject fixes which would need a higher speed than this, in km/h
+    #[arg(long, value_name = "KMH", default_value_t = 1000)]
+    max_speed: u32,
+
+    /// Distance within which the station counts as stopped, in meters
+    #[arg(long, value_name = "METERS", default_value_t = 50)]
+    stop_radius: u32,
+
+    /// Minimum time for a stop
+    #[arg(long, value_name = "INTERVAL", default_value = "5m", value_parser = retention::parse_duration)]
+    stop_duration: Duration,
+
+    /// Print the track as JSON instead of a summary
+    #[clap(long, action=ArgAction::SetTrue)]
+    json: bool,
+
+    /// Database holding the positions
+    #[command(subcommand)]
+    database: DatabaseTarget,
+}
+
+impl TrackSettings {
+    fn options(&self) -> track::TrackOptions {
+        track::TrackOptions {
+            max_gap: self.max_gap,
+            max_speed_kmh: self.max_speed as f64,
+            stop_radius_m: self.stop_radius as f64,
+            min_stop_duration: self.stop_duration,
+        }
+    }
+}
+
+#[derive(Args, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct ServeSettings {
+    /// Database to serve
+    #[command(subcommand)]
+    database: DatabaseTarget,
+}
+
+#[derive(Args, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct ArchiveSettings {
+    /// Directory the files are written to
+    directory: std::path::PathBuf,
+
+    /// Start of every file name
+    #[arg(long, default_value = "aprs")]
+    prefix: String,
+
+    /// Record format
+    #[arg(long, value_enum, default_value_t = archive::ArchiveFormat::Ndjson)]
+    format: archive::ArchiveFormat,
+
+    /// Compression of the files
+    #[arg(long, value_enum, default_value_t = archive::Compression::None)]
+    compression: archive::Compression,
+
+    /// How often new files are started
+    #[arg(long, value_enum, default_value_t = archive::Rotation::Daily)]
+    rotate: archive::Rotation,
+}
+
+#[derive(Args, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct StdoutSettings {
+    /// Output format
+    #[arg(long, value_enum, default_value_t = output::OutputFormat::Human)]
+    format: output::OutputFormat,
+
+    /// Only print packets of this type, may be repeated
+    #[arg(long = "type", value_name = "TYPE", value_parser = clap::builder::PossibleValuesParser::new(libk0hax_aprs::data::PACKET_TYPES.iter().copied()))]
+    types: Vec<String>,
+
+    /// Only print packets from stations matching this pattern, may be repeated
+    /// (`*` matches any run of characters and `?` a single one)
+    #[arg(long = "callsign", value_name = "PATTERN")]
+    callsigns: Vec<String>,
+}
+
+#[derive(Args, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct MqttSettings {
+    /// MQTT broker host
+    host: String,
+
+    /// Broker port [default: 1883, or 8883 with TLS]
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Client id [default: k0hax-aprs-<pid>]
+    #[arg(long)]
+    client_id: Option<String>,
+
+    /// Username, the password is read from the `mqtt` section of the configuration
+    #[arg(long)]
+    username: Option<String>,
+
+    /// Quality of service of the messages
+    #[arg(long, value_enum, default_value_t = mqtt::MqttQos::AtMostOnce)]
+    qos: mqtt::MqttQos,
+
+    /// First level of the topics, giving `<prefix>/<type>/<callsign>`
+    #[arg(long, default_value = "aprs")]
+    topic_prefix: String,
+
+    /// Also publish positions to `<prefix>/last_position/<callsign>` as retained messages
+    #[clap(long, action=ArgAction::SetTrue)]
+    retain_positions: bool,
+
+    /// Connect with TLS, trusting the system certificates unless --ca-file is given
+    #[clap(long, action=ArgAction::SetTrue)]
+    tls: bool,
+
+    /// CA certificate of the broker in PEM format (implies --tls)
+    #[arg(long, value_name = "FILE")]
+    ca_file: Option<std::path::PathBuf>,
+
+    /// Client certificate in PEM format (needs --ca-file and --client-key)
+    #[arg(long, value_name = "FILE", requires_all = ["ca_file", "client_key"])]
+    client_cert: Option<std::path::PathBuf>,
+
+    /// Client private key in PEM format
+    #[arg(long, value_name = "FILE", requires = "client_cert")]
+    client_key: Option<std::path::PathBuf>,
+}
+
+impl MqttSettings {
+    fn options(&self, config: &config::Config) -> Result<mqtt::MqttSinkOptions> {
+        let tls = (self.tls || self.ca_file.is_some()).then(|| mqtt::MqttTls {
+            ca_file: self.ca_file.clone(),
+            client_auth: self.client_cert.clone().zip(self.client_key.clone()),
+        });
+        Ok(mqtt::MqttSinkOptions {
+            host: self.host.clone(),
+            port: self.port.unwrap_or(if tls.is_some() { 8883 } else { 1883 }),
+            client_id: self
+                .client_id
+                .clone()
+                .unwrap_or_else(|| format!("k0hax-aprs-{}", std::process::id())),
+            username: self.username.clone(),
+            password: config.mqtt.password("mqtt")?,
+            tls,
+            qos: self.qos,
+            topic_prefix: self.topic_prefix.clone(),
+            retain_positions: self.retain_positions,
+        })
+    }
+}
+
+/// File formats of `export`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum ExportFormat {
+    /// GPX tracks with a waypoint at the last position of each station
+    Gpx,
+
+    /// KML placemarks with timestamps, symbol styles and track lines
+    Kml,
+
+    /// GeoJSON FeatureCollection of points
+    Geojson,
+
+    /// One JSON document per position
+    Ndjson,
+
+    /// CSV with a header row
+    Csv,
+}
+
+#[derive(Args, Clone, PartialEq, Debug)]
+struct ExportSettings {
+    /// Output format
+    #[arg(long, value_enum)]
+    format: ExportFormat,
+
+    /// Only export stations matching this pattern, `*` and `?` are wildcards
+    #[arg(long, value_name = "PATTERN")]
+    callsign: Option<String>,
+
+    /// Only export positions received at or after this RFC 3339 time
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    start: Option<DateTime<Utc>>,
+
+    /// Only export positions received before this RFC 3339 time
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    end: Option<DateTime<Utc>>,
+
+    /// Only export positions inside WEST,SOUTH,EAST,NORTH (degrees)
+    #[arg(long, value_name = "BBOX", allow_hyphen_values = true, value_parser = export::parse_bounding_box)]
+    bbox: Option<export::BoundingBox>,
+
+    /// KML icon link, `{table}` and `{code}` are replaced by the hex codes of the symbol
+    #[arg(long, value_name = "URL")]
+    icon_url: Option<String>,
+
+    /// File to write to instead of stdout
+    #[arg(long, short, value_name = "FILE")]
+    output: Option<std::path::PathBuf>,
+
+    /// Compress the output
+    #[arg(long, value_enum, default_value_t = archive::Compression::None)]
+    compression: archive::Compression,
+
+    /// Database holding the positions
+    #[command(subcommand)]
+    database: DatabaseTarget,
+}
+
+impl ExportSettings {
+    fn filter(&self) -> export::ExportFilter {
+        export::ExportFilter {
+            callsign: self.callsign.clone(),
+            start: self.start,
+            end: self.end,
+            bounding_box: self.bbox,
+        }
+    }
+}
+
+/// Parse an RFC 3339 time such as `2024-05-01T12:00:00Z`
+fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|x| x.with_timezone(&Utc))
+        .map_err(|e| format!("invalid time `{}`: {}", value, e))
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Subcommand, Debug)]
+enum DatabaseTarget {
+    /// Use the Sqlite3 database
+    Sqlite3,
+
+    /// Use a MariaDB database
+    Mariadb(MariaDbSettings),
+
+    /// Use a PostgreSQL database
+    Postgres(PostgresSettings),
 }
 
 #[derive(Args, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
@@ -97,91 +507,458 @@ struct MariaDbSettings {
     #[arg(default_value = "aprs")]
     database: String,
 
-    /// Drop (if exists) and create tables
+    /// Drop all tables (if they exist) before migrating to the current schema
     #[clap(long, short, action=ArgAction::SetTrue)]
     create_tables: bool,
+
+    /// Maximum number of pooled connections
+    #[arg(long, default_value_t = 3)]
+    pool_size: u32,
+
+    /// Maximum number of lines written per multi-row insert
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u16).range(1..=mariadb::MAX_BATCH_SIZE as i64))]
+    batch_size: u16,
+
+    /// Number of times a transient database error is retried before a batch is spooled or dropped
+    #[arg(long, default_value_t = 5)]
+    max_retries: u32,
+
+    #[command(flatten)]
+    spool: SpoolSettings,
+}
+
+#[derive(Args, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct PostgresSettings {
+    /// PostgreSQL Host
+    host: String,
+
+    /// PostgreSQL Username
+    username: String,
+
+    /// PostgreSQL Database
+    #[arg(default_value = "aprs")]
+    database: String,
+
+    /// Drop all tables (if they exist) before migrating to the current schema
+    #[clap(long, short, action=ArgAction::SetTrue)]
+    create_tables: bool,
+
+    /// Maximum number of pooled connections
+    #[arg(long, default_value_t = 3)]
+    pool_size: u32,
+
+    /// Maximum number of lines written per COPY batch
+    #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(u16).range(1..))]
+    batch_size: u16,
+
+    /// Number of times a transient database error is retried before a batch is spooled or dropped
+    #[arg(long, default_value_t = 5)]
+    max_retries: u32,
+
+    #[command(flatten)]
+    spool: SpoolSettings,
+}
+
+#[derive(Args, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct SpoolSettings {
+    /// Keep batches in DIR while the database cannot be reached and store them,
+    /// in order, once it is back (also across restarts)
+    #[arg(long, value_name = "DIR")]
+    spool_dir: Option<std::path::PathBuf>,
+
+    /// Largest size of the spool in MiB, beyond which the oldest batches are dropped
+    #[arg(long, value_name = "MIB", default_value_t = 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    spool_max_size: u64,
+}
+
+impl SpoolSettings {
+    fn open(&self, name: &str) -> Result<Option<Arc<Mutex<spool::Spool>>>> {
+        let Some(directory) = &self.spool_dir else {
+            return Ok(None);
+        };
+        let spool = spool::Spool::open(spool::SpoolOptions {
+            name: name.to_string(),
+            directory: directory.clone(),
+            max_size: self.spool_max_size.saturating_mul(1024 * 1024),
+        })?;
+        Ok(Some(Arc::new(Mutex::new(spool))))
+    }
+}
+
+#[derive(Args, Clone, PartialEq, Eq, Debug)]
+struct MaintenanceSettings {
+    /// Delete data once it is older than AGE, e.g. `position=30d` or `message=1y`
+    /// (repeatable; tables: position, message, status, mic_e, unknown, paths)
+    #[arg(long = "retain", value_name = "TABLE=AGE", value_parser = retention::parse_retention)]
+    retain: Vec<retention::Retention>,
+
+    /// Thin out positions older than AGE to one point per station and --downsample-interval
+    #[arg(long, value_name = "AGE", value_parser = retention::parse_duration)]
+    downsample_after: Option<Duration>,
+
+    /// Interval kept per station when downsampling
+    #[arg(long, value_name = "INTERVAL", default_value = "5m", value_parser = retention::parse_duration)]
+    downsample_interval: Duration,
+
+    /// How often retention and downsampling run
+    #[arg(long, value_name = "INTERVAL", default_value = "1h", value_parser = retention::parse_duration)]
+    maintenance_interval: Duration,
+
+    /// How often the database is vacuumed/optimized (0 disables)
+    #[arg(long, value_name = "INTERVAL", default_value = "1d", value_parser = retention::parse_duration)]
+    optimize_interval: Duration,
+}
+
+impl MaintenanceSettings {
+    fn policy(&self) -> retention::RetentionPolicy {
+        retention::RetentionPolicy {
+            retain: self.retain.clone(),
+            downsample: self
+                .downsample_after
+                .map(|older_than| retention::Downsample {
+                    older_than,
+                    interval: self.downsample_interval,
+                }),
+        }
+    }
+}
+
+#[derive(Args, Clone, PartialEq, Debug)]
+struct SourceSettings {
+    /// Play back raw logs instead of connecting to APRS-IS (repeatable, played in order;
+    /// .gz and .zst are decompressed)
+    #[arg(long, value_name = "FILE")]
+    replay: Vec<std::path::PathBuf>,
+
+    /// Replay pace: `original`, `max` or an acceleration factor such as `10`
+    #[arg(long, value_name = "SPEED", default_value = "original", value_parser = rawlog::parse_replay_speed)]
+    replay_speed: rawlog::ReplaySpeed,
+
+    /// Record every received line with its receive time to rotating logs in DIR
+    #[arg(long, value_name = "DIR")]
+    record: Option<std::path::PathBuf>,
+
+    /// Start of the names of recorded logs
+    #[arg(long, value_name = "PREFIX", default_value = "aprs-raw")]
+    record_prefix: String,
+
+    /// How often a new recorded log is started
+    #[arg(long, value_enum, default_value_t = archive::Rotation::Hourly)]
+    record_rotate: archive::Rotation,
+
+    /// Compression of recorded logs
+    #[arg(long, value_enum, default_value_t = archive::Compression::None)]
+    record_compression: archive::Compression,
+}
+
+#[derive(Args, Clone, PartialEq, Debug)]
+struct AprsIsSettings {
+    /// Serve the feed to APRS-IS clients on this address, e.g. `127.0.0.1:14580`,
+    /// passing on the packets of verified clients
+    #[arg(long, value_name = "ADDR")]
+    aprs_is_listen: Option<std::net::SocketAddr>,
+
+    /// Server name given to APRS-IS clients and added to their packets [default: the callsign]
+    #[arg(long, value_name = "NAME")]
+    aprs_is_name: Option<String>,
+}
+
+/// A pooled backend which stores lines in batches
+trait BatchInsert: Clone + Send + Sync + 'static {
+    fn insert_batch(
+        &self,
+        data: &[libk0hax_aprs::data::ParsedLine],
+        paths: &[libk0hax_aprs::dedup::DuplicatePath],
+    ) -> impl std::future::Future<Output = Result<()>> + Send;
+
+    /// Whether an error of `insert_batch` means the database is unavailable
+    fn is_unavailable(error: &anyhow::Error) -> bool;
+
+    /// Check the schema before ingest and create it in an empty database,
+    /// see `migrations::check_startup`
+    fn prepare_schema(&self) -> impl std::future::Future<Output = Result<()>> + Send;
+}
+
+impl BatchInsert for mariadb::MariaDb {
+    async fn insert_batch(
+        &self,
+        data: &[libk0hax_aprs::data::ParsedLine],
+        paths: &[libk0hax_aprs::dedup::DuplicatePath],
+    ) -> Result<()> {
+        mariadb::MariaDb::insert_batch(self, data, paths).await
+    }
+
+    fn is_unavailable(error: &anyhow::Error) -> bool {
+        mariadb::is_unavailable(error)
+    }
+
+    async fn prepare_schema(&self) -> Result<()> {
+        if migrations::check_startup(self.schema_version().await?, mariadb::MIGRATIONS)? {
+            self.migrate().await?;
+        }
+        Ok(())
+    }
+}
+
+impl BatchInsert for postgres::PostgresDb {
+    async fn insert_batch(
+        &self,
+        data: &[libk0hax_aprs::data::ParsedLine],
+        paths: &[libk0hax_aprs::dedup::DuplicatePath],
+    ) -> Result<()> {
+        postgres::PostgresDb::insert_batch(self, data, paths).await
+    }
+
+    fn is_unavailable(error: &anyhow::Error) -> bool {
+        postgres::is_unavailable(error)
+    }
+
+    async fn prepare_schema(&self) -> Result<()> {
+        if migrations::check_startup(self.schema_version().await?, postgres::MIGRATIONS)? {
+            self.migrate().await?;
+        }
+        Ok(())
+    }
+}
+
+/// A backend which can prune and optimize itself
+trait Maintenance: Clone + Send + Sync + 'static {
+    fn prune(
+        &self,
+        policy: &retention::RetentionPolicy,
+    ) -> impl std::future::Future<Output = Result<retention::PruneStats>> + Send;
+
+    fn optimize(&self) -> impl std::future::Future<Output = Result<()>> + Send;
+}
+
+impl Maintenance for SqliteDb {
+    async fn prune(&self, policy: &retention::RetentionPolicy) -> Result<retention::PruneStats> {
+        let db = self.clone();
+        let policy = policy.clone();
+        tokio::task::spawn_blocking(move || db.prune(&policy)).await?
+    }
+
+    async fn optimize(&self) -> Result<()> {
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || db.optimize()).await?
+    }
+}
+
+impl Maintenance for mariadb::MariaDb {
+    async fn prune(&self, policy: &retention::RetentionPolicy) -> Result<retention::PruneStats> {
+        mariadb::MariaDb::prune(self, policy).await
+    }
+
+    async fn optimize(&self) -> Result<()> {
+        mariadb::MariaDb::optimize(self).await
+    }
+}
+
+impl Maintenance for postgres::PostgresDb {
+    async fn prune(&self, policy: &retention::RetentionPolicy) -> Result<retention::PruneStats> {
+        postgres::PostgresDb::prune(self, policy).await
+    }
+
+    async fn optimize(&self) -> Result<()> {
+        postgres::PostgresDb::optimize(self).await
+    }
 }
 
-#[derive(Clone)]
+/// A parsed line shared by the queues of all sinks
+#[derive(Clone, Serialize, Deserialize)]
 struct AsyncLine {
-    line: Arc<Mutex<libk0hax_aprs::data::ParsedLine>>,
+    line: Arc<libk0hax_aprs::data::ParsedLine>,
+    /// The line as received
+    raw: String,
 }
 
 impl AsyncLine {
-    fn insert_aprs_line(&self, db: SqliteDb) {
-        let line_handle = Arc::clone(&self.line);
+    fn new(line: libk0hax_aprs::data::ParsedLine, raw: String) -> Self {
+        AsyncLine {
+            line: Arc::new(line),
+            raw,
+        }
+    }
+}
 
-        tokio::spawn(async move {
-            let parsed_line = line_handle.lock().await; // Get exclusive access to the line
-            let db_result = db.insert_aprs_line(&parsed_line);
-            match db_result {
-                Ok(_) => info!("Parsed DB result!"),
-                Err(e) => error!("DB Result Error: {}", e),
-            }
-        });
+/// Work queued for the sinks
+#[derive(Clone, Serialize, Deserialize)]
+enum DbItem {
+    /// First copy of a packet
+    Line(AsyncLine),
+    /// Another path which delivered a packet queued earlier
+    Path(libk0hax_aprs::dedup::DuplicatePath),
+}
+
+/// Where `main_loop` reads lines from
+enum LineSource {
+    Live(Arc<libk0hax_aprs::client::AprsClient>),
+    Replay(rawlog::ReplaySource),
+}
+
+impl LineSource {
+    /// Next raw line and its receive time, `None` once a replay has ended
+    async fn next_line(&mut self) -> Result<Option<(DateTime<Utc>, String)>, Box<dyn Error>> {
+        match self {
+            LineSource::Live(client) => Ok(Some((Utc::now(), client.read_raw_line().await?))),
+            LineSource::Replay(source) => Ok(source.next_line().await?),
+        }
     }
 
-    fn print_parsed(&self) {
-        let line_handle = Arc::clone(&self.line);
+    /// Times the source connected again after losing its connection
+    fn reconnects(&self) -> u64 {
+        match self {
+            LineSource::Live(client) => client.reconnects(),
+            LineSource::Replay(_) => 0,
+        }
+    }
+}
 
-        tokio::spawn(async move {
-            let parsed_line = line_handle.lock().await; // Get exclusive access to the line
-            libk0hax_aprs::utils::print_parsed(&parsed_line).unwrap();
-        });
+/// Where new lines are passed on besides the sinks
+#[derive(Default)]
+struct Feeds {
+    live: Option<live::LiveFeed>,
+    aprs_is: Option<server::AprsIsServer>,
+}
+
+impl Feeds {
+    fn publish(&self, line: &libk0hax_aprs::data::ParsedLine, raw: &str) {
+        if let Some(feed) = &self.live {
+            feed.publish(line);
+        }
+        if let Some(server) = &self.aprs_is {
+            server.publish(line, raw);
+        }
     }
+}
 
-    fn new(line: libk0hax_aprs::data::ParsedLine) -> Self {
-        AsyncLine {
-            line: Arc::new(Mutex::new(line)),
+/// Send the packets of APRS-IS server clients upstream
+async fn uplink_loop(
+    client: Arc<libk0hax_aprs::client::AprsClient>,
+    mut uploads: tokio::sync::mpsc::Receiver<String>,
+) {
+    while let Some(line) = uploads.recv().await {
+        if !client.login().verified {
+            // The server would drop it anyway
+            warn!("Not logged in with a passcode, not sending: {}", line);
+            continue;
+        }
+        if let Err(e) = client.send_line(&line).await {
+            error!("Upload Error: {}", e);
         }
     }
 }
 
+/// Reads lines until the source ends or `shutdown` is cancelled. The
+/// recorder comes with the receiver of reloads, on which it reopens its file.
 async fn main_loop(
-    aprs_client: libk0hax_aprs::client::AprsClient,
-    tx: mpsc::Sender<AsyncLine>,
+    mut source: LineSource,
+    mut recorder: Option<(rawlog::RawRecorder, watch::Receiver<Arc<config::Config>>)>,
+    mut dedup: Option<libk0hax_aprs::dedup::Deduplicator>,
+    feeds: Feeds,
+    mut sinks: Vec<queue::Sender<DbItem>>,
     counter_arc: Arc<RwLock<u64>>,
-    mut ctrlc_rx: mpsc::Receiver<()>,
+    shutdown: CancellationToken,
 ) {
+    let mut last_flush = tokio::time::Instant::now();
+    let mut reconnects = source.reconnects();
     loop {
-        match ctrlc_rx.try_recv() {
-            Err(mpsc::error::TryRecvError::Empty) => {
-                // No ctrl-c found yet, do nothing
-                ()
-            }
-            Err(mpsc::error::TryRecvError::Disconnected) => {
-                // this should be unreachable
-                break;
+        let next_line = tokio::select! {
+            // Also ends a read in progress, which waits as long as the feed is quiet
+            _ = shutdown.cancelled() => break,
+            x = source.next_line() => x,
+        };
+        if source.reconnects() > reconnects {
+            metrics::METRICS
+                .reconnects
+                .inc_by(source.reconnects() - reconnects);
+            reconnects = source.reconnects();
+            if let LineSource::Live(client) = &source {
+                metrics::METRICS.set_server(client.hostname(), &client.get_addr().to_string());
             }
-            Ok(()) => {
-                println!("Ctrl-C Received! Breaking out of main loop!");
+        }
+        let (received_at, raw) = match next_line {
+            Ok(Some(x)) => x,
+            Ok(None) => {
+                eprintln!("Replay finished!");
                 break;
             }
-        };
-        let parsed_line = match aprs_client.read_line().await {
-            Ok(x) => x,
             Err(x) => {
                 error!("{}", x);
                 continue;
             }
         };
-        let async_line = AsyncLine::new(parsed_line);
-        let _ = tx.send(async_line.clone()).await;
-        //async_line.insert_aprs_line(db.clone());
-        let mut counter = counter_arc.write().await;
-        *counter += 1;
-        drop(counter);
-    }
-}
-
-async fn db_loop(
-    db: SqliteDb,
-    rx: Arc<RwLock<mpsc::Receiver<AsyncLine>>>,
-    counter_arc: Arc<RwLock<u64>>,
-    error_counter_arc: Arc<RwLock<u64>>,
-) {
+        if let Some((recorder, reload)) = recorder.as_mut() {
+            if reload.has_changed().unwrap_or(false) {
+                // Closed on SIGHUP so a rotated file is let go of, and opened again below
+                reload.borrow_and_update();
+                if let Err(e) = recorder.finish() {
+                    error!("Recorder Error: {}", e);
+                }
+            }
+            if let Err(e) = recorder.record(received_at, &raw) {
+                error!("Recorder Error: {}", e);
+            }
+            if last_flush.elapsed() >= FILE_FLUSH_INTERVAL {
+                if let Err(e) = recorder.flush() {
+                    error!("Recorder Error: {}", e);
+                }
+                last_flush = tokio::time::Instant::now();
+            }
+        }
+        // Lines which fail to decode are kept as `Unknown` so they can be stored
+        let mut parsed_line = libk0hax_aprs::parse_line_or_unknown(&raw);
+        parsed_line.received_at = received_at;
+        metrics::METRICS.packet_received(parsed_line.data.type_name());
+        let item = match dedup.as_mut().map(|x| x.check(parsed_line.clone())) {
+            Some(libk0hax_aprs::dedup::Deduped::Duplicate(path)) => {
+                metrics::METRICS.duplicates.inc();
+                DbItem::Path(path)
+            }
+            _ => {
+                feeds.publish(&parsed_line, &raw);
+                DbItem::Line(AsyncLine::new(parsed_line, raw))
+            }
+        };
+        let stopped = tokio::select! {
+            // A full queue which blocks must not hold up the shutdown
+            _ = shutdown.cancelled() => break,
+            x = send_to_sinks(&sinks, &item) => x,
+        };
+        for i in stopped.into_iter().rev() {
+            // The task has ended, e.g. because stdout was closed
+            eprintln!("Sink {} stopped!", sinks.remove(i).name());
+        }
+        if sinks.is_empty() {
+            break;
+        }
+        let mut counter = counter_arc.write().await;
+        *counter += 1;
+        drop(counter);
+    }
+}
+
+/// Give every sink its own copy of an item, sharing the parsed line.
+/// Returns the positions of sinks whose task has ended.
+async fn send_to_sinks(sinks: &[queue::Sender<DbItem>], item: &DbItem) -> Vec<usize> {
+    let mut stopped = Vec::new();
+    for (i, sink) in sinks.iter().enumerate() {
+        if !sink.send(item.clone()).await {
+            stopped.push(i);
+        }
+    }
+    stopped
+}
+
+async fn db_loop(
+    db: SqliteDb,
+    workers: usize,
+    rx: queue::Receiver<DbItem>,
+    counter_arc: Arc<RwLock<u64>>,
+    error_counter_arc: Arc<RwLock<u64>>,
+) {
     let mut handles = Vec::new();
-    for i in 0..3 {
+    for i in 0..workers {
         let counter_outer = counter_arc.clone();
         let err_counter_outer = error_counter_arc.clone();
         let db_outer = db.clone();
@@ -190,26 +967,30 @@ async fn db_loop(
             i,
             tokio::spawn(async move {
                 let db_inner = db_outer.clone();
-                while let Some(async_line) = {
-                    let rx_inner = rx_outer.clone();
-                    let mut rx = rx_inner.write().await;
-                    let x = rx.recv().await;
-                    drop(rx);
-                    x
-                } {
+                while let Some(item) = rx_outer.recv().await {
                     let db_inner = db_inner.clone();
                     let counter_job = counter_outer.clone();
                     let err_counter_job = err_counter_outer.clone();
-                    let parsed_line = async_line.line.lock().await;
-                    let db_result = db_inner.insert_aprs_line(&parsed_line);
+                    let timer = metrics::METRICS.insert_latency.start_timer();
+                    // SQLite blocks, which would keep other tasks such as the
+                    // signal handler from running and the task from being aborted
+                    let db_result = tokio::task::spawn_blocking(move || match item {
+                        DbItem::Line(async_line) => db_inner.insert_aprs_line(&async_line.line),
+                        DbItem::Path(path) => db_inner.insert_path(&path),
+                    })
+                    .await
+                    .expect("Panic in task");
+                    timer.observe_duration();
                     match db_result {
                         Ok(_) => {
                             info!("Parsed DB result!");
+                            metrics::METRICS.stored.inc();
                             let mut counter = counter_job.write().await;
                             *counter += 1;
                             drop(counter);
                         }
                         Err(e) => {
+                            metrics::METRICS.store_errors.inc();
                             let mut counter = err_counter_job.write().await;
                             *counter += 1;
                             drop(counter);
@@ -222,76 +1003,1090 @@ async fn db_loop(
         ));
     }
     for (i, handle) in handles {
-        let _ = handle.await.expect("Panic in task");
-        println!("DB [{}] Task Finished!", i);
+        handle.await.expect("Panic in task");
+        eprintln!("DB [{}] Task Finished!", i);
     }
 }
 
-async fn mysql_loop(
-    hostname: String,
-    username: String,
-    password: String,
-    database: String,
-    rx: Arc<RwLock<mpsc::Receiver<AsyncLine>>>,
+async fn batch_loop<D: BatchInsert>(
+    db: D,
+    workers: usize,
+    batch_size: usize,
+    rx: queue::Receiver<DbItem>,
     counter_arc: Arc<RwLock<u64>>,
     err_counter_arc: Arc<RwLock<u64>>,
+    spool: Option<(Arc<Mutex<spool::Spool>>, watch::Receiver<bool>)>,
 ) {
     let mut handles = Vec::new();
-    for i in 0..3 {
+    for i in 0..workers {
         let counter_outer = counter_arc.clone();
         let err_counter_outer = err_counter_arc.clone();
-        let host_inner = hostname.clone();
-        let user_inner = username.clone();
-        let pass_inner = password.clone();
-        let db_inner = database.clone();
+        let db_inner = db.clone();
         let rx_outer = rx.clone();
+        let spool_inner = spool.clone();
         handles.push((
             i,
             tokio::spawn(async move {
-                let mut db_inner =
-                    mariadb::ConnectionArc::new(host_inner, user_inner, pass_inner, db_inner).await;
-                while let Some(async_line) = {
-                    let rx_inner = rx_outer.clone();
-                    let mut rx = rx_inner.write().await;
-                    let x = rx.recv().await;
-                    drop(rx);
-                    x
-                } {
+                let mut batch = Vec::with_capacity(batch_size);
+                let mut paths = Vec::new();
+                loop {
+                    // Wait for one line, then take whatever else is already queued
+                    let mut next = match rx_outer.recv().await {
+                        Some(first) => first,
+                        None => break,
+                    };
+                    loop {
+                        match next {
+                            DbItem::Line(x) => batch.push((*x.line).clone()),
+                            DbItem::Path(x) => paths.push(x),
+                        }
+                        if batch.len() + paths.len() >= batch_size {
+                            break;
+                        }
+                        next = match rx_outer.try_recv() {
+                            Some(x) => x,
+                            None => break,
+                        };
+                    }
+                    let queued = (batch.len() + paths.len()) as u64;
                     let counter_job = counter_outer.clone();
                     let err_counter_job = err_counter_outer.clone();
-                    let parsed_line = async_line.line.lock().await;
-                    let db_result = db_inner.insert_aprs_line(&parsed_line).await;
+                    let spool_ready = spool_inner
+                        .as_ref()
+                        .map(|(spool, ready)| (&**spool, *ready.borrow()));
+                    let db_result = store_or_spool(&db_inner, spool_ready, &batch, &paths).await;
                     match db_result {
-                        Ok(_) => {
-                            info!("Parsed DB result!");
-                            let mut counter = counter_job.write().await;
-                            *counter += 1;
-                            drop(counter);
+                        Ok(Stored::Spooled) => {}
+                        Ok(Stored::Inserted(rejected)) => {
+                            let rejected = rejected as u64;
+                            info!("Parsed DB result! ({} lines)", queued - rejected);
+                            metrics::METRICS.stored.inc_by(queued - rejected);
+                            *counter_job.write().await += queued - rejected;
+                            if rejected > 0 {
+                                metrics::METRICS.store_errors.inc_by(rejected);
+                                *err_counter_job.write().await += rejected;
+                            }
                         }
                         Err(e) => {
+                            metrics::METRICS.store_errors.inc_by(queued);
                             let mut counter = err_counter_job.write().await;
-                            *counter += 1;
+                            *counter += queued;
                             drop(counter);
                             error!("DB Result Error: {}", e)
                         }
                     }
+                    batch.clear();
+                    paths.clear();
                 }
             }),
         ));
     }
+    for (i, handle) in handles {
+        handle.await.expect("Panic in task");
+        eprintln!("DB [{}] Task Finished!", i);
+    }
+}
+
+/// What became of a batch given to `store_or_spool`
+enum Stored {
+    /// Stored, except for this many lines and paths the database rejected
+    Inserted(usize),
+    Spooled,
+}
+
+/// A batch of lines and paths which have not been stored yet
+type Part<'a> = (
+    &'a [libk0hax_aprs::data::ParsedLine],
+    &'a [libk0hax_aprs::dedup::DuplicatePath],
+);
+
+/// Split a batch in two halves of as many items as possible
+fn split_batch(part: Part) -> [Part; 2] {
+    let (batch, paths) = part;
+    let half = (batch.len() + paths.len()) / 2;
+    match half.checked_sub(batch.len()) {
+        None => [(&batch[..half], &[]), (&batch[half..], paths)],
+        Some(x) => [(batch, &paths[..x]), (&[], &paths[x..])],
+    }
+}
+
+/// Insert a batch. When the database rejects it, e.g. for one malformed
+/// line, it is stored in halves down to single items, so only the items the
+/// database rejects are left out. Their number is returned.
+///
+/// An unavailable database ends the attempt, even if some halves were
+/// already stored.
+async fn insert_or_split<D: BatchInsert>(
+    db: &D,
+    batch: &[libk0hax_aprs::data::ParsedLine],
+    paths: &[libk0hax_aprs::dedup::DuplicatePath],
+) -> Result<usize> {
+    let mut parts = vec![(batch, paths)];
+    let mut rejected = 0;
+    while let Some(part) = parts.pop() {
+        let e = match db.insert_batch(part.0, part.1).await {
+            Ok(()) => continue,
+            Err(e) if D::is_unavailable(&e) => return Err(e),
+            Err(e) => e,
+        };
+        match part.0.len() + part.1.len() {
+            0 => {}
+            1 => {
+                match part.0.first() {
+                    Some(line) => error!("Leaving out line {} from {}: {}", line.id, line.from, e),
+                    None => error!("Leaving out path of {}: {}", part.1[0].id, e),
+                }
+                rejected += 1;
+            }
+            _ => {
+                if part.0.len() + part.1.len() == batch.len() + paths.len() {
+                    warn!("Batch rejected, storing it in parts: {}", e);
+                }
+                // The second half is taken after the first
+                let [first, second] = split_batch(part);
+                parts.push(second);
+                parts.push(first);
+            }
+        }
+    }
+    Ok(rejected)
+}
+
+/// Insert a batch, or add it to the spool while the database is unavailable,
+/// its schema is not `ready` or earlier batches are still waiting there
+async fn store_or_spool<D: BatchInsert>(
+    db: &D,
+    spool: Option<(&Mutex<spool::Spool>, bool)>,
+    batch: &[libk0hax_aprs::data::ParsedLine],
+    paths: &[libk0hax_aprs::dedup::DuplicatePath],
+) -> Result<Stored> {
+    if let Some((spool, ready)) = spool {
+        let mut spool = spool.lock().await;
+        if !ready || !spool.is_empty() {
+            // Join the batches already waiting so they are stored in order
+            spool.append(batch, paths)?;
+            return Ok(Stored::Spooled);
+        }
+    }
+    let timer = metrics::METRICS.insert_latency.start_timer();
+    let db_result = insert_or_split(db, batch, paths).await;
+    timer.observe_duration();
+    match (db_result, spool) {
+        (Err(e), Some((spool, _))) if D::is_unavailable(&e) => {
+            warn!(
+                "Database unavailable, spooling {} lines: {}",
+                batch.len() + paths.len(),
+                e
+            );
+            spool.lock().await.append(batch, paths)?;
+            Ok(Stored::Spooled)
+        }
+        (db_result, _) => db_result.map(Stored::Inserted),
+    }
+}
+
+/// Shortest wait before a spooled batch is tried again
+const SPOOL_RETRY_MIN: Duration = Duration::from_secs(1);
+
+/// Longest wait before a spooled batch is tried again
+const SPOOL_RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// Check the schema once the database can be reached and set `ready`, then
+/// store spooled batches, oldest first, whenever the database is available.
+/// A schema which does not fit ends the process, as it would at startup.
+async fn spool_loop<D: BatchInsert>(
+    db: D,
+    spool: Arc<Mutex<spool::Spool>>,
+    ready: watch::Sender<bool>,
+    counter_arc: Arc<RwLock<u64>>,
+    err_counter_arc: Arc<RwLock<u64>>,
+) {
+    let mut delay = SPOOL_RETRY_MIN;
+    loop {
+        match db.prepare_schema().await {
+            Ok(()) => break,
+            Err(e) if D::is_unavailable(&e) => {
+                warn!(
+                    "Database unavailable, checking its schema in {}s: {}",
+                    delay.as_secs(),
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(SPOOL_RETRY_MAX);
+            }
+            Err(e) => {
+                eprintln!("Schema Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    ready.send_replace(true);
+    delay = SPOOL_RETRY_MIN;
+    loop {
+        let next = spool.lock().await.peek();
+        let (batch, position) = match next {
+            Ok(Some(x)) => x,
+            Ok(None) => {
+                tokio::time::sleep(SPOOL_RETRY_MIN).await;
+                continue;
+            }
+            Err(e) => {
+                error!("Spool Error: {}", e);
+                tokio::time::sleep(SPOOL_RETRY_MAX).await;
+                continue;
+            }
+        };
+        let items = batch.len();
+        let timer = metrics::METRICS.insert_latency.start_timer();
+        let db_result = insert_or_split(&db, &batch.lines, &batch.paths).await;
+        timer.observe_duration();
+        match db_result {
+            Ok(rejected) => {
+                let stored = (items - rejected) as u64;
+                info!("Stored spooled batch ({} lines)", stored);
+                metrics::METRICS.stored.inc_by(stored);
+                *counter_arc.write().await += stored;
+                if rejected > 0 {
+                    metrics::METRICS.store_errors.inc_by(rejected as u64);
+                    *err_counter_arc.write().await += rejected as u64;
+                }
+            }
+            Err(e) if D::is_unavailable(&e) => {
+                warn!(
+                    "Database still unavailable, retrying spool in {}s: {}",
+                    delay.as_secs(),
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(SPOOL_RETRY_MAX);
+                continue;
+            }
+            Err(e) => {
+                // Rejected data would block the spool for good, so it is left out
+                metrics::METRICS.store_errors.inc_by(items as u64);
+                *err_counter_arc.write().await += items as u64;
+                error!("DB Result Error: {}: dropping spooled batch", e);
+            }
+        }
+        delay = SPOOL_RETRY_MIN;
+        if let Err(e) = spool.lock().await.commit(position, items) {
+            error!("Spool Error: {}", e);
+        }
+    }
+}
+
+/// How often archive and recorded files are flushed to disk
+const FILE_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+async fn archive_loop(
+    mut archive: archive::ArchiveWriter,
+    rx: queue::Receiver<DbItem>,
+    mut reload: watch::Receiver<Arc<config::Config>>,
+    counter_arc: Arc<RwLock<u64>>,
+    err_counter_arc: Arc<RwLock<u64>>,
+) {
+    let mut flush_timer = tokio::time::interval(FILE_FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            item = rx.recv() => {
+                let result = match item {
+                    Some(DbItem::Line(async_line)) => archive.write(&async_line.line),
+                    Some(DbItem::Path(path)) => archive.write_path(&path),
+                    None => break,
+                };
+                match result {
+                    Ok(_) => {
+                        metrics::METRICS.stored.inc();
+                        let mut counter = counter_arc.write().await;
+                        *counter += 1;
+                        drop(counter);
+                    }
+                    Err(e) => {
+                        metrics::METRICS.store_errors.inc();
+                        let mut counter = err_counter_arc.write().await;
+                        *counter += 1;
+                        drop(counter);
+                        error!("Archive Error: {}", e)
+                    }
+                }
+            }
+            _ = flush_timer.tick() => {
+                if let Err(e) = archive.flush() {
+                    error!("Archive Error: {}", e);
+                }
+            }
+            Ok(()) = reload.changed() => {
+                // Closed on SIGHUP so rotated files are let go of, and
+                // opened again by the next write
+                if let Err(e) = archive.finish() {
+                    error!("Archive Error: {}", e);
+                }
+            }
+        }
+    }
+    if let Err(e) = archive.finish() {
+        error!("Archive Error: {}", e);
+    }
+    eprintln!("Archive Task Finished!");
+}
+
+async fn mqtt_loop(
+    sink: mqtt::MqttSink,
+    rx: queue::Receiver<DbItem>,
+    counter_arc: Arc<RwLock<u64>>,
+    err_counter_arc: Arc<RwLock<u64>>,
+) {
+    while let Some(item) = rx.recv().await {
+        // Other paths of a packet are only of interest to the databases
+        let DbItem::Line(async_line) = item else {
+            continue;
+        };
+        let result = sink.publish(&async_line.line).await;
+        match result {
+            Ok(()) => {
+                metrics::METRICS.stored.inc();
+                let mut counter = counter_arc.write().await;
+                *counter += 1;
+                drop(counter);
+            }
+            Err(e) => {
+                metrics::METRICS.store_errors.inc();
+                let mut counter = err_counter_arc.write().await;
+                *counter += 1;
+                drop(counter);
+                error!("MQTT Error: {}", e);
+            }
+        }
+    }
+    if let Err(e) = sink.finish().await {
+        error!("MQTT Error: {}", e);
+    }
+    eprintln!("MQTT Task Finished!");
+}
+
+async fn stdout_loop(
+    mut writer: output::OutputWriter<std::io::Stdout>,
+    rx: queue::Receiver<DbItem>,
+    counter_arc: Arc<RwLock<u64>>,
+) {
+    while let Some(item) = rx.recv().await {
+        // Duplicate paths only matter to stored data
+        let DbItem::Line(async_line) = item else {
+            continue;
+        };
+        if let Err(e) = writer.write(&async_line.line, &async_line.raw) {
+            if e.kind() != std::io::ErrorKind::BrokenPipe {
+                error!("Output Error: {}", e);
+            }
+            break;
+        }
+        let mut counter = counter_arc.write().await;
+        *counter += 1;
+        drop(counter);
+    }
+}
+
+async fn open_mariadb(
+    db_settings: &MariaDbSettings,
+    config: &config::Config,
+    lazy: bool,
+) -> Result<mariadb::MariaDb> {
+    let db_password = match config.mariadb.password("mariadb")? {
+        Some(x) => x,
+        None => rpassword::prompt_password("MySQL Password: ")?,
+    };
+    let db = mariadb::MariaDb::new(mariadb::MariaDbOptions {
+        hostname: db_settings.host.clone(),
+        username: db_settings.username.clone(),
+        password: db_password,
+        database: db_settings.database.clone(),
+        pool_size: db_settings.pool_size,
+        max_retries: db_settings.max_retries,
+        lazy,
+    })
+    .await?;
+    if db_settings.create_tables {
+        db.drop_tables().await?;
+    }
+    Ok(db)
+}
+
+async fn open_postgres(
+    db_settings: &PostgresSettings,
+    config: &config::Config,
+    lazy: bool,
+) -> Result<postgres::PostgresDb> {
+    let db_password = match config.postgres.password("postgres")? {
+        Some(x) => x,
+        None => rpassword::prompt_password("PostgreSQL Password: ")?,
+    };
+    let db = postgres::PostgresDb::new(postgres::PostgresOptions {
+        hostname: db_settings.host.clone(),
+        username: db_settings.username.clone(),
+        password: db_password,
+        database: db_settings.database.clone(),
+        pool_size: db_settings.pool_size,
+        max_retries: db_settings.max_retries,
+        lazy,
+    })
+    .await?;
+    if db_settings.create_tables {
+        db.drop_tables().await?;
+    }
+    Ok(db)
+}
+
+/// Settings of a database sink which writes in batches
+trait BatchSinkSettings {
+    type Db: BatchInsert + Maintenance;
+
+    fn spool(&self) -> &SpoolSettings;
+
+    fn batch_size(&self) -> u16;
+
+    /// Open the database, without connecting yet when `lazy`
+    fn connect(
+        &self,
+        config: &config::Config,
+        lazy: bool,
+    ) -> impl std::future::Future<Output = Result<Self::Db>>;
+}
+
+impl BatchSinkSettings for MariaDbSettings {
+    type Db = mariadb::MariaDb;
+
+    fn spool(&self) -> &SpoolSettings {
+        &self.spool
+    }
+
+    fn batch_size(&self) -> u16 {
+        self.batch_size
+    }
+
+    async fn connect(&self, config: &config::Config, lazy: bool) -> Result<Self::Db> {
+        open_mariadb(self, config, lazy).await
+    }
+}
+
+impl BatchSinkSettings for PostgresSettings {
+    type Db = postgres::PostgresDb;
+
+    fn spool(&self) -> &SpoolSettings {
+        &self.spool
+    }
+
+    fn batch_size(&self) -> u16 {
+        self.batch_size
+    }
+
+    async fn connect(&self, config: &config::Config, lazy: bool) -> Result<Self::Db> {
+        open_postgres(self, config, lazy).await
+    }
+}
+
+/// Open a database sink and start its spool, maintenance and batch writers.
+/// Returns the database and the task of the batch writers.
+async fn start_batch_sink<S: BatchSinkSettings>(
+    name: &str,
+    db_settings: &S,
+    config: &config::Config,
+    maintenance: &MaintenanceSettings,
+    workers: usize,
+    rx: queue::Receiver<DbItem>,
+    (insert_counter, error_counter): (Arc<RwLock<u64>>, Arc<RwLock<u64>>),
+) -> Result<(S::Db, tokio::task::JoinHandle<()>)> {
+    let spool = db_settings.spool().open(name)?;
+    // With a spool, lines are taken while the database is still down
+    let db = db_settings.connect(config, spool.is_some()).await?;
+    let ready = match &spool {
+        Some(spool) => {
+            let (ready_tx, ready) = watch::channel(false);
+            tokio::spawn(spool_loop(
+                db.clone(),
+                spool.clone(),
+                ready_tx,
+                insert_counter.clone(),
+                error_counter.clone(),
+            ));
+            ready
+        }
+        None => {
+            db.prepare_schema().await?;
+            watch::channel(true).1
+        }
+    };
+    tokio::spawn(maintenance_loop(
+        db.clone(),
+        maintenance.clone(),
+        ready.clone(),
+    ));
+    let handle = tokio::spawn(batch_loop(
+        db.clone(),
+        workers,
+        db_settings.batch_size() as usize,
+        rx,
+        insert_counter,
+        error_counter,
+        spool.map(|x| (x, ready)),
+    ));
+    Ok((db, handle))
+}
+
+/// Open the destination of a sink and start the tasks draining its queue.
+/// Databases also return the store the JSON API reads from.
+async fn start_sink(
+    name: String,
+    spec: &SinkSpec,
+    config: &config::Config,
+    maintenance: &MaintenanceSettings,
+    reload: &watch::Receiver<Arc<config::Config>>,
+    insert_counter: Arc<RwLock<u64>>,
+    error_counter: Arc<RwLock<u64>>,
+) -> Result<(
+    queue::Sender<DbItem>,
+    tokio::task::JoinHandle<()>,
+    Option<api::Store>,
+)> {
+    let workers = spec.queue.workers.unwrap_or(config.queue.workers);
+    let (tx, rx) = queue::channel(queue::QueueOptions {
+        name: name.clone(),
+        capacity: spec.queue.queue_size.unwrap_or(config.queue.channel_size),
+        overflow: spec.queue.overflow,
+        spill_dir: config.queue.spill_dir.clone(),
+        high_water: config.queue.high_water,
+    })?;
+    let mut store = None;
+    let handle = match &spec.mode {
+        DatabaseMode::Sqlite3 => {
+            let db = SqliteDb::new(&config.sqlite.path);
+            if migrations::check_startup(db.schema_version()?, sqlite::MIGRATIONS)? {
+                db.migrate()?;
+            }
+            tokio::spawn(maintenance_loop(
+                db.clone(),
+                maintenance.clone(),
+                watch::channel(true).1,
+            ));
+            store = Some(api::Store::Sqlite(db.clone()));
+            tokio::spawn(db_loop(db, workers, rx, insert_counter, error_counter))
+        }
+        DatabaseMode::Mariadb(db_settings) => {
+            let (db, handle) = start_batch_sink(
+                &name,
+                db_settings,
+                config,
+                maintenance,
+                workers,
+                rx,
+                (insert_counter, error_counter),
+            )
+            .await?;
+            store = Some(api::Store::Mariadb(db));
+            handle
+        }
+        DatabaseMode::Postgres(db_settings) => {
+            let (db, handle) = start_batch_sink(
+                &name,
+                db_settings,
+                config,
+                maintenance,
+                workers,
+                rx,
+                (insert_counter, error_counter),
+            )
+            .await?;
+            store = Some(api::Store::Postgres(db));
+            handle
+        }
+        DatabaseMode::Archive(archive_settings) => {
+            let archive = archive::ArchiveWriter::new(archive::ArchiveOptions {
+                directory: archive_settings.directory.clone(),
+                prefix: archive_settings.prefix.clone(),
+                format: archive_settings.format,
+                compression: archive_settings.compression,
+                rotation: archive_settings.rotate,
+            })?;
+            tokio::spawn(archive_loop(
+                archive,
+                rx,
+                reload.clone(),
+                insert_counter,
+                error_counter,
+            ))
+        }
+        DatabaseMode::Mqtt(mqtt_settings) => {
+            let sink = mqtt::MqttSink::new(mqtt_settings.options(config)?)?;
+            tokio::spawn(mqtt_loop(sink, rx, insert_counter, error_counter))
+        }
+        DatabaseMode::Stdout(stdout_settings) => {
+            let writer = output::OutputWriter::new(
+                std::io::stdout(),
+                stdout_settings.format,
+                output::OutputFilter {
+                    types: stdout_settings.types.clone(),
+                    callsigns: stdout_settings.callsigns.clone(),
+                },
+            );
+            tokio::spawn(stdout_loop(writer, rx, insert_counter))
+        }
+        DatabaseMode::Migrate(_)
+        | DatabaseMode::Reprocess(_)
+        | DatabaseMode::Track(_)
+        | DatabaseMode::Export(_)
+        | DatabaseMode::Serve(_) => {
+            unreachable!("handled before connecting")
+        }
+    };
+    Ok((tx, handle, store))
+}
+
+async fn run_migrate(settings: &MigrateSettings, config: &config::Config) -> Result<()> {
+    let (current, latest) = match &settings.database {
+        DatabaseTarget::Sqlite3 => {
+            let db = SqliteDb::new(&config.sqlite.path);
+            if !settings.status {
+                db.migrate()?;
+            }
+            (
+                db.schema_version()?,
+                migrations::latest_version(sqlite::MIGRATIONS),
+            )
+        }
+        DatabaseTarget::Mariadb(db_settings) => {
+            let db = open_mariadb(db_settings, config, false).await?;
+            if !settings.status {
+                db.migrate().await?;
+            }
+            (
+                db.schema_version().await?,
+                migrations::latest_version(mariadb::MIGRATIONS),
+            )
+        }
+        DatabaseTarget::Postgres(db_settings) => {
+            let db = open_postgres(db_settings, config, false).await?;
+            if !settings.status {
+                db.migrate().await?;
+            }
+            (
+                db.schema_version().await?,
+                migrations::latest_version(postgres::MIGRATIONS),
+            )
+        }
+    };
+    println!("Schema version: {} | Latest: {}", current, latest);
+    Ok(())
+}
+
+async fn run_reprocess(settings: &ReprocessSettings, config: &config::Config) -> Result<()> {
+    let stats = match &settings.database {
+        DatabaseTarget::Sqlite3 => {
+            let db = SqliteDb::new(&config.sqlite.path);
+            if migrations::check_startup(db.schema_version()?, sqlite::MIGRATIONS)? {
+                db.migrate()?;
+            }
+            db.reprocess()?
+        }
+        DatabaseTarget::Mariadb(db_settings) => {
+            let db = open_mariadb(db_settings, config, false).await?;
+            if migrations::check_startup(db.schema_version().await?, mariadb::MIGRATIONS)? {
+                db.migrate().await?;
+            }
+            db.reprocess().await?
+        }
+        DatabaseTarget::Postgres(db_settings) => {
+            let db = open_postgres(db_settings, config, false).await?;
+            if migrations::check_startup(db.schema_version().await?, postgres::MIGRATIONS)? {
+                db.migrate().await?;
+            }
+            db.reprocess().await?
+        }
+    };
+    println!(
+        "Scanned: {} | Decoded: {} | Still unknown: {}",
+        stats.scanned,
+        stats.decoded,
+        stats.scanned - stats.decoded
+    );
+    Ok(())
+}
+
+async fn run_track(settings: &TrackSettings, config: &config::Config) -> Result<()> {
+    let end = settings.end.unwrap_or_else(Utc::now);
+    let start = settings
+        .start
+        .unwrap_or_else(|| retention::cutoff(settings.since));
+    let fixes = match &settings.database {
+        DatabaseTarget::Sqlite3 => {
+            let db = SqliteDb::new(&config.sqlite.path);
+            if migrations::check_startup(db.schema_version()?, sqlite::MIGRATIONS)? {
+                db.migrate()?;
+            }
+            db.fixes(&settings.station, start, end)?
+        }
+        DatabaseTarget::Mariadb(db_settings) => {
+            let db = open_mariadb(db_settings, config, false).await?;
+            if migrations::check_startup(db.schema_version().await?, mariadb::MIGRATIONS)? {
+                db.migrate().await?;
+            }
+            db.fixes(&settings.station, start, end).await?
+        }
+        DatabaseTarget::Postgres(db_settings) => {
+            let db = open_postgres(db_settings, config, false).await?;
+            if migrations::check_startup(db.schema_version().await?, postgres::MIGRATIONS)? {
+                db.migrate().await?;
+            }
+            db.fixes(&settings.station, start, end).await?
+        }
+    };
+    let track = track::build_track(&fixes, &settings.options());
+    if settings.json {
+        println!("{}", serde_json::to_string_pretty(&track)?);
+        return Ok(());
+    }
+    for (i, segment) in track.segments.iter().enumerate() {
+        let (Some(first), Some(last)) = (segment.start(), segment.end()) else {
+            continue;
+        };
+        println!(
+            "Segment {}: {} - {} | Fixes: {} | Distance: {:.2} km | Max: {:.1} km/h | Avg: {:.1} km/h | Stops: {}",
+            i + 1,
+            first.format("%+"),
+            last.format("%+"),
+            segment.fixes.len(),
+            segment.distance_m / 1000.0,
+            segment.max_speed_kmh,
+            segment.avg_speed_kmh,
+            segment.stops.len()
+        );
+        for stop in &segment.stops {
+            println!(
+                "  Stop: {} - {} at {:.5}, {:.5}",
+                stop.start.format("%+"),
+                stop.end.format("%+"),
+                stop.latitude,
+                stop.longitude
+            );
+        }
+    }
+    println!(
+        "Segments: {} | Distance: {:.2} km | Max: {:.1} km/h | Avg: {:.1} km/h | Rejected: {}",
+        track.segments.len(),
+        track.distance_m / 1000.0,
+        track.max_speed_kmh,
+        track.avg_speed_kmh,
+        track.rejected
+    );
+    Ok(())
 }
 
-async fn log_loop(parse_counter_arc: Arc<RwLock<u64>>, insert_counter_arc: Arc<RwLock<u64>>, err_counter_arc: Arc<RwLock<u64>>) {
+async fn run_export(settings: &ExportSettings, config: &config::Config) -> Result<()> {
+    let filter = settings.filter();
+    let positions = match &settings.database {
+        DatabaseTarget::Sqlite3 => {
+            let db = SqliteDb::new(&config.sqlite.path);
+            if migrations::check_startup(db.schema_version()?, sqlite::MIGRATIONS)? {
+                db.migrate()?;
+            }
+            db.positions(&filter)?
+        }
+        DatabaseTarget::Mariadb(db_settings) => {
+            let db = open_mariadb(db_settings, config, false).await?;
+            if migrations::check_startup(db.schema_version().await?, mariadb::MIGRATIONS)? {
+                db.migrate().await?;
+            }
+            db.positions(&filter).await?
+        }
+        DatabaseTarget::Postgres(db_settings) => {
+            let db = open_postgres(db_settings, config, false).await?;
+            if migrations::check_startup(db.schema_version().await?, postgres::MIGRATIONS)? {
+                db.migrate().await?;
+            }
+            db.positions(&filter).await?
+        }
+    };
+    let mut out: Box<dyn std::io::Write> = match &settings.output {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let mut out =
+        archive::CompressedWriter::new(std::io::BufWriter::new(&mut out), settings.compression)?;
+    match settings.format {
+        ExportFormat::Gpx => export::write_gpx(&mut out, &positions)?,
+        ExportFormat::Kml => export::write_kml(&mut out, &positions, settings.icon_url.as_deref())?,
+        ExportFormat::Geojson => export::write_geojson(&mut out, &positions)?,
+        ExportFormat::Ndjson => export::write_ndjson(&mut out, &positions)?,
+        ExportFormat::Csv => export::write_csv(&mut out, &positions)?,
+    }
+    std::io::Write::flush(&mut out.finish()?)?;
+    info!("Exported {} positions", positions.len());
+    Ok(())
+}
+
+async fn run_serve(
+    settings: &ServeSettings,
+    config: &config::Config,
+    addr: Option<std::net::SocketAddr>,
+) -> Result<()> {
+    let Some(addr) = addr else {
+        return Err(anyhow::anyhow!("serve needs --http-listen"));
+    };
+    let store = match &settings.database {
+        DatabaseTarget::Sqlite3 => {
+            let db = SqliteDb::new(&config.sqlite.path);
+            if migrations::check_startup(db.schema_version()?, sqlite::MIGRATIONS)? {
+                db.migrate()?;
+            }
+            api::Store::Sqlite(db)
+        }
+        DatabaseTarget::Mariadb(db_settings) => {
+            let db = open_mariadb(db_settings, config, false).await?;
+            if migrations::check_startup(db.schema_version().await?, mariadb::MIGRATIONS)? {
+                db.migrate().await?;
+            }
+            api::Store::Mariadb(db)
+        }
+        DatabaseTarget::Postgres(db_settings) => {
+            let db = open_postgres(db_settings, config, false).await?;
+            if migrations::check_startup(db.schema_version().await?, postgres::MIGRATIONS)? {
+                db.migrate().await?;
+            }
+            api::Store::Postgres(db)
+        }
+    };
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    eprintln!("HTTP Address: {}", listener.local_addr()?);
+    axum::serve(listener, metrics::router().merge(api::router(store))).await?;
+    Ok(())
+}
+
+/// Run the retention policy and optimize the database on their schedules,
+/// starting once `ready` is set
+async fn maintenance_loop<D: Maintenance>(
+    db: D,
+    settings: MaintenanceSettings,
+    mut ready: watch::Receiver<bool>,
+) {
+    if ready.wait_for(|x| *x).await.is_err() {
+        return;
+    }
+    let policy = settings.policy();
+    let mut prune_timer =
+        tokio::time::interval(settings.maintenance_interval.max(Duration::from_secs(1)));
+    // Optimizing is expensive, so it is not done on every restart
+    let optimize_every = settings.optimize_interval.max(Duration::from_secs(1));
+    let mut optimize_timer =
+        tokio::time::interval_at(tokio::time::Instant::now() + optimize_every, optimize_every);
     loop {
-        let parse_counter = parse_counter_arc.read().await;
-        let insert_counter = insert_counter_arc.read().await;
-        let err_counter = err_counter_arc.read().await;
-        let total_combined = *insert_counter + *err_counter;
-        println!("Parsed: {} | Inserted: {} | Failed: {} | Total Insert + Failed: {}", parse_counter, insert_counter, err_counter, total_combined);
-        drop(parse_counter);
-        drop(insert_counter);
-        drop(err_counter);
-        sleep(Duration::from_secs(60)).await;
+        tokio::select! {
+            _ = prune_timer.tick(), if !policy.is_empty() => {
+                match db.prune(&policy).await {
+                    Ok(stats) => info!(
+                        "Maintenance: {} rows expired, {} positions downsampled",
+                        stats.expired, stats.downsampled
+                    ),
+                    Err(e) => error!("Maintenance Error: {}", e),
+                }
+            }
+            _ = optimize_timer.tick(), if !settings.optimize_interval.is_zero() => {
+                match db.optimize().await {
+                    Ok(()) => info!("Maintenance: database optimized"),
+                    Err(e) => error!("Maintenance Error: {}", e),
+                }
+            }
+            else => break,
+        }
+    }
+}
+
+async fn counters_text(
+    parse_counter_arc: &RwLock<u64>,
+    insert_counter_arc: &RwLock<u64>,
+    err_counter_arc: &RwLock<u64>,
+) -> String {
+    let parse_counter = parse_counter_arc.read().await;
+    let insert_counter = insert_counter_arc.read().await;
+    let err_counter = err_counter_arc.read().await;
+    let total_combined = *insert_counter + *err_counter;
+    format!(
+        "Parsed: {} | Inserted: {} | Failed: {} | Total Insert + Failed: {}",
+        parse_counter, insert_counter, err_counter, total_combined
+    )
+}
+
+/// Print the counters every `queue.stats_interval`, following reloads
+async fn log_loop(
+    mut config: watch::Receiver<Arc<config::Config>>,
+    parse_counter_arc: Arc<RwLock<u64>>,
+    insert_counter_arc: Arc<RwLock<u64>>,
+    err_counter_arc: Arc<RwLock<u64>>,
+) {
+    loop {
+        let interval = config.borrow_and_update().queue.stats_interval;
+        if !interval.is_zero() {
+            let text = counters_text(&parse_counter_arc, &insert_counter_arc, &err_counter_arc);
+            eprintln!("{}", text.await);
+        }
+        tokio::select! {
+            _ = sleep(interval), if !interval.is_zero() => {}
+            changed = config.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// How often the counters are shown by `systemctl status`
+const SYSTEMD_STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Keep systemd up to date: the counters as the status and, with
+/// `WatchdogSec=`, keepalives for as long as packets arrive, so a stalled
+/// feed gets the service restarted
+async fn systemd_loop(
+    parse_counter_arc: Arc<RwLock<u64>>,
+    insert_counter_arc: Arc<RwLock<u64>>,
+    err_counter_arc: Arc<RwLock<u64>>,
+) {
+    let watchdog = systemd::watchdog_timeout();
+    // Keepalives go out at half the timeout, as sd_watchdog_enabled(3) advises
+    let period = watchdog.map_or(SYSTEMD_STATUS_INTERVAL, |x| {
+        (x / 2).min(SYSTEMD_STATUS_INTERVAL)
+    });
+    let started = tokio::time::Instant::now();
+    let mut stalled = false;
+    let mut timer = tokio::time::interval(period);
+    loop {
+        timer.tick().await;
+        let text = counters_text(&parse_counter_arc, &insert_counter_arc, &err_counter_arc);
+        systemd::status(&text.await);
+        let Some(timeout) = watchdog else {
+            continue;
+        };
+        let quiet = metrics::METRICS
+            .last_packet_age()
+            .unwrap_or_else(|| started.elapsed());
+        if quiet < timeout {
+            stalled = false;
+            systemd::watchdog();
+        } else if !stalled {
+            stalled = true;
+            error!(
+                "No packets for {:?}, leaving the service to the systemd watchdog",
+                quiet
+            );
+        }
+    }
+}
+
+/// What a signal asks of the daemon
+enum DaemonSignal {
+    /// SIGINT or SIGTERM, or Ctrl-C where there are no Unix signals
+    Stop(&'static str),
+    /// SIGHUP
+    Reload,
+}
+
+/// The signals handled by `signal_loop`
+struct DaemonSignals {
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    hangup: tokio::signal::unix::Signal,
+}
+
+impl DaemonSignals {
+    /// Start catching the signals, which no longer end the process by themselves
+    fn new() -> std::io::Result<DaemonSignals> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            Ok(DaemonSignals {
+                interrupt: signal(SignalKind::interrupt())?,
+                terminate: signal(SignalKind::terminate())?,
+                hangup: signal(SignalKind::hangup())?,
+            })
+        }
+        #[cfg(not(unix))]
+        Ok(DaemonSignals {})
+    }
+
+    /// Wait for the next signal
+    async fn recv(&mut self) -> DaemonSignal {
+        #[cfg(unix)]
+        {
+            tokio::select! {
+                _ = self.interrupt.recv() => DaemonSignal::Stop("SIGINT"),
+                _ = self.terminate.recv() => DaemonSignal::Stop("SIGTERM"),
+                _ = self.hangup.recv() => DaemonSignal::Reload,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                error!("Could not wait for Ctrl-C: {}", e);
+                std::future::pending::<()>().await;
+            }
+            DaemonSignal::Stop("Ctrl-C")
+        }
+    }
+}
+
+/// Read the configuration again. Receivers reopen their files even when it
+/// cannot be read, so rotated logs are let go of either way.
+fn reload_config(reload: &watch::Sender<Arc<config::Config>>, path: Option<&std::path::Path>) {
+    systemd::reloading();
+    let old = reload.borrow().clone();
+    let config = match config::Config::load(path) {
+        Ok(config) => {
+            eprintln!("Configuration reloaded!");
+            let keys = old.restart_needed(&config);
+            if !keys.is_empty() {
+                eprintln!(
+                    "Changes to {} take effect after a restart!",
+                    keys.join(", ")
+                );
+            }
+            Arc::new(config)
+        }
+        Err(e) => {
+            error!(
+                "Could not reload configuration, keeping the current one: {}",
+                e
+            );
+            old
+        }
+    };
+    reload.send_replace(config);
+    systemd::reloaded();
+}
+
+/// Reload on SIGHUP. Cancel `shutdown` on the first signal to stop, and exit
+/// on the second one for when the sinks take too long to store what is queued.
+async fn signal_loop(
+    mut signals: DaemonSignals,
+    shutdown: CancellationToken,
+    reload: watch::Sender<Arc<config::Config>>,
+    config_path: Option<std::path::PathBuf>,
+) {
+    loop {
+        match signals.recv().await {
+            DaemonSignal::Reload => reload_config(&reload, config_path.as_deref()),
+            DaemonSignal::Stop(name) if !shutdown.is_cancelled() => {
+                eprintln!("{} received, shutting down!", name);
+                systemd::stopping();
+                shutdown.cancel();
+            }
+            DaemonSignal::Stop(name) => {
+                eprintln!(
+                    "{} received again, exiting without storing what is queued!",
+                    name
+                );
+                std::process::exit(1);
+            }
+        }
     }
 }
 
@@ -311,114 +2106,245 @@ async fn main() -> Result<(), Box<dyn Error>> {
     };
     stderrlog::new()
         .module(module_path!())
+        .module("libk0hax_aprs")
         .quiet(quiet)
         .verbosity(verbose)
         .timestamp(ts)
         .init()
         .unwrap();
 
-    let (db_tx, db_rx) = mpsc::channel(65534);
-    let (ctrlc_tx, ctrlc_rx) = mpsc::channel(1);
-
-    ctrlc::set_handler(move || {
-        let cc_tx = ctrlc_tx.clone();
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        let _enter = rt.enter();
-        rt.spawn(async move {
-            cc_tx
-                .send(())
-                .await
-                .expect("Could not send signal on channel.");
-        });
-    })
-    .expect("Error setting Ctrl-C handler");
-
-    let my_callsign = args.callsign;
-    let client_hostname = "rotate.aprs.net";
-    let client_port: u16 = 10152;
+    let config_path = args
+        .config
+        .clone()
+        .or_else(|| std::env::var_os("K0HAX_APRS_CONFIG").map(Into::into));
+    let config = config::Config::load(config_path.as_deref())?;
 
-    let my_client =
-        libk0hax_aprs::client::AprsClient::new(client_hostname, client_port, &my_callsign).await;
+    if let DatabaseMode::Migrate(settings) = &args.database_mode {
+        run_migrate(settings, &config).await?;
+        return Ok(());
+    }
+    if let DatabaseMode::Reprocess(settings) = &args.database_mode {
+        run_reprocess(settings, &config).await?;
+        return Ok(());
+    }
+    if let DatabaseMode::Track(settings) = &args.database_mode {
+        run_track(settings, &config).await?;
+        return Ok(());
+    }
+    if let DatabaseMode::Export(settings) = &args.database_mode {
+        run_export(settings, &config).await?;
+        return Ok(());
+    }
+    if let DatabaseMode::Serve(settings) = &args.database_mode {
+        run_serve(settings, &config, args.http_listen).await?;
+        return Ok(());
+    }
+    let my_callsign = match (&args.callsign, args.source.replay.is_empty()) {
+        (Some(x), _) => x.clone(),
+        (None, false) => String::new(),
+        (None, true) => return Err("A callsign is required to connect to APRS-IS".into()),
+    };
+    let recorder = match &args.source.record {
+        Some(directory) => Some(rawlog::RawRecorder::new(rawlog::RawLogOptions {
+            directory: directory.clone(),
+            prefix: args.source.record_prefix.clone(),
+            compression: args.source.record_compression,
+            rotation: args.source.record_rotate,
+        })?),
+        None => None,
+    };
 
-    println!("Server Address: {:?}", my_client.get_addr());
+    let shutdown = CancellationToken::new();
+    let (reload, reload_rx) = watch::channel(Arc::new(config.clone()));
+    tokio::spawn(signal_loop(
+        DaemonSignals::new()?,
+        shutdown.clone(),
+        reload,
+        config_path,
+    ));
 
     // Create counters
     let parse_counter = Arc::new(RwLock::new(0u64));
     let insert_counter = Arc::new(RwLock::new(0u64));
     let error_counter = Arc::new(RwLock::new(0u64));
 
-    let db_rx_arc = Arc::new(RwLock::new(db_rx));
-
-    let sql_insert_counter = insert_counter.clone();
-    let sql_error_counter = error_counter.clone();
+    // The subcommand comes first, followed by the configured and `--sink` sinks
+    let mut specs = vec![SinkSpec {
+        queue: args.queue.clone(),
+        mode: args.database_mode.clone(),
+    }];
+    for value in &config.sinks {
+        specs.push(parse_sink_spec(value).map_err(|e| format!("sinks: {}", e))?);
+    }
+    specs.extend(args.sinks.iter().cloned());
 
+    let mut sinks = Vec::new();
     let mut handles = Vec::new();
+    let mut api_store = None;
+    for (i, spec) in specs.iter().enumerate() {
+        // Sinks of the same kind are numbered from the second one on
+        let same = specs[..i]
+            .iter()
+            .filter(|x| x.mode.name() == spec.mode.name())
+            .count();
+        let name = match same {
+            0 => spec.mode.name().to_string(),
+            x => format!("{}-{}", spec.mode.name(), x + 1),
+        };
+        let (queue, handle, store) = start_sink(
+            name,
+            spec,
+            &config,
+            &args.maintenance,
+            &reload_rx,
+            insert_counter.clone(),
+            error_counter.clone(),
+        )
+        .await?;
+        handles.push((queue.name().to_string(), handle));
+        sinks.push(queue);
+        api_store = api_store.or(store);
+    }
 
-    // Begin SQL Loop!
-    match &args.database_mode {
-        DatabaseMode::Sqlite3 => {
-            let db_path = "aprs.sqlite";
-            let db = SqliteDb::new(db_path);
-            let _ = db.create_db();
-            handles.push(tokio::spawn(async move {
-                db_loop(db, db_rx_arc, sql_insert_counter, sql_error_counter).await;
-            }));
+    let client_hostname = config.server.hostname.as_str();
+    let client_port = config.server.port;
+
+    let source = if args.source.replay.is_empty() {
+        let my_client =
+            libk0hax_aprs::client::AprsClient::new(client_hostname, client_port, &my_callsign)
+                .await
+                .map_err(|e| {
+                    anyhow::anyhow!(
+                        "Cannot connect to {}:{}: {}",
+                        client_hostname,
+                        client_port,
+                        e
+                    )
+                })?;
+        let my_client = Arc::new(my_client);
+        eprintln!("Server Address: {:?}", my_client.get_addr());
+        metrics::METRICS.set_server(client_hostname, &my_client.get_addr().to_string());
+        LineSource::Live(my_client)
+    } else {
+        LineSource::Replay(rawlog::ReplaySource::new(
+            args.source.replay.clone(),
+            args.source.replay_speed,
+        ))
+    };
+
+    let mut feeds = Feeds::default();
+    if let Some(addr) = args.aprs_is.aprs_is_listen {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        eprintln!("APRS-IS Address: {}", listener.local_addr()?);
+        let name = match (&args.aprs_is.aprs_is_name, my_callsign.as_str()) {
+            (Some(x), _) => x.clone(),
+            (None, "") => "REPLAY".to_string(),
+            (None, x) => x.to_uppercase(),
+        };
+        let (aprs_is, uploads) = server::AprsIsServer::new(server::ServerOptions {
+            name,
+            buffer: live::CLIENT_BUFFER,
+        });
+        // While replaying the packets of clients only go to the other clients
+        if let LineSource::Live(client) = &source {
+            tokio::spawn(uplink_loop(client.clone(), uploads));
         }
-        DatabaseMode::Mariadb(db_settings) => {
-            let db_host = db_settings.host.clone();
-            let db_user = db_settings.username.clone();
-            let db_password = rpassword::prompt_password("MySQL Password: ")?;
-            let db_database = db_settings.database.clone();
-            if db_settings.create_tables == true {
-                let mut conn = mariadb::ConnectionArc::new(
-                    db_host.clone(),
-                    db_user.clone(),
-                    db_password.clone(),
-                    db_database.clone(),
-                )
-                .await;
-                conn.create_tables().await?;
-            }
-            handles.push(tokio::spawn(async move {
-                mysql_loop(
-                    db_host,
-                    db_user,
-                    db_password,
-                    db_database,
-                    db_rx_arc,
-                    sql_insert_counter,
-                    sql_error_counter
-                )
-                .await;
-            }));
-        }
-    }
-
-    let log_parse_counter = parse_counter.clone();
-    let log_insert_counter = insert_counter.clone();
-    let log_error_counter = error_counter.clone();
+        feeds.aprs_is = Some(aprs_is.clone());
+        tokio::spawn(async move {
+            if let Err(e) = aprs_is.serve(listener).await {
+                error!("APRS-IS Server Error: {}", e);
+            }
+        });
+    }
+    if let Some(addr) = args.http_listen {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        eprintln!("HTTP Address: {}", listener.local_addr()?);
+        let live_feed = live::LiveFeed::new(live::CLIENT_BUFFER);
+        let router = metrics::router().merge(live::router(live_feed.clone()));
+        let router = match api_store {
+            Some(store) => router.merge(api::router(store)),
+            None => router,
+        };
+        feeds.live = Some(live_feed);
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, router).await {
+                error!("HTTP Error: {}", e);
+            }
+        });
+    }
+
     // Begin print Loop!
-    tokio::spawn(async move {
-        log_loop(log_parse_counter, log_insert_counter, log_error_counter).await;
-    });
+    tokio::spawn(log_loop(
+        reload_rx.clone(),
+        parse_counter.clone(),
+        insert_counter.clone(),
+        error_counter.clone(),
+    ));
+    if systemd::enabled() {
+        tokio::spawn(systemd_loop(
+            parse_counter.clone(),
+            insert_counter.clone(),
+            error_counter.clone(),
+        ));
+    }
+    // Logged in, the sinks are open and the listening addresses are bound
+    systemd::ready();
 
     let main_parse_counter = parse_counter.clone();
-    main_loop(my_client, db_tx, main_parse_counter, ctrlc_rx).await;
-    for handle in handles {
-        println!("Joining handle!");
-        let _ = handle.await.expect("Panic in task");
-    }
-
-    {
-        let parse_counter = parse_counter.read().await;
-        let insert_counter = insert_counter.read().await;
-        let err_counter = error_counter.read().await;
-        let total_combined = *insert_counter + *err_counter;
-        println!("Parsed: {} | Inserted: {} | Failed: {} | Total Insert + Failed: {}", parse_counter, insert_counter, err_counter, total_combined);
-        drop(parse_counter);
-        drop(insert_counter);
-        drop(err_counter);
+    let dedup = match args.dedup_window {
+        0 => None,
+        x => Some(libk0hax_aprs::dedup::Deduplicator::new(
+            Duration::from_secs(x),
+        )),
+    };
+    main_loop(
+        source,
+        recorder.map(|x| (x, reload_rx.clone())),
+        dedup,
+        feeds,
+        sinks,
+        main_parse_counter,
+        shutdown.clone(),
+    )
+    .await;
+
+    // main_loop has closed the queues, so each sink ends once it has stored
+    // what is left. Only after a shutdown signal is that limited in time.
+    let drain_timeout = reload_rx.borrow().queue.drain_timeout;
+    let deadline = async {
+        shutdown.cancelled().await;
+        sleep(drain_timeout).await;
+    };
+    tokio::pin!(deadline);
+    let mut timed_out = false;
+    for (name, handle) in handles.iter_mut() {
+        eprintln!("Waiting for sink {}!", name);
+        tokio::select! {
+            result = handle => result.expect("Panic in task"),
+            _ = &mut deadline => {
+                timed_out = true;
+                break;
+            }
+        }
     }
+    if timed_out {
+        for (name, handle) in handles.iter().filter(|(_, x)| !x.is_finished()) {
+            let left = metrics::METRICS
+                .queue_depth
+                .with_label_values(&[name])
+                .get();
+            eprintln!(
+                "Sink {} did not finish within {:?}, giving up {} queued items!",
+                name, drain_timeout, left
+            );
+            handle.abort();
+        }
+    }
+
+    eprintln!(
+        "{}",
+        counters_text(&parse_counter, &insert_counter, &error_counter).await
+    );
 
     Ok(())
 }
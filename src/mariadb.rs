@@ -1,198 +1,857 @@
 use anyhow::{anyhow, Result};
 use chrono::prelude::*;
-use log::debug;
-use sqlx::Connection;
-use sqlx::MySqlConnection;
+use log::{debug, warn};
+use sqlx::mysql::{MySqlConnectOptions, MySqlDatabaseError, MySqlPoolOptions};
+use sqlx::{Connection, MySql, MySqlConnection, MySqlPool, QueryBuilder};
+use std::collections::BTreeMap;
+use tokio::time::{sleep, Duration};
 use uuid::Uuid;
 
-pub struct ConnectionArc {
-    conn: MySqlConnection,
+use crate::data::ParsedLine;
+use crate::export::{ExportFilter, PositionColumns, PositionRecord};
+use crate::migrations::{self, Migration};
+use crate::query::{
+    message_line, mic_e_line, newest_lines, position_line, MessageLineColumns, MessageQuery,
+    MicELineColumns, PositionLineColumns, PositionQuery,
+};
+use crate::reprocess::{reprocess_line, ReprocessStats, REPROCESS_PAGE_SIZE};
+use crate::retention::{
+    self, Downsample, PruneStats, RetentionPolicy, ORPHAN_PATH_GRACE, PRUNE_BATCH_SIZE,
+};
+use crate::stations::{StationColumns, StationState, StationUpdate};
+use crate::track::Fix;
+
+/// Largest batch accepted by `insert_batch`. The widest table has 11 columns,
+/// which keeps a full batch well under MySQL's 65535 placeholder limit.
+pub const MAX_BATCH_SIZE: usize = 5000;
+
+/// Delay before the first retry of a transient error; doubled on every attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+
+/// Upper bound for the delay between two retries.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Connection settings for `MariaDb::new`
+#[derive(Clone, Debug)]
+pub struct MariaDbOptions {
+    /// Host name, optionally followed by `:port`
+    pub hostname: String,
+    pub username: String,
+    pub password: String,
+    pub database: String,
+    /// Maximum number of pooled connections
+    pub pool_size: u32,
+    /// Number of times a transient error is retried before a batch is failed
+    pub max_retries: u32,
+    /// Open the pool without connecting, so the database may still be down
+    pub lazy: bool,
+}
+
+/// A pooled MariaDB backend.
+///
+/// The pool transparently replaces connections which were dropped by the
+/// server, so a restart of MariaDB only shows up as a handful of retried
+/// batches.
+#[derive(Clone)]
+pub struct MariaDb {
+    pool: MySqlPool,
+    max_retries: u32,
 }
 
-impl ConnectionArc {
-    pub async fn new(
-        hostname: String,
-        username: String,
-        password: String,
-        database: String,
-    ) -> Self {
-        let connection_string: String =
-            format!("mysql://{username}:{password}@{hostname}/{database}");
-        ConnectionArc {
-            conn: MySqlConnection::connect(&connection_string).await.unwrap(),
+/// Columns read back into a `StationState`, see `StationColumns`
+const STATION_COLUMNS: &str = "`callsign`,
+    DATE_FORMAT(`first_seen`, '%Y-%m-%dT%H:%i:%s.%fZ'),
+    DATE_FORMAT(`last_heard`, '%Y-%m-%dT%H:%i:%s.%fZ'),
+    `last_via`,
+    DATE_FORMAT(`last_position_time`, '%Y-%m-%dT%H:%i:%s.%fZ'),
+    `latitude`, `longitude`, `symbol_table`, `symbol_code`, `comment`, `device`,
+    `position_count`, `message_count`, `status_count`, `mic_e_count`, `unknown_count`";
+
+/// Format a time for a `DATETIME(6)` column, YYYY-MM-DD HH:MM:SS.ffffff
+fn mariadb_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S%.6f").to_string()
+}
+
+/// A Unix time as a `DATETIME(6)` value
+fn unix_time(time: i64) -> String {
+    mariadb_time(DateTime::from_timestamp(time, 0).unwrap_or(DateTime::<Utc>::MIN_UTC))
+}
+
+/// A `DATETIME(6)` value, or `None` when the packet timestamp could not be resolved
+fn datetime(timestamp: &Option<crate::data::Timestamp>) -> Option<String> {
+    timestamp
+        .as_ref()
+        .map(|x| x.mariadb_string())
+        .filter(|x| !x.is_empty())
+}
+
+/// A line queued for insertion, with the id and receive time it will be stored under
+struct Row<'a> {
+    id: String,
+    parsed_time: String,
+    from: Option<&'a str>,
+    via: String,
+    data: &'a crate::data::ParsedAprsData,
+}
+
+impl<'a> Row<'a> {
+    fn new(line: &'a crate::data::ParsedLine) -> Self {
+        Row {
+            id: line.id.hyphenated().to_string(),
+            parsed_time: mariadb_time(line.received_at),
+            from: line.source(),
+            via: line.via.join(", "),
+            data: &line.data,
         }
     }
+}
 
-    pub async fn insert_aprs_line(&mut self, data: &libk0hax_aprs::data::ParsedLine) -> Result<()> {
-        let record_uuid = Uuid::new_v4();
+impl MariaDb {
+    pub async fn new(options: MariaDbOptions) -> Result<Self> {
+        let (host, port) = match options.hostname.rsplit_once(':') {
+            Some((host, port)) => (host.to_string(), port.parse::<u16>()?),
+            None => (options.hostname.clone(), 3306),
+        };
+        let connect_options = MySqlConnectOptions::new()
+            .host(&host)
+            .port(port)
+            .username(&options.username)
+            .password(&options.password)
+            .database(&options.database);
+        let pool_options = MySqlPoolOptions::new()
+            .max_connections(options.pool_size)
+            .acquire_timeout(Duration::from_secs(30))
+            .test_before_acquire(true);
+        let pool = match options.lazy {
+            true => pool_options.connect_lazy_with(connect_options),
+            false => pool_options.connect_with(connect_options).await?,
+        };
         debug!(
-            "[MariaDB::insert_aprs_line] [{}]: {:?}",
-            record_uuid.hyphenated().to_string(),
-            &data
+            "[MariaDB::new] Pool of up to {} connections opened to {}:{}",
+            options.pool_size, host, port
         );
-        let utc_now: DateTime<Utc> = Utc::now();
-        // YYYY-MM-DD HH:MM:SS
-        let parsed_time: String = utc_now.format("%Y-%m-%d %H:%M:%S%.6f").to_string();
-
-        let from: String = data.from.clone();
-        let via: String = data
-            .via
-            .clone()
-            .iter()
-            .map(|x| x.to_string() + ", ")
-            .collect::<String>();
-        let via: String = via.trim_end_matches(", ").to_string();
-
-        let type_info: u8 = match &data.data {
-            libk0hax_aprs::data::ParsedAprsData::Position(x) => {
-                let statement_text = "INSERT INTO `position` (`id`, `to`, `timestamp`, `messaging_supported`, `latitude`, `longitude`, `precision`, `symbol_table`, `symbol_code`, `comment`, `cst`) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
-                let statement = sqlx::query(statement_text);
-                let conn = &mut self.conn;
-                let record_timestamp: Option<String> = match &x.timestamp {
-                    Some(y) => Some(y.mariadb_string()),
-                    None => None,
-                };
-                let _ = statement
-                    .bind(record_uuid.hyphenated().to_string())
-                    .bind(x.to.clone())
-                    .bind(record_timestamp)
-                    .bind(x.messaging_supported)
-                    .bind(x.latitude)
-                    .bind(x.longitude)
-                    .bind(x.precision)
-                    .bind(x.symbol_table.to_string())
-                    .bind(x.symbol_code.to_string())
-                    .bind(x.comment.clone())
-                    .bind(x.cst.clone())
-                    .execute(conn)
-                    .await?;
-                2
+        Ok(MariaDb {
+            pool,
+            max_retries: options.max_retries,
+        })
+    }
+
+    /// Insert a batch of lines and duplicate paths, retrying transient errors
+    /// with exponential backoff.
+    ///
+    /// Each attempt runs in a single transaction, so a batch is either stored
+    /// completely or not at all.
+    pub async fn insert_batch(
+        &self,
+        data: &[crate::data::ParsedLine],
+        paths: &[crate::dedup::DuplicatePath],
+    ) -> Result<()> {
+        if data.len() + paths.len() > MAX_BATCH_SIZE {
+            return Err(anyhow!(
+                "batch of {} lines exceeds the maximum of {}",
+                data.len() + paths.len(),
+                MAX_BATCH_SIZE
+            ));
+        }
+
+        if data.is_empty() && paths.is_empty() {
+            return Ok(());
+        }
+
+        let mut attempt: u32 = 0;
+        loop {
+            match self.try_insert_batch(data, paths).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.max_retries && is_transient(&e) => {
+                    let delay = RETRY_BASE_DELAY
+                        .saturating_mul(2u32.saturating_pow(attempt))
+                        .min(RETRY_MAX_DELAY);
+                    attempt += 1;
+                    warn!(
+                        "[MariaDB::insert_batch] Transient error, retry {}/{} in {:?}: {}",
+                        attempt, self.max_retries, delay, e
+                    );
+                    sleep(delay).await;
+                }
+                Err(e) => return Err(e.into()),
             }
-            libk0hax_aprs::data::ParsedAprsData::Message(x) => {
-                let statement_text = "INSERT INTO `messages` (`id`, `to`, `addressee`, `text`, `msg_id`) VALUES (?, ?, ?, ?, ?)";
-                let statement = sqlx::query(statement_text);
-                let conn = &mut self.conn;
-                match &x.id {
-                    Some(y) => {
-                        let _ = statement
-                            .bind(record_uuid.hyphenated().to_string())
-                            .bind(x.to.clone())
-                            .bind(x.addressee.clone())
-                            .bind(x.text.clone())
-                            .bind(y)
-                            .execute(conn)
-                            .await?;
-                    }
-                    None => {
-                        let _ = statement
-                            .bind(record_uuid.hyphenated().to_string())
-                            .bind(x.to.clone())
-                            .bind(x.addressee.clone())
-                            .bind(x.text.clone())
-                            .bind(0)
-                            .execute(conn)
-                            .await?;
-                    }
+        }
+    }
+
+    async fn try_insert_batch(
+        &self,
+        data: &[crate::data::ParsedLine],
+        paths: &[crate::dedup::DuplicatePath],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        if !data.is_empty() {
+            Self::write_lines(&mut tx, data).await?;
+        }
+        if !paths.is_empty() {
+            let mut statement: QueryBuilder<MySql> =
+                QueryBuilder::new("INSERT INTO `paths` (`id`, `via`, `received_at`) ");
+            statement.push_values(paths, |mut b, path| {
+                b.push_bind(path.id.hyphenated().to_string())
+                    .push_bind(path.via.join(", "))
+                    .push_bind(mariadb_time(path.received_at));
+            });
+            statement.build().execute(&mut *tx).await?;
+        }
+        tx.commit().await
+    }
+
+    /// Insert lines and update their stations inside an open transaction
+    async fn write_lines(
+        tx: &mut MySqlConnection,
+        lines: &[crate::data::ParsedLine],
+    ) -> Result<(), sqlx::Error> {
+        Self::upsert_stations(tx, &StationUpdate::from_lines(lines)).await?;
+        let rows: Vec<Row> = lines.iter().map(Row::new).collect();
+        let rows = rows.as_slice();
+
+        {
+            let mut statement: QueryBuilder<MySql> = QueryBuilder::new(
+                "INSERT INTO main_data (`id`, `from`, `via`, `type`, `parsed_time`) ",
+            );
+            statement.push_values(rows, |mut b, row| {
+                b.push_bind(&row.id)
+                    .push_bind(row.from)
+                    .push_bind(&row.via)
+                    .push_bind(row.data.type_name())
+                    .push_bind(&row.parsed_time);
+            });
+            statement.build().execute(&mut *tx).await?;
+        }
+
+        let mut positions = Vec::new();
+        let mut messages = Vec::new();
+        let mut statuses = Vec::new();
+        let mut mic_es = Vec::new();
+        let mut unknowns = Vec::new();
+        for row in rows {
+            debug!("[MariaDB::insert_batch] [{}]: {:?}", row.id, row.data);
+            match row.data {
+                crate::data::ParsedAprsData::Position(x) => positions.push((&row.id, x)),
+                crate::data::ParsedAprsData::Message(x) => messages.push((&row.id, x)),
+                crate::data::ParsedAprsData::Status(x) => statuses.push((&row.id, x)),
+                crate::data::ParsedAprsData::MicE(x) => mic_es.push((&row.id, x)),
+                crate::data::ParsedAprsData::Unknown(x) => unknowns.push((&row.id, x)),
+            }
+        }
+
+        if !positions.is_empty() {
+            let mut statement: QueryBuilder<MySql> = QueryBuilder::new("INSERT INTO `position` (`id`, `to`, `timestamp`, `messaging_supported`, `latitude`, `longitude`, `precision`, `symbol_table`, `symbol_code`, `comment`, `cst`) ");
+            statement.push_values(positions, |mut b, (id, x)| {
+                b.push_bind(id)
+                    .push_bind(&x.to)
+                    .push_bind(datetime(&x.timestamp))
+                    .push_bind(x.messaging_supported)
+                    .push_bind(x.latitude)
+                    .push_bind(x.longitude)
+                    .push_bind(x.precision)
+                    .push_bind(x.symbol_table.to_string())
+                    .push_bind(x.symbol_code.to_string())
+                    .push_bind(&x.comment)
+                    .push_bind(&x.cst);
+            });
+            statement.build().execute(&mut *tx).await?;
+        }
+
+        if !messages.is_empty() {
+            let mut statement: QueryBuilder<MySql> = QueryBuilder::new(
+                "INSERT INTO `messages` (`id`, `to`, `addressee`, `text`, `msg_id`) ",
+            );
+            statement.push_values(messages, |mut b, (id, x)| {
+                b.push_bind(id)
+                    .push_bind(&x.to)
+                    .push_bind(&x.addressee)
+                    .push_bind(&x.text)
+                    .push_bind(x.id.clone().unwrap_or_else(|| b"0".to_vec()));
+            });
+            statement.build().execute(&mut *tx).await?;
+        }
+
+        if !statuses.is_empty() {
+            let mut statement: QueryBuilder<MySql> =
+                QueryBuilder::new("INSERT INTO `status` (`id`, `to`, `timestamp`, `comment`) ");
+            statement.push_values(statuses, |mut b, (id, x)| {
+                b.push_bind(id)
+                    .push_bind(&x.to)
+                    .push_bind(datetime(&x.timestamp))
+                    .push_bind(&x.comment);
+            });
+            statement.build().execute(&mut *tx).await?;
+        }
+
+        if !mic_es.is_empty() {
+            let mut statement: QueryBuilder<MySql> = QueryBuilder::new("INSERT INTO `MicE` (`id`, `latitude`, `longitude`, `precision`, `message`, `speed`, `course`, `symbol_table`, `symbol_code`, `comment`, `current`) ");
+            statement.push_values(mic_es, |mut b, (id, x)| {
+                b.push_bind(id)
+                    .push_bind(x.latitude)
+                    .push_bind(x.longitude)
+                    .push_bind(x.precision)
+                    .push_bind(&x.message)
+                    .push_bind(x.speed)
+                    .push_bind(x.course)
+                    .push_bind(x.symbol_table.to_string())
+                    .push_bind(x.symbol_code.to_string())
+                    .push_bind(&x.comment)
+                    .push_bind(x.current);
+            });
+            statement.build().execute(&mut *tx).await?;
+        }
+
+        if !unknowns.is_empty() {
+            let mut statement: QueryBuilder<MySql> =
+                QueryBuilder::new("INSERT INTO `unknown` (`id`, `data`, `error`) ");
+            statement.push_values(unknowns, |mut b, (id, x)| {
+                b.push_bind(id).push_bind(&x.raw).push_bind(&x.error);
+            });
+            statement.build().execute(&mut *tx).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Merge station updates into the `stations` table.
+    ///
+    /// MariaDB evaluates the assignments left to right, so every column which
+    /// is compared against `last_heard` or `last_position_time` is assigned
+    /// before them.
+    async fn upsert_stations(
+        tx: &mut MySqlConnection,
+        updates: &[StationUpdate],
+    ) -> Result<(), sqlx::Error> {
+        if updates.is_empty() {
+            return Ok(());
+        }
+        let mut statement: QueryBuilder<MySql> = QueryBuilder::new(
+            "INSERT INTO `stations` (`callsign`, `first_seen`, `last_heard`, `last_via`, `last_position_time`, `latitude`, `longitude`, `symbol_table`, `symbol_code`, `comment`, `device`, `position_count`, `message_count`, `status_count`, `mic_e_count`, `unknown_count`) ",
+        );
+        statement.push_values(updates, |mut b, update| {
+            let position = update.last_position.as_ref();
+            b.push_bind(&update.callsign)
+                .push_bind(mariadb_time(update.first_seen))
+                .push_bind(mariadb_time(update.last_heard))
+                .push_bind(update.last_via.join(", "))
+                .push_bind(position.map(|x| mariadb_time(x.time)))
+                .push_bind(position.map(|x| x.latitude))
+                .push_bind(position.map(|x| x.longitude))
+                .push_bind(position.map(|x| x.symbol_table.to_string()))
+                .push_bind(position.map(|x| x.symbol_code.to_string()))
+                .push_bind(position.map(|x| x.comment.clone()))
+                .push_bind(&update.device)
+                .push_bind(update.packets.position as i64)
+                .push_bind(update.packets.message as i64)
+                .push_bind(update.packets.status as i64)
+                .push_bind(update.packets.mic_e as i64)
+                .push_bind(update.packets.unknown as i64);
+        });
+        statement.push(
+            " ON DUPLICATE KEY UPDATE
+                `first_seen` = LEAST(`first_seen`, VALUES(`first_seen`)),
+                `last_via` = IF(VALUES(`last_heard`) >= `last_heard`, VALUES(`last_via`), `last_via`),
+                `device` = IF(VALUES(`last_heard`) >= `last_heard`, COALESCE(VALUES(`device`), `device`), COALESCE(`device`, VALUES(`device`))),
+                `last_heard` = GREATEST(`last_heard`, VALUES(`last_heard`)),
+                `latitude` = IF(VALUES(`last_position_time`) >= COALESCE(`last_position_time`, '1000-01-01'), VALUES(`latitude`), `latitude`),
+                `longitude` = IF(VALUES(`last_position_time`) >= COALESCE(`last_position_time`, '1000-01-01'), VALUES(`longitude`), `longitude`),
+                `symbol_table` = IF(VALUES(`last_position_time`) >= COALESCE(`last_position_time`, '1000-01-01'), VALUES(`symbol_table`), `symbol_table`),
+                `symbol_code` = IF(VALUES(`last_position_time`) >= COALESCE(`last_position_time`, '1000-01-01'), VALUES(`symbol_code`), `symbol_code`),
+                `comment` = IF(VALUES(`last_position_time`) >= COALESCE(`last_position_time`, '1000-01-01'), VALUES(`comment`), `comment`),
+                `last_position_time` = IF(VALUES(`last_position_time`) >= COALESCE(`last_position_time`, '1000-01-01'), VALUES(`last_position_time`), `last_position_time`),
+                `position_count` = `position_count` + VALUES(`position_count`),
+                `message_count` = `message_count` + VALUES(`message_count`),
+                `status_count` = `status_count` + VALUES(`status_count`),
+                `mic_e_count` = `mic_e_count` + VALUES(`mic_e_count`),
+                `unknown_count` = `unknown_count` + VALUES(`unknown_count`)",
+        );
+        statement.build().execute(&mut *tx).await?;
+        Ok(())
+    }
+
+    /// Current state of one station
+    pub async fn station(&self, callsign: &str) -> Result<Option<StationState>> {
+        let statement_text = format!(
+            "SELECT {} FROM `stations` WHERE `callsign` = ?",
+            STATION_COLUMNS
+        );
+        let row: Option<StationColumns> = sqlx::query_as(&statement_text)
+            .bind(callsign)
+            .fetch_optional(&self.pool)
+            .await?;
+        row.map(StationState::from_columns).transpose()
+    }
+
+    /// Up to `limit` stations heard since a time, most recently heard first
+    pub async fn stations_heard_since(
+        &self,
+        since: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<StationState>> {
+        let statement_text = format!(
+            "SELECT {} FROM `stations` WHERE `last_heard` >= ? ORDER BY `last_heard` DESC LIMIT ?",
+            STATION_COLUMNS
+        );
+        let rows: Vec<StationColumns> = sqlx::query_as(&statement_text)
+            .bind(mariadb_time(since))
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter().map(StationState::from_columns).collect()
+    }
+
+    /// Positions of a station received in `[start, end)`, oldest first
+    pub async fn fixes(
+        &self,
+        callsign: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Fix>> {
+        let statement_text = "SELECT DATE_FORMAT(m.`parsed_time`, '%Y-%m-%dT%H:%i:%s.%fZ') AS `time`, x.`latitude`, x.`longitude` FROM `main_data` m JOIN `position` x ON x.`id` = m.`id` WHERE m.`from` = ? AND m.`parsed_time` >= ? AND m.`parsed_time` < ?
+            UNION ALL
+            SELECT DATE_FORMAT(m.`parsed_time`, '%Y-%m-%dT%H:%i:%s.%fZ'), x.`latitude`, x.`longitude` FROM `main_data` m JOIN `MicE` x ON x.`id` = m.`id` WHERE m.`from` = ? AND m.`parsed_time` >= ? AND m.`parsed_time` < ?
+            ORDER BY `time`";
+        let rows: Vec<(String, f64, f64)> = sqlx::query_as(statement_text)
+            .bind(callsign)
+            .bind(mariadb_time(start))
+            .bind(mariadb_time(end))
+            .bind(callsign)
+            .bind(mariadb_time(start))
+            .bind(mariadb_time(end))
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter()
+            .map(|(time, latitude, longitude)| {
+                Ok(Fix {
+                    time: DateTime::parse_from_rfc3339(&time)?.with_timezone(&Utc),
+                    latitude,
+                    longitude,
+                })
+            })
+            .collect()
+    }
+
+    /// Stored positions matching an export filter, ordered by station and
+    /// receive time
+    pub async fn positions(&self, filter: &ExportFilter) -> Result<Vec<PositionRecord>> {
+        let select = "SELECT m.`from` AS `callsign`, DATE_FORMAT(m.`parsed_time`, '%Y-%m-%dT%H:%i:%s.%fZ') AS `time`, x.`latitude`, x.`longitude`, x.`symbol_table`, x.`symbol_code`, x.`comment`";
+        let condition = "WHERE m.`from` LIKE ? ESCAPE '!' AND (? IS NULL OR m.`parsed_time` >= ?) AND (? IS NULL OR m.`parsed_time` < ?)
+                AND x.`latitude` BETWEEN ? AND ? AND x.`longitude` BETWEEN ? AND ?";
+        let statement_text = format!(
+            "{select} FROM `main_data` m JOIN `position` x ON x.`id` = m.`id` {condition}
+            UNION ALL
+            {select} FROM `main_data` m JOIN `MicE` x ON x.`id` = m.`id` {condition}
+            ORDER BY `callsign`, `time`"
+        );
+        let bounds = filter.bounds();
+        let start = filter.start.map(mariadb_time);
+        let end = filter.end.map(mariadb_time);
+        let mut query = sqlx::query_as(&statement_text);
+        for _ in 0..2 {
+            query = query
+                .bind(filter.callsign_like())
+                .bind(start.clone())
+                .bind(start.clone())
+                .bind(end.clone())
+                .bind(end.clone())
+                .bind(bounds.south)
+                .bind(bounds.north)
+                .bind(bounds.west)
+                .bind(bounds.east);
+        }
+        let rows: Vec<PositionColumns> = query.fetch_all(&self.pool).await?;
+        rows.into_iter().map(PositionRecord::from_columns).collect()
+    }
+
+    /// Stored position and Mic-E lines matching a query, newest first
+    pub async fn position_lines(&self, query: &PositionQuery) -> Result<Vec<ParsedLine>> {
+        let condition = format!(
+            "WHERE m.`from` LIKE ? ESCAPE '!' AND (? IS NULL OR m.`parsed_time` >= ?) AND (? IS NULL OR m.`parsed_time` < ?)
+                AND x.`latitude` BETWEEN ? AND ? AND x.`longitude` BETWEEN ? AND ?{}
+                ORDER BY m.`parsed_time` DESC LIMIT ?",
+            if query.weather { " AND x.`symbol_code` = '_'" } else { "" }
+        );
+        let filter = &query.filter;
+        let bounds = filter.bounds();
+        let start = filter.start.map(mariadb_time);
+        let end = filter.end.map(mariadb_time);
+        let statement_text = format!(
+            "SELECT m.`id`, DATE_FORMAT(m.`parsed_time`, '%Y-%m-%dT%H:%i:%s.%fZ'), m.`from`, m.`via`, x.`to`, DATE_FORMAT(x.`timestamp`, '%Y-%m-%dT%H:%i:%s.%fZ'), x.`messaging_supported`, x.`latitude`, x.`longitude`, x.`precision`, x.`symbol_table`, x.`symbol_code`, x.`comment`, x.`cst`
+                FROM `main_data` m JOIN `position` x ON x.`id` = m.`id` {}",
+            condition
+        );
+        let rows: Vec<PositionLineColumns> = sqlx::query_as(&statement_text)
+            .bind(filter.callsign_like())
+            .bind(start.clone())
+            .bind(start.clone())
+            .bind(end.clone())
+            .bind(end.clone())
+            .bind(bounds.south)
+            .bind(bounds.north)
+            .bind(bounds.west)
+            .bind(bounds.east)
+            .bind(query.limit)
+            .fetch_all(&self.pool)
+            .await?;
+        let positions = rows
+            .into_iter()
+            .map(position_line)
+            .collect::<Result<Vec<_>>>()?;
+        let statement_text = format!(
+            "SELECT m.`id`, DATE_FORMAT(m.`parsed_time`, '%Y-%m-%dT%H:%i:%s.%fZ'), m.`from`, m.`via`, x.`latitude`, x.`longitude`, x.`precision`, x.`message`, x.`speed`, x.`course`, x.`symbol_table`, x.`symbol_code`, x.`comment`, x.`current`
+                FROM `main_data` m JOIN `MicE` x ON x.`id` = m.`id` {}",
+            condition
+        );
+        let rows: Vec<MicELineColumns> = sqlx::query_as(&statement_text)
+            .bind(filter.callsign_like())
+            .bind(start.clone())
+            .bind(start)
+            .bind(end.clone())
+            .bind(end)
+            .bind(bounds.south)
+            .bind(bounds.north)
+            .bind(bounds.west)
+            .bind(bounds.east)
+            .bind(query.limit)
+            .fetch_all(&self.pool)
+            .await?;
+        let mic_es = rows
+            .into_iter()
+            .map(mic_e_line)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(newest_lines(positions, mic_es, query.limit))
+    }
+
+    /// Stored messages matching a query, newest first
+    pub async fn message_lines(&self, query: &MessageQuery) -> Result<Vec<ParsedLine>> {
+        // Messages without an id are stored with "0" in its place
+        let statement_text = format!(
+            "SELECT m.`id`, DATE_FORMAT(m.`parsed_time`, '%Y-%m-%dT%H:%i:%s.%fZ'), m.`from`, m.`via`, x.`to`, x.`addressee`, x.`text`, NULLIF(x.`msg_id`, '0')
+                FROM `main_data` m JOIN `messages` x ON x.`id` = m.`id`
+                WHERE (? IS NULL OR m.`from` = ? OR x.`addressee` = ?) AND (? IS NULL OR m.`parsed_time` >= ?) AND (? IS NULL OR m.`parsed_time` < ?){}
+                ORDER BY m.`parsed_time` DESC LIMIT ?",
+            if query.bulletins { " AND x.`addressee` LIKE 'BLN%'" } else { "" }
+        );
+        let start = query.start.map(mariadb_time);
+        let end = query.end.map(mariadb_time);
+        let rows: Vec<MessageLineColumns> = sqlx::query_as(&statement_text)
+            .bind(&query.callsign)
+            .bind(&query.callsign)
+            .bind(&query.callsign)
+            .bind(start.clone())
+            .bind(start)
+            .bind(end.clone())
+            .bind(end)
+            .bind(query.limit)
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter().map(message_line).collect()
+    }
+
+    /// Re-run `parse_line` over stored `unknown` packets and move every line
+    /// which now decodes into its proper table, keeping its id and receive time.
+    pub async fn reprocess(&self) -> Result<ReprocessStats> {
+        let mut stats = ReprocessStats::default();
+        let mut last_id = String::new();
+        loop {
+            let statement_text = "SELECT u.`id`, u.`data`, DATE_FORMAT(m.`parsed_time`, '%Y-%m-%d %H:%i:%s.%f') FROM `unknown` u JOIN `main_data` m ON m.`id` = u.`id` WHERE u.`id` > ? ORDER BY u.`id` LIMIT ?";
+            let rows: Vec<(String, Option<String>, String)> = sqlx::query_as(statement_text)
+                .bind(&last_id)
+                .bind(REPROCESS_PAGE_SIZE)
+                .fetch_all(&self.pool)
+                .await?;
+            let Some((id, _, _)) = rows.last() else {
+                break;
+            };
+            last_id = id.clone();
+
+            let mut lines = Vec::new();
+            for (id, raw, parsed_time) in rows {
+                stats.scanned += 1;
+                let raw = raw.unwrap_or_default();
+                let received_at =
+                    NaiveDateTime::parse_from_str(&parsed_time, "%Y-%m-%d %H:%M:%S%.f")?.and_utc();
+                if let Some(line) = reprocess_line(Uuid::parse_str(&id)?, &raw, received_at) {
+                    lines.push(line);
                 }
-                1
             }
-            libk0hax_aprs::data::ParsedAprsData::Status(x) => {
-                let statement_text =
-                    "INSERT INTO `status` (`id`, `to`, `timestamp`, `comment`) VALUES (?, ?, ?, ?)";
-                let statement = sqlx::query(statement_text);
-                let conn = &mut self.conn;
-                let record_timestamp: Option<String> = match &x.timestamp {
-                    Some(y) => Some(y.mariadb_string()),
-                    None => None,
-                };
-                let _ = statement
-                    .bind(record_uuid.hyphenated().to_string())
-                    .bind(x.to.clone())
-                    .bind(record_timestamp)
-                    .bind(x.comment.clone())
-                    .execute(conn)
-                    .await?;
-                3
+            if lines.is_empty() {
+                continue;
             }
-            libk0hax_aprs::data::ParsedAprsData::MicE(x) => {
-                let statement_text = "INSERT INTO `MicE` (`id`, `latitude`, `longitude`, `precision`, `message`, `speed`, `course`, `symbol_table`, `symbol_code`, `comment`, `current`) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
-                let statement = sqlx::query(statement_text);
-                let conn = &mut self.conn;
-                let _ = statement
-                    .bind(record_uuid.hyphenated().to_string())
-                    .bind(x.latitude)
-                    .bind(x.longitude)
-                    .bind(x.precision)
-                    .bind(x.message.clone())
-                    .bind(x.speed)
-                    .bind(x.course)
-                    .bind(x.symbol_table.to_string())
-                    .bind(x.symbol_code.to_string())
-                    .bind(x.comment.clone())
-                    .bind(x.current)
-                    .execute(conn)
-                    .await?;
-                4
+
+            let mut tx = self.pool.begin().await?;
+            let mut statement: QueryBuilder<MySql> =
+                QueryBuilder::new("DELETE FROM `main_data` WHERE `id` IN ");
+            statement.push_tuples(&lines, |mut b, line| {
+                b.push_bind(line.id.hyphenated().to_string());
+            });
+            statement.build().execute(&mut *tx).await?;
+            // The packets were counted as unknown when they were first stored
+            let mut reclassified: BTreeMap<&str, i64> = BTreeMap::new();
+            for line in &lines {
+                *reclassified.entry(&line.from).or_default() += 1;
             }
-            libk0hax_aprs::data::ParsedAprsData::Unknown(x) => {
-                let statement_text = "INSERT INTO `unknown` (`id`, `data`) VALUES (?, ?)";
-                let statement = sqlx::query(statement_text);
-                let conn = &mut self.conn;
-                let _ = statement
-                    .bind(record_uuid.hyphenated().to_string())
-                    .bind(x)
-                    .execute(conn)
+            for (callsign, count) in reclassified {
+                sqlx::query("UPDATE `stations` SET `unknown_count` = `unknown_count` - ? WHERE `callsign` = ?")
+                    .bind(count)
+                    .bind(callsign)
+                    .execute(&mut *tx)
                     .await?;
-                5
             }
-        };
-        debug!("[MariaDB::insert_aprs_line] Data Type: {:?}", &type_info);
+            Self::write_lines(&mut tx, &lines).await?;
+            tx.commit().await?;
+            stats.decoded += lines.len() as u64;
+        }
+        Ok(stats)
+    }
 
-        {
+    /// Remove rows past their retention period and thin out old positions.
+    ///
+    /// Rows are deleted in chunks of `PRUNE_BATCH_SIZE`, so no single
+    /// statement holds its locks for the whole run.
+    pub async fn prune(&self, policy: &RetentionPolicy) -> Result<PruneStats> {
+        let mut stats = PruneStats::default();
+        for retention in &policy.retain {
+            let cutoff = mariadb_time(retention::cutoff(retention.max_age));
+            loop {
+                let result = if retention.table == "paths" {
+                    sqlx::query("DELETE FROM `paths` WHERE `received_at` < ? LIMIT ?")
+                        .bind(&cutoff)
+                        .bind(PRUNE_BATCH_SIZE)
+                        .execute(&self.pool)
+                        .await?
+                } else {
+                    sqlx::query(
+                        "DELETE FROM `main_data` WHERE `type` = ? AND `parsed_time` < ? LIMIT ?",
+                    )
+                    .bind(&retention.table)
+                    .bind(&cutoff)
+                    .bind(PRUNE_BATCH_SIZE)
+                    .execute(&self.pool)
+                    .await?
+                };
+                let deleted = result.rows_affected();
+                debug!(
+                    "[MariaDB::prune] {} expired from {}",
+                    deleted, retention.table
+                );
+                stats.expired += deleted;
+                if (deleted as i64) < PRUNE_BATCH_SIZE {
+                    break;
+                }
+            }
+        }
+
+        if let Some(downsample) = &policy.downsample {
+            stats.downsampled += self.downsample(downsample).await?;
+        }
+
+        // Paths of packets which were removed above
+        let statement_text = "DELETE FROM `paths` WHERE `received_at` < ? AND NOT EXISTS (SELECT 1 FROM `main_data` m WHERE m.`id` = `paths`.`id`) LIMIT ?";
+        let cutoff = mariadb_time(retention::cutoff(ORPHAN_PATH_GRACE));
+        loop {
+            let result = sqlx::query(statement_text)
+                .bind(&cutoff)
+                .bind(PRUNE_BATCH_SIZE)
+                .execute(&self.pool)
+                .await?;
+            let deleted = result.rows_affected();
+            stats.expired += deleted;
+            if (deleted as i64) < PRUNE_BATCH_SIZE {
+                break;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Keep one position per station and interval, ranking the positions one
+    /// window at a time from where the last run stopped
+    async fn downsample(&self, downsample: &Downsample) -> Result<u64> {
+        let interval = downsample.interval_secs();
+        let until = downsample.until();
+        let state: Option<(i64,)> = sqlx::query_as(
+            "SELECT `downsampled_until` FROM `downsample_state` WHERE `interval_seconds` = ?",
+        )
+        .bind(interval)
+        .fetch_optional(&self.pool)
+        .await?;
+        let mut start = state.map(|x| x.0).unwrap_or(0);
+
+        let mut downsampled = 0;
+        while start < until {
+            // Skip ahead to the next position, instead of stepping through
+            // gaps one window at a time
             let statement_text =
-                "INSERT INTO main_data (`id`, `from`, `via`, `type`, `parsed_time`) VALUES (?, ?, ?, ?, ?)";
-            let statement = sqlx::query(statement_text);
-            let conn = &mut self.conn;
-            let _ = statement
-                .bind(record_uuid.hyphenated().to_string())
-                .bind(from)
-                .bind(via)
-                .bind(type_info)
-                .bind(parsed_time.clone())
-                .execute(conn)
+                "SELECT TIMESTAMPDIFF(SECOND, '1970-01-01', MIN(`parsed_time`)) FROM `main_data`
+                WHERE `type` IN ('position', 'mic_e') AND `parsed_time` >= ? AND `parsed_time` < ?";
+            let (next,): (Option<i64>,) = sqlx::query_as(statement_text)
+                .bind(unix_time(start))
+                .bind(unix_time(until))
+                .fetch_one(&self.pool)
                 .await?;
+            let window_start = match next {
+                Some(next) => downsample.interval_start(next),
+                None => until,
+            };
+            let window_end = downsample.window_end(window_start, until);
+
+            // MariaDB has no LIMIT in `IN` subqueries, but in a joined one
+            let statement_text = "DELETE `main_data` FROM `main_data` JOIN (
+                SELECT `id` FROM (
+                    SELECT `id`, ROW_NUMBER() OVER (
+                        PARTITION BY `from`, FLOOR(TIMESTAMPDIFF(SECOND, '1970-01-01', `parsed_time`) / ?)
+                        ORDER BY `parsed_time`, `id`
+                    ) AS `rn`
+                    FROM `main_data` WHERE `type` IN ('position', 'mic_e') AND `parsed_time` >= ? AND `parsed_time` < ?
+                ) AS `ranked` WHERE `rn` > 1 LIMIT ?
+            ) AS `extra` USING (`id`)";
+            loop {
+                let result = sqlx::query(statement_text)
+                    .bind(interval)
+                    .bind(unix_time(window_start))
+                    .bind(unix_time(window_end))
+                    .bind(PRUNE_BATCH_SIZE)
+                    .execute(&self.pool)
+                    .await?;
+                let deleted = result.rows_affected();
+                debug!("[MariaDB::prune] {} downsampled", deleted);
+                downsampled += deleted;
+                if (deleted as i64) < PRUNE_BATCH_SIZE {
+                    break;
+                }
+            }
+
+            let statement_text = "INSERT INTO `downsample_state` (`interval_seconds`, `downsampled_until`) VALUES (?, ?)
+                ON DUPLICATE KEY UPDATE `downsampled_until` = VALUES(`downsampled_until`)";
+            sqlx::query(statement_text)
+                .bind(interval)
+                .bind(window_end)
+                .execute(&self.pool)
+                .await?;
+            start = window_end;
         }
+        Ok(downsampled)
+    }
+
+    /// Rebuild the tables to reclaim the space of deleted rows and refresh
+    /// index statistics
+    pub async fn optimize(&self) -> Result<()> {
+        let statement_text = "OPTIMIZE TABLE `main_data`, `position`, `messages`, `status`, `MicE`, `unknown`, `paths`, `stations`";
+        let _ = sqlx::query(statement_text).fetch_all(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Drop every table, including `schema_version`
+    pub async fn drop_tables(&self) -> Result<()> {
+        let statement_text =
+            "DROP TABLE IF EXISTS MicE, messages, position, status, unknown, paths, main_data, stations, type, downsample_state, schema_version;";
+        let statement = sqlx::query(statement_text);
+        let _ = statement.execute(&self.pool).await?;
         Ok(())
     }
 
-    pub async fn create_tables(&mut self) -> Result<()> {
-        let conn = &mut self.conn;
-        let mut tx = conn.begin().await?;
+    /// Current schema version, `EMPTY_SCHEMA` for a new database.
+    ///
+    /// Databases created before versioning was introduced have our tables but
+    /// no `schema_version` table, and are reported as version 1.
+    pub async fn schema_version(&self) -> Result<i64> {
+        let mut conn = self.pool.acquire().await?;
+        Self::schema_version_on(&mut conn).await
+    }
 
-        // Drop the tables if they exist
-        {
-            let statement_text =
-                "DROP TABLE IF EXISTS MicE, main_data, messages, position, status, unknown, type;";
-            let statement = sqlx::query(statement_text);
-            let _ = statement.execute(&mut *tx).await?;
+    async fn schema_version_on(conn: &mut MySqlConnection) -> Result<i64> {
+        let statement_text = "SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = DATABASE() AND table_name = ?";
+        let (versioned,): (i64,) = sqlx::query_as(statement_text)
+            .bind("schema_version")
+            .fetch_one(&mut *conn)
+            .await?;
+        if versioned > 0 {
+            let (version,): (Option<i64>,) =
+                sqlx::query_as("SELECT MAX(`version`) FROM `schema_version`")
+                    .fetch_one(&mut *conn)
+                    .await?;
+            return Ok(version.unwrap_or(migrations::EMPTY_SCHEMA));
         }
+        let (legacy,): (i64,) = sqlx::query_as(statement_text)
+            .bind("main_data")
+            .fetch_one(&mut *conn)
+            .await?;
+        if legacy > 0 {
+            Ok(1)
+        } else {
+            Ok(migrations::EMPTY_SCHEMA)
+        }
+    }
 
-        // Create the message table
-        {
-            let statement_text = "CREATE TABLE `messages` (
+    /// Apply every pending migration. Returns the versions which were applied.
+    ///
+    /// MariaDB commits implicitly around DDL, so a migration which fails
+    /// half-way has to be repaired by hand before it can be retried.
+    pub async fn migrate(&self) -> Result<Vec<i64>> {
+        let mut conn = self.pool.acquire().await?;
+        let current = Self::schema_version_on(&mut conn).await?;
+        migrations::check_not_newer(current, MIGRATIONS)?;
+        debug!("[MariaDB::migrate] Schema is at version {}", current);
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS `schema_version` (
+                `version`             BIGINT NOT NULL PRIMARY KEY,
+                `description`         TEXT NOT NULL,
+                `applied_time`        DATETIME(6) NOT NULL
+            )",
+        )
+        .execute(&mut *conn)
+        .await?;
+        let statement_text = "INSERT IGNORE INTO `schema_version` (`version`, `description`, `applied_time`) VALUES (?, ?, UTC_TIMESTAMP(6))";
+        if current > migrations::EMPTY_SCHEMA {
+            // Record the implicit baseline of a pre-versioning database
+            for migration in MIGRATIONS.iter().filter(|x| x.version <= current) {
+                sqlx::query(statement_text)
+                    .bind(migration.version)
+                    .bind(migration.description)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+        }
+
+        let mut applied = Vec::new();
+        for migration in migrations::pending(MIGRATIONS, current) {
+            let mut tx = conn.begin().await?;
+            for statement_text in migration.statements {
+                let _ = sqlx::query(statement_text).execute(&mut *tx).await?;
+            }
+            sqlx::query(statement_text)
+                .bind(migration.version)
+                .bind(migration.description)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            debug!(
+                "[MariaDB::migrate] Applied migration {}: {}",
+                migration.version, migration.description
+            );
+            applied.push(migration.version);
+        }
+        Ok(applied)
+    }
+}
+
+/// Schema migrations, applied in order of `version`
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Initial schema",
+        statements: &[
+            // Create the message table
+            "CREATE TABLE `messages` (
                 `id`        CHAR(36) NOT NULL PRIMARY KEY,
                 `to`        TEXT NOT NULL,
                 `addressee` TEXT NOT NULL,
                 `text`      TEXT NOT NULL,
                 `msg_id`    TEXT
-            )";
-            let statement = sqlx::query(statement_text);
-            let _ = statement.execute(&mut *tx).await?;
-        }
-
-        // Create the position table
-        {
-            let statement_text = "CREATE TABLE `position` (
+            )",
+            // Create the position table
+            "CREATE TABLE `position` (
                 `id`                  CHAR(36) NOT NULL PRIMARY KEY,
                 `to`                  TEXT NOT NULL,
                 `timestamp`           DATETIME(6),
@@ -204,26 +863,16 @@ impl ConnectionArc {
                 `symbol_code`         TEXT NOT NULL,
                 `comment`             TEXT NOT NULL,
                 `cst`                 TEXT NOT NULL
-            )";
-            let statement = sqlx::query(statement_text);
-            let _ = statement.execute(&mut *tx).await?;
-        }
-
-        // Create the Status table
-        {
-            let statement_text = "CREATE TABLE `status` (
+            )",
+            // Create the Status table
+            "CREATE TABLE `status` (
                 `id`                  CHAR(36) NOT NULL PRIMARY KEY,
                 `to`                  TEXT NOT NULL,
                 `timestamp`           DATETIME(6),
                 `comment`             TEXT NOT NULL
-            )";
-            let statement = sqlx::query(statement_text);
-            let _ = statement.execute(&mut *tx).await?;
-        }
-
-        // Create the MicE table
-        {
-            let statement_text = "CREATE TABLE `MicE` (
+            )",
+            // Create the MicE table
+            "CREATE TABLE `MicE` (
                 `id`                  CHAR(36) NOT NULL PRIMARY KEY,
                 `latitude`            DOUBLE NOT NULL,
                 `longitude`           DOUBLE NOT NULL,
@@ -235,62 +884,296 @@ impl ConnectionArc {
                 `symbol_code`         TEXT NOT NULL,
                 `comment`             TEXT NOT NULL,
                 `current`             INTEGER NOT NULL
-            )";
-            let statement = sqlx::query(statement_text);
-            let _ = statement.execute(&mut *tx).await?;
-        }
-
-        // Create the `unknown` table
-        {
-            let statement_text = "CREATE TABLE `unknown` (
+            )",
+            // Create the `unknown` table
+            "CREATE TABLE `unknown` (
                 `id`                  CHAR(36) NOT NULL PRIMARY KEY,
                 `data`                TEXT
-            )";
-            let statement = sqlx::query(statement_text);
-            let _ = statement.execute(&mut *tx).await?;
-        }
-
-        // Create the Type Lookup table
-        {
-            let statement_text = "CREATE TABLE `type` (
+            )",
+            // Create and populate the Type Lookup table
+            "CREATE TABLE `type` (
                 `id`                  CHAR(36) NOT NULL PRIMARY KEY,
                 `table`             TEXT NOT NULL
-            )";
-            let statement = sqlx::query(statement_text);
-            let _ = statement.execute(&mut *tx).await?;
-        }
-
-        // Populate the Type Lookup table
-        {
-            let tables = vec![(1, "messages"), (2, "position"), (3, "status"), (4, "MicE"), (5, "unknown")];
-            debug!(
-                "[MariaDb::create_db] prepared records to insert into `type` table: {:?}",
-                &tables
-            );
-            let statement_text = "INSERT INTO `type` (id, `table`) VALUES (?, ?)";
-            for table in tables {
-                let statement = sqlx::query(statement_text);
-                let _ = statement
-                    .bind(table.0)
-                    .bind(table.1)
-                    .execute(&mut *tx)
-                    .await?;
-            }
-        }
-
-        // Create the main lookup table
-        {
-            let statement_text = "CREATE TABLE `main_data` (
+            )",
+            "INSERT INTO `type` (id, `table`) VALUES (1, 'messages'), (2, 'position'), (3, 'status'), (4, 'MicE'), (5, 'unknown')",
+            // Create the main lookup table
+            "CREATE TABLE `main_data` (
                 `id`                  CHAR(36) NOT NULL PRIMARY KEY,
                 `from`              TEXT NOT NULL,
                 `via`                 TEXT NOT NULL,
                 `type`                INTEGER NOT NULL,
                 `parsed_time`       DATETIME(6)
-            )";
-            let statement = sqlx::query(statement_text);
-            let _ = statement.execute(&mut *tx).await?;
-        }
+            )",
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "Foreign keys, indexes, packet type enum and stations table",
+        statements: &[
+            "CREATE TABLE `stations` (
+                `callsign`            VARCHAR(32) NOT NULL PRIMARY KEY,
+                `first_seen`          DATETIME(6) NOT NULL
+            )",
+            "UPDATE `main_data` SET `parsed_time` = UTC_TIMESTAMP(6) WHERE `parsed_time` IS NULL",
+            "INSERT INTO `stations` (`callsign`, `first_seen`)
+                SELECT `from`, MIN(`parsed_time`) FROM `main_data` GROUP BY `from`",
+            "ALTER TABLE `main_data`
+                MODIFY `from` VARCHAR(32) NOT NULL,
+                MODIFY `parsed_time` DATETIME(6) NOT NULL,
+                ADD COLUMN `packet_type` ENUM('message', 'position', 'status', 'mic_e', 'unknown') NOT NULL DEFAULT 'unknown'",
+            "UPDATE `main_data` SET `packet_type` = ELT(`type`, 'message', 'position', 'status', 'mic_e', 'unknown')
+                WHERE `type` BETWEEN 1 AND 5",
+            "ALTER TABLE `main_data`
+                DROP COLUMN `type`,
+                CHANGE `packet_type` `type` ENUM('message', 'position', 'status', 'mic_e', 'unknown') NOT NULL,
+                ADD CONSTRAINT `main_data_station_fk` FOREIGN KEY (`from`) REFERENCES `stations` (`callsign`),
+                ADD INDEX `main_data_from_time_idx` (`from`, `parsed_time`),
+                ADD INDEX `main_data_time_idx` (`parsed_time`),
+                ADD INDEX `main_data_type_time_idx` (`type`, `parsed_time`)",
+            // Rows left behind by a crash between the two inserts of the old
+            // writer would violate the new constraints
+            "DELETE FROM `messages` WHERE `id` NOT IN (SELECT `id` FROM `main_data`)",
+            "DELETE FROM `position` WHERE `id` NOT IN (SELECT `id` FROM `main_data`)",
+            "DELETE FROM `status` WHERE `id` NOT IN (SELECT `id` FROM `main_data`)",
+            "DELETE FROM `MicE` WHERE `id` NOT IN (SELECT `id` FROM `main_data`)",
+            "DELETE FROM `unknown` WHERE `id` NOT IN (SELECT `id` FROM `main_data`)",
+            "ALTER TABLE `messages`
+                ADD CONSTRAINT `messages_main_data_fk` FOREIGN KEY (`id`) REFERENCES `main_data` (`id`) ON DELETE CASCADE,
+                ADD INDEX `messages_addressee_idx` (`addressee`(16))",
+            "ALTER TABLE `position`
+                ADD CONSTRAINT `position_main_data_fk` FOREIGN KEY (`id`) REFERENCES `main_data` (`id`) ON DELETE CASCADE,
+                ADD INDEX `position_lat_lon_idx` (`latitude`, `longitude`)",
+            "ALTER TABLE `status`
+                ADD CONSTRAINT `status_main_data_fk` FOREIGN KEY (`id`) REFERENCES `main_data` (`id`) ON DELETE CASCADE",
+            "ALTER TABLE `MicE`
+                ADD CONSTRAINT `MicE_main_data_fk` FOREIGN KEY (`id`) REFERENCES `main_data` (`id`) ON DELETE CASCADE,
+                ADD INDEX `MicE_lat_lon_idx` (`latitude`, `longitude`)",
+            "ALTER TABLE `unknown`
+                ADD CONSTRAINT `unknown_main_data_fk` FOREIGN KEY (`id`) REFERENCES `main_data` (`id`) ON DELETE CASCADE",
+            // The packet type is now stored by name
+            "DROP TABLE `type`",
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "Store undecoded packets",
+        statements: &[
+            "UPDATE `unknown` SET `data` = '' WHERE `data` IS NULL",
+            "ALTER TABLE `unknown` MODIFY `data` TEXT NOT NULL, ADD COLUMN `error` TEXT",
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "Paths of suppressed duplicate packets",
+        statements: &[
+            // No foreign key: the first copy may still be queued in another
+            // worker when its duplicates are written
+            "CREATE TABLE `paths` (
+                `id`                  CHAR(36) NOT NULL,
+                `via`                 TEXT NOT NULL,
+                `received_at`         DATETIME(6) NOT NULL,
+                INDEX `paths_id_idx` (`id`)
+            )",
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "Station state: last position, path, device and packet counts",
+        statements: &[
+            "ALTER TABLE `stations`
+                ADD COLUMN `last_heard`          DATETIME(6) NOT NULL DEFAULT '1000-01-01',
+                ADD COLUMN `last_via`            TEXT NOT NULL DEFAULT '',
+                ADD COLUMN `last_position_time`  DATETIME(6),
+                ADD COLUMN `latitude`            DOUBLE,
+                ADD COLUMN `longitude`           DOUBLE,
+                ADD COLUMN `symbol_table`        VARCHAR(1),
+                ADD COLUMN `symbol_code`         VARCHAR(1),
+                ADD COLUMN `comment`             TEXT,
+                ADD COLUMN `device`              VARCHAR(16),
+                ADD COLUMN `position_count`      BIGINT NOT NULL DEFAULT 0,
+                ADD COLUMN `message_count`       BIGINT NOT NULL DEFAULT 0,
+                ADD COLUMN `status_count`        BIGINT NOT NULL DEFAULT 0,
+                ADD COLUMN `mic_e_count`         BIGINT NOT NULL DEFAULT 0,
+                ADD COLUMN `unknown_count`       BIGINT NOT NULL DEFAULT 0",
+            // Backfill from the packets stored so far
+            "UPDATE `stations` s JOIN (
+                SELECT `from`, MAX(`parsed_time`) AS `last_heard`,
+                    SUM(`type` = 'position') AS `position_count`,
+                    SUM(`type` = 'message') AS `message_count`,
+                    SUM(`type` = 'status') AS `status_count`,
+                    SUM(`type` = 'mic_e') AS `mic_e_count`,
+                    SUM(`type` = 'unknown') AS `unknown_count`
+                FROM `main_data` GROUP BY `from`
+            ) c ON c.`from` = s.`callsign` SET
+                s.`last_heard` = c.`last_heard`,
+                s.`position_count` = c.`position_count`,
+                s.`message_count` = c.`message_count`,
+                s.`status_count` = c.`status_count`,
+                s.`mic_e_count` = c.`mic_e_count`,
+                s.`unknown_count` = c.`unknown_count`",
+            "UPDATE `stations` SET `last_heard` = `first_seen` WHERE `last_heard` = '1000-01-01'",
+            "UPDATE `stations` s JOIN (
+                SELECT `from`, `via`, ROW_NUMBER() OVER (PARTITION BY `from` ORDER BY `parsed_time` DESC) AS `rn`
+                FROM `main_data`
+            ) l ON l.`from` = s.`callsign` AND l.`rn` = 1 SET s.`last_via` = l.`via`",
+            "UPDATE `stations` s JOIN (
+                SELECT p.*, ROW_NUMBER() OVER (PARTITION BY `from` ORDER BY `parsed_time` DESC) AS `rn` FROM (
+                    SELECT m.`from`, m.`parsed_time`, x.`latitude`, x.`longitude`, x.`symbol_table`, x.`symbol_code`, x.`comment`
+                        FROM `main_data` m JOIN `position` x ON x.`id` = m.`id`
+                    UNION ALL
+                    SELECT m.`from`, m.`parsed_time`, x.`latitude`, x.`longitude`, x.`symbol_table`, x.`symbol_code`, x.`comment`
+                        FROM `main_data` m JOIN `MicE` x ON x.`id` = m.`id`
+                ) p
+            ) p ON p.`from` = s.`callsign` AND p.`rn` = 1 SET
+                s.`last_position_time` = p.`parsed_time`,
+                s.`latitude` = p.`latitude`,
+                s.`longitude` = p.`longitude`,
+                s.`symbol_table` = LEFT(p.`symbol_table`, 1),
+                s.`symbol_code` = LEFT(p.`symbol_code`, 1),
+                s.`comment` = p.`comment`",
+            "UPDATE `stations` s JOIN (
+                SELECT d.*, ROW_NUMBER() OVER (PARTITION BY `from` ORDER BY `parsed_time` DESC) AS `rn` FROM (
+                    SELECT m.`from`, m.`parsed_time`, x.`to` FROM `main_data` m JOIN `position` x ON x.`id` = m.`id`
+                    UNION ALL
+                    SELECT m.`from`, m.`parsed_time`, x.`to` FROM `main_data` m JOIN `messages` x ON x.`id` = m.`id`
+                    UNION ALL
+                    SELECT m.`from`, m.`parsed_time`, x.`to` FROM `main_data` m JOIN `status` x ON x.`id` = m.`id`
+                ) d
+            ) d ON d.`from` = s.`callsign` AND d.`rn` = 1 SET s.`device` = LEFT(d.`to`, 16)",
+            "CREATE INDEX `stations_last_heard_idx` ON `stations` (`last_heard`)",
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "Lines without a source callsign",
+        statements: &[
+            "ALTER TABLE `main_data` MODIFY `from` VARCHAR(32) NULL",
+            "UPDATE `main_data` SET `from` = NULL WHERE `from` = ''",
+            "DELETE FROM `stations` WHERE `callsign` = ''",
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "Downsampling progress",
+        statements: &["CREATE TABLE `downsample_state` (
+                `interval_seconds`    BIGINT NOT NULL PRIMARY KEY,
+                -- Unix time
+                `downsampled_until`   BIGINT NOT NULL
+            )"],
+    },
+];
 
-        Ok(())
+/// Whether an error returned by this backend means the database could not
+/// be reached or is overloaded, rather than that the data was rejected
+pub fn is_unavailable(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<sqlx::Error>()
+        .is_some_and(is_transient)
+}
+
+/// Whether an error is worth retrying: lost connections, pool exhaustion,
+/// deadlocks and lock wait timeouts.
+fn is_transient(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => true,
+        sqlx::Error::Database(e) => match e.try_downcast_ref::<MySqlDatabaseError>() {
+            // ER_CON_COUNT_ERROR, ER_LOCK_WAIT_TIMEOUT, ER_LOCK_DEADLOCK,
+            // CR_SERVER_GONE_ERROR, CR_SERVER_LOST
+            Some(e) => matches!(e.number(), 1040 | 1205 | 1213 | 2006 | 2013),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::parse_line_or_unknown;
+    use tokio::sync::{Mutex, MutexGuard};
+
+    /// Scratch database named by `K0HAX_APRS_TEST_MARIADB` as
+    /// `user:password@host:port/database`. Its tables are dropped and
+    /// recreated; without the variable the test is skipped. The tests take
+    /// turns on it.
+    async fn test_db() -> Option<(MariaDb, MutexGuard<'static, ()>)> {
+        static SCRATCH: Mutex<()> = Mutex::const_new(());
+        let url = std::env::var("K0HAX_APRS_TEST_MARIADB").ok()?;
+        let (credentials, location) = url.rsplit_once('@').expect("user:password@host/database");
+        let (username, password) = credentials.split_once(':').expect("user:password");
+        let (hostname, database) = location.split_once('/').expect("host/database");
+        let db = MariaDb::new(MariaDbOptions {
+            hostname: hostname.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            database: database.to_string(),
+            pool_size: 2,
+            max_retries: 0,
+            lazy: false,
+        })
+        .await
+        .unwrap();
+        let guard = SCRATCH.lock().await;
+        db.drop_tables().await.unwrap();
+        db.migrate().await.unwrap();
+        Some((db, guard))
+    }
+
+    #[tokio::test]
+    async fn line_without_a_source_is_stored() {
+        let Some((db, _guard)) = test_db().await else {
+            return;
+        };
+        let line = parse_line_or_unknown("garbage without header");
+        db.insert_batch(&[line], &[]).await.unwrap();
+        let (sourceless,): (i64,) =
+            sqlx::query_as("SELECT count(*) FROM main_data WHERE `from` IS NULL")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(sourceless, 1);
+        let (stations,): (i64,) = sqlx::query_as("SELECT count(*) FROM stations")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(stations, 0);
+    }
+
+    #[tokio::test]
+    async fn downsampling_resumes_where_it_stopped() {
+        let Some((db, _guard)) = test_db().await else {
+            return;
+        };
+        let downsample = Downsample {
+            older_than: Duration::from_secs(24 * 60 * 60),
+            interval: Duration::from_secs(600),
+        };
+        let policy = RetentionPolicy {
+            retain: Vec::new(),
+            downsample: Some(downsample),
+        };
+        let start = downsample.interval_start(Utc::now().timestamp() - 3 * 24 * 60 * 60);
+        let line = |offset: i64| {
+            let mut line = parse_line_or_unknown("N0CALL>APRS:!4903.50N/07201.75W-Test");
+            line.received_at = DateTime::from_timestamp(start + offset, 0).unwrap();
+            line
+        };
+        let lines: Vec<_> = [10, 20, 30, 700, 2 * 24 * 60 * 60].map(line).into();
+        db.insert_batch(&lines, &[]).await.unwrap();
+        let positions = || async {
+            let (positions,): (i64,) = sqlx::query_as("SELECT count(*) FROM `position`")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+            positions
+        };
+
+        assert_eq!(db.prune(&policy).await.unwrap().downsampled, 2);
+        assert_eq!(positions().await, 3);
+
+        // Later positions of an interval which was already thinned are left
+        db.insert_batch(&[line(40)], &[]).await.unwrap();
+        assert_eq!(db.prune(&policy).await.unwrap().downsampled, 0);
+        assert_eq!(positions().await, 4);
     }
 }
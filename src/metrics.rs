@@ -0,0 +1,283 @@
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Counters and histograms of the ingest pipeline, exported in the
+/// Prometheus text format by `router`
+pub struct Metrics {
+    registry: Registry,
+    /// Packets read from the source, by `ParsedAprsData::type_name`
+    pub packets_received: IntCounterVec,
+    /// Packets which could not be decoded and are kept as `unknown`
+    pub decode_failures: IntCounter,
+    /// Packets dropped as copies of an earlier one
+    pub duplicates: IntCounter,
+    /// Lines and paths written by the storage tasks
+    pub stored: IntCounter,
+    /// Lines and paths the storage tasks failed to write
+    pub store_errors: IntCounter,
+    /// Seconds taken by a single insert or batch insert
+    pub insert_latency: Histogram,
+    /// Items waiting in the queue of each sink, in memory or on disk
+    pub queue_depth: IntGaugeVec,
+    /// Items the queue of each sink can hold
+    pub queue_capacity: IntGaugeVec,
+    /// Items of each sink's queue waiting in its spill file
+    pub queue_spilled: IntGaugeVec,
+    /// Items a sink missed because its queue was full
+    pub sink_dropped: IntCounterVec,
+    /// Lines and paths of each database sink waiting in its spool
+    pub spool_items: IntGaugeVec,
+    /// Bytes of each database sink waiting in its spool
+    pub spool_bytes: IntGaugeVec,
+    /// Lines and paths dropped from a full spool
+    pub spool_dropped: IntCounterVec,
+    /// Times the APRS-IS connection was established again
+    pub reconnects: IntCounter,
+    /// Always 1, labelled with the APRS-IS server in use
+    pub server_info: IntGaugeVec,
+    /// WebSocket clients subscribed to the live feed
+    pub live_clients: IntGauge,
+    /// Live feed clients disconnected for falling behind
+    pub live_dropped: IntCounter,
+    /// Clients logged in to the APRS-IS server
+    pub server_clients: IntGauge,
+    /// APRS-IS server clients disconnected for falling behind
+    pub server_dropped: IntCounter,
+    /// Packets accepted from APRS-IS server clients
+    pub server_uploads: IntCounter,
+    last_packet_age: prometheus::Gauge,
+    last_packet: Mutex<Option<Instant>>,
+}
+
+/// The metrics of this process
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new();
+        let packets_received = IntCounterVec::new(
+            Opts::new(
+                "aprs_packets_received_total",
+                "Packets read from the source",
+            ),
+            &["type"],
+        )
+        .unwrap();
+        let decode_failures = IntCounter::new(
+            "aprs_decode_failures_total",
+            "Packets which could not be decoded",
+        )
+        .unwrap();
+        let duplicates = IntCounter::new(
+            "aprs_duplicates_total",
+            "Packets dropped as copies arriving via another path",
+        )
+        .unwrap();
+        let stored = IntCounter::new("aprs_stored_total", "Lines and paths written").unwrap();
+        let store_errors = IntCounter::new(
+            "aprs_store_errors_total",
+            "Lines and paths which failed to be written",
+        )
+        .unwrap();
+        let insert_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "aprs_insert_duration_seconds",
+                "Time taken by an insert or batch insert",
+            )
+            .buckets(prometheus::exponential_buckets(0.0005, 2.0, 14).unwrap()),
+        )
+        .unwrap();
+        let queue_depth = IntGaugeVec::new(
+            Opts::new("aprs_queue_depth", "Items waiting in the queue of a sink"),
+            &["sink"],
+        )
+        .unwrap();
+        let queue_capacity = IntGaugeVec::new(
+            Opts::new("aprs_queue_capacity", "Items the queue of a sink can hold"),
+            &["sink"],
+        )
+        .unwrap();
+        let queue_spilled = IntGaugeVec::new(
+            Opts::new(
+                "aprs_queue_spilled",
+                "Items of the queue of a sink waiting on disk",
+            ),
+            &["sink"],
+        )
+        .unwrap();
+        let sink_dropped = IntCounterVec::new(
+            Opts::new(
+                "aprs_sink_dropped_total",
+                "Items a sink missed because its queue was full",
+            ),
+            &["sink"],
+        )
+        .unwrap();
+        let spool_items = IntGaugeVec::new(
+            Opts::new(
+                "aprs_spool_items",
+                "Lines and paths waiting in the spool of a sink",
+            ),
+            &["sink"],
+        )
+        .unwrap();
+        let spool_bytes = IntGaugeVec::new(
+            Opts::new("aprs_spool_bytes", "Bytes waiting in the spool of a sink"),
+            &["sink"],
+        )
+        .unwrap();
+        let spool_dropped = IntCounterVec::new(
+            Opts::new(
+                "aprs_spool_dropped_total",
+                "Lines and paths dropped because the spool of a sink was full",
+            ),
+            &["sink"],
+        )
+        .unwrap();
+        let reconnects = IntCounter::new(
+            "aprs_reconnects_total",
+            "Times the APRS-IS connection was established again",
+        )
+        .unwrap();
+        let server_info = IntGaugeVec::new(
+            Opts::new("aprs_server_info", "APRS-IS server in use"),
+            &["hostname", "address"],
+        )
+        .unwrap();
+        let live_clients = IntGauge::new(
+            "aprs_live_clients",
+            "WebSocket clients subscribed to the live feed",
+        )
+        .unwrap();
+        let live_dropped = IntCounter::new(
+            "aprs_live_dropped_total",
+            "Live feed clients disconnected for falling behind",
+        )
+        .unwrap();
+        let server_clients = IntGauge::new(
+            "aprs_server_clients",
+            "Clients logged in to the APRS-IS server",
+        )
+        .unwrap();
+        let server_dropped = IntCounter::new(
+            "aprs_server_dropped_total",
+            "APRS-IS server clients disconnected for falling behind",
+        )
+        .unwrap();
+        let server_uploads = IntCounter::new(
+            "aprs_server_uploads_total",
+            "Packets accepted from APRS-IS server clients",
+        )
+        .unwrap();
+        let last_packet_age = prometheus::Gauge::new(
+            "aprs_last_packet_age_seconds",
+            "Seconds since the last packet was read, -1 before the first one",
+        )
+        .unwrap();
+        registry
+            .register(Box::new(packets_received.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(decode_failures.clone()))
+            .unwrap();
+        registry.register(Box::new(duplicates.clone())).unwrap();
+        registry.register(Box::new(stored.clone())).unwrap();
+        registry.register(Box::new(store_errors.clone())).unwrap();
+        registry.register(Box::new(insert_latency.clone())).unwrap();
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+        registry.register(Box::new(queue_capacity.clone())).unwrap();
+        registry.register(Box::new(queue_spilled.clone())).unwrap();
+        registry.register(Box::new(sink_dropped.clone())).unwrap();
+        registry.register(Box::new(spool_items.clone())).unwrap();
+        registry.register(Box::new(spool_bytes.clone())).unwrap();
+        registry.register(Box::new(spool_dropped.clone())).unwrap();
+        registry.register(Box::new(reconnects.clone())).unwrap();
+        registry.register(Box::new(server_info.clone())).unwrap();
+        registry.register(Box::new(live_clients.clone())).unwrap();
+        registry.register(Box::new(live_dropped.clone())).unwrap();
+        registry.register(Box::new(server_clients.clone())).unwrap();
+        registry.register(Box::new(server_dropped.clone())).unwrap();
+        registry.register(Box::new(server_uploads.clone())).unwrap();
+        registry
+            .register(Box::new(last_packet_age.clone()))
+            .unwrap();
+        Metrics {
+            registry,
+            packets_received,
+            decode_failures,
+            duplicates,
+            stored,
+            store_errors,
+            insert_latency,
+            queue_depth,
+            queue_capacity,
+            queue_spilled,
+            sink_dropped,
+            spool_items,
+            spool_bytes,
+            spool_dropped,
+            reconnects,
+            server_info,
+            live_clients,
+            live_dropped,
+            server_clients,
+            server_dropped,
+            server_uploads,
+            last_packet_age,
+            last_packet: Mutex::new(None),
+        }
+    }
+
+    /// Count a packet read from the source
+    pub fn packet_received(&self, type_name: &str) {
+        self.packets_received.with_label_values(&[type_name]).inc();
+        if type_name == "unknown" {
+            self.decode_failures.inc();
+        }
+        *self.last_packet.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Replace the server labels of `server_info`
+    pub fn set_server(&self, hostname: &str, address: &str) {
+        self.server_info.reset();
+        self.server_info
+            .with_label_values(&[hostname, address])
+            .set(1);
+    }
+
+    /// Time since the last packet was read, if one was
+    pub fn last_packet_age(&self) -> Option<Duration> {
+        self.last_packet.lock().unwrap().map(|x| x.elapsed())
+    }
+
+    /// Everything in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let age = match self.last_packet_age() {
+            Some(x) => x.as_secs_f64(),
+            None => -1.0,
+        };
+        self.last_packet_age.set(age);
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Could not encode metrics: {}", e);
+        }
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+async fn metrics_handler() -> impl IntoResponse {
+    ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], METRICS.encode())
+}
+
+/// Routes serving `GET /metrics`
+pub fn router() -> Router {
+    Router::new().route("/metrics", get(metrics_handler))
+}
@@ -0,0 +1,60 @@
+use anyhow::{anyhow, Result};
+
+/// A forward schema migration embedded in the binary
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub statements: &'static [&'static str],
+}
+
+/// Version reported for a database without any of our tables
+pub const EMPTY_SCHEMA: i64 = 0;
+
+/// Highest version in a list of migrations
+pub fn latest_version(migrations: &[Migration]) -> i64 {
+    migrations
+        .iter()
+        .map(|x| x.version)
+        .max()
+        .unwrap_or(EMPTY_SCHEMA)
+}
+
+/// Migrations which still have to be applied to a database at `current`
+pub fn pending(migrations: &[Migration], current: i64) -> impl Iterator<Item = &Migration> {
+    migrations.iter().filter(move |x| x.version > current)
+}
+
+/// Fail if the database was written by a newer release than this binary
+pub fn check_not_newer(current: i64, migrations: &[Migration]) -> Result<()> {
+    let latest = latest_version(migrations);
+    if current > latest {
+        return Err(anyhow!(
+            "Database schema version {} is newer than this binary supports ({}); refusing to start",
+            current,
+            latest
+        ));
+    }
+    Ok(())
+}
+
+/// Decide whether ingest may start against a database at schema version `current`.
+///
+/// An empty database may be initialized in place and yields `Ok(true)`. A
+/// database behind the binary has to be upgraded with `migrate` first, and a
+/// database ahead of the binary was written by a newer release and is never
+/// touched.
+pub fn check_startup(current: i64, migrations: &[Migration]) -> Result<bool> {
+    check_not_newer(current, migrations)?;
+    let latest = latest_version(migrations);
+    if current == EMPTY_SCHEMA {
+        Ok(true)
+    } else if current < latest {
+        Err(anyhow!(
+            "Database schema version {} is older than {}; run the `migrate` subcommand first",
+            current,
+            latest
+        ))
+    } else {
+        Ok(false)
+    }
+}
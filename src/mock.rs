@@ -0,0 +1,345 @@
+use crate::client::LoginResponse;
+use crate::server::Login;
+use futures_util::sink::SinkExt;
+use futures_util::StreamExt;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+
+/// How the mock answers a login
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoginReply {
+    /// `verified` when the passcode is the one of the callsign, as a real
+    /// server does
+    Check,
+    /// Always `verified` or always `unverified`
+    Fixed(bool),
+    /// This line instead of a `# logresp`
+    Line(String),
+    /// Nothing, so the client waits until it gives up
+    Silent,
+}
+
+/// One thing the mock does on a connection once the client has logged in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Send a packet or any other line
+    Line(String),
+    /// Send a comment like the keepalives of aprsc
+    Keepalive,
+    /// Wait before the next step
+    Wait(Duration),
+    /// Wait until the client has sent this many lines after its login
+    Expect(usize),
+    /// Close the connection
+    Disconnect,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockOptions {
+    /// First line sent on every connection
+    pub banner: String,
+    /// Server name in `# logresp` lines
+    pub name: String,
+    pub login: LoginReply,
+    /// Steps of the first connection, the second and so on. Later
+    /// connections only log in. A connection stays open after its last step
+    /// until the client closes it.
+    pub scripts: Vec<Vec<Step>>,
+}
+
+impl Default for MockOptions {
+    fn default() -> Self {
+        MockOptions {
+            banner: "# aprsc 2.1.14-mock".to_string(),
+            name: "T2MOCK".to_string(),
+            login: LoginReply::Check,
+            scripts: Vec::new(),
+        }
+    }
+}
+
+/// A line a client sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Received {
+    /// Number of the connection, counted from 0
+    pub connection: usize,
+    /// The line without its line ending, the first one being the login
+    pub line: String,
+}
+
+#[derive(Default)]
+struct MockState {
+    scripts: VecDeque<Vec<Step>>,
+    connections: usize,
+    received: Vec<Received>,
+}
+
+/// An APRS-IS server for tests, listening on a free port of 127.0.0.1.
+///
+/// Each connection gets the banner and the configured answer to its login,
+/// then runs the next script. Every line clients send is kept, so tests can
+/// check logins, filter commands and transmitted packets. The server stops
+/// when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    /// Woken whenever a line is received
+    received: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    pub async fn start(options: MockOptions) -> std::io::Result<MockServer> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState {
+            scripts: options.scripts.iter().cloned().collect(),
+            ..Default::default()
+        }));
+        let received = Arc::new(Notify::new());
+        let task = tokio::spawn(accept_loop(
+            listener,
+            Arc::new(options),
+            state.clone(),
+            received.clone(),
+        ));
+        Ok(MockServer {
+            addr,
+            state,
+            received,
+            task,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Number of connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    /// Lines received so far, in order
+    pub fn received(&self) -> Vec<Received> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Lines received on one connection, starting with the login
+    pub fn lines(&self, connection: usize) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .received
+            .iter()
+            .filter(|x| x.connection == connection)
+            .map(|x| x.line.clone())
+            .collect()
+    }
+
+    /// Wait until `count` lines were received in total, giving up after
+    /// `timeout`. All lines received by then are returned either way.
+    pub async fn wait_for_lines(&self, count: usize, timeout: Duration) -> Vec<Received> {
+        let wait = async {
+            loop {
+                let notified = self.received.notified();
+                if self.state.lock().unwrap().received.len() >= count {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        let _ = tokio::time::timeout(timeout, wait).await;
+        self.received()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    options: Arc<MockOptions>,
+    state: Arc<Mutex<MockState>>,
+    received: Arc<Notify>,
+) {
+    let mut connections = Vec::new();
+    while let Ok((stream, _)) = listener.accept().await {
+        let (connection, script) = {
+            let mut state = state.lock().unwrap();
+            state.connections += 1;
+            (
+                state.connections - 1,
+                state.scripts.pop_front().unwrap_or_default(),
+            )
+        };
+        let handle = tokio::spawn(serve(
+            stream,
+            connection,
+            script,
+            options.clone(),
+            state.clone(),
+            received.clone(),
+        ));
+        connections.push(AbortOnDrop(handle));
+    }
+}
+
+/// Ends the connection tasks along with the accept loop
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    connection: usize,
+    script: Vec<Step>,
+    options: Arc<MockOptions>,
+    state: Arc<Mutex<MockState>>,
+    received: Arc<Notify>,
+) {
+    let (reader, writer) = stream.into_split();
+    let mut reader = FramedRead::new(reader, LinesCodec::new_with_max_length(2048));
+    let mut writer = FramedWrite::new(writer, LinesCodec::new());
+    if send(&mut writer, &options.banner).await.is_err() {
+        return;
+    }
+    let Some(login) = record_next(&mut reader, connection, &state, &received).await else {
+        return;
+    };
+    let reply = match &options.login {
+        LoginReply::Check => Login::parse(&login)
+            .ok()
+            .map(|x| response(&options, x.callsign, x.verified)),
+        LoginReply::Fixed(verified) => {
+            let callsign = login.split_whitespace().nth(1).unwrap_or_default();
+            Some(response(&options, callsign.to_uppercase(), *verified))
+        }
+        LoginReply::Line(x) => Some(x.clone()),
+        LoginReply::Silent => None,
+    };
+    if let Some(reply) = reply {
+        if send(&mut writer, &reply).await.is_err() {
+            return;
+        }
+    }
+    let lines = ConnectionLines {
+        connection,
+        state: state.clone(),
+        received: received.clone(),
+    };
+    let mut reading = AbortOnDrop(tokio::spawn(async move {
+        while record_next(&mut reader, connection, &state, &received)
+            .await
+            .is_some()
+        {}
+    }));
+    for step in script {
+        let sent = match step {
+            Step::Line(x) => send(&mut writer, &x).await,
+            Step::Keepalive => {
+                let text = format!(
+                    "{} {} {}",
+                    options.banner,
+                    chrono::Utc::now().format("%d %b %Y %H:%M:%S GMT"),
+                    options.name
+                );
+                send(&mut writer, &text).await
+            }
+            Step::Wait(x) => {
+                tokio::time::sleep(x).await;
+                Ok(())
+            }
+            Step::Expect(count) => {
+                lines.wait(count).await;
+                Ok(())
+            }
+            // Dropping both halves closes the connection
+            Step::Disconnect => return,
+        };
+        if sent.is_err() {
+            return;
+        }
+    }
+    let _ = (&mut reading.0).await;
+}
+
+/// Waits for lines of one connection
+struct ConnectionLines {
+    connection: usize,
+    state: Arc<Mutex<MockState>>,
+    received: Arc<Notify>,
+}
+
+impl ConnectionLines {
+    /// Wait until the client has sent `count` lines after its login
+    async fn wait(&self, count: usize) {
+        loop {
+            let notified = self.received.notified();
+            let sent = {
+                let state = self.state.lock().unwrap();
+                state
+                    .received
+                    .iter()
+                    .filter(|x| x.connection == self.connection)
+                    .count()
+            };
+            if sent > count {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+fn response(options: &MockOptions, callsign: String, verified: bool) -> String {
+    LoginResponse {
+        callsign,
+        verified,
+        server: Some(options.name.clone()),
+    }
+    .to_string()
+}
+
+async fn send(
+    writer: &mut FramedWrite<OwnedWriteHalf, LinesCodec>,
+    text: &str,
+) -> anyhow::Result<()> {
+    writer.send(format!("{}\r", text)).await?;
+    Ok(())
+}
+
+/// Keep the next line which is not blank, `None` once the client is gone
+async fn record_next(
+    reader: &mut FramedRead<OwnedReadHalf, LinesCodec>,
+    connection: usize,
+    state: &Mutex<MockState>,
+    received: &Notify,
+) -> Option<String> {
+    loop {
+        let line = reader.next().await?.ok()?;
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        state.lock().unwrap().received.push(Received {
+            connection,
+            line: line.to_string(),
+        });
+        received.notify_waiters();
+        return Some(line.to_string());
+    }
+}
@@ -0,0 +1,204 @@
+use crate::data::*;
+use anyhow::{anyhow, Result};
+use log::{debug, error, info, warn};
+use rumqttc::{
+    AsyncClient, ConnectionError, Event, EventLoop, MqttOptions, Outgoing, Packet, QoS,
+    TlsConfiguration, Transport,
+};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Publishes queued before `publish` waits for the connection
+const REQUEST_CAPACITY: usize = 1024;
+
+/// Time `finish` waits for queued publishes to go out
+const FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time the broker gets to close the connection after a disconnect
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Quality of service of published messages
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum MqttQos {
+    /// At most once
+    #[value(name = "0")]
+    AtMostOnce,
+
+    /// At least once
+    #[value(name = "1")]
+    AtLeastOnce,
+
+    /// Exactly once
+    #[value(name = "2")]
+    ExactlyOnce,
+}
+
+impl From<MqttQos> for QoS {
+    fn from(item: MqttQos) -> Self {
+        match item {
+            MqttQos::AtMostOnce => QoS::AtMostOnce,
+            MqttQos::AtLeastOnce => QoS::AtLeastOnce,
+            MqttQos::ExactlyOnce => QoS::ExactlyOnce,
+        }
+    }
+}
+
+/// Certificates for a TLS connection. Without a CA file the system
+/// certificates are trusted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MqttTls {
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate and key files for brokers requiring them
+    pub client_auth: Option<(PathBuf, PathBuf)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttSinkOptions {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<MqttTls>,
+    pub qos: MqttQos,
+    /// First level of every topic
+    pub topic_prefix: String,
+    /// Also publish positions to `<prefix>/last_position/<callsign>` as
+    /// retained messages, so new subscribers see every station at once
+    pub retain_positions: bool,
+}
+
+fn read_file(path: &PathBuf) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+fn transport(tls: &MqttTls) -> Result<Transport> {
+    let Some(ca_file) = &tls.ca_file else {
+        return Ok(Transport::tls_with_default_config());
+    };
+    let client_auth = match &tls.client_auth {
+        Some((cert, key)) => Some((read_file(cert)?, read_file(key)?)),
+        None => None,
+    };
+    Ok(Transport::tls_with_config(TlsConfiguration::Simple {
+        ca: read_file(ca_file)?,
+        alpn: None,
+        client_auth,
+    }))
+}
+
+/// Topic level for a callsign. The MQTT wildcards and separator cannot
+/// appear in a level, and an empty level would merge topics.
+fn topic_level(callsign: &str) -> String {
+    if callsign.is_empty() {
+        return "_".to_string();
+    }
+    callsign.replace(['/', '+', '#'], "_")
+}
+
+/// Publishes every line as JSON to `<prefix>/<type>/<callsign>`
+pub struct MqttSink {
+    client: AsyncClient,
+    qos: QoS,
+    topic_prefix: String,
+    retain_positions: bool,
+    connection: JoinHandle<()>,
+}
+
+impl MqttSink {
+    /// Start connecting to the broker. Publishing waits while the broker is
+    /// unreachable and the connection is retried in the background.
+    pub fn new(options: MqttSinkOptions) -> Result<MqttSink> {
+        let mut mqtt_options = MqttOptions::new(&options.client_id, &options.host, options.port);
+        mqtt_options.set_keep_alive(Duration::from_secs(30));
+        if let Some(username) = &options.username {
+            mqtt_options.set_credentials(username, options.password.clone().unwrap_or_default());
+        }
+        if let Some(tls) = &options.tls {
+            mqtt_options.set_transport(transport(tls)?);
+        }
+        let (client, eventloop) = AsyncClient::new(mqtt_options, REQUEST_CAPACITY);
+        let broker = format!("{}:{}", options.host, options.port);
+        Ok(MqttSink {
+            client,
+            qos: options.qos.into(),
+            topic_prefix: options.topic_prefix.trim_end_matches('/').to_string(),
+            retain_positions: options.retain_positions,
+            connection: tokio::spawn(connection_loop(eventloop, broker)),
+        })
+    }
+
+    pub async fn publish(&self, line: &ParsedLine) -> Result<()> {
+        let payload = serde_json::to_vec(line)?;
+        let callsign = topic_level(&line.from);
+        let topic = format!(
+            "{}/{}/{}",
+            self.topic_prefix,
+            line.data.type_name(),
+            callsign
+        );
+        let position = matches!(
+            line.data,
+            ParsedAprsData::Position(_) | ParsedAprsData::MicE(_)
+        );
+        if self.retain_positions && position {
+            let topic = format!("{}/last_position/{}", self.topic_prefix, callsign);
+            self.client
+                .publish(topic, self.qos, true, payload.clone())
+                .await?;
+        }
+        self.client.publish(topic, self.qos, false, payload).await?;
+        Ok(())
+    }
+
+    /// Send what is still queued and disconnect
+    pub async fn finish(self) -> Result<()> {
+        self.client.disconnect().await?;
+        tokio::time::timeout(FINISH_TIMEOUT, self.connection)
+            .await
+            .map_err(|_| anyhow!("timed out sending queued MQTT messages"))??;
+        Ok(())
+    }
+}
+
+/// Drive the connection, reconnecting with a growing delay after errors,
+/// until the client disconnects
+async fn connection_loop(mut eventloop: EventLoop, broker: String) {
+    let mut failures: u64 = 0;
+    let mut disconnecting = false;
+    loop {
+        let event = if disconnecting {
+            match tokio::time::timeout(CLOSE_TIMEOUT, eventloop.poll()).await {
+                Ok(x) => x,
+                Err(_) => break,
+            }
+        } else {
+            eventloop.poll().await
+        };
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {}", broker);
+                failures = 0;
+            }
+            // Keep reading until the broker closes the connection, as closing
+            // it first with acks unread resets it and the broker may drop the
+            // last publishes
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => disconnecting = true,
+            Ok(event) => debug!("[MqttSink] {:?}", event),
+            Err(_) if disconnecting => break,
+            Err(ConnectionError::RequestsDone) => break,
+            Err(e) => {
+                failures += 1;
+                let delay = crate::utils::reconnect_delay(failures);
+                if failures == 1 {
+                    warn!("MQTT connection to {} lost: {}", broker, e);
+                } else {
+                    error!("MQTT connection to {} failed: {}", broker, e);
+                }
+                debug!("[MqttSink] Reconnecting in {:?}", delay);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use crate::data::*;
+use crate::utils::{callsign_matches, format_parsed};
+use std::io::{self, Write};
+
+/// How `stdout` mode prints packets
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// One line summary of every packet
+    Human,
+
+    /// One JSON document per packet, as serialized by serde
+    Json,
+
+    /// The TNC2 line as received
+    Raw,
+
+    /// Fixed width columns under a header
+    Table,
+}
+
+/// Which packets are printed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutputFilter {
+    /// Names from `PACKET_TYPES`, any type when empty
+    pub types: Vec<String>,
+    /// Patterns for `callsign_matches`, any station when empty
+    pub callsigns: Vec<String>,
+}
+
+impl OutputFilter {
+    pub fn matches(&self, line: &ParsedLine) -> bool {
+        (self.types.is_empty() || self.types.iter().any(|x| x == line.data.type_name()))
+            && (self.callsigns.is_empty()
+                || self
+                    .callsigns
+                    .iter()
+                    .any(|x| callsign_matches(x, &line.from)))
+    }
+}
+
+const TABLE_HEADER: &str = concat!(
+    "TIME                  FROM        TYPE      ",
+    "LATITUDE   LONGITUDE   SYM  TEXT"
+);
+
+/// Columns of the table output, matching `TABLE_HEADER`
+fn table_row(line: &ParsedLine) -> String {
+    let (position, symbol, text) = match &line.data {
+        ParsedAprsData::Position(x) => (
+            Some((x.latitude, x.longitude)),
+            format!("{}{}", x.symbol_table, x.symbol_code),
+            x.comment.clone(),
+        ),
+        ParsedAprsData::MicE(x) => (
+            Some((x.latitude, x.longitude)),
+            format!("{}{}", x.symbol_table, x.symbol_code),
+            x.comment.clone(),
+        ),
+        ParsedAprsData::Message(x) => (None, String::new(), format!("{}: {}", x.addressee, x.text)),
+        ParsedAprsData::Status(x) => (None, String::new(), x.comment.clone()),
+        ParsedAprsData::Unknown(x) => (None, String::new(), x.error.clone()),
+    };
+    let (latitude, longitude) = match position {
+        Some((latitude, longitude)) => (format!("{:.5}", latitude), format!("{:.5}", longitude)),
+        None => (String::new(), String::new()),
+    };
+    format!(
+        "{:<21} {:<11} {:<9} {:>9}  {:>10}  {:<4} {}",
+        line.received_at.format("%Y-%m-%d %H:%M:%S"),
+        line.from,
+        line.data.type_name(),
+        latitude,
+        longitude,
+        symbol,
+        text.trim_end()
+    )
+}
+
+/// Writes packets to a stream in an `OutputFormat`
+pub struct OutputWriter<W: Write> {
+    out: W,
+    format: OutputFormat,
+    filter: OutputFilter,
+    header_written: bool,
+}
+
+impl<W: Write> OutputWriter<W> {
+    pub fn new(out: W, format: OutputFormat, filter: OutputFilter) -> OutputWriter<W> {
+        OutputWriter {
+            out,
+            format,
+            filter,
+            header_written: false,
+        }
+    }
+
+    /// Write a packet if it passes the filter. `raw` is the line as received,
+    /// printed by `OutputFormat::Raw`.
+    pub fn write(&mut self, line: &ParsedLine, raw: &str) -> io::Result<()> {
+        if !self.filter.matches(line) {
+            return Ok(());
+        }
+        match self.format {
+            OutputFormat::Human => writeln!(self.out, "{}", format_parsed(line))?,
+            OutputFormat::Json => {
+                serde_json::to_writer(&mut self.out, line)?;
+                writeln!(self.out)?;
+            }
+            OutputFormat::Raw => writeln!(self.out, "{}", raw)?,
+            OutputFormat::Table => {
+                if !self.header_written {
+                    writeln!(self.out, "{}", TABLE_HEADER)?;
+                    self.header_written = true;
+                }
+                writeln!(self.out, "{}", table_row(line))?;
+            }
+        }
+        // Packets arrive slowly, so a monitor should see each one right away
+        self.out.flush()
+    }
+}
@@ -0,0 +1,1189 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use log::{debug, warn};
+use sqlx::postgres::{PgConnectOptions, PgDatabaseError, PgPoolOptions};
+use sqlx::{Connection, PgConnection, PgPool};
+use std::collections::BTreeMap;
+use tokio::time::{sleep, Duration};
+use uuid::Uuid;
+
+use crate::data::ParsedLine;
+use crate::export::{ExportFilter, PositionColumns, PositionRecord};
+use crate::migrations::{self, Migration};
+use crate::query::{
+    message_line, mic_e_line, newest_lines, position_line, MessageLineColumns, MessageQuery,
+    MicELineColumns, PositionLineColumns, PositionQuery,
+};
+use crate::reprocess::{reprocess_line, ReprocessStats, REPROCESS_PAGE_SIZE};
+use crate::retention::{
+    self, Downsample, PruneStats, RetentionPolicy, ORPHAN_PATH_GRACE, PRUNE_BATCH_SIZE,
+};
+use crate::stations::{StationColumns, StationState, StationUpdate};
+use crate::track::Fix;
+
+/// Delay before the first retry of a transient error; doubled on every attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+
+/// Upper bound for the delay between two retries.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Connection settings for `PostgresDb::new`
+#[derive(Clone, Debug)]
+pub struct PostgresOptions {
+    /// Host name, optionally followed by `:port`
+    pub hostname: String,
+    pub username: String,
+    pub password: String,
+    pub database: String,
+    /// Maximum number of pooled connections
+    pub pool_size: u32,
+    /// Number of times a transient error is retried before a batch is failed
+    pub max_retries: u32,
+    /// Open the pool without connecting, so the database may still be down
+    pub lazy: bool,
+}
+
+/// A pooled PostgreSQL backend storing coordinates as PostGIS
+/// `geography(Point, 4326)` values.
+///
+/// Batches are written with `COPY ... FROM STDIN`, one `COPY` per table, all
+/// inside a single transaction.
+#[derive(Clone)]
+pub struct PostgresDb {
+    pool: PgPool,
+    max_retries: u32,
+}
+
+/// One `COPY` payload in CSV format
+struct CopyBuffer {
+    data: String,
+    rows: usize,
+}
+
+impl CopyBuffer {
+    fn new() -> Self {
+        CopyBuffer {
+            data: String::new(),
+            rows: 0,
+        }
+    }
+
+    /// Append a row. `None` fields are written as SQL NULL.
+    fn push_row(&mut self, fields: &[Option<&str>]) {
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                self.data.push(',');
+            }
+            if let Some(field) = field {
+                // PostgreSQL text can not hold NUL bytes
+                let field = field.replace('\0', "");
+                self.data.push('"');
+                self.data.push_str(&field.replace('"', "\"\""));
+                self.data.push('"');
+            }
+        }
+        self.data.push('\n');
+        self.rows += 1;
+    }
+
+    async fn copy(
+        &self,
+        conn: &mut sqlx::PgConnection,
+        statement: &str,
+    ) -> Result<(), sqlx::Error> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let mut copy = conn.copy_in_raw(statement).await?;
+        copy.send(self.data.as_bytes()).await?;
+        copy.finish().await?;
+        Ok(())
+    }
+}
+
+/// Columns read back into a `StationState`, see `StationColumns`
+const STATION_COLUMNS: &str = "\"callsign\",
+    to_char(\"first_seen\" AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"'),
+    to_char(\"last_heard\" AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"'),
+    \"last_via\",
+    to_char(\"last_position_time\" AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"'),
+    ST_Y(\"location\"::geometry), ST_X(\"location\"::geometry),
+    \"symbol_table\", \"symbol_code\", \"comment\", \"device\",
+    \"position_count\", \"message_count\", \"status_count\", \"mic_e_count\", \"unknown_count\"";
+
+/// EWKT for a WGS84 point, accepted by the `geography` input function
+fn ewkt_point(latitude: f64, longitude: f64) -> String {
+    format!("SRID=4326;POINT({} {})", longitude, latitude)
+}
+
+/// An RFC 3339 timestamp, or `None` when the packet timestamp could not be resolved
+fn timestamptz(timestamp: &Option<crate::data::Timestamp>) -> Option<String> {
+    timestamp
+        .as_ref()
+        .map(|x| x.fmt_string())
+        .filter(|x| !x.is_empty())
+}
+
+impl PostgresDb {
+    pub async fn new(options: PostgresOptions) -> Result<Self> {
+        let (host, port) = match options.hostname.rsplit_once(':') {
+            Some((host, port)) => (host.to_string(), port.parse::<u16>()?),
+            None => (options.hostname.clone(), 5432),
+        };
+        let connect_options = PgConnectOptions::new()
+            .host(&host)
+            .port(port)
+            .username(&options.username)
+            .password(&options.password)
+            .database(&options.database);
+        let pool_options = PgPoolOptions::new()
+            .max_connections(options.pool_size)
+            .acquire_timeout(Duration::from_secs(30))
+            .test_before_acquire(true);
+        let pool = match options.lazy {
+            true => pool_options.connect_lazy_with(connect_options),
+            false => pool_options.connect_with(connect_options).await?,
+        };
+        debug!(
+            "[PostgresDb::new] Pool of up to {} connections opened to {}:{}",
+            options.pool_size, host, port
+        );
+        Ok(PostgresDb {
+            pool,
+            max_retries: options.max_retries,
+        })
+    }
+
+    /// Insert a batch of lines and duplicate paths, retrying transient errors
+    /// with exponential backoff.
+    ///
+    /// Each attempt runs in a single transaction, so a batch is either stored
+    /// completely or not at all.
+    pub async fn insert_batch(
+        &self,
+        data: &[crate::data::ParsedLine],
+        paths: &[crate::dedup::DuplicatePath],
+    ) -> Result<()> {
+        if data.is_empty() && paths.is_empty() {
+            return Ok(());
+        }
+
+        let mut attempt: u32 = 0;
+        loop {
+            match self.try_insert_batch(data, paths).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.max_retries && is_transient(&e) => {
+                    let delay = RETRY_BASE_DELAY
+                        .saturating_mul(2u32.saturating_pow(attempt))
+                        .min(RETRY_MAX_DELAY);
+                    attempt += 1;
+                    warn!(
+                        "[PostgresDb::insert_batch] Transient error, retry {}/{} in {:?}: {}",
+                        attempt, self.max_retries, delay, e
+                    );
+                    sleep(delay).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    async fn try_insert_batch(
+        &self,
+        data: &[crate::data::ParsedLine],
+        paths: &[crate::dedup::DuplicatePath],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        if !data.is_empty() {
+            Self::write_lines(&mut tx, data).await?;
+        }
+        if !paths.is_empty() {
+            let mut buffer = CopyBuffer::new();
+            for path in paths {
+                buffer.push_row(&[
+                    Some(&path.id.hyphenated().to_string()),
+                    Some(&path.via.join(", ")),
+                    Some(&path.received_at.format("%+").to_string()),
+                ]);
+            }
+            buffer
+                .copy(
+                    &mut tx,
+                    "COPY \"paths\" (\"id\", \"via\", \"received_at\") FROM STDIN WITH (FORMAT csv)",
+                )
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    /// Insert lines and their stations inside an open transaction
+    async fn write_lines(
+        tx: &mut PgConnection,
+        data: &[crate::data::ParsedLine],
+    ) -> Result<(), sqlx::Error> {
+        let mut positions = CopyBuffer::new();
+        let mut messages = CopyBuffer::new();
+        let mut statuses = CopyBuffer::new();
+        let mut mic_es = CopyBuffer::new();
+        let mut unknowns = CopyBuffer::new();
+        let mut main_data = CopyBuffer::new();
+
+        for line in data {
+            let id = &line.id.hyphenated().to_string();
+            debug!("[PostgresDb::insert_batch] [{}]: {:?}", id, line);
+            match &line.data {
+                crate::data::ParsedAprsData::Position(x) => {
+                    positions.push_row(&[
+                        Some(id),
+                        Some(&x.to),
+                        timestamptz(&x.timestamp).as_deref(),
+                        Some(&x.messaging_supported.to_string()),
+                        Some(&ewkt_point(x.latitude, x.longitude)),
+                        Some(&x.precision.to_string()),
+                        Some(&x.symbol_table.to_string()),
+                        Some(&x.symbol_code.to_string()),
+                        Some(&x.comment),
+                        Some(&x.cst),
+                    ]);
+                }
+                crate::data::ParsedAprsData::Message(x) => {
+                    let msg_id =
+                        x.id.as_ref()
+                            .map(|y| String::from_utf8_lossy(y).to_string());
+                    messages.push_row(&[
+                        Some(id),
+                        Some(&x.to),
+                        Some(&x.addressee),
+                        Some(&x.text),
+                        msg_id.as_deref(),
+                    ]);
+                }
+                crate::data::ParsedAprsData::Status(x) => {
+                    statuses.push_row(&[
+                        Some(id),
+                        Some(&x.to),
+                        timestamptz(&x.timestamp).as_deref(),
+                        Some(&x.comment),
+                    ]);
+                }
+                crate::data::ParsedAprsData::MicE(x) => {
+                    mic_es.push_row(&[
+                        Some(id),
+                        Some(&ewkt_point(x.latitude, x.longitude)),
+                        Some(&x.precision.to_string()),
+                        Some(&x.message),
+                        Some(&x.speed.to_string()),
+                        Some(&x.course.to_string()),
+                        Some(&x.symbol_table.to_string()),
+                        Some(&x.symbol_code.to_string()),
+                        Some(&x.comment),
+                        Some(&x.current.to_string()),
+                    ]);
+                }
+                crate::data::ParsedAprsData::Unknown(x) => {
+                    unknowns.push_row(&[Some(id), Some(&x.raw), Some(&x.error)]);
+                }
+            };
+            main_data.push_row(&[
+                Some(id),
+                line.source(),
+                Some(&line.via.join(", ")),
+                Some(line.data.type_name()),
+                Some(&line.received_at.format("%+").to_string()),
+            ]);
+        }
+
+        Self::upsert_stations(tx, &StationUpdate::from_lines(data)).await?;
+        main_data
+            .copy(tx, "COPY \"main_data\" (\"id\", \"from\", \"via\", \"type\", \"parsed_time\") FROM STDIN WITH (FORMAT csv)")
+            .await?;
+        positions
+            .copy(tx, "COPY \"position\" (\"id\", \"to\", \"timestamp\", \"messaging_supported\", \"location\", \"precision\", \"symbol_table\", \"symbol_code\", \"comment\", \"cst\") FROM STDIN WITH (FORMAT csv)")
+            .await?;
+        messages
+            .copy(tx, "COPY \"messages\" (\"id\", \"to\", \"addressee\", \"text\", \"msg_id\") FROM STDIN WITH (FORMAT csv)")
+            .await?;
+        statuses
+            .copy(
+                tx,
+                "COPY \"status\" (\"id\", \"to\", \"timestamp\", \"comment\") FROM STDIN WITH (FORMAT csv)",
+            )
+            .await?;
+        mic_es
+            .copy(tx, "COPY \"MicE\" (\"id\", \"location\", \"precision\", \"message\", \"speed\", \"course\", \"symbol_table\", \"symbol_code\", \"comment\", \"current\") FROM STDIN WITH (FORMAT csv)")
+            .await?;
+        unknowns
+            .copy(
+                tx,
+                "COPY \"unknown\" (\"id\", \"data\", \"error\") FROM STDIN WITH (FORMAT csv)",
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Merge station updates into the `stations` table
+    async fn upsert_stations(
+        tx: &mut PgConnection,
+        updates: &[StationUpdate],
+    ) -> Result<(), sqlx::Error> {
+        let mut callsigns = Vec::with_capacity(updates.len());
+        let mut first_seen = Vec::with_capacity(updates.len());
+        let mut last_heard = Vec::with_capacity(updates.len());
+        let mut last_via = Vec::with_capacity(updates.len());
+        let mut last_position_time = Vec::with_capacity(updates.len());
+        let mut location = Vec::with_capacity(updates.len());
+        let mut symbol_table = Vec::with_capacity(updates.len());
+        let mut symbol_code = Vec::with_capacity(updates.len());
+        let mut comment = Vec::with_capacity(updates.len());
+        let mut device = Vec::with_capacity(updates.len());
+        let mut counts: [Vec<i64>; 5] = Default::default();
+        for update in updates {
+            let position = update.last_position.as_ref();
+            callsigns.push(update.callsign.as_str());
+            first_seen.push(update.first_seen.format("%+").to_string());
+            last_heard.push(update.last_heard.format("%+").to_string());
+            last_via.push(update.last_via.join(", "));
+            last_position_time.push(position.map(|x| x.time.format("%+").to_string()));
+            location.push(position.map(|x| ewkt_point(x.latitude, x.longitude)));
+            symbol_table.push(position.map(|x| x.symbol_table.to_string()));
+            symbol_code.push(position.map(|x| x.symbol_code.to_string()));
+            comment.push(position.map(|x| x.comment.replace('\0', "")));
+            device.push(update.device.clone());
+            counts[0].push(update.packets.position as i64);
+            counts[1].push(update.packets.message as i64);
+            counts[2].push(update.packets.status as i64);
+            counts[3].push(update.packets.mic_e as i64);
+            counts[4].push(update.packets.unknown as i64);
+        }
+        let [position_count, message_count, status_count, mic_e_count, unknown_count] = counts;
+
+        let statement_text = "INSERT INTO \"stations\" AS s (\"callsign\", \"first_seen\", \"last_heard\", \"last_via\", \"last_position_time\", \"location\", \"symbol_table\", \"symbol_code\", \"comment\", \"device\", \"position_count\", \"message_count\", \"status_count\", \"mic_e_count\", \"unknown_count\")
+            SELECT u.\"callsign\", u.\"first_seen\", u.\"last_heard\", u.\"last_via\", u.\"last_position_time\", ST_GeogFromText(u.\"location\"), u.\"symbol_table\", u.\"symbol_code\", u.\"comment\", u.\"device\", u.\"position_count\", u.\"message_count\", u.\"status_count\", u.\"mic_e_count\", u.\"unknown_count\"
+            FROM unnest($1::text[], $2::timestamptz[], $3::timestamptz[], $4::text[], $5::timestamptz[], $6::text[], $7::text[], $8::text[], $9::text[], $10::text[], $11::int8[], $12::int8[], $13::int8[], $14::int8[], $15::int8[])
+                AS u(\"callsign\", \"first_seen\", \"last_heard\", \"last_via\", \"last_position_time\", \"location\", \"symbol_table\", \"symbol_code\", \"comment\", \"device\", \"position_count\", \"message_count\", \"status_count\", \"mic_e_count\", \"unknown_count\")
+            ON CONFLICT (\"callsign\") DO UPDATE SET
+                \"first_seen\" = LEAST(s.\"first_seen\", EXCLUDED.\"first_seen\"),
+                \"last_heard\" = GREATEST(s.\"last_heard\", EXCLUDED.\"last_heard\"),
+                \"last_via\" = CASE WHEN EXCLUDED.\"last_heard\" >= s.\"last_heard\" THEN EXCLUDED.\"last_via\" ELSE s.\"last_via\" END,
+                \"device\" = CASE WHEN EXCLUDED.\"last_heard\" >= s.\"last_heard\" THEN COALESCE(EXCLUDED.\"device\", s.\"device\") ELSE COALESCE(s.\"device\", EXCLUDED.\"device\") END,
+                \"last_position_time\" = CASE WHEN EXCLUDED.\"last_position_time\" >= COALESCE(s.\"last_position_time\", '-infinity') THEN EXCLUDED.\"last_position_time\" ELSE s.\"last_position_time\" END,
+                \"location\" = CASE WHEN EXCLUDED.\"last_position_time\" >= COALESCE(s.\"last_position_time\", '-infinity') THEN EXCLUDED.\"location\" ELSE s.\"location\" END,
+                \"symbol_table\" = CASE WHEN EXCLUDED.\"last_position_time\" >= COALESCE(s.\"last_position_time\", '-infinity') THEN EXCLUDED.\"symbol_table\" ELSE s.\"symbol_table\" END,
+                \"symbol_code\" = CASE WHEN EXCLUDED.\"last_position_time\" >= COALESCE(s.\"last_position_time\", '-infinity') THEN EXCLUDED.\"symbol_code\" ELSE s.\"symbol_code\" END,
+                \"comment\" = CASE WHEN EXCLUDED.\"last_position_time\" >= COALESCE(s.\"last_position_time\", '-infinity') THEN EXCLUDED.\"comment\" ELSE s.\"comment\" END,
+                \"position_count\" = s.\"position_count\" + EXCLUDED.\"position_count\",
+                \"message_count\" = s.\"message_count\" + EXCLUDED.\"message_count\",
+                \"status_count\" = s.\"status_count\" + EXCLUDED.\"status_count\",
+                \"mic_e_count\" = s.\"mic_e_count\" + EXCLUDED.\"mic_e_count\",
+                \"unknown_count\" = s.\"unknown_count\" + EXCLUDED.\"unknown_count\"";
+        sqlx::query(statement_text)
+            .bind(callsigns)
+            .bind(first_seen)
+            .bind(last_heard)
+            .bind(last_via)
+            .bind(last_position_time)
+            .bind(location)
+            .bind(symbol_table)
+            .bind(symbol_code)
+            .bind(comment)
+            .bind(device)
+            .bind(position_count)
+            .bind(message_count)
+            .bind(status_count)
+            .bind(mic_e_count)
+            .bind(unknown_count)
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Current state of one station
+    pub async fn station(&self, callsign: &str) -> Result<Option<StationState>> {
+        let statement_text = format!(
+            "SELECT {} FROM \"stations\" WHERE \"callsign\" = $1",
+            STATION_COLUMNS
+        );
+        let row: Option<StationColumns> = sqlx::query_as(&statement_text)
+            .bind(callsign)
+            .fetch_optional(&self.pool)
+            .await?;
+        row.map(StationState::from_columns).transpose()
+    }
+
+    /// Up to `limit` stations heard since a time, most recently heard first
+    pub async fn stations_heard_since(
+        &self,
+        since: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<StationState>> {
+        let statement_text = format!(
+            "SELECT {} FROM \"stations\" WHERE \"last_heard\" >= $1::timestamptz ORDER BY \"last_heard\" DESC LIMIT $2",
+            STATION_COLUMNS
+        );
+        let rows: Vec<StationColumns> = sqlx::query_as(&statement_text)
+            .bind(since.format("%+").to_string())
+            .bind(i64::from(limit))
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter().map(StationState::from_columns).collect()
+    }
+
+    /// Positions of a station received in `[start, end)`, oldest first
+    pub async fn fixes(
+        &self,
+        callsign: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Fix>> {
+        let statement_text = "SELECT to_char(f.\"parsed_time\" AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"'), ST_Y(f.\"location\"::geometry), ST_X(f.\"location\"::geometry)
+            FROM (
+                SELECT m.\"parsed_time\", x.\"location\" FROM \"main_data\" m JOIN \"position\" x ON x.\"id\" = m.\"id\" WHERE m.\"from\" = $1 AND m.\"parsed_time\" >= $2::timestamptz AND m.\"parsed_time\" < $3::timestamptz
+                UNION ALL
+                SELECT m.\"parsed_time\", x.\"location\" FROM \"main_data\" m JOIN \"MicE\" x ON x.\"id\" = m.\"id\" WHERE m.\"from\" = $1 AND m.\"parsed_time\" >= $2::timestamptz AND m.\"parsed_time\" < $3::timestamptz
+            ) f
+            ORDER BY f.\"parsed_time\"";
+        let rows: Vec<(String, f64, f64)> = sqlx::query_as(statement_text)
+            .bind(callsign)
+            .bind(start.format("%+").to_string())
+            .bind(end.format("%+").to_string())
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter()
+            .map(|(time, latitude, longitude)| {
+                Ok(Fix {
+                    time: DateTime::parse_from_rfc3339(&time)?.with_timezone(&Utc),
+                    latitude,
+                    longitude,
+                })
+            })
+            .collect()
+    }
+
+    /// Stored positions matching an export filter, ordered by station and
+    /// receive time
+    pub async fn positions(&self, filter: &ExportFilter) -> Result<Vec<PositionRecord>> {
+        let statement_text = "SELECT p.\"from\", to_char(p.\"parsed_time\" AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"'), ST_Y(p.\"location\"::geometry), ST_X(p.\"location\"::geometry), p.\"symbol_table\", p.\"symbol_code\", p.\"comment\"
+            FROM (
+                SELECT m.\"from\", m.\"parsed_time\", x.\"location\", x.\"symbol_table\", x.\"symbol_code\", x.\"comment\" FROM \"main_data\" m JOIN \"position\" x ON x.\"id\" = m.\"id\"
+                    WHERE m.\"from\" LIKE $1 ESCAPE '!' AND ($2::timestamptz IS NULL OR m.\"parsed_time\" >= $2::timestamptz) AND ($3::timestamptz IS NULL OR m.\"parsed_time\" < $3::timestamptz)
+                UNION ALL
+                SELECT m.\"from\", m.\"parsed_time\", x.\"location\", x.\"symbol_table\", x.\"symbol_code\", x.\"comment\" FROM \"main_data\" m JOIN \"MicE\" x ON x.\"id\" = m.\"id\"
+                    WHERE m.\"from\" LIKE $1 ESCAPE '!' AND ($2::timestamptz IS NULL OR m.\"parsed_time\" >= $2::timestamptz) AND ($3::timestamptz IS NULL OR m.\"parsed_time\" < $3::timestamptz)
+            ) p
+            WHERE ST_Y(p.\"location\"::geometry) BETWEEN $4 AND $5 AND ST_X(p.\"location\"::geometry) BETWEEN $6 AND $7
+            ORDER BY p.\"from\", p.\"parsed_time\"";
+        let bounds = filter.bounds();
+        let rows: Vec<PositionColumns> = sqlx::query_as(statement_text)
+            .bind(filter.callsign_like())
+            .bind(filter.start.map(|x| x.format("%+").to_string()))
+            .bind(filter.end.map(|x| x.format("%+").to_string()))
+            .bind(bounds.south)
+            .bind(bounds.north)
+            .bind(bounds.west)
+            .bind(bounds.east)
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter().map(PositionRecord::from_columns).collect()
+    }
+
+    /// Stored position and Mic-E lines matching a query, newest first
+    pub async fn position_lines(&self, query: &PositionQuery) -> Result<Vec<ParsedLine>> {
+        let condition = format!(
+            "WHERE m.\"from\" LIKE $1 ESCAPE '!' AND ($2::timestamptz IS NULL OR m.\"parsed_time\" >= $2::timestamptz) AND ($3::timestamptz IS NULL OR m.\"parsed_time\" < $3::timestamptz)
+                AND ST_Y(x.\"location\"::geometry) BETWEEN $4 AND $5 AND ST_X(x.\"location\"::geometry) BETWEEN $6 AND $7{}
+                ORDER BY m.\"parsed_time\" DESC LIMIT $8",
+            if query.weather { " AND x.\"symbol_code\" = '_'" } else { "" }
+        );
+        let filter = &query.filter;
+        let bounds = filter.bounds();
+        let start = filter.start.map(|x| x.format("%+").to_string());
+        let end = filter.end.map(|x| x.format("%+").to_string());
+        let statement_text = format!(
+            "SELECT m.\"id\"::text, to_char(m.\"parsed_time\" AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"'), m.\"from\", m.\"via\", x.\"to\", to_char(x.\"timestamp\" AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"'), x.\"messaging_supported\"::int4::int8, ST_Y(x.\"location\"::geometry), ST_X(x.\"location\"::geometry), x.\"precision\", x.\"symbol_table\", x.\"symbol_code\", x.\"comment\", x.\"cst\"
+                FROM \"main_data\" m JOIN \"position\" x ON x.\"id\" = m.\"id\" {}",
+            condition
+        );
+        let rows: Vec<PositionLineColumns> = sqlx::query_as(&statement_text)
+            .bind(filter.callsign_like())
+            .bind(&start)
+            .bind(&end)
+            .bind(bounds.south)
+            .bind(bounds.north)
+            .bind(bounds.west)
+            .bind(bounds.east)
+            .bind(i64::from(query.limit))
+            .fetch_all(&self.pool)
+            .await?;
+        let positions = rows
+            .into_iter()
+            .map(position_line)
+            .collect::<Result<Vec<_>>>()?;
+        let statement_text = format!(
+            "SELECT m.\"id\"::text, to_char(m.\"parsed_time\" AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"'), m.\"from\", m.\"via\", ST_Y(x.\"location\"::geometry), ST_X(x.\"location\"::geometry), x.\"precision\", x.\"message\", x.\"speed\"::int8, x.\"course\"::int8, x.\"symbol_table\", x.\"symbol_code\", x.\"comment\", x.\"current\"::int4::int8
+                FROM \"main_data\" m JOIN \"MicE\" x ON x.\"id\" = m.\"id\" {}",
+            condition
+        );
+        let rows: Vec<MicELineColumns> = sqlx::query_as(&statement_text)
+            .bind(filter.callsign_like())
+            .bind(&start)
+            .bind(&end)
+            .bind(bounds.south)
+            .bind(bounds.north)
+            .bind(bounds.west)
+            .bind(bounds.east)
+            .bind(i64::from(query.limit))
+            .fetch_all(&self.pool)
+            .await?;
+        let mic_es = rows
+            .into_iter()
+            .map(mic_e_line)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(newest_lines(positions, mic_es, query.limit))
+    }
+
+    /// Stored messages matching a query, newest first
+    pub async fn message_lines(&self, query: &MessageQuery) -> Result<Vec<ParsedLine>> {
+        let statement_text = format!(
+            "SELECT m.\"id\"::text, to_char(m.\"parsed_time\" AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"'), m.\"from\", m.\"via\", x.\"to\", x.\"addressee\", x.\"text\", x.\"msg_id\"
+                FROM \"main_data\" m JOIN \"messages\" x ON x.\"id\" = m.\"id\"
+                WHERE ($1::text IS NULL OR m.\"from\" = $1 OR x.\"addressee\" = $1) AND ($2::timestamptz IS NULL OR m.\"parsed_time\" >= $2::timestamptz) AND ($3::timestamptz IS NULL OR m.\"parsed_time\" < $3::timestamptz){}
+                ORDER BY m.\"parsed_time\" DESC LIMIT $4",
+            if query.bulletins { " AND x.\"addressee\" LIKE 'BLN%'" } else { "" }
+        );
+        let rows: Vec<MessageLineColumns> = sqlx::query_as(&statement_text)
+            .bind(&query.callsign)
+            .bind(query.start.map(|x| x.format("%+").to_string()))
+            .bind(query.end.map(|x| x.format("%+").to_string()))
+            .bind(i64::from(query.limit))
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter().map(message_line).collect()
+    }
+
+    /// Re-run `parse_line` over stored `unknown` packets and move every line
+    /// which now decodes into its proper table, keeping its id and receive time.
+    pub async fn reprocess(&self) -> Result<ReprocessStats> {
+        let mut stats = ReprocessStats::default();
+        let mut last_id = Uuid::nil().hyphenated().to_string();
+        loop {
+            let statement_text = "SELECT u.\"id\"::text, u.\"data\", to_char(m.\"parsed_time\" AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"') FROM \"unknown\" u JOIN \"main_data\" m ON m.\"id\" = u.\"id\" WHERE u.\"id\" > $1::uuid ORDER BY u.\"id\" LIMIT $2";
+            let rows: Vec<(String, Option<String>, String)> = sqlx::query_as(statement_text)
+                .bind(&last_id)
+                .bind(REPROCESS_PAGE_SIZE)
+                .fetch_all(&self.pool)
+                .await?;
+            let Some((id, _, _)) = rows.last() else {
+                break;
+            };
+            last_id = id.clone();
+
+            let mut lines = Vec::new();
+            for (id, raw, parsed_time) in rows {
+                stats.scanned += 1;
+                let raw = raw.unwrap_or_default();
+                let received_at = DateTime::parse_from_rfc3339(&parsed_time)?.with_timezone(&Utc);
+                if let Some(line) = reprocess_line(Uuid::parse_str(&id)?, &raw, received_at) {
+                    lines.push(line);
+                }
+            }
+            if lines.is_empty() {
+                continue;
+            }
+
+            let ids: Vec<String> = lines
+                .iter()
+                .map(|line| line.id.hyphenated().to_string())
+                .collect();
+            let mut tx = self.pool.begin().await?;
+            sqlx::query("DELETE FROM \"main_data\" WHERE \"id\" = ANY($1::uuid[])")
+                .bind(ids)
+                .execute(&mut *tx)
+                .await?;
+            // The packets were counted as unknown when they were first stored
+            let mut reclassified: BTreeMap<&str, i64> = BTreeMap::new();
+            for line in &lines {
+                *reclassified.entry(&line.from).or_default() += 1;
+            }
+            let (callsigns, counts): (Vec<&str>, Vec<i64>) = reclassified.into_iter().unzip();
+            sqlx::query("UPDATE \"stations\" s SET \"unknown_count\" = s.\"unknown_count\" - u.\"count\" FROM unnest($1::text[], $2::int8[]) AS u(\"callsign\", \"count\") WHERE s.\"callsign\" = u.\"callsign\"")
+                .bind(callsigns)
+                .bind(counts)
+                .execute(&mut *tx)
+                .await?;
+            Self::write_lines(&mut tx, &lines).await?;
+            tx.commit().await?;
+            stats.decoded += lines.len() as u64;
+        }
+        Ok(stats)
+    }
+
+    /// Remove rows past their retention period and thin out old positions.
+    ///
+    /// Rows are deleted in chunks of `PRUNE_BATCH_SIZE`, so no single
+    /// statement holds its locks for the whole run.
+    pub async fn prune(&self, policy: &RetentionPolicy) -> Result<PruneStats> {
+        let mut stats = PruneStats::default();
+        for retention in &policy.retain {
+            let cutoff = retention::cutoff(retention.max_age)
+                .format("%+")
+                .to_string();
+            loop {
+                let result = if retention.table == "paths" {
+                    sqlx::query("DELETE FROM \"paths\" WHERE \"ctid\" IN (SELECT \"ctid\" FROM \"paths\" WHERE \"received_at\" < $1::timestamptz LIMIT $2)")
+                        .bind(&cutoff)
+                        .bind(PRUNE_BATCH_SIZE)
+                        .execute(&self.pool)
+                        .await?
+                } else {
+                    sqlx::query("DELETE FROM \"main_data\" WHERE \"id\" IN (SELECT \"id\" FROM \"main_data\" WHERE \"type\" = $1::packet_type AND \"parsed_time\" < $2::timestamptz LIMIT $3)")
+                        .bind(&retention.table)
+                        .bind(&cutoff)
+                        .bind(PRUNE_BATCH_SIZE)
+                        .execute(&self.pool)
+                        .await?
+                };
+                let deleted = result.rows_affected();
+                debug!(
+                    "[PostgresDb::prune] {} expired from {}",
+                    deleted, retention.table
+                );
+                stats.expired += deleted;
+                if (deleted as i64) < PRUNE_BATCH_SIZE {
+                    break;
+                }
+            }
+        }
+
+        if let Some(downsample) = &policy.downsample {
+            stats.downsampled += self.downsample(downsample).await?;
+        }
+
+        // Paths of packets which were removed above
+        let statement_text = "DELETE FROM \"paths\" WHERE \"ctid\" IN (SELECT \"ctid\" FROM \"paths\" p WHERE \"received_at\" < $1::timestamptz AND NOT EXISTS (SELECT 1 FROM \"main_data\" m WHERE m.\"id\" = p.\"id\") LIMIT $2)";
+        let cutoff = retention::cutoff(ORPHAN_PATH_GRACE)
+            .format("%+")
+            .to_string();
+        loop {
+            let result = sqlx::query(statement_text)
+                .bind(&cutoff)
+                .bind(PRUNE_BATCH_SIZE)
+                .execute(&self.pool)
+                .await?;
+            let deleted = result.rows_affected();
+            stats.expired += deleted;
+            if (deleted as i64) < PRUNE_BATCH_SIZE {
+                break;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Keep one position per station and interval, ranking the positions one
+    /// window at a time from where the last run stopped
+    async fn downsample(&self, downsample: &Downsample) -> Result<u64> {
+        let interval = downsample.interval_secs();
+        let until = downsample.until();
+        let state: Option<(i64,)> = sqlx::query_as(
+            "SELECT \"downsampled_until\" FROM \"downsample_state\" WHERE \"interval_seconds\" = $1",
+        )
+        .bind(interval)
+        .fetch_optional(&self.pool)
+        .await?;
+        let mut start = state.map(|x| x.0).unwrap_or(0);
+
+        let mut downsampled = 0;
+        while start < until {
+            // Skip ahead to the next position, instead of stepping through
+            // gaps one window at a time
+            let statement_text = "SELECT floor(extract(epoch FROM min(\"parsed_time\")))::bigint FROM \"main_data\"
+                WHERE \"type\" IN ('position', 'mic_e') AND \"parsed_time\" >= to_timestamp($1) AND \"parsed_time\" < to_timestamp($2)";
+            let (next,): (Option<i64>,) = sqlx::query_as(statement_text)
+                .bind(start)
+                .bind(until)
+                .fetch_one(&self.pool)
+                .await?;
+            let window_start = match next {
+                Some(next) => downsample.interval_start(next),
+                None => until,
+            };
+            let window_end = downsample.window_end(window_start, until);
+
+            let statement_text = "DELETE FROM \"main_data\" WHERE \"id\" IN (
+                SELECT \"id\" FROM (
+                    SELECT \"id\", row_number() OVER (
+                        PARTITION BY \"from\", floor(extract(epoch FROM \"parsed_time\") / $3)
+                        ORDER BY \"parsed_time\", \"id\"
+                    ) AS \"rn\"
+                    FROM \"main_data\" WHERE \"type\" IN ('position', 'mic_e')
+                        AND \"parsed_time\" >= to_timestamp($1) AND \"parsed_time\" < to_timestamp($2)
+                ) AS \"ranked\" WHERE \"rn\" > 1 LIMIT $4
+            )";
+            loop {
+                let result = sqlx::query(statement_text)
+                    .bind(window_start)
+                    .bind(window_end)
+                    .bind(interval)
+                    .bind(PRUNE_BATCH_SIZE)
+                    .execute(&self.pool)
+                    .await?;
+                let deleted = result.rows_affected();
+                debug!("[PostgresDb::prune] {} downsampled", deleted);
+                downsampled += deleted;
+                if (deleted as i64) < PRUNE_BATCH_SIZE {
+                    break;
+                }
+            }
+
+            let statement_text = "INSERT INTO \"downsample_state\" (\"interval_seconds\", \"downsampled_until\") VALUES ($1, $2)
+                ON CONFLICT (\"interval_seconds\") DO UPDATE SET \"downsampled_until\" = EXCLUDED.\"downsampled_until\"";
+            sqlx::query(statement_text)
+                .bind(interval)
+                .bind(window_end)
+                .execute(&self.pool)
+                .await?;
+            start = window_end;
+        }
+        Ok(downsampled)
+    }
+
+    /// Reclaim the space of deleted rows and refresh planner statistics
+    pub async fn optimize(&self) -> Result<()> {
+        let statement_text = "VACUUM (ANALYZE) \"main_data\", \"position\", \"messages\", \"status\", \"MicE\", \"unknown\", \"paths\", \"stations\"";
+        let _ = sqlx::query(statement_text).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Drop every table, including `schema_version`
+    pub async fn drop_tables(&self) -> Result<()> {
+        let statement_text = "DROP TABLE IF EXISTS \"MicE\", \"messages\", \"position\", \"status\", \"unknown\", \"paths\", \"main_data\", \"stations\", \"type\", \"downsample_state\", \"schema_version\" CASCADE";
+        let statement = sqlx::query(statement_text);
+        let _ = statement.execute(&self.pool).await?;
+        let statement = sqlx::query("DROP TYPE IF EXISTS \"packet_type\"");
+        let _ = statement.execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Current schema version, `EMPTY_SCHEMA` for a new database.
+    ///
+    /// Databases created before versioning was introduced have our tables but
+    /// no `schema_version` table, and are reported as version 1.
+    pub async fn schema_version(&self) -> Result<i64> {
+        let mut conn = self.pool.acquire().await?;
+        Self::schema_version_on(&mut conn).await
+    }
+
+    async fn schema_version_on(conn: &mut PgConnection) -> Result<i64> {
+        let statement_text = "SELECT to_regclass($1) IS NOT NULL";
+        let (versioned,): (bool,) = sqlx::query_as(statement_text)
+            .bind("\"schema_version\"")
+            .fetch_one(&mut *conn)
+            .await?;
+        if versioned {
+            let (version,): (Option<i64>,) =
+                sqlx::query_as("SELECT MAX(\"version\") FROM \"schema_version\"")
+                    .fetch_one(&mut *conn)
+                    .await?;
+            return Ok(version.unwrap_or(migrations::EMPTY_SCHEMA));
+        }
+        let (legacy,): (bool,) = sqlx::query_as(statement_text)
+            .bind("\"main_data\"")
+            .fetch_one(&mut *conn)
+            .await?;
+        if legacy {
+            Ok(1)
+        } else {
+            Ok(migrations::EMPTY_SCHEMA)
+        }
+    }
+
+    /// Apply every pending migration, each in its own transaction.
+    ///
+    /// Returns the versions which were applied.
+    pub async fn migrate(&self) -> Result<Vec<i64>> {
+        let mut conn = self.pool.acquire().await?;
+        let current = Self::schema_version_on(&mut conn).await?;
+        migrations::check_not_newer(current, MIGRATIONS)?;
+        debug!("[PostgresDb::migrate] Schema is at version {}", current);
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS \"schema_version\" (
+                \"version\"             BIGINT NOT NULL PRIMARY KEY,
+                \"description\"         TEXT NOT NULL,
+                \"applied_time\"        TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .execute(&mut *conn)
+        .await?;
+        let statement_text = "INSERT INTO \"schema_version\" (\"version\", \"description\") VALUES ($1, $2) ON CONFLICT DO NOTHING";
+        if current > migrations::EMPTY_SCHEMA {
+            // Record the implicit baseline of a pre-versioning database
+            for migration in MIGRATIONS.iter().filter(|x| x.version <= current) {
+                sqlx::query(statement_text)
+                    .bind(migration.version)
+                    .bind(migration.description)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+        }
+
+        let mut applied = Vec::new();
+        for migration in migrations::pending(MIGRATIONS, current) {
+            let mut tx = conn.begin().await?;
+            for statement_text in migration.statements {
+                let _ = sqlx::query(statement_text).execute(&mut *tx).await?;
+            }
+            sqlx::query(statement_text)
+                .bind(migration.version)
+                .bind(migration.description)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            debug!(
+                "[PostgresDb::migrate] Applied migration {}: {}",
+                migration.version, migration.description
+            );
+            applied.push(migration.version);
+        }
+        Ok(applied)
+    }
+}
+
+/// Schema migrations, applied in order of `version`
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Initial schema",
+        statements: &[
+            "CREATE EXTENSION IF NOT EXISTS postgis",
+            // Create the message table
+            "CREATE TABLE \"messages\" (
+                \"id\"        UUID NOT NULL PRIMARY KEY,
+                \"to\"        TEXT NOT NULL,
+                \"addressee\" TEXT NOT NULL,
+                \"text\"      TEXT NOT NULL,
+                \"msg_id\"    TEXT
+            )",
+            // Create the position table
+            "CREATE TABLE \"position\" (
+                \"id\"                  UUID NOT NULL PRIMARY KEY,
+                \"to\"                  TEXT NOT NULL,
+                \"timestamp\"           TIMESTAMPTZ,
+                \"messaging_supported\" BOOLEAN NOT NULL,
+                \"location\"            GEOGRAPHY(POINT, 4326) NOT NULL,
+                \"precision\"           DOUBLE PRECISION NOT NULL,
+                \"symbol_table\"        TEXT NOT NULL,
+                \"symbol_code\"         TEXT NOT NULL,
+                \"comment\"             TEXT NOT NULL,
+                \"cst\"                 TEXT NOT NULL
+            )",
+            "CREATE INDEX \"position_location_idx\" ON \"position\" USING GIST (\"location\")",
+            // Create the Status table
+            "CREATE TABLE \"status\" (
+                \"id\"                  UUID NOT NULL PRIMARY KEY,
+                \"to\"                  TEXT NOT NULL,
+                \"timestamp\"           TIMESTAMPTZ,
+                \"comment\"             TEXT NOT NULL
+            )",
+            // Create the MicE table
+            "CREATE TABLE \"MicE\" (
+                \"id\"                  UUID NOT NULL PRIMARY KEY,
+                \"location\"            GEOGRAPHY(POINT, 4326) NOT NULL,
+                \"precision\"           DOUBLE PRECISION NOT NULL,
+                \"message\"             TEXT NOT NULL,
+                \"speed\"               INTEGER NOT NULL,
+                \"course\"              INTEGER NOT NULL,
+                \"symbol_table\"        TEXT NOT NULL,
+                \"symbol_code\"         TEXT NOT NULL,
+                \"comment\"             TEXT NOT NULL,
+                \"current\"             BOOLEAN NOT NULL
+            )",
+            "CREATE INDEX \"MicE_location_idx\" ON \"MicE\" USING GIST (\"location\")",
+            // Create the `unknown` table
+            "CREATE TABLE \"unknown\" (
+                \"id\"                  UUID NOT NULL PRIMARY KEY,
+                \"data\"                TEXT
+            )",
+            // Create the Type Lookup table
+            "CREATE TABLE \"type\" (
+                \"id\"                  INTEGER NOT NULL PRIMARY KEY,
+                \"table\"               TEXT NOT NULL
+            )",
+            // Populate the Type Lookup table
+            "INSERT INTO \"type\" (\"id\", \"table\") VALUES (1, 'messages'), (2, 'position'), (3, 'status'), (4, 'MicE'), (5, 'unknown')",
+            // Create the main lookup table
+            "CREATE TABLE \"main_data\" (
+                \"id\"                  UUID NOT NULL PRIMARY KEY,
+                \"from\"                TEXT NOT NULL,
+                \"via\"                 TEXT NOT NULL,
+                \"type\"                INTEGER NOT NULL,
+                \"parsed_time\"         TIMESTAMPTZ
+            )",
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "Foreign keys, indexes, packet type enum and stations table",
+        statements: &[
+            "CREATE TABLE \"stations\" (
+                \"callsign\"            TEXT NOT NULL PRIMARY KEY,
+                \"first_seen\"          TIMESTAMPTZ NOT NULL
+            )",
+            "UPDATE \"main_data\" SET \"parsed_time\" = now() WHERE \"parsed_time\" IS NULL",
+            "INSERT INTO \"stations\" (\"callsign\", \"first_seen\")
+                SELECT \"from\", MIN(\"parsed_time\") FROM \"main_data\" GROUP BY \"from\"",
+            "CREATE TYPE \"packet_type\" AS ENUM ('message', 'position', 'status', 'mic_e', 'unknown')",
+            "ALTER TABLE \"main_data\"
+                ALTER COLUMN \"parsed_time\" SET NOT NULL,
+                ALTER COLUMN \"type\" TYPE \"packet_type\" USING (
+                    CASE \"type\" WHEN 1 THEN 'message' WHEN 2 THEN 'position' WHEN 3 THEN 'status' WHEN 4 THEN 'mic_e' ELSE 'unknown' END
+                )::\"packet_type\",
+                ADD CONSTRAINT \"main_data_station_fk\" FOREIGN KEY (\"from\") REFERENCES \"stations\" (\"callsign\")",
+            "CREATE INDEX \"main_data_from_time_idx\" ON \"main_data\" (\"from\", \"parsed_time\")",
+            "CREATE INDEX \"main_data_time_idx\" ON \"main_data\" (\"parsed_time\")",
+            "CREATE INDEX \"main_data_type_time_idx\" ON \"main_data\" (\"type\", \"parsed_time\")",
+            // Rows left behind by a crash of an older writer would violate the new constraints
+            "DELETE FROM \"messages\" WHERE \"id\" NOT IN (SELECT \"id\" FROM \"main_data\")",
+            "DELETE FROM \"position\" WHERE \"id\" NOT IN (SELECT \"id\" FROM \"main_data\")",
+            "DELETE FROM \"status\" WHERE \"id\" NOT IN (SELECT \"id\" FROM \"main_data\")",
+            "DELETE FROM \"MicE\" WHERE \"id\" NOT IN (SELECT \"id\" FROM \"main_data\")",
+            "DELETE FROM \"unknown\" WHERE \"id\" NOT IN (SELECT \"id\" FROM \"main_data\")",
+            "ALTER TABLE \"messages\" ADD CONSTRAINT \"messages_main_data_fk\" FOREIGN KEY (\"id\") REFERENCES \"main_data\" (\"id\") ON DELETE CASCADE",
+            "CREATE INDEX \"messages_addressee_idx\" ON \"messages\" (\"addressee\")",
+            "ALTER TABLE \"position\" ADD CONSTRAINT \"position_main_data_fk\" FOREIGN KEY (\"id\") REFERENCES \"main_data\" (\"id\") ON DELETE CASCADE",
+            "ALTER TABLE \"status\" ADD CONSTRAINT \"status_main_data_fk\" FOREIGN KEY (\"id\") REFERENCES \"main_data\" (\"id\") ON DELETE CASCADE",
+            "ALTER TABLE \"MicE\" ADD CONSTRAINT \"MicE_main_data_fk\" FOREIGN KEY (\"id\") REFERENCES \"main_data\" (\"id\") ON DELETE CASCADE",
+            "ALTER TABLE \"unknown\" ADD CONSTRAINT \"unknown_main_data_fk\" FOREIGN KEY (\"id\") REFERENCES \"main_data\" (\"id\") ON DELETE CASCADE",
+            // The packet type is now stored by name
+            "DROP TABLE \"type\"",
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "Store undecoded packets",
+        statements: &[
+            "UPDATE \"unknown\" SET \"data\" = '' WHERE \"data\" IS NULL",
+            "ALTER TABLE \"unknown\" ALTER COLUMN \"data\" SET NOT NULL, ADD COLUMN \"error\" TEXT",
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "Paths of suppressed duplicate packets",
+        statements: &[
+            // No foreign key: the first copy may still be queued in another
+            // worker when its duplicates are written
+            "CREATE TABLE \"paths\" (
+                \"id\"                  UUID NOT NULL,
+                \"via\"                 TEXT NOT NULL,
+                \"received_at\"         TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE INDEX \"paths_id_idx\" ON \"paths\" (\"id\")",
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "Station state: last position, path, device and packet counts",
+        statements: &[
+            "ALTER TABLE \"stations\"
+                ADD COLUMN \"last_heard\"          TIMESTAMPTZ,
+                ADD COLUMN \"last_via\"            TEXT NOT NULL DEFAULT '',
+                ADD COLUMN \"last_position_time\"  TIMESTAMPTZ,
+                ADD COLUMN \"location\"            GEOGRAPHY(POINT, 4326),
+                ADD COLUMN \"symbol_table\"        TEXT,
+                ADD COLUMN \"symbol_code\"         TEXT,
+                ADD COLUMN \"comment\"             TEXT,
+                ADD COLUMN \"device\"              TEXT,
+                ADD COLUMN \"position_count\"      BIGINT NOT NULL DEFAULT 0,
+                ADD COLUMN \"message_count\"       BIGINT NOT NULL DEFAULT 0,
+                ADD COLUMN \"status_count\"        BIGINT NOT NULL DEFAULT 0,
+                ADD COLUMN \"mic_e_count\"         BIGINT NOT NULL DEFAULT 0,
+                ADD COLUMN \"unknown_count\"       BIGINT NOT NULL DEFAULT 0",
+            // Backfill from the packets stored so far
+            "UPDATE \"stations\" s SET
+                \"last_heard\" = c.\"last_heard\",
+                \"position_count\" = c.\"position_count\",
+                \"message_count\" = c.\"message_count\",
+                \"status_count\" = c.\"status_count\",
+                \"mic_e_count\" = c.\"mic_e_count\",
+                \"unknown_count\" = c.\"unknown_count\"
+            FROM (
+                SELECT \"from\", max(\"parsed_time\") AS \"last_heard\",
+                    count(*) FILTER (WHERE \"type\" = 'position') AS \"position_count\",
+                    count(*) FILTER (WHERE \"type\" = 'message') AS \"message_count\",
+                    count(*) FILTER (WHERE \"type\" = 'status') AS \"status_count\",
+                    count(*) FILTER (WHERE \"type\" = 'mic_e') AS \"mic_e_count\",
+                    count(*) FILTER (WHERE \"type\" = 'unknown') AS \"unknown_count\"
+                FROM \"main_data\" GROUP BY \"from\"
+            ) c WHERE c.\"from\" = s.\"callsign\"",
+            "UPDATE \"stations\" SET \"last_heard\" = \"first_seen\" WHERE \"last_heard\" IS NULL",
+            "ALTER TABLE \"stations\" ALTER COLUMN \"last_heard\" SET NOT NULL",
+            "UPDATE \"stations\" s SET \"last_via\" = l.\"via\"
+            FROM (
+                SELECT DISTINCT ON (\"from\") \"from\", \"via\" FROM \"main_data\"
+                ORDER BY \"from\", \"parsed_time\" DESC
+            ) l WHERE l.\"from\" = s.\"callsign\"",
+            "UPDATE \"stations\" s SET
+                \"last_position_time\" = p.\"parsed_time\",
+                \"location\" = p.\"location\",
+                \"symbol_table\" = p.\"symbol_table\",
+                \"symbol_code\" = p.\"symbol_code\",
+                \"comment\" = p.\"comment\"
+            FROM (
+                SELECT DISTINCT ON (\"from\") * FROM (
+                    SELECT m.\"from\", m.\"parsed_time\", x.\"location\", x.\"symbol_table\", x.\"symbol_code\", x.\"comment\"
+                        FROM \"main_data\" m JOIN \"position\" x ON x.\"id\" = m.\"id\"
+                    UNION ALL
+                    SELECT m.\"from\", m.\"parsed_time\", x.\"location\", x.\"symbol_table\", x.\"symbol_code\", x.\"comment\"
+                        FROM \"main_data\" m JOIN \"MicE\" x ON x.\"id\" = m.\"id\"
+                ) p ORDER BY \"from\", \"parsed_time\" DESC
+            ) p WHERE p.\"from\" = s.\"callsign\"",
+            "UPDATE \"stations\" s SET \"device\" = d.\"to\"
+            FROM (
+                SELECT DISTINCT ON (\"from\") * FROM (
+                    SELECT m.\"from\", m.\"parsed_time\", x.\"to\" FROM \"main_data\" m JOIN \"position\" x ON x.\"id\" = m.\"id\"
+                    UNION ALL
+                    SELECT m.\"from\", m.\"parsed_time\", x.\"to\" FROM \"main_data\" m JOIN \"messages\" x ON x.\"id\" = m.\"id\"
+                    UNION ALL
+                    SELECT m.\"from\", m.\"parsed_time\", x.\"to\" FROM \"main_data\" m JOIN \"status\" x ON x.\"id\" = m.\"id\"
+                ) d ORDER BY \"from\", \"parsed_time\" DESC
+            ) d WHERE d.\"from\" = s.\"callsign\"",
+            "CREATE INDEX \"stations_last_heard_idx\" ON \"stations\" (\"last_heard\")",
+            "CREATE INDEX \"stations_location_idx\" ON \"stations\" USING GIST (\"location\")",
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "Lines without a source callsign",
+        statements: &[
+            "ALTER TABLE \"main_data\" ALTER COLUMN \"from\" DROP NOT NULL",
+            "UPDATE \"main_data\" SET \"from\" = NULL WHERE \"from\" = ''",
+            "DELETE FROM \"stations\" WHERE \"callsign\" = ''",
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "Downsampling progress",
+        statements: &["CREATE TABLE \"downsample_state\" (
+                \"interval_seconds\"    BIGINT NOT NULL PRIMARY KEY,
+                -- Unix time
+                \"downsampled_until\"   BIGINT NOT NULL
+            )"],
+    },
+];
+
+/// Whether an error returned by this backend means the database could not
+/// be reached or is overloaded, rather than that the data was rejected
+pub fn is_unavailable(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<sqlx::Error>()
+        .is_some_and(is_transient)
+}
+
+/// Whether an error is worth retrying: lost connections, pool exhaustion,
+/// serialization failures and deadlocks.
+fn is_transient(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => true,
+        sqlx::Error::Database(e) => match e.try_downcast_ref::<PgDatabaseError>() {
+            // Class 08 connection exceptions, serialization_failure,
+            // deadlock_detected, too_many_connections, admin_shutdown
+            Some(e) => {
+                e.code().starts_with("08")
+                    || matches!(e.code(), "40001" | "40P01" | "53300" | "57P01")
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::parse_line_or_unknown;
+    use tokio::sync::{Mutex, MutexGuard};
+
+    /// Scratch database named by `K0HAX_APRS_TEST_POSTGRES` as
+    /// `user:password@host:port/database`. Its tables are dropped and
+    /// recreated; without the variable the test is skipped. The tests take
+    /// turns on it.
+    async fn test_db() -> Option<(PostgresDb, MutexGuard<'static, ()>)> {
+        static SCRATCH: Mutex<()> = Mutex::const_new(());
+        let url = std::env::var("K0HAX_APRS_TEST_POSTGRES").ok()?;
+        let (credentials, location) = url.rsplit_once('@').expect("user:password@host/database");
+        let (username, password) = credentials.split_once(':').expect("user:password");
+        let (hostname, database) = location.split_once('/').expect("host/database");
+        let db = PostgresDb::new(PostgresOptions {
+            hostname: hostname.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            database: database.to_string(),
+            pool_size: 2,
+            max_retries: 0,
+            lazy: false,
+        })
+        .await
+        .unwrap();
+        let guard = SCRATCH.lock().await;
+        db.drop_tables().await.unwrap();
+        db.migrate().await.unwrap();
+        Some((db, guard))
+    }
+
+    #[tokio::test]
+    async fn line_without_a_source_is_stored() {
+        let Some((db, _guard)) = test_db().await else {
+            return;
+        };
+        let line = parse_line_or_unknown("garbage without header");
+        db.insert_batch(&[line], &[]).await.unwrap();
+        let (sourceless,): (i64,) =
+            sqlx::query_as("SELECT count(*) FROM main_data WHERE \"from\" IS NULL")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(sourceless, 1);
+        let (stations,): (i64,) = sqlx::query_as("SELECT count(*) FROM stations")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(stations, 0);
+    }
+
+    #[tokio::test]
+    async fn downsampling_resumes_where_it_stopped() {
+        let Some((db, _guard)) = test_db().await else {
+            return;
+        };
+        let downsample = Downsample {
+            older_than: Duration::from_secs(24 * 60 * 60),
+            interval: Duration::from_secs(600),
+        };
+        let policy = RetentionPolicy {
+            retain: Vec::new(),
+            downsample: Some(downsample),
+        };
+        let start = downsample.interval_start(Utc::now().timestamp() - 3 * 24 * 60 * 60);
+        let line = |offset: i64| {
+            let mut line = parse_line_or_unknown("N0CALL>APRS:!4903.50N/07201.75W-Test");
+            line.received_at = DateTime::from_timestamp(start + offset, 0).unwrap();
+            line
+        };
+        let lines: Vec<_> = [10, 20, 30, 700, 2 * 24 * 60 * 60].map(line).into();
+        db.insert_batch(&lines, &[]).await.unwrap();
+        let positions = || async {
+            let (positions,): (i64,) = sqlx::query_as("SELECT count(*) FROM \"position\"")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+            positions
+        };
+
+        assert_eq!(db.prune(&policy).await.unwrap().downsampled, 2);
+        assert_eq!(positions().await, 3);
+
+        // Later positions of an interval which was already thinned are left
+        db.insert_batch(&[line(40)], &[]).await.unwrap();
+        assert_eq!(db.prune(&policy).await.unwrap().downsampled, 0);
+        assert_eq!(positions().await, 4);
+    }
+}
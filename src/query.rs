@@ -0,0 +1,206 @@
+use crate::data::*;
+use crate::export::ExportFilter;
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use uuid::Uuid;
+
+/// Most lines a single query returns
+pub const MAX_QUERY_LIMIT: u32 = 1000;
+
+/// Which stored position and Mic-E lines to read back
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionQuery {
+    pub filter: ExportFilter,
+    /// Only weather reports, i.e. lines with the `_` symbol
+    pub weather: bool,
+    pub limit: u32,
+}
+
+/// Which stored messages to read back
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageQuery {
+    /// Messages sent by or addressed to this callsign
+    pub callsign: Option<String>,
+    /// Only bulletins, i.e. messages addressed to `BLN*`
+    pub bulletins: bool,
+    /// First receive time included
+    pub start: Option<DateTime<Utc>>,
+    /// Receive time up to which messages are included, exclusive
+    pub end: Option<DateTime<Utc>>,
+    pub limit: u32,
+}
+
+/// Columns of a stored position read back by the backends: id, receive
+/// time, from, via, to, timestamp, messaging_supported, latitude, longitude,
+/// precision, symbol_table, symbol_code, comment and cst. Times are RFC 3339.
+pub type PositionLineColumns = (
+    String,
+    String,
+    String,
+    String,
+    String,
+    Option<String>,
+    i64,
+    f64,
+    f64,
+    f64,
+    String,
+    String,
+    String,
+    String,
+);
+
+/// Columns of a stored Mic-E report read back by the backends: id, receive
+/// time, from, via, latitude, longitude, precision, message, speed, course,
+/// symbol_table, symbol_code, comment and current
+pub type MicELineColumns = (
+    String,
+    String,
+    String,
+    String,
+    f64,
+    f64,
+    f64,
+    String,
+    i64,
+    i64,
+    String,
+    String,
+    String,
+    i64,
+);
+
+/// Columns of a stored message read back by the backends: id, receive time,
+/// from, via, to, addressee, text and msg_id
+pub type MessageLineColumns = (
+    String,
+    String,
+    String,
+    String,
+    String,
+    String,
+    String,
+    Option<String>,
+);
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(value)?.with_timezone(&Utc))
+}
+
+fn first_char(value: &str) -> char {
+    value.chars().next().unwrap_or(' ')
+}
+
+/// A stored line with the data read from its type's table
+fn stored_line(
+    id: &str,
+    received_at: &str,
+    from: String,
+    via: &str,
+    data: ParsedAprsData,
+) -> Result<ParsedLine> {
+    Ok(ParsedLine {
+        id: Uuid::parse_str(id).map_err(|e| anyhow!("invalid id `{}`: {}", id, e))?,
+        received_at: parse_time(received_at)?,
+        from,
+        via: via
+            .split(", ")
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_string())
+            .collect(),
+        data,
+    })
+}
+
+pub fn position_line(columns: PositionLineColumns) -> Result<ParsedLine> {
+    let (
+        id,
+        received_at,
+        from,
+        via,
+        to,
+        timestamp,
+        messaging_supported,
+        latitude,
+        longitude,
+        precision,
+        symbol_table,
+        symbol_code,
+        comment,
+        cst,
+    ) = columns;
+    // An unresolvable timestamp is stored as NULL, or empty in SQLite
+    let timestamp = match timestamp.as_deref() {
+        None | Some("") => None,
+        Some(x) => Some(Timestamp::from_stored(parse_time(x)?)),
+    };
+    let data = ParsedAprsData::Position(ParsedAprsPosition {
+        to,
+        timestamp,
+        messaging_supported: messaging_supported != 0,
+        latitude,
+        longitude,
+        precision,
+        symbol_table: first_char(&symbol_table),
+        symbol_code: first_char(&symbol_code),
+        comment,
+        cst,
+    });
+    stored_line(&id, &received_at, from, &via, data)
+}
+
+pub fn mic_e_line(columns: MicELineColumns) -> Result<ParsedLine> {
+    let (
+        id,
+        received_at,
+        from,
+        via,
+        latitude,
+        longitude,
+        precision,
+        message,
+        speed,
+        course,
+        symbol_table,
+        symbol_code,
+        comment,
+        current,
+    ) = columns;
+    let data = ParsedAprsData::MicE(ParsedAprsMicE {
+        latitude,
+        longitude,
+        precision,
+        message,
+        speed: speed.max(0) as u32,
+        course: course.max(0) as u32,
+        symbol_table: first_char(&symbol_table),
+        symbol_code: first_char(&symbol_code),
+        comment,
+        current: current != 0,
+    });
+    stored_line(&id, &received_at, from, &via, data)
+}
+
+pub fn message_line(columns: MessageLineColumns) -> Result<ParsedLine> {
+    let (id, received_at, from, via, to, addressee, text, msg_id) = columns;
+    let data = ParsedAprsData::Message(ParsedAprsMessage {
+        to,
+        addressee,
+        text,
+        id: msg_id.map(|x| x.into_bytes()),
+    });
+    stored_line(&id, &received_at, from, &via, data)
+}
+
+/// Merge position and Mic-E lines, each newest first, into the newest
+/// `limit` lines
+pub fn newest_lines(
+    mut lines: Vec<ParsedLine>,
+    more: Vec<ParsedLine>,
+    limit: u32,
+) -> Vec<ParsedLine> {
+    lines.extend(more);
+    lines.sort_by_key(|x| std::cmp::Reverse(x.received_at));
+    lines.truncate(limit as usize);
+    lines
+}
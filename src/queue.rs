@@ -0,0 +1,503 @@
+use crate::metrics::METRICS;
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use prometheus::{IntCounter, IntGauge};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+
+/// Most items read back from the spill file at once, which happens while the
+/// queue is locked
+const SPILL_READ_CHUNK: usize = 256;
+
+/// What happens to an item pushed while the queue is full
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum OverflowPolicy {
+    /// Wait for room, holding up every sink and the connection
+    Block,
+
+    /// Make room by leaving out the oldest queued item
+    DropOldest,
+
+    /// Leave out the new item
+    DropNewest,
+
+    /// Write items to a file until the sink catches up. The file is started
+    /// over on startup, so items still in it when the process stopped are
+    /// lost; `--spool-dir` keeps batches across restarts.
+    Spill,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueOptions {
+    /// Name in logs and metric labels, also naming the spill file
+    pub name: String,
+    /// Items held in memory
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+    /// Directory of the spill file with `OverflowPolicy::Spill`
+    pub spill_dir: PathBuf,
+    /// Percentage of the capacity above which a warning is logged
+    pub high_water: u8,
+}
+
+/// Items waiting on disk as JSON lines, read back in order
+struct SpillFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    reader: BufReader<File>,
+    /// Items written and not read back yet
+    len: usize,
+}
+
+impl SpillFile {
+    /// Start an empty file, replacing one left behind by an earlier run
+    fn create(path: PathBuf) -> Result<SpillFile> {
+        if let Ok(metadata) = std::fs::metadata(&path) {
+            if metadata.len() > 0 {
+                warn!(
+                    "Spill file {} of an earlier run is replaced, its items are lost",
+                    path.display()
+                );
+            }
+        }
+        let writer = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&path)
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        let reader = File::open(&path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        Ok(SpillFile {
+            path,
+            writer: BufWriter::new(writer),
+            reader: BufReader::new(reader),
+            len: 0,
+        })
+    }
+
+    fn push<T: Serialize>(&mut self, item: &T) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, item)?;
+        self.writer.write_all(b"\n")?;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        self.writer.flush()?;
+        while self.len > 0 {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(anyhow!("ended early"));
+            }
+            self.len -= 1;
+            if self.len == 0 {
+                // Everything was read back, so the file can start over
+                self.writer.get_ref().set_len(0)?;
+                self.writer.seek(SeekFrom::Start(0))?;
+                self.reader.seek(SeekFrom::Start(0))?;
+            }
+            match serde_json::from_str(&line) {
+                Ok(x) => return Ok(Some(x)),
+                Err(e) => error!("Spill Error: {}: skipping item: {}", self.path.display(), e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Forget what is on disk after it could not be read
+    fn clear(&mut self) -> usize {
+        let lost = self.len;
+        self.len = 0;
+        if let Err(e) = self.writer.get_ref().set_len(0) {
+            error!("{}: {}", self.path.display(), e);
+        }
+        let _ = self.writer.seek(SeekFrom::Start(0));
+        let _ = self.reader.seek(SeekFrom::Start(0));
+        lost
+    }
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    spill: Option<SpillFile>,
+    senders: usize,
+    receivers: usize,
+    /// Whether the high-water warning was logged and not cleared yet
+    above_high_water: bool,
+}
+
+struct Shared<T> {
+    options: QueueOptions,
+    state: Mutex<State<T>>,
+    /// Signalled when an item is queued or the last sender is gone
+    pushed: Notify,
+    /// Signalled when an item is taken or the last receiver is gone
+    taken: Notify,
+    depth: IntGauge,
+    spilled: IntGauge,
+    dropped: IntCounter,
+}
+
+impl<T> Shared<T> {
+    /// Publish the depth and log crossings of the high-water mark
+    fn update_depth(&self, state: &mut State<T>) {
+        let spilled = state.spill.as_ref().map_or(0, |x| x.len);
+        let depth = state.items.len() + spilled;
+        self.depth.set(depth as i64);
+        self.spilled.set(spilled as i64);
+        let mark = self.options.capacity * usize::from(self.options.high_water) / 100;
+        if !state.above_high_water && depth > mark {
+            state.above_high_water = true;
+            warn!(
+                "Queue of sink {} is above {}% ({} of {} items)",
+                self.options.name, self.options.high_water, depth, self.options.capacity
+            );
+        } else if state.above_high_water && depth <= mark / 2 {
+            // Only cleared well below the mark so a queue hovering around
+            // it does not flood the log
+            state.above_high_water = false;
+            info!(
+                "Queue of sink {} is back to {} items",
+                self.options.name, depth
+            );
+        }
+    }
+}
+
+/// Bounded queue from the reader of the feed to the tasks of one sink.
+/// Items beyond the capacity are handled by the queue's `OverflowPolicy`.
+pub fn channel<T>(options: QueueOptions) -> Result<(Sender<T>, Receiver<T>)>
+where
+    T: Serialize + DeserializeOwned + Send,
+{
+    let spill = match options.overflow {
+        OverflowPolicy::Spill => {
+            std::fs::create_dir_all(&options.spill_dir)
+                .map_err(|e| anyhow!("{}: {}", options.spill_dir.display(), e))?;
+            let path = options.spill_dir.join(format!("{}.spill", options.name));
+            Some(SpillFile::create(path)?)
+        }
+        _ => None,
+    };
+    let labels = [options.name.as_str()];
+    METRICS
+        .queue_capacity
+        .with_label_values(&labels)
+        .set(options.capacity as i64);
+    let shared = Arc::new(Shared {
+        depth: METRICS.queue_depth.with_label_values(&labels),
+        spilled: METRICS.queue_spilled.with_label_values(&labels),
+        dropped: METRICS.sink_dropped.with_label_values(&labels),
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(options.capacity.min(4096)),
+            spill,
+            senders: 1,
+            receivers: 1,
+            above_high_water: false,
+        }),
+        pushed: Notify::new(),
+        taken: Notify::new(),
+        options,
+    });
+    Ok((
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    ))
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    pub fn name(&self) -> &str {
+        &self.shared.options.name
+    }
+}
+
+impl<T: Serialize> Sender<T> {
+    /// Queue an item, returning false once every receiver is gone
+    pub async fn send(&self, mut item: T) -> bool {
+        loop {
+            let taken = self.shared.taken.notified();
+            tokio::pin!(taken);
+            match self.push(item, taken.as_mut()) {
+                Ok(open) => return open,
+                Err(x) => item = x,
+            }
+            taken.await;
+        }
+    }
+
+    /// Queue an item unless it has to wait for room, in which case it is
+    /// handed back with `taken` registered for the next take
+    fn push(&self, item: T, taken: Pin<&mut Notified<'_>>) -> Result<bool, T> {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if state.receivers == 0 {
+            return Ok(false);
+        }
+        let full = state.items.len() >= shared.options.capacity;
+        // Once spilling, items keep going to disk until it is read back, so
+        // they stay in order
+        let spilling = state.spill.as_ref().is_some_and(|x| x.len > 0);
+        if !full && !spilling {
+            state.items.push_back(item);
+        } else {
+            match shared.options.overflow {
+                OverflowPolicy::Block => {
+                    // Registered while locked so a take in between is not missed
+                    taken.enable();
+                    return Err(item);
+                }
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                    state.items.push_back(item);
+                    shared.dropped.inc();
+                }
+                OverflowPolicy::DropNewest => shared.dropped.inc(),
+                OverflowPolicy::Spill => {
+                    let spill = state.spill.as_mut().expect("spill file");
+                    if let Err(e) = spill.push(&item) {
+                        error!("Spill Error: {}: {}", spill.path.display(), e);
+                        shared.dropped.inc();
+                    }
+                }
+            }
+        }
+        shared.update_depth(&mut state);
+        drop(state);
+        shared.pushed.notify_one();
+        Ok(true)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.pushed.notify_waiters();
+        }
+    }
+}
+
+/// Receiving side of a queue, cloned for every task of a sink
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: DeserializeOwned> Receiver<T> {
+    /// Next item, waiting for one. `None` once the senders are gone and
+    /// the queue is empty.
+    pub async fn recv(&self) -> Option<T> {
+        loop {
+            let pushed = self.shared.pushed.notified();
+            tokio::pin!(pushed);
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(item) = self.take(&mut state) {
+                    return Some(item);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+                pushed.as_mut().enable();
+            }
+            pushed.await;
+        }
+    }
+
+    /// Next item if one is queued
+    pub fn try_recv(&self) -> Option<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        self.take(&mut state)
+    }
+
+    fn take(&self, state: &mut State<T>) -> Option<T> {
+        let shared = &*self.shared;
+        if state.items.is_empty() {
+            if let Some(spill) = state.spill.as_mut() {
+                // Read back in small chunks as the queue is locked meanwhile
+                let chunk = shared.options.capacity.clamp(1, SPILL_READ_CHUNK);
+                while state.items.len() < chunk {
+                    match spill.pop() {
+                        Ok(Some(item)) => state.items.push_back(item),
+                        Ok(None) => break,
+                        Err(e) => {
+                            error!("Spill Error: {}: {}", spill.path.display(), e);
+                            shared.dropped.inc_by(spill.clear() as u64);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        let item = state.items.pop_front()?;
+        shared.update_depth(state);
+        shared.taken.notify_one();
+        Some(item)
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().receivers += 1;
+        Receiver {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            drop(state);
+            self.shared.taken.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn options(name: &str, capacity: usize, overflow: OverflowPolicy) -> QueueOptions {
+        QueueOptions {
+            name: format!("test-{}", name),
+            capacity,
+            overflow,
+            spill_dir: std::env::temp_dir().join(format!("k0hax-queue-{}", uuid::Uuid::new_v4())),
+            high_water: 80,
+        }
+    }
+
+    fn drain(rx: &Receiver<u32>) -> Vec<u32> {
+        std::iter::from_fn(|| rx.try_recv()).collect()
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (tx, rx) = channel(options("block", 2, OverflowPolicy::Block)).unwrap();
+        assert!(tx.send(1).await);
+        assert!(tx.send(2).await);
+        let send = tx.send(3);
+        tokio::pin!(send);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut send)
+            .await
+            .is_err());
+        assert_eq!(rx.try_recv(), Some(1));
+        assert!(send.await);
+        assert_eq!(drain(&rx), vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_latest_items() {
+        let (tx, rx) = channel(options("drop-oldest", 2, OverflowPolicy::DropOldest)).unwrap();
+        for x in 1..=4 {
+            assert!(tx.send(x).await);
+        }
+        assert_eq!(drain(&rx), vec![3, 4]);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_the_first_items() {
+        let (tx, rx) = channel(options("drop-newest", 2, OverflowPolicy::DropNewest)).unwrap();
+        for x in 1..=4 {
+            assert!(tx.send(x).await);
+        }
+        assert_eq!(drain(&rx), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn spilled_items_are_read_back_in_order() {
+        let options = options("spill", 2, OverflowPolicy::Spill);
+        let dir = options.spill_dir.clone();
+        let (tx, rx) = channel(options).unwrap();
+        for x in 1..=5 {
+            assert!(tx.send(x).await);
+        }
+        assert_eq!(rx.try_recv(), Some(1));
+        // Goes to disk behind the spilled items although there is room
+        assert!(tx.send(6).await);
+        assert_eq!(rx.try_recv(), Some(2));
+        assert_eq!(rx.try_recv(), Some(3));
+        assert!(tx.send(7).await);
+        assert_eq!(drain(&rx), vec![4, 5, 6, 7]);
+        // Once read back, new items stay in memory again
+        assert!(tx.send(8).await);
+        assert_eq!(rx.shared.state.lock().unwrap().items.len(), 1);
+        assert_eq!(drain(&rx), vec![8]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn spill_is_read_back_in_chunks() {
+        let options = options("spill-chunks", 300, OverflowPolicy::Spill);
+        let dir = options.spill_dir.clone();
+        let (tx, rx) = channel(options).unwrap();
+        for x in 0..900 {
+            assert!(tx.send(x).await);
+        }
+        for x in 0..300 {
+            assert_eq!(rx.try_recv(), Some(x));
+        }
+        assert_eq!(rx.try_recv(), Some(300));
+        {
+            let state = rx.shared.state.lock().unwrap();
+            assert_eq!(state.items.len(), SPILL_READ_CHUNK - 1);
+            assert_eq!(state.spill.as_ref().unwrap().len, 600 - SPILL_READ_CHUNK);
+        }
+        assert_eq!(drain(&rx), (301..900).collect::<Vec<_>>());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn spill_file_of_earlier_run_is_started_over() {
+        let options = options("spill-restart", 2, OverflowPolicy::Spill);
+        let dir = options.spill_dir.clone();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("test-spill-restart.spill"), "1\n2\n").unwrap();
+        let (tx, rx) = channel::<u32>(options).unwrap();
+        assert_eq!(rx.try_recv(), None);
+        assert!(tx.send(3).await);
+        assert_eq!(drain(&rx), vec![3]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn closed_ends_are_reported() {
+        let (tx, rx) = channel(options("closed", 2, OverflowPolicy::Block)).unwrap();
+        assert!(tx.send(1).await);
+        drop(tx);
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, None);
+
+        let (tx, rx) = channel::<u32>(options("closed", 2, OverflowPolicy::Block)).unwrap();
+        drop(rx);
+        assert!(!tx.send(1).await);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::archive::{CompressedWriter, Compression, Rotation};
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use tokio::time::Instant;
+
+/// Where and how `RawRecorder` writes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawLogOptions {
+    pub directory: PathBuf,
+    /// Start of every file name
+    pub prefix: String,
+    pub compression: Compression,
+    pub rotation: Rotation,
+}
+
+/// Records every line received from APRS-IS as `RFC3339-TIME TNC2-LINE` into
+/// `PREFIX-PERIOD.log`, which `ReplaySource` can play back later
+pub struct RawRecorder {
+    options: RawLogOptions,
+    period: Option<String>,
+    file: Option<CompressedWriter<BufWriter<File>>>,
+}
+
+impl RawRecorder {
+    pub fn new(options: RawLogOptions) -> Result<RawRecorder> {
+        std::fs::create_dir_all(&options.directory)?;
+        Ok(RawRecorder {
+            options,
+            period: None,
+            file: None,
+        })
+    }
+
+    pub fn record(&mut self, received_at: DateTime<Utc>, raw: &str) -> Result<()> {
+        let period = self.options.rotation.period(received_at);
+        if self.period.as_ref() != Some(&period) {
+            self.finish()?;
+            let name = match period.as_str() {
+                "" => self.options.prefix.clone(),
+                x => format!("{}-{}", self.options.prefix, x),
+            };
+            let path = self.options.directory.join(format!(
+                "{}.log{}",
+                name,
+                self.options.compression.extension()
+            ));
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            self.file = Some(CompressedWriter::new(
+                BufWriter::new(file),
+                self.options.compression,
+            )?);
+            self.period = Some(period);
+        }
+        let file = self.file.as_mut().expect("opened above");
+        writeln!(
+            file,
+            "{} {}",
+            received_at.to_rfc3339_opts(SecondsFormat::Micros, true),
+            raw
+        )?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Complete and close the current file
+    pub fn finish(&mut self) -> Result<()> {
+        if let Some(file) = self.file.take() {
+            file.finish()?.flush()?;
+        }
+        self.period = None;
+        Ok(())
+    }
+}
+
+impl Drop for RawRecorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::error!("Could not close raw log: {}", e);
+        }
+    }
+}
+
+/// Split a log line into its receive time, when it has one, and the TNC2
+/// packet. Returns `None` for blank lines and `#` comments.
+///
+/// Understands the `RFC3339-TIME LINE` lines of `RawRecorder`,
+/// `YYYY-MM-DD HH:MM:SS[ UTC]: LINE` lines as written by aprs.fi, APRSdroid
+/// and most loggers, `UNIX-TIME[:] LINE` lines as written by aprsc, and plain
+/// TNC2 lines without a time.
+pub fn parse_log_line(line: &str) -> Option<(Option<DateTime<Utc>>, &str)> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    if line.trim().is_empty() || line.starts_with('#') {
+        return None;
+    }
+    if let Some((time, rest)) = line.split_once(' ') {
+        if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+            return Some((Some(time.with_timezone(&Utc)), rest));
+        }
+    }
+    if let Some(time) = line.get(..19) {
+        if let Ok(time) = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S") {
+            let rest = line[19..].trim_start_matches(|x: char| x.is_ascii_digit() || x == '.');
+            let rest = rest.strip_prefix(" UTC").unwrap_or(rest);
+            let rest = rest.strip_prefix(':').unwrap_or(rest).trim_start();
+            return Some((Some(time.and_utc()), rest));
+        }
+    }
+    let end = line.find([' ', ':', '\t']).unwrap_or(line.len());
+    let (seconds, rest) = line.split_at(end);
+    if seconds.len() >= 9 && seconds.chars().all(|x| x.is_ascii_digit() || x == '.') {
+        if let Ok(seconds) = seconds.parse::<f64>() {
+            let time = DateTime::from_timestamp_micros((seconds * 1_000_000.0) as i64);
+            let rest = rest.strip_prefix(':').unwrap_or(rest).trim_start();
+            return Some((time, rest));
+        }
+    }
+    Some((None, line))
+}
+
+/// How fast `ReplaySource` plays back lines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// Keep the original spacing of the lines
+    Original,
+    /// Divide the original spacing by a factor
+    Accelerated(f64),
+    /// Read lines as fast as they are consumed
+    Maximum,
+}
+
+/// Parse `original`, `max` or an acceleration factor such as `10`
+pub fn parse_replay_speed(value: &str) -> Result<ReplaySpeed, String> {
+    match value {
+        "original" => Ok(ReplaySpeed::Original),
+        "max" => Ok(ReplaySpeed::Maximum),
+        x => match x.trim_end_matches('x').parse::<f64>() {
+            Ok(factor) if factor > 0.0 && factor.is_finite() => {
+                Ok(ReplaySpeed::Accelerated(factor))
+            }
+            _ => Err(format!(
+                "expected `original`, `max` or a factor above 0, got `{}`",
+                value
+            )),
+        },
+    }
+}
+
+/// Plays back log files in order, pacing the lines by their receive times.
+/// Files ending in `.gz` or `.zst` are decompressed.
+pub struct ReplaySource {
+    files: VecDeque<PathBuf>,
+    reader: Option<Box<dyn BufRead + Send>>,
+    speed: ReplaySpeed,
+    // Receive time of the first timed line and when it was played back
+    start: Option<(DateTime<Utc>, Instant)>,
+}
+
+impl ReplaySource {
+    pub fn new(files: Vec<PathBuf>, speed: ReplaySpeed) -> ReplaySource {
+        ReplaySource {
+            files: files.into(),
+            reader: None,
+            speed,
+            start: None,
+        }
+    }
+
+    /// Next packet with its receive time, or `None` once every file is read.
+    /// Lines without a time are given the current time.
+    pub async fn next_line(&mut self) -> Result<Option<(DateTime<Utc>, String)>> {
+        loop {
+            let Some(reader) = &mut self.reader else {
+                let Some(path) = self.files.pop_front() else {
+                    return Ok(None);
+                };
+                self.reader = Some(open_log(&path)?);
+                continue;
+            };
+            let mut buf = Vec::new();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) => {
+                    self.reader = None;
+                    continue;
+                }
+                Ok(_) => {}
+                // Move on to the next file rather than failing on this one forever
+                Err(e) => {
+                    self.reader = None;
+                    return Err(e.into());
+                }
+            }
+            let line = String::from_utf8_lossy(&buf);
+            let Some((time, raw)) = parse_log_line(&line) else {
+                continue;
+            };
+            if let Some(time) = time {
+                self.pace(time).await;
+            }
+            return Ok(Some((time.unwrap_or_else(Utc::now), raw.to_string())));
+        }
+    }
+
+    /// Wait until a line received at `time` is due
+    async fn pace(&mut self, time: DateTime<Utc>) {
+        let factor = match self.speed {
+            ReplaySpeed::Maximum => return,
+            ReplaySpeed::Original => 1.0,
+            ReplaySpeed::Accelerated(x) => x,
+        };
+        let (first, started) = *self.start.get_or_insert((time, Instant::now()));
+        // Lines older than the first one are played back right away
+        if let Ok(offset) = time.signed_duration_since(first).to_std() {
+            tokio::time::sleep_until(started + offset.div_f64(factor)).await;
+        }
+    }
+}
+
+fn open_log(path: &PathBuf) -> Result<Box<dyn BufRead + Send>> {
+    let file = File::open(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    let name = path.to_string_lossy();
+    Ok(if name.ends_with(".gz") {
+        Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(file)))
+    } else if name.ends_with(".zst") {
+        Box::new(BufReader::new(zstd::stream::read::Decoder::new(file)?))
+    } else {
+        Box::new(BufReader::new(file))
+    })
+}
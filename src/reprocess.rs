@@ -0,0 +1,36 @@
+use chrono::prelude::*;
+use uuid::Uuid;
+
+/// Number of `unknown` rows read per round trip by `reprocess`
+pub const REPROCESS_PAGE_SIZE: i64 = 1000;
+
+/// Outcome of a `reprocess` run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReprocessStats {
+    /// Unknown packets which were decoded again
+    pub scanned: u64,
+    /// Packets which now decode and were moved to their proper table
+    pub decoded: u64,
+}
+
+/// Decode a stored unknown packet again.
+///
+/// Returns `None` when the packet still does not decode. Otherwise the line
+/// carries the original id and receive time, so it replaces the unknown row
+/// in place.
+pub fn reprocess_line(
+    id: Uuid,
+    raw: &str,
+    received_at: DateTime<Utc>,
+) -> Option<crate::data::ParsedLine> {
+    match crate::utils::parse_line(raw) {
+        Ok(line) if !matches!(line.data, crate::data::ParsedAprsData::Unknown(_)) => {
+            Some(crate::data::ParsedLine {
+                id,
+                received_at,
+                ..line
+            })
+        }
+        _ => None,
+    }
+}
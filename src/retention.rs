@@ -0,0 +1,178 @@
+use chrono::prelude::*;
+use std::time::Duration;
+
+/// Names accepted by `--retain`: the packet types of `main_data`, plus the
+/// `paths` of suppressed duplicates
+pub const RETENTION_TABLES: &[&str] =
+    &["position", "message", "status", "mic_e", "unknown", "paths"];
+
+/// Maximum number of rows removed per statement while expiring, so the
+/// ingest tasks are not locked out for the whole run
+pub const PRUNE_BATCH_SIZE: i64 = 10_000;
+
+/// How long a path may outlive its packet before it is removed. The packet of
+/// a fresh path may still be queued in another worker.
+pub const ORPHAN_PATH_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// Maximum age of the rows of one table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Retention {
+    pub table: String,
+    pub max_age: Duration,
+}
+
+/// Time span of the positions ranked by one downsampling statement, rounded
+/// up to whole intervals
+pub const DOWNSAMPLE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Keep one position per station and `interval` once they are `older_than`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Downsample {
+    pub older_than: Duration,
+    pub interval: Duration,
+}
+
+impl Downsample {
+    /// Length of an interval in whole seconds
+    pub fn interval_secs(&self) -> i64 {
+        self.interval.as_secs().clamp(1, i64::MAX as u64) as i64
+    }
+
+    /// Start of the interval holding a Unix time
+    pub fn interval_start(&self, time: i64) -> i64 {
+        time.div_euclid(self.interval_secs()) * self.interval_secs()
+    }
+
+    /// Unix time up to which positions are downsampled. The interval holding
+    /// the cutoff is left alone until it has aged completely.
+    pub fn until(&self) -> i64 {
+        self.interval_start(cutoff(self.older_than).timestamp())
+    }
+
+    /// End of the window of whole intervals starting at `start`
+    pub fn window_end(&self, start: i64, until: i64) -> i64 {
+        let intervals = DOWNSAMPLE_WINDOW
+            .as_secs()
+            .div_ceil(self.interval_secs() as u64)
+            .max(1) as i64;
+        start
+            .saturating_add(intervals.saturating_mul(self.interval_secs()))
+            .min(until)
+    }
+}
+
+/// Everything the maintenance task removes on each run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub retain: Vec<Retention>,
+    pub downsample: Option<Downsample>,
+}
+
+impl RetentionPolicy {
+    /// Whether the policy would never remove anything
+    pub fn is_empty(&self) -> bool {
+        self.retain.is_empty() && self.downsample.is_none()
+    }
+}
+
+/// Outcome of a prune run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PruneStats {
+    /// Rows removed because they were past their retention period
+    pub expired: u64,
+    /// Positions removed by downsampling
+    pub downsampled: u64,
+}
+
+/// Oldest time which is still kept for a maximum age
+pub fn cutoff(max_age: Duration) -> DateTime<Utc> {
+    let max_age = chrono::Duration::from_std(max_age).unwrap_or(chrono::Duration::zero());
+    Utc::now()
+        .checked_sub_signed(max_age)
+        .unwrap_or(DateTime::<Utc>::MIN_UTC)
+}
+
+/// Parse a duration such as `90s`, `10m`, `12h`, `30d`, `2w` or `1y`. A bare
+/// `0` is accepted as zero.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    if value == "0" {
+        return Ok(Duration::ZERO);
+    }
+    let split = value.find(|x: char| !x.is_ascii_digit()).ok_or(format!(
+        "missing unit in `{}` (use s, m, h, d, w or y)",
+        value
+    ))?;
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid number in `{}`", value))?;
+    let seconds: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "y" => 365 * 24 * 60 * 60,
+        _ => return Err(format!("unknown unit `{}` (use s, m, h, d, w or y)", unit)),
+    };
+    number
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or(format!("duration `{}` is too large", value))
+}
+
+/// Parse a `TABLE=AGE` retention, e.g. `position=30d`
+pub fn parse_retention(value: &str) -> Result<Retention, String> {
+    let (table, age) = value
+        .split_once('=')
+        .ok_or(format!("expected TABLE=AGE, got `{}`", value))?;
+    if !RETENTION_TABLES.contains(&table) {
+        return Err(format!(
+            "unknown table `{}` (use one of {})",
+            table,
+            RETENTION_TABLES.join(", ")
+        ));
+    }
+    Ok(Retention {
+        table: table.to_string(),
+        max_age: parse_duration(age)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn downsample(interval: u64) -> Downsample {
+        Downsample {
+            older_than: Duration::from_secs(24 * 60 * 60),
+            interval: Duration::from_secs(interval),
+        }
+    }
+
+    #[test]
+    fn downsample_windows_hold_whole_intervals() {
+        let ten_minutes = downsample(600);
+        assert_eq!(ten_minutes.interval_start(1_000_000), 999_600);
+        assert_eq!(ten_minutes.window_end(999_600, i64::MAX), 1_003_200);
+        assert_eq!(ten_minutes.window_end(999_600, 1_000_200), 1_000_200);
+
+        // Intervals longer than a window are ranked one at a time
+        let day = downsample(24 * 60 * 60);
+        assert_eq!(day.window_end(86_400, i64::MAX), 172_800);
+        let odd = downsample(7 * 60);
+        assert_eq!(odd.window_end(0, i64::MAX) % (7 * 60), 0);
+        assert!(odd.window_end(0, i64::MAX) >= 60 * 60);
+
+        assert_eq!(downsample(0).interval_secs(), 1);
+    }
+
+    #[test]
+    fn downsampling_stops_at_a_whole_interval() {
+        let ten_minutes = downsample(600);
+        let until = ten_minutes.until();
+        assert_eq!(until % 600, 0);
+        let cutoff = cutoff(ten_minutes.older_than).timestamp();
+        assert!(until <= cutoff && cutoff - until < 600);
+    }
+}
@@ -0,0 +1,746 @@
+use crate::client::LoginResponse;
+use crate::data::*;
+use crate::live::SEND_TIMEOUT;
+use crate::metrics::METRICS;
+use crate::track::{distance_m_between, Fix};
+use crate::utils::{generate_passcode, parse_line_or_unknown};
+use futures_util::sink::SinkExt;
+use futures_util::StreamExt;
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::codec::{Framed, LinesCodec};
+
+/// Name and version of the software, sent in the banner and keepalives
+const SOFTWARE: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// Packets of clients waiting to be sent upstream before more are dropped
+const UPLOAD_BUFFER: usize = 256;
+
+/// Time a client gets to log in after connecting
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a comment is sent so idle connections are known to be alive
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerOptions {
+    /// Server id in `# logresp` lines and the q constructs of uploads
+    pub name: String,
+    /// Lines a client may fall behind before it is disconnected
+    pub buffer: usize,
+}
+
+/// A line with the text clients are sent
+struct FeedItem {
+    line: ParsedLine,
+    raw: String,
+    /// Client which uploaded the line, which is not sent it back
+    origin: Option<u64>,
+}
+
+/// A small APRS-IS server passing the feed on to local clients, such as
+/// tools which would otherwise each connect to APRS-IS themselves.
+///
+/// Clients log in with `user CALL pass PASSCODE [vers NAME VERSION] [filter
+/// FILTER]` and get the lines matching their filter, which can be changed
+/// with `#filter FILTER`. Packets sent by clients whose passcode is right
+/// are given a q construct like aprsc does, passed to the other clients and
+/// queued for the upstream connection. Packets of unverified clients are
+/// dropped.
+#[derive(Clone)]
+pub struct AprsIsServer {
+    options: Arc<ServerOptions>,
+    tx: broadcast::Sender<Arc<FeedItem>>,
+    uploads: mpsc::Sender<String>,
+    next_client: Arc<AtomicU64>,
+}
+
+impl AprsIsServer {
+    /// The server and the receiver of the packets it was sent, to be passed
+    /// upstream
+    pub fn new(options: ServerOptions) -> (AprsIsServer, mpsc::Receiver<String>) {
+        let (tx, _) = broadcast::channel(options.buffer);
+        let (uploads, uploads_rx) = mpsc::channel(UPLOAD_BUFFER);
+        let server = AprsIsServer {
+            options: Arc::new(options),
+            tx,
+            uploads,
+            next_client: Arc::new(AtomicU64::new(0)),
+        };
+        (server, uploads_rx)
+    }
+
+    /// Send a line from upstream to every client whose filter matches it
+    pub fn publish(&self, line: &ParsedLine, raw: &str) {
+        self.send(line.clone(), raw.to_string(), None);
+    }
+
+    fn send(&self, line: ParsedLine, raw: String, origin: Option<u64>) {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.tx.send(Arc::new(FeedItem { line, raw, origin }));
+    }
+
+    /// Accept clients until the listener fails
+    pub async fn serve(self, listener: TcpListener) -> std::io::Result<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let id = self.next_client.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(self.clone().serve_client(stream, addr, id));
+        }
+    }
+
+    async fn serve_client(self, stream: TcpStream, addr: SocketAddr, id: u64) {
+        let mut lines = Framed::new(stream, LinesCodec::new_with_max_length(2048));
+        if send_line(&mut lines, &format!("# {}", SOFTWARE))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        let login = match tokio::time::timeout(LOGIN_TIMEOUT, read_login(&mut lines)).await {
+            Ok(Some(x)) => x,
+            Ok(None) => return,
+            Err(_) => {
+                debug!("APRS-IS client {} did not log in", addr);
+                return;
+            }
+        };
+        let login = match Login::parse(&login) {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("APRS-IS client {} sent a bad login: {}", addr, e);
+                let _ = send_line(&mut lines, &format!("# Login failed: {}", e)).await;
+                return;
+            }
+        };
+        let response = LoginResponse {
+            callsign: login.callsign.clone(),
+            verified: login.verified,
+            server: Some(self.options.name.clone()),
+        };
+        // Lines published once the client knows it is logged in are its
+        let mut rx = self.tx.subscribe();
+        if send_line(&mut lines, &response.to_string()).await.is_err() {
+            return;
+        }
+        let mut filter = match ServerFilter::parse(&login.filter) {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = send_line(&mut lines, &format!("# Filter parsing error: {}", e)).await;
+                ServerFilter::default()
+            }
+        };
+        info!(
+            "APRS-IS client {} logged in as {} ({}){}",
+            addr,
+            login.callsign,
+            if login.verified {
+                "verified"
+            } else {
+                "unverified"
+            },
+            match login.software.as_str() {
+                "" => String::new(),
+                x => format!(" using {}", x),
+            }
+        );
+        METRICS.server_clients.inc();
+        let mut keepalive = tokio::time::interval_at(
+            tokio::time::Instant::now() + KEEPALIVE_INTERVAL,
+            KEEPALIVE_INTERVAL,
+        );
+        loop {
+            tokio::select! {
+                line = lines.next() => {
+                    let line = match line {
+                        Some(Ok(x)) => x,
+                        Some(Err(e)) => {
+                            debug!("APRS-IS client {}: {}", addr, e);
+                            break;
+                        }
+                        None => break,
+                    };
+                    let line = line.trim_end_matches('\r');
+                    if let Some(text) = line.strip_prefix('#') {
+                        let Some(text) = text.trim().strip_prefix("filter") else {
+                            continue;
+                        };
+                        let reply = match ServerFilter::parse(text.trim()) {
+                            Ok(x) => {
+                                filter = x;
+                                format!("# filter {} active", text.trim())
+                            }
+                            Err(e) => format!("# Filter parsing error: {}", e),
+                        };
+                        if send_line(&mut lines, &reply).await.is_err() {
+                            break;
+                        }
+                    } else if !line.is_empty() {
+                        self.upload(&login, id, line);
+                    }
+                }
+                item = rx.recv() => match item {
+                    Ok(item) => {
+                        if item.origin == Some(id) || !filter.matches(&item.line, &login.callsign) {
+                            continue;
+                        }
+                        let sent =
+                            tokio::time::timeout(SEND_TIMEOUT, send_line(&mut lines, &item.raw)).await;
+                        match sent {
+                            Ok(Ok(())) => {}
+                            Ok(Err(_)) => break,
+                            Err(_) => {
+                                warn!("Dropping APRS-IS client {} which stopped reading", addr);
+                                METRICS.server_dropped.inc();
+                                break;
+                            }
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Dropping APRS-IS client {} {} lines behind", addr, missed);
+                        METRICS.server_dropped.inc();
+                        break;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = keepalive.tick() => {
+                    let text = format!(
+                        "# {} {} {}",
+                        SOFTWARE,
+                        chrono::Utc::now().format("%d %b %Y %H:%M:%S GMT"),
+                        self.options.name
+                    );
+                    let sent = tokio::time::timeout(SEND_TIMEOUT, send_line(&mut lines, &text)).await;
+                    if !matches!(sent, Ok(Ok(()))) {
+                        break;
+                    }
+                }
+            }
+        }
+        METRICS.server_clients.dec();
+        info!("APRS-IS client {} ({}) disconnected", addr, login.callsign);
+    }
+
+    /// Pass on a packet sent by a client
+    fn upload(&self, login: &Login, id: u64, line: &str) {
+        if !login.verified {
+            debug!("Dropping packet of unverified {}: {}", login.callsign, line);
+            return;
+        }
+        let Some(line) = add_q_construct(line, &login.callsign, &self.options.name) else {
+            debug!("Dropping packet of {}: {}", login.callsign, line);
+            return;
+        };
+        METRICS.server_uploads.inc();
+        self.send(parse_line_or_unknown(&line), line.clone(), Some(id));
+        match self.uploads.try_send(line) {
+            // Without an upstream connection, e.g. while replaying, only the
+            // local clients get the packet
+            Ok(()) | Err(mpsc::error::TrySendError::Closed(_)) => {}
+            Err(mpsc::error::TrySendError::Full(line)) => {
+                warn!("Upstream is not keeping up, dropping packet: {}", line)
+            }
+        }
+    }
+}
+
+async fn send_line(lines: &mut Framed<TcpStream, LinesCodec>, text: &str) -> anyhow::Result<()> {
+    lines.send(format!("{}\r", text)).await?;
+    Ok(())
+}
+
+/// First line of a client which is not a comment or blank, `None` when it
+/// disconnects first
+async fn read_login(lines: &mut Framed<TcpStream, LinesCodec>) -> Option<String> {
+    loop {
+        let line = lines.next().await?.ok()?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            return Some(line.to_string());
+        }
+    }
+}
+
+/// A client's `user` line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Login {
+    pub(crate) callsign: String,
+    /// Whether the passcode is the one of the callsign
+    pub(crate) verified: bool,
+    /// `NAME VERSION` after `vers`, when given
+    pub(crate) software: String,
+    /// Text after `filter`, when given
+    pub(crate) filter: String,
+}
+
+impl Login {
+    pub(crate) fn parse(line: &str) -> Result<Login, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let callsign = match words[..] {
+            ["user", callsign, ..] => callsign.to_uppercase(),
+            _ => return Err("expected `user CALLSIGN pass PASSCODE`".to_string()),
+        };
+        let valid = (1..=9).contains(&callsign.len())
+            && callsign
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || x == '-');
+        if !valid {
+            return Err(format!("invalid callsign `{}`", callsign));
+        }
+        let value = |key: &str| words.iter().position(|x| *x == key).map(|x| x + 1);
+        let passcode = value("pass").and_then(|x| words.get(x)?.parse::<i64>().ok());
+        let expected = generate_passcode(&callsign).and_then(|x| x.parse::<i64>().ok());
+        let software = match value("vers") {
+            Some(x) => words[x.min(words.len())..]
+                .iter()
+                .take_while(|x| **x != "filter")
+                .copied()
+                .collect::<Vec<_>>()
+                .join(" "),
+            None => String::new(),
+        };
+        let filter = match value("filter") {
+            Some(x) => words[x.min(words.len())..].join(" "),
+            None => String::new(),
+        };
+        Ok(Login {
+            verified: passcode.is_some() && passcode == expected,
+            callsign,
+            software,
+            filter,
+        })
+    }
+}
+
+/// Add the q construct to a packet of a verified client: `qAC` and the
+/// server for the logged in station's own packets, `qAS` and the login for
+/// ones it sends on behalf of others. The `qAR`, `qAr`, `qAo` or `qAO` of a
+/// gate is kept, while a `qAC` or `qAS` sent by the client is replaced, so
+/// clients cannot pass packets off as coming from elsewhere. `None` for
+/// packets which are malformed or must not be passed to APRS-IS.
+fn add_q_construct(line: &str, login: &str, server: &str) -> Option<String> {
+    let (header, body) = line.split_once(':')?;
+    let (from, path) = header.split_once('>')?;
+    if from.is_empty() || path.is_empty() {
+        return None;
+    }
+    let mut path: Vec<&str> = path.split(',').collect();
+    if path
+        .iter()
+        .any(|x| matches!(*x, "NOGATE" | "RFONLY" | "TCPXX" | "TCPXX*"))
+    {
+        return None;
+    }
+    // The q construct and the station after it end the path
+    match path.iter().position(|x| x.starts_with("qA")) {
+        // A destination comes first
+        Some(0) => return None,
+        Some(x) if matches!(path[x], "qAC" | "qAS") => path.truncate(x),
+        Some(_) => return Some(line.to_string()),
+        None => {}
+    }
+    let (q, station) = match from.eq_ignore_ascii_case(login) {
+        true => ("qAC", server),
+        false => ("qAS", login),
+    };
+    Some(format!(
+        "{}>{},{},{}:{}",
+        from,
+        path.join(","),
+        q,
+        station,
+        body
+    ))
+}
+
+/// One part of a filter, such as `r/45.5/-122.6/50`
+#[derive(Debug, Clone, PartialEq)]
+enum FilterPart {
+    /// `r/lat/lon/km`: positions within a distance of a point
+    Range {
+        latitude: f64,
+        longitude: f64,
+        km: f64,
+    },
+    /// `a/north/west/south/east`: positions within an area
+    Area {
+        north: f64,
+        west: f64,
+        south: f64,
+        east: f64,
+    },
+    /// `p/aa/bb`: source callsigns starting with any of these
+    Prefix(Vec<String>),
+    /// `b/call1/call2`: these source callsigns, `*` matching the rest
+    Budlist(Vec<String>),
+    /// `t/pmsw`: positions, messages, status and weather
+    Types(Vec<char>),
+    /// `d/digi1/digi2`: packets with one of these in their path
+    Digipeater(Vec<String>),
+    /// `u/unproto1`: packets to one of these destinations
+    Unproto(Vec<String>),
+    /// `g/call1`: messages addressed to one of these
+    Group(Vec<String>),
+}
+
+/// A server side filter in the syntax of aprsc, made of parts separated by
+/// spaces which each let lines through, except those starting with `-`
+/// which hold lines back. Messages to the logged in callsign always pass.
+///
+/// Supported are `r/`, `a/`, `p/`, `b/`, `t/` (with `p`, `m`, `s` and `w`),
+/// `d/`, `u/` and `g/`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerFilter {
+    include: Vec<FilterPart>,
+    exclude: Vec<FilterPart>,
+}
+
+fn parse_numbers<const N: usize>(part: &str, values: &[&str]) -> Result<[f64; N], String> {
+    let numbers: Vec<f64> = values
+        .iter()
+        .map(|x| x.parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid number in `{}`", part))?;
+    numbers
+        .try_into()
+        .map_err(|_| format!("expected {} numbers in `{}`", N, part))
+}
+
+fn parse_list(part: &str, values: &[&str]) -> Result<Vec<String>, String> {
+    if values.is_empty() || values.iter().any(|x| x.is_empty()) {
+        return Err(format!("empty value in `{}`", part));
+    }
+    Ok(values.iter().map(|x| x.to_uppercase()).collect())
+}
+
+/// Whether a callsign matches a pattern, which may end in `*`
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let value = value.to_uppercase();
+    match pattern.strip_suffix('*') {
+        Some(prefix) => value.starts_with(prefix),
+        None => value == pattern,
+    }
+}
+
+impl FilterPart {
+    fn parse(part: &str) -> Result<FilterPart, String> {
+        let mut fields = part.split('/');
+        let kind = fields.next().unwrap_or_default();
+        let values: Vec<&str> = fields.collect();
+        Ok(match kind {
+            "r" => {
+                let [latitude, longitude, km] = parse_numbers(part, &values)?;
+                if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+                    return Err(format!("invalid coordinates in `{}`", part));
+                }
+                FilterPart::Range {
+                    latitude,
+                    longitude,
+                    km,
+                }
+            }
+            "a" => {
+                let [north, west, south, east] = parse_numbers(part, &values)?;
+                if south > north || west > east {
+                    return Err(format!("invalid area in `{}`", part));
+                }
+                FilterPart::Area {
+                    north,
+                    west,
+                    south,
+                    east,
+                }
+            }
+            "p" => FilterPart::Prefix(parse_list(part, &values)?),
+            "b" => FilterPart::Budlist(parse_list(part, &values)?),
+            "t" => {
+                let types: Vec<char> = values.first().unwrap_or(&"").chars().collect();
+                if types.is_empty() {
+                    return Err(format!("no types in `{}`", part));
+                }
+                if let Some(x) = types.iter().find(|x| !"pmsw".contains(**x)) {
+                    return Err(format!("unsupported type `{}` in `{}`", x, part));
+                }
+                FilterPart::Types(types)
+            }
+            "d" => FilterPart::Digipeater(parse_list(part, &values)?),
+            "u" => FilterPart::Unproto(parse_list(part, &values)?),
+            "g" => FilterPart::Group(parse_list(part, &values)?),
+            _ => return Err(format!("unsupported filter `{}`", part)),
+        })
+    }
+
+    fn matches(&self, line: &ParsedLine) -> bool {
+        match self {
+            FilterPart::Range {
+                latitude,
+                longitude,
+                km,
+            } => line.data.position().is_some_and(|(x, y)| {
+                let fix = |latitude, longitude| Fix {
+                    time: line.received_at,
+                    latitude,
+                    longitude,
+                };
+                distance_m_between(&fix(*latitude, *longitude), &fix(x, y)) <= km * 1000.0
+            }),
+            FilterPart::Area {
+                north,
+                west,
+                south,
+                east,
+            } => line.data.position().is_some_and(|(latitude, longitude)| {
+                (*south..=*north).contains(&latitude) && (*west..=*east).contains(&longitude)
+            }),
+            FilterPart::Prefix(prefixes) => {
+                let from = line.from.to_uppercase();
+                prefixes.iter().any(|x| from.starts_with(x))
+            }
+            FilterPart::Budlist(calls) => calls.iter().any(|x| matches_pattern(x, &line.from)),
+            FilterPart::Types(types) => types.iter().any(|x| match (x, &line.data) {
+                ('p', ParsedAprsData::Position(_) | ParsedAprsData::MicE(_)) => true,
+                ('m', ParsedAprsData::Message(_)) => true,
+                ('s', ParsedAprsData::Status(_)) => true,
+                ('w', ParsedAprsData::Position(x)) => x.symbol_code == '_',
+                _ => false,
+            }),
+            FilterPart::Digipeater(calls) => {
+                line.via.iter().filter(|x| !x.starts_with("qA")).any(|via| {
+                    calls
+                        .iter()
+                        .any(|x| matches_pattern(x, via.trim_end_matches('*')))
+                })
+            }
+            FilterPart::Unproto(calls) => destination(&line.data)
+                .is_some_and(|to| calls.iter().any(|x| matches_pattern(x, to))),
+            FilterPart::Group(calls) => match &line.data {
+                ParsedAprsData::Message(x) => calls
+                    .iter()
+                    .any(|call| matches_pattern(call, x.addressee.trim())),
+                _ => false,
+            },
+        }
+    }
+}
+
+impl ServerFilter {
+    pub fn parse(text: &str) -> Result<ServerFilter, String> {
+        let mut filter = ServerFilter::default();
+        for part in text.split_whitespace() {
+            match part.strip_prefix('-') {
+                Some(x) => filter.exclude.push(FilterPart::parse(x)?),
+                None => filter.include.push(FilterPart::parse(part)?),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Whether a client logged in as `login` gets a line
+    pub fn matches(&self, line: &ParsedLine, login: &str) -> bool {
+        if let ParsedAprsData::Message(x) = &line.data {
+            if x.addressee.trim().eq_ignore_ascii_case(login) {
+                return true;
+            }
+        }
+        self.include.iter().any(|x| x.matches(line))
+            && !self.exclude.iter().any(|x| x.matches(line))
+    }
+}
+
+fn destination(data: &ParsedAprsData) -> Option<&str> {
+    match data {
+        ParsedAprsData::Position(x) => Some(&x.to),
+        ParsedAprsData::Message(x) => Some(&x.to),
+        ParsedAprsData::Status(x) => Some(&x.to),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POSITION: &str = "K1ABC>APRS,WIDE1-1*,qAR,IGATE:!4530.00N/12230.00W-test";
+    const WEATHER: &str = "K1ABC>APRS:!4530.00N/12230.00W_090/000g000t066";
+    const STATUS: &str = "N0XYZ>APZ123,TCPIP*,qAC,T2TEST:>status";
+    const MESSAGE: &str = "N0XYZ>APRS,TCPIP*,qAC,T2TEST::BLN1     :bulletin";
+
+    fn passes(filter: &str, raw: &str) -> bool {
+        ServerFilter::parse(filter)
+            .unwrap()
+            .matches(&parse_line_or_unknown(raw), "N0CALL")
+    }
+
+    #[test]
+    fn range_filter_is_in_km() {
+        // 0.1 degrees of longitude at 45.5 degrees north are 7.8 km
+        assert!(passes("r/45.5/-122.6/10", POSITION));
+        assert!(!passes("r/45.5/-122.6/5", POSITION));
+        assert!(!passes("r/45.5/-122.6/10", STATUS));
+    }
+
+    #[test]
+    fn area_filter() {
+        assert!(passes("a/46/-123/45/-122", POSITION));
+        assert!(!passes("a/47/-121/46/-120", POSITION));
+    }
+
+    #[test]
+    fn callsign_filters() {
+        assert!(passes("p/K1", POSITION));
+        assert!(passes("p/n0 p/K1", POSITION));
+        assert!(!passes("p/N", POSITION));
+        assert!(passes("b/K1ABC", POSITION));
+        assert!(passes("b/k1*", POSITION));
+        assert!(!passes("b/K1AB", POSITION));
+    }
+
+    #[test]
+    fn type_filter() {
+        assert!(passes("t/p", POSITION));
+        assert!(!passes("t/w", POSITION));
+        assert!(passes("t/w", WEATHER));
+        assert!(passes("t/s", STATUS));
+        assert!(!passes("t/pm", STATUS));
+        assert!(passes("t/m", MESSAGE));
+    }
+
+    #[test]
+    fn path_and_destination_filters() {
+        assert!(passes("d/WIDE1*", POSITION));
+        assert!(!passes("d/WIDE2*", POSITION));
+        // The q construct is not a digipeater
+        assert!(!passes("d/qAR", POSITION));
+        assert!(passes("u/APZ*", STATUS));
+        assert!(!passes("u/APRS", STATUS));
+        assert!(passes("g/BLN*", MESSAGE));
+        assert!(!passes("g/BLN2", MESSAGE));
+    }
+
+    #[test]
+    fn exclusions_hold_lines_back() {
+        assert!(passes("t/ps -p/K1", STATUS));
+        assert!(!passes("t/ps -p/K1", POSITION));
+        // Exclusions alone let nothing through
+        assert!(!passes("-p/K1", STATUS));
+    }
+
+    #[test]
+    fn messages_to_the_client_always_pass() {
+        let message = "N0XYZ>APRS,TCPIP*,qAC,T2TEST::N0CALL   :hello{1";
+        assert!(passes("", message));
+        assert!(passes("-t/m", message));
+        assert!(!passes("", STATUS));
+    }
+
+    #[test]
+    fn invalid_filters_are_rejected() {
+        for filter in [
+            "r/91/0/10",
+            "r/45/-122",
+            "a/45/-122/46/-123",
+            "t/x",
+            "t/",
+            "b/",
+            "p/K1/",
+            "x/1",
+        ] {
+            assert!(ServerFilter::parse(filter).is_err(), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn login_is_parsed() {
+        let login =
+            Login::parse("user n0call pass 13023 vers aprs-tool 1.0 filter r/45/-122/50 t/p")
+                .unwrap();
+        assert_eq!(
+            login,
+            Login {
+                callsign: "N0CALL".to_string(),
+                verified: true,
+                software: "aprs-tool 1.0".to_string(),
+                filter: "r/45/-122/50 t/p".to_string(),
+            }
+        );
+        let login = Login::parse("user N0CALL-9 pass -1").unwrap();
+        assert!(!login.verified);
+        assert_eq!(login.software, "");
+        assert_eq!(login.filter, "");
+        assert!(!Login::parse("user N0CALL").unwrap().verified);
+    }
+
+    #[test]
+    fn invalid_logins_are_rejected() {
+        for line in [
+            "",
+            "login N0CALL",
+            "user",
+            "user N0_CALL",
+            "user N0CALL-1234",
+        ] {
+            assert!(Login::parse(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn q_construct_is_added() {
+        assert_eq!(
+            add_q_construct("N0CALL>APRS,TCPIP*:>status", "N0CALL", "T2LOCAL").as_deref(),
+            Some("N0CALL>APRS,TCPIP*,qAC,T2LOCAL:>status")
+        );
+        assert_eq!(
+            add_q_construct("K1ABC>APRS,WIDE1-1:>status", "N0CALL", "T2LOCAL").as_deref(),
+            Some("K1ABC>APRS,WIDE1-1,qAS,N0CALL:>status")
+        );
+    }
+
+    #[test]
+    fn q_construct_of_a_gate_is_kept() {
+        for line in [
+            "K1ABC>APRS,WIDE1-1,qAR,N0CALL:>x",
+            "K1ABC>APRS,qAr,N0CALL:>x",
+            "K1ABC>APRS,qAo,N0CALL:>x",
+            "K1ABC>APRS,TCPIP*,qAO,N0CALL:>x",
+        ] {
+            assert_eq!(
+                add_q_construct(line, "N0CALL", "T2LOCAL").as_deref(),
+                Some(line)
+            );
+        }
+    }
+
+    #[test]
+    fn spoofed_q_construct_is_replaced() {
+        assert_eq!(
+            add_q_construct("K1ABC>APRS,TCPIP*,qAC,T2OTHER:>x", "N0CALL", "T2LOCAL").as_deref(),
+            Some("K1ABC>APRS,TCPIP*,qAS,N0CALL:>x")
+        );
+        assert_eq!(
+            add_q_construct("N0CALL>APRS,qAS,K1ABC:>x", "N0CALL", "T2LOCAL").as_deref(),
+            Some("N0CALL>APRS,qAC,T2LOCAL:>x")
+        );
+    }
+
+    #[test]
+    fn packets_not_for_aprs_is_are_dropped() {
+        for line in [
+            "N0CALL>APRS,NOGATE:>x",
+            "N0CALL>APRS,RFONLY:>x",
+            "N0CALL>APRS,TCPXX*:>x",
+            "N0CALL>APRS",
+            ">APRS:>x",
+            "N0CALL>:>x",
+            "N0CALL>qAR,T2OTHER:>x",
+        ] {
+            assert_eq!(add_q_construct(line, "N0CALL", "T2LOCAL"), None, "{}", line);
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use crate::data::ParsedLine;
+use crate::dedup::DuplicatePath;
+use crate::metrics::METRICS;
+use anyhow::{anyhow, Result};
+use log::{debug, error, warn};
+use prometheus::{IntCounter, IntGauge};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Extension of segment files, which are named by their number
+const SEGMENT_EXTENSION: &str = "seg";
+
+/// File holding the segment and offset of the next batch to store
+const POSITION_FILE: &str = "position";
+
+/// Largest segment file; smaller spools use an eighth of their size
+const MAX_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Smallest segment file
+const MIN_SEGMENT_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpoolOptions {
+    /// Name of the sink in logs and metric labels
+    pub name: String,
+    pub directory: PathBuf,
+    /// Bytes kept on disk before the oldest batches are dropped
+    pub max_size: u64,
+}
+
+/// A batch kept on disk until the database takes it
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SpooledBatch {
+    pub lines: Vec<ParsedLine>,
+    pub paths: Vec<DuplicatePath>,
+}
+
+impl SpooledBatch {
+    /// Lines and paths in the batch
+    pub fn len(&self) -> usize {
+        self.lines.len() + self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A batch as appended, without copying the lines
+#[derive(Serialize)]
+struct BatchRef<'a> {
+    lines: &'a [ParsedLine],
+    paths: &'a [DuplicatePath],
+}
+
+/// Place in the spool, returned by `Spool::peek` for `Spool::commit`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SpoolPosition {
+    segment: u64,
+    offset: u64,
+}
+
+struct Segment {
+    number: u64,
+    size: u64,
+    /// Lines and paths not stored yet
+    items: u64,
+}
+
+/// Write-ahead spool of batches for a database which cannot be reached.
+///
+/// Batches are appended as JSON lines to numbered segment files and read
+/// back in order. The read position is saved after every stored batch, so
+/// what is on disk survives a restart, and whole segments are dropped,
+/// oldest first, when the spool outgrows its size limit.
+pub struct Spool {
+    options: SpoolOptions,
+    segment_size: u64,
+    /// Oldest first, the front one holding the read position
+    segments: VecDeque<Segment>,
+    /// Appends to the last segment
+    writer: Option<BufWriter<File>>,
+    read: SpoolPosition,
+    items_gauge: IntGauge,
+    bytes_gauge: IntGauge,
+    dropped: IntCounter,
+}
+
+fn segment_number(path: &Path) -> Option<u64> {
+    if path.extension()? != SEGMENT_EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Remove a partly written batch from the end of a segment and count the
+/// lines and paths from `offset` on
+fn scan_segment(path: &Path, offset: u64) -> Result<(u64, u64)> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut reader = BufReader::new(&file);
+    let mut complete = 0;
+    let mut items = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)? as u64;
+        if read == 0 || !line.ends_with('\n') {
+            break;
+        }
+        if complete >= offset {
+            match serde_json::from_str::<SpooledBatch>(&line) {
+                Ok(x) => items += x.len() as u64,
+                Err(e) => warn!("{}: unreadable batch: {}", path.display(), e),
+            }
+        }
+        complete += read;
+    }
+    if file.metadata()?.len() > complete {
+        warn!("{}: removing a partly written batch", path.display());
+        file.set_len(complete)?;
+    }
+    Ok((complete, items))
+}
+
+impl Spool {
+    /// Open the spool in `directory`, picking up batches left by an earlier run
+    pub fn open(options: SpoolOptions) -> Result<Spool> {
+        let directory = &options.directory;
+        std::fs::create_dir_all(directory)
+            .map_err(|e| anyhow!("{}: {}", directory.display(), e))?;
+        let mut numbers = Vec::new();
+        for entry in std::fs::read_dir(directory)? {
+            if let Some(x) = segment_number(&entry?.path()) {
+                numbers.push(x);
+            }
+        }
+        numbers.sort_unstable();
+        let position = directory.join(POSITION_FILE);
+        let mut read = match std::fs::read_to_string(&position) {
+            Ok(text) => {
+                let mut values = text.split_whitespace().map(|x| x.parse::<u64>());
+                match (values.next(), values.next()) {
+                    (Some(Ok(segment)), Some(Ok(offset))) => SpoolPosition { segment, offset },
+                    _ => return Err(anyhow!("{}: invalid position", position.display())),
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => SpoolPosition {
+                segment: numbers.first().copied().unwrap_or(0),
+                offset: 0,
+            },
+            Err(e) => return Err(anyhow!("{}: {}", position.display(), e)),
+        };
+        let mut segments = VecDeque::new();
+        for number in numbers {
+            let path = directory.join(format!("{}.{}", number, SEGMENT_EXTENSION));
+            if number < read.segment {
+                // Stored before the last run ended
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+            if segments.is_empty() && number > read.segment {
+                read = SpoolPosition {
+                    segment: number,
+                    offset: 0,
+                };
+            }
+            let offset = if number == read.segment {
+                read.offset
+            } else {
+                0
+            };
+            let (size, items) =
+                scan_segment(&path, offset).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            segments.push_back(Segment {
+                number,
+                size,
+                items,
+            });
+        }
+        let labels = [options.name.as_str()];
+        let spool = Spool {
+            segment_size: (options.max_size / 8).clamp(MIN_SEGMENT_SIZE, MAX_SEGMENT_SIZE),
+            segments,
+            writer: None,
+            read,
+            items_gauge: METRICS.spool_items.with_label_values(&labels),
+            bytes_gauge: METRICS.spool_bytes.with_label_values(&labels),
+            dropped: METRICS.spool_dropped.with_label_values(&labels),
+            options,
+        };
+        spool.update_metrics();
+        if !spool.is_empty() {
+            warn!(
+                "Spool of sink {} holds {} lines from an earlier run",
+                spool.options.name,
+                spool.items()
+            );
+        }
+        Ok(spool)
+    }
+
+    fn segment_path(&self, number: u64) -> PathBuf {
+        self.options
+            .directory
+            .join(format!("{}.{}", number, SEGMENT_EXTENSION))
+    }
+
+    /// Lines and paths waiting to be stored
+    pub fn items(&self) -> u64 {
+        self.segments.iter().map(|x| x.items).sum()
+    }
+
+    /// Bytes waiting to be stored
+    fn pending_bytes(&self) -> u64 {
+        let total: u64 = self.segments.iter().map(|x| x.size).sum();
+        match self.segments.front() {
+            Some(x) if x.number == self.read.segment => total - self.read.offset.min(x.size),
+            _ => total,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending_bytes() == 0
+    }
+
+    fn update_metrics(&self) {
+        self.items_gauge.set(self.items() as i64);
+        self.bytes_gauge.set(self.pending_bytes() as i64);
+    }
+
+    /// Add a batch after the ones already spooled
+    pub fn append(&mut self, lines: &[ParsedLine], paths: &[DuplicatePath]) -> Result<()> {
+        let mut record = serde_json::to_vec(&BatchRef { lines, paths })?;
+        record.push(b'\n');
+        let start_new = match self.segments.back() {
+            Some(x) => x.size >= self.segment_size,
+            None => true,
+        };
+        if start_new || self.writer.is_none() {
+            let number = match self.segments.back() {
+                Some(x) if start_new => x.number + 1,
+                Some(x) => x.number,
+                None => self.read.segment,
+            };
+            let path = self.segment_path(number);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            self.writer = Some(BufWriter::new(file));
+            if start_new {
+                debug!("[Spool] Started {}", path.display());
+                self.segments.push_back(Segment {
+                    number,
+                    size: 0,
+                    items: 0,
+                });
+            }
+        }
+        let writer = self.writer.as_mut().expect("open segment");
+        writer.write_all(&record)?;
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        let segment = self.segments.back_mut().expect("open segment");
+        segment.size += record.len() as u64;
+        segment.items += (lines.len() + paths.len()) as u64;
+        self.enforce_limit()?;
+        self.update_metrics();
+        Ok(())
+    }
+
+    /// Drop the oldest segments while the spool is over its size limit,
+    /// keeping the one being written
+    fn enforce_limit(&mut self) -> Result<()> {
+        while self.pending_bytes() > self.options.max_size && self.segments.len() > 1 {
+            let segment = self.segments.pop_front().expect("segment");
+            std::fs::remove_file(self.segment_path(segment.number))?;
+            self.dropped.inc_by(segment.items);
+            error!(
+                "Spool of sink {} is over {} bytes, dropped {} lines",
+                self.options.name, self.options.max_size, segment.items
+            );
+            self.read = SpoolPosition {
+                segment: self.segments[0].number,
+                offset: 0,
+            };
+            self.save_position()?;
+        }
+        Ok(())
+    }
+
+    /// Oldest batch not stored yet and the position after it
+    pub fn peek(&mut self) -> Result<Option<(SpooledBatch, SpoolPosition)>> {
+        loop {
+            let Some(segment) = self.segments.front() else {
+                return Ok(None);
+            };
+            if self.read.offset >= segment.size {
+                if self.segments.len() == 1 {
+                    return Ok(None);
+                }
+                self.finish_segment()?;
+                continue;
+            }
+            let path = self.segment_path(segment.number);
+            let mut file = File::open(&path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            file.seek(SeekFrom::Start(self.read.offset))?;
+            let mut line = String::new();
+            let read = BufReader::new(file).read_line(&mut line)? as u64;
+            let next = SpoolPosition {
+                segment: segment.number,
+                offset: self.read.offset + read,
+            };
+            if read == 0 || !line.ends_with('\n') {
+                return Err(anyhow!("{}: ends before its recorded size", path.display()));
+            }
+            match serde_json::from_str(&line) {
+                Ok(batch) => return Ok(Some((batch, next))),
+                Err(e) => {
+                    error!("{}: skipping unreadable batch: {}", path.display(), e);
+                    self.read = next;
+                    self.save_position()?;
+                }
+            }
+        }
+    }
+
+    /// Mark everything up to `position` as stored, `items` being the lines
+    /// and paths of the batches before it
+    pub fn commit(&mut self, position: SpoolPosition, items: usize) -> Result<()> {
+        if position < self.read {
+            // The batch was dropped from a full spool while it was being stored
+            return Ok(());
+        }
+        if let Some(segment) = self.segments.front_mut() {
+            if segment.number == position.segment {
+                segment.items = segment.items.saturating_sub(items as u64);
+            }
+        }
+        self.read = position;
+        let done = self
+            .segments
+            .front()
+            .is_some_and(|x| x.number == position.segment && position.offset >= x.size);
+        if done && self.segments.len() == 1 {
+            // Everything was stored, so the spool starts over empty
+            let number = self.segments[0].number;
+            self.writer = None;
+            self.segments.clear();
+            std::fs::remove_file(self.segment_path(number))?;
+            self.read = SpoolPosition {
+                segment: number + 1,
+                offset: 0,
+            };
+        } else if done {
+            self.finish_segment()?;
+        }
+        self.save_position()?;
+        self.update_metrics();
+        Ok(())
+    }
+
+    /// Delete the fully stored front segment and read on in the next one
+    fn finish_segment(&mut self) -> Result<()> {
+        let segment = self.segments.pop_front().expect("segment");
+        std::fs::remove_file(self.segment_path(segment.number))?;
+        self.read = SpoolPosition {
+            segment: self
+                .segments
+                .front()
+                .map_or(segment.number + 1, |x| x.number),
+            offset: 0,
+        };
+        self.save_position()
+    }
+
+    fn save_position(&self) -> Result<()> {
+        let path = self.options.directory.join(POSITION_FILE);
+        let temporary = path.with_extension("tmp");
+        std::fs::write(
+            &temporary,
+            format!("{} {}\n", self.read.segment, self.read.offset),
+        )?;
+        std::fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::parse_line_or_unknown;
+
+    fn options(max_size: u64) -> SpoolOptions {
+        SpoolOptions {
+            name: "test".to_string(),
+            directory: std::env::temp_dir().join(format!("k0hax-spool-{}", uuid::Uuid::new_v4())),
+            max_size,
+        }
+    }
+
+    fn lines(tag: &str, count: usize) -> Vec<ParsedLine> {
+        (0..count)
+            .map(|i| parse_line_or_unknown(&format!("N0CALL>APRS:>{} {}", tag, i)))
+            .collect()
+    }
+
+    /// Contents of lines, as ids and receive times differ on every call of `lines`
+    fn data(lines: &[ParsedLine]) -> Vec<&crate::data::ParsedAprsData> {
+        lines.iter().map(|x| &x.data).collect()
+    }
+
+    fn segments(directory: &Path) -> Vec<u64> {
+        let mut numbers: Vec<u64> = std::fs::read_dir(directory)
+            .unwrap()
+            .filter_map(|x| segment_number(&x.unwrap().path()))
+            .collect();
+        numbers.sort_unstable();
+        numbers
+    }
+
+    #[test]
+    fn batches_are_read_back_in_order() {
+        let options = options(1024 * 1024);
+        let directory = options.directory.clone();
+        let mut spool = Spool::open(options).unwrap();
+        assert!(spool.is_empty());
+        let batches = [lines("a", 2), lines("b", 1), lines("c", 3)];
+        for batch in &batches {
+            spool.append(batch, &[]).unwrap();
+        }
+        assert_eq!(spool.items(), 6);
+        for batch in &batches {
+            let (spooled, position) = spool.peek().unwrap().unwrap();
+            assert_eq!(&spooled.lines, batch);
+            spool.commit(position, spooled.len()).unwrap();
+        }
+        assert!(spool.peek().unwrap().is_none());
+        assert!(spool.is_empty());
+        assert_eq!(spool.items(), 0);
+        assert!(segments(&directory).is_empty());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn saved_position_is_restored() {
+        let options = options(1024 * 1024);
+        let directory = options.directory.clone();
+        let mut spool = Spool::open(options.clone()).unwrap();
+        for tag in ["a", "b", "c"] {
+            spool.append(&lines(tag, 1), &[]).unwrap();
+        }
+        let (_, position) = spool.peek().unwrap().unwrap();
+        spool.commit(position, 1).unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(options).unwrap();
+        assert_eq!(spool.items(), 2);
+        let (batch, _) = spool.peek().unwrap().unwrap();
+        assert_eq!(data(&batch.lines), data(&lines("b", 1)));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn batch_not_committed_before_a_crash_is_stored_again() {
+        let options = options(1024 * 1024);
+        let directory = options.directory.clone();
+        let mut spool = Spool::open(options.clone()).unwrap();
+        spool.append(&lines("a", 1), &[]).unwrap();
+        spool.append(&lines("b", 1), &[]).unwrap();
+        // Taken for storing, but the process ends before the commit
+        spool.peek().unwrap().unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(options).unwrap();
+        assert_eq!(spool.items(), 2);
+        let (batch, _) = spool.peek().unwrap().unwrap();
+        assert_eq!(data(&batch.lines), data(&lines("a", 1)));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn partly_written_batch_is_removed_on_open() {
+        let options = options(1024 * 1024);
+        let directory = options.directory.clone();
+        let mut spool = Spool::open(options.clone()).unwrap();
+        spool.append(&lines("a", 1), &[]).unwrap();
+        drop(spool);
+        let path = directory.join(format!("0.{}", SEGMENT_EXTENSION));
+        let complete = std::fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"lines":[{"id":"#).unwrap();
+        drop(file);
+
+        let mut spool = Spool::open(options).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), complete);
+        assert_eq!(spool.items(), 1);
+        // Appends continue after the last complete batch
+        spool.append(&lines("b", 1), &[]).unwrap();
+        for tag in ["a", "b"] {
+            let (batch, position) = spool.peek().unwrap().unwrap();
+            assert_eq!(data(&batch.lines), data(&lines(tag, 1)));
+            spool.commit(position, batch.len()).unwrap();
+        }
+        assert!(spool.is_empty());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn oldest_segments_are_dropped_over_the_limit() {
+        // Each batch fills a segment of the smallest size on its own
+        let options = options(MIN_SEGMENT_SIZE * 3 / 2);
+        let directory = options.directory.clone();
+        let mut spool = Spool::open(options).unwrap();
+        let first = lines("a", 500);
+        spool.append(&first, &[]).unwrap();
+        assert!(spool.segments[0].size >= MIN_SEGMENT_SIZE);
+        let (batch, position) = spool.peek().unwrap().unwrap();
+        assert_eq!(data(&batch.lines), data(&first));
+
+        spool.append(&lines("b", 500), &[]).unwrap();
+        assert_eq!(segments(&directory), vec![1]);
+        assert_eq!(spool.items(), 500);
+        // The batch being stored was dropped meanwhile, so its commit is ignored
+        spool.commit(position, batch.len()).unwrap();
+        assert_eq!(spool.items(), 500);
+        let (batch, _) = spool.peek().unwrap().unwrap();
+        assert_eq!(data(&batch.lines), data(&lines("b", 500)));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
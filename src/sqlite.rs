@@ -1,10 +1,55 @@
 use anyhow::{anyhow, Result};
 use chrono::prelude::*;
 use log::debug;
-use rusqlite::Connection;
+use rusqlite::{Connection, Transaction};
 use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
+use crate::data::ParsedLine;
+use crate::export::{ExportFilter, PositionRecord};
+use crate::migrations::{self, Migration};
+use crate::query::{
+    message_line, mic_e_line, newest_lines, position_line, MessageQuery, PositionQuery,
+};
+use crate::reprocess::{reprocess_line, ReprocessStats, REPROCESS_PAGE_SIZE};
+use crate::retention::{
+    self, Downsample, PruneStats, RetentionPolicy, ORPHAN_PATH_GRACE, PRUNE_BATCH_SIZE,
+};
+use crate::stations::{StationColumns, StationState, StationUpdate};
+use crate::track::Fix;
+
+/// A Unix time in the format of the stored times, which compares as text
+fn sqlite_time(time: i64) -> String {
+    DateTime::from_timestamp(time, 0)
+        .unwrap_or(DateTime::<Utc>::MIN_UTC)
+        .format("%+")
+        .to_string()
+}
+
+/// Columns read back into a `StationState`, see `StationColumns`
+const STATION_COLUMNS: &str = "callsign, first_seen, last_heard, last_via, last_position_time, latitude, longitude, symbol_table, symbol_code, comment, device, position_count, message_count, status_count, mic_e_count, unknown_count";
+
+fn station_columns(row: &rusqlite::Row) -> rusqlite::Result<StationColumns> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+        row.get(6)?,
+        row.get(7)?,
+        row.get(8)?,
+        row.get(9)?,
+        row.get(10)?,
+        row.get(11)?,
+        row.get(12)?,
+        row.get(13)?,
+        row.get(14)?,
+        row.get(15)?,
+    ))
+}
+
 #[derive(Clone)]
 pub struct SqliteDb {
     conn: Arc<Mutex<Connection>>,
@@ -13,23 +58,49 @@ pub struct SqliteDb {
 impl SqliteDb {
     pub fn new(path: &str) -> Self {
         let conn = Connection::open(path).unwrap();
+        // Foreign keys are off by default and have to be enabled per connection
+        conn.pragma_update(None, "foreign_keys", "ON").unwrap();
         debug!("[SqliteDb::new] Connection opened.");
         SqliteDb {
             conn: Arc::new(Mutex::new(conn)),
         }
     }
 
-    pub fn insert_aprs_line(&self, data: &libk0hax_aprs::data::ParsedLine) -> Result<()> {
-        let record_uuid = Uuid::new_v4();
+    pub fn insert_aprs_line(&self, data: &crate::data::ParsedLine) -> Result<()> {
+        let conn_handle = Arc::clone(&self.conn);
+        let mut conn = conn_handle.lock().unwrap();
+        let tx = conn.transaction()?;
+        Self::insert_line(&tx, data)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Record another path which delivered an already stored packet
+    pub fn insert_path(&self, path: &crate::dedup::DuplicatePath) -> Result<()> {
+        let conn_handle = Arc::clone(&self.conn);
+        let conn = conn_handle.lock().unwrap();
+        debug!("[SqliteDb::insert_path] [{}]: {:?}", path.id, &path.via);
+        let statement_text = "INSERT INTO paths (id, via, received_at) VALUES (?1, ?2, ?3)";
+        let mut statement = conn.prepare_cached(statement_text)?;
+        let _ = statement.execute((
+            path.id.hyphenated().to_string(),
+            path.via.join(", "),
+            path.received_at.format("%+").to_string(),
+        ))?;
+        Ok(())
+    }
+
+    /// Insert a line and its station inside an open transaction
+    fn insert_line(conn: &Transaction, data: &crate::data::ParsedLine) -> Result<()> {
+        let record_uuid = data.id;
         debug!(
             "[SqliteDb::insert_aprs_line] [{}]: {:?}",
             record_uuid.hyphenated().to_string(),
             &data
         );
-        let utc_now: DateTime<Utc> = Utc::now();
-        let parsed_time: String = utc_now.format("%+").to_string();
+        let parsed_time: String = data.received_at.format("%+").to_string();
 
-        let from: String = data.from.clone();
+        let from: Option<&str> = data.source();
         let via: String = data
             .via
             .clone()
@@ -38,11 +109,28 @@ impl SqliteDb {
             .collect::<String>();
         let via: String = via.trim_end_matches(", ").to_string();
 
-        let conn_handle = Arc::clone(&self.conn);
-        let conn = conn_handle.lock().unwrap();
+        let type_info = data.data.type_name();
+        debug!("[SqliteDb::insert_aprs_line] Data Type: {:?}", &type_info);
+
+        for update in StationUpdate::from_lines(std::slice::from_ref(data)) {
+            Self::upsert_station(conn, &update)?;
+        }
+
+        {
+            let statement_text =
+                "INSERT INTO main_data (id, `from`, via, type, `parsed_time`) VALUES (?1, ?2, ?3, ?4, ?5)";
+            let mut statement = conn.prepare_cached(statement_text)?;
+            let _ = statement.execute((
+                record_uuid.hyphenated().to_string(),
+                from,
+                via,
+                type_info,
+                parsed_time.clone(),
+            ))?;
+        }
 
-        let type_info: u8 = match &data.data {
-            libk0hax_aprs::data::ParsedAprsData::Position(x) => {
+        match &data.data {
+            crate::data::ParsedAprsData::Position(x) => {
                 let statement_text = "INSERT INTO `position` (id, `to`, timestamp, messaging_supported, latitude, longitude, precision, symbol_table, symbol_code, comment, cst) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)";
                 let mut statement = conn.prepare_cached(statement_text)?;
                 match &x.timestamp {
@@ -77,9 +165,8 @@ impl SqliteDb {
                         ))?;
                     }
                 }
-                2
             }
-            libk0hax_aprs::data::ParsedAprsData::Message(x) => {
+            crate::data::ParsedAprsData::Message(x) => {
                 let statement_text = "INSERT INTO `messages` (`id`, `to`, `addressee`, `text`, `msg_id`) VALUES (?1, ?2, ?3, ?4, ?5)";
                 let mut statement = conn.prepare_cached(statement_text)?;
                 match &x.id {
@@ -102,9 +189,8 @@ impl SqliteDb {
                         ))?;
                     }
                 }
-                1
             }
-            libk0hax_aprs::data::ParsedAprsData::Status(x) => {
+            crate::data::ParsedAprsData::Status(x) => {
                 let statement_text = "INSERT INTO `status` (`id`, `to`, `timestamp`, `comment`) VALUES (?1, ?2, ?3, ?4)";
                 let mut statement = conn.prepare_cached(statement_text)?;
                 match &x.timestamp {
@@ -125,9 +211,8 @@ impl SqliteDb {
                         ))?;
                     }
                 }
-                3
             }
-            libk0hax_aprs::data::ParsedAprsData::MicE(x) => {
+            crate::data::ParsedAprsData::MicE(x) => {
                 let statement_text = "INSERT INTO `MicE` (`id`, `latitude`, `longitude`, `precision`, `message`, `speed`, `course`, `symbol_table`, `symbol_code`, `comment`, `current`) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)";
                 let mut statement = conn.prepare_cached(statement_text)?;
                 let _ = statement.execute((
@@ -143,36 +228,558 @@ impl SqliteDb {
                     x.comment.clone(),
                     x.current,
                 ))?;
-                4
             }
-            libk0hax_aprs::data::ParsedAprsData::Unknown(_x) => {
-                return Err(anyhow!("Unknown data type").into())
+            crate::data::ParsedAprsData::Unknown(x) => {
+                let statement_text =
+                    "INSERT INTO `unknown` (`id`, `data`, `error`) VALUES (?1, ?2, ?3)";
+                let mut statement = conn.prepare_cached(statement_text)?;
+                let _ = statement.execute((
+                    record_uuid.hyphenated().to_string(),
+                    x.raw.clone(),
+                    x.error.clone(),
+                ))?;
             }
         };
-        debug!("[SqliteDb::insert_aprs_line] Data Type: {:?}", &type_info);
+        Ok(())
+    }
 
-        {
+    /// Merge a station update into the `stations` table
+    fn upsert_station(conn: &Transaction, update: &StationUpdate) -> Result<()> {
+        let statement_text = "INSERT INTO stations (callsign, first_seen, last_heard, last_via, last_position_time, latitude, longitude, symbol_table, symbol_code, comment, device, position_count, message_count, status_count, mic_e_count, unknown_count)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)
+            ON CONFLICT (callsign) DO UPDATE SET
+                first_seen = min(first_seen, excluded.first_seen),
+                last_heard = max(last_heard, excluded.last_heard),
+                last_via = CASE WHEN excluded.last_heard >= last_heard THEN excluded.last_via ELSE last_via END,
+                device = CASE WHEN excluded.last_heard >= last_heard THEN coalesce(excluded.device, device) ELSE coalesce(device, excluded.device) END,
+                last_position_time = CASE WHEN excluded.last_position_time >= coalesce(last_position_time, '') THEN excluded.last_position_time ELSE last_position_time END,
+                latitude = CASE WHEN excluded.last_position_time >= coalesce(last_position_time, '') THEN excluded.latitude ELSE latitude END,
+                longitude = CASE WHEN excluded.last_position_time >= coalesce(last_position_time, '') THEN excluded.longitude ELSE longitude END,
+                symbol_table = CASE WHEN excluded.last_position_time >= coalesce(last_position_time, '') THEN excluded.symbol_table ELSE symbol_table END,
+                symbol_code = CASE WHEN excluded.last_position_time >= coalesce(last_position_time, '') THEN excluded.symbol_code ELSE symbol_code END,
+                comment = CASE WHEN excluded.last_position_time >= coalesce(last_position_time, '') THEN excluded.comment ELSE comment END,
+                position_count = position_count + excluded.position_count,
+                message_count = message_count + excluded.message_count,
+                status_count = status_count + excluded.status_count,
+                mic_e_count = mic_e_count + excluded.mic_e_count,
+                unknown_count = unknown_count + excluded.unknown_count";
+        let mut statement = conn.prepare_cached(statement_text)?;
+        let position = update.last_position.as_ref();
+        let _ = statement.execute(rusqlite::params![
+            update.callsign,
+            update.first_seen.format("%+").to_string(),
+            update.last_heard.format("%+").to_string(),
+            update.last_via.join(", "),
+            position.map(|x| x.time.format("%+").to_string()),
+            position.map(|x| x.latitude),
+            position.map(|x| x.longitude),
+            position.map(|x| x.symbol_table.to_string()),
+            position.map(|x| x.symbol_code.to_string()),
+            position.map(|x| x.comment.clone()),
+            update.device,
+            update.packets.position as i64,
+            update.packets.message as i64,
+            update.packets.status as i64,
+            update.packets.mic_e as i64,
+            update.packets.unknown as i64,
+        ])?;
+        Ok(())
+    }
+
+    /// Current state of one station
+    pub fn station(&self, callsign: &str) -> Result<Option<StationState>> {
+        let statement_text = format!(
+            "SELECT {} FROM stations WHERE callsign = ?1",
+            STATION_COLUMNS
+        );
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare_cached(&statement_text)?;
+        let mut rows = statement.query_map([callsign], station_columns)?;
+        rows.next()
+            .map(|x| StationState::from_columns(x?))
+            .transpose()
+    }
+
+    /// Up to `limit` stations heard since a time, most recently heard first
+    pub fn stations_heard_since(
+        &self,
+        since: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<StationState>> {
+        let statement_text = format!(
+            "SELECT {} FROM stations WHERE last_heard >= ?1 ORDER BY last_heard DESC LIMIT ?2",
+            STATION_COLUMNS
+        );
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare_cached(&statement_text)?;
+        let rows = statement.query_map((since.format("%+").to_string(), limit), station_columns)?;
+        rows.map(|x| StationState::from_columns(x?)).collect()
+    }
+
+    /// Positions of a station received in `[start, end)`, oldest first
+    pub fn fixes(
+        &self,
+        callsign: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Fix>> {
+        let statement_text = "SELECT m.parsed_time, x.latitude, x.longitude FROM main_data m JOIN position x ON x.id = m.id WHERE m.`from` = ?1 AND m.parsed_time >= ?2 AND m.parsed_time < ?3
+            UNION ALL
+            SELECT m.parsed_time, x.latitude, x.longitude FROM main_data m JOIN MicE x ON x.id = m.id WHERE m.`from` = ?1 AND m.parsed_time >= ?2 AND m.parsed_time < ?3
+            ORDER BY 1";
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare_cached(statement_text)?;
+        let rows = statement.query_map(
+            (
+                callsign,
+                start.format("%+").to_string(),
+                end.format("%+").to_string(),
+            ),
+            |row| Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        rows.map(|x| {
+            let (time, latitude, longitude) = x?;
+            Ok(Fix {
+                time: DateTime::parse_from_rfc3339(&time)?.with_timezone(&Utc),
+                latitude,
+                longitude,
+            })
+        })
+        .collect()
+    }
+
+    /// Stored positions matching an export filter, ordered by station and
+    /// receive time
+    pub fn positions(&self, filter: &ExportFilter) -> Result<Vec<PositionRecord>> {
+        let statement_text = "SELECT m.`from`, m.parsed_time, x.latitude, x.longitude, x.symbol_table, x.symbol_code, x.comment FROM main_data m JOIN position x ON x.id = m.id
+                WHERE m.`from` LIKE ?1 ESCAPE '!' AND (?2 IS NULL OR m.parsed_time >= ?2) AND (?3 IS NULL OR m.parsed_time < ?3)
+                AND x.latitude BETWEEN ?4 AND ?5 AND x.longitude BETWEEN ?6 AND ?7
+            UNION ALL
+            SELECT m.`from`, m.parsed_time, x.latitude, x.longitude, x.symbol_table, x.symbol_code, x.comment FROM main_data m JOIN MicE x ON x.id = m.id
+                WHERE m.`from` LIKE ?1 ESCAPE '!' AND (?2 IS NULL OR m.parsed_time >= ?2) AND (?3 IS NULL OR m.parsed_time < ?3)
+                AND x.latitude BETWEEN ?4 AND ?5 AND x.longitude BETWEEN ?6 AND ?7
+            ORDER BY 1, 2";
+        let bounds = filter.bounds();
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare_cached(statement_text)?;
+        let rows = statement.query_map(
+            rusqlite::params![
+                filter.callsign_like(),
+                filter.start.map(|x| x.format("%+").to_string()),
+                filter.end.map(|x| x.format("%+").to_string()),
+                bounds.south,
+                bounds.north,
+                bounds.west,
+                bounds.east,
+            ],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                ))
+            },
+        )?;
+        rows.map(|x| PositionRecord::from_columns(x?)).collect()
+    }
+
+    /// Stored position and Mic-E lines matching a query, newest first
+    pub fn position_lines(&self, query: &PositionQuery) -> Result<Vec<ParsedLine>> {
+        let condition = format!(
+            "WHERE m.`from` LIKE ?1 ESCAPE '!' AND (?2 IS NULL OR m.parsed_time >= ?2) AND (?3 IS NULL OR m.parsed_time < ?3)
+                AND x.latitude BETWEEN ?4 AND ?5 AND x.longitude BETWEEN ?6 AND ?7{}
+                ORDER BY m.parsed_time DESC LIMIT ?8",
+            if query.weather { " AND x.symbol_code = '_'" } else { "" }
+        );
+        let filter = &query.filter;
+        let bounds = filter.bounds();
+        let params = rusqlite::params![
+            filter.callsign_like(),
+            filter.start.map(|x| x.format("%+").to_string()),
+            filter.end.map(|x| x.format("%+").to_string()),
+            bounds.south,
+            bounds.north,
+            bounds.west,
+            bounds.east,
+            query.limit,
+        ];
+        let conn = self.conn.lock().unwrap();
+        let statement_text = format!(
+            "SELECT m.id, m.parsed_time, m.`from`, m.via, x.`to`, x.timestamp, x.messaging_supported, x.latitude, x.longitude, x.precision, x.symbol_table, x.symbol_code, x.comment, x.cst
+                FROM main_data m JOIN position x ON x.id = m.id {}",
+            condition
+        );
+        let mut statement = conn.prepare_cached(&statement_text)?;
+        let positions = statement
+            .query_map(params, |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                    row.get(8)?,
+                    row.get(9)?,
+                    row.get(10)?,
+                    row.get(11)?,
+                    row.get(12)?,
+                    row.get(13)?,
+                ))
+            })?
+            .map(|x| position_line(x?))
+            .collect::<Result<Vec<_>>>()?;
+        let statement_text = format!(
+            "SELECT m.id, m.parsed_time, m.`from`, m.via, x.latitude, x.longitude, x.precision, x.message, x.speed, x.course, x.symbol_table, x.symbol_code, x.comment, x.current
+                FROM main_data m JOIN MicE x ON x.id = m.id {}",
+            condition
+        );
+        let mut statement = conn.prepare_cached(&statement_text)?;
+        let mic_es = statement
+            .query_map(params, |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                    row.get(8)?,
+                    row.get(9)?,
+                    row.get(10)?,
+                    row.get(11)?,
+                    row.get(12)?,
+                    row.get(13)?,
+                ))
+            })?
+            .map(|x| mic_e_line(x?))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(newest_lines(positions, mic_es, query.limit))
+    }
+
+    /// Stored messages matching a query, newest first
+    pub fn message_lines(&self, query: &MessageQuery) -> Result<Vec<ParsedLine>> {
+        // Messages without an id are stored with a 0 in place of the bytes
+        let statement_text = format!(
+            "SELECT m.id, m.parsed_time, m.`from`, m.via, x.`to`, x.addressee, x.text, CASE typeof(x.msg_id) WHEN 'integer' THEN NULL ELSE CAST(x.msg_id AS TEXT) END
+                FROM main_data m JOIN messages x ON x.id = m.id
+                WHERE (?1 IS NULL OR m.`from` = ?1 OR x.addressee = ?1) AND (?2 IS NULL OR m.parsed_time >= ?2) AND (?3 IS NULL OR m.parsed_time < ?3){}
+                ORDER BY m.parsed_time DESC LIMIT ?4",
+            if query.bulletins { " AND x.addressee LIKE 'BLN%'" } else { "" }
+        );
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare_cached(&statement_text)?;
+        let rows = statement.query_map(
+            rusqlite::params![
+                query.callsign,
+                query.start.map(|x| x.format("%+").to_string()),
+                query.end.map(|x| x.format("%+").to_string()),
+                query.limit,
+            ],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                ))
+            },
+        )?;
+        rows.map(|x| message_line(x?)).collect()
+    }
+
+    /// Re-run `parse_line` over stored `unknown` packets and move every line
+    /// which now decodes into its proper table, keeping its id and receive time.
+    pub fn reprocess(&self) -> Result<ReprocessStats> {
+        let conn_handle = Arc::clone(&self.conn);
+        let mut conn = conn_handle.lock().unwrap();
+        let mut stats = ReprocessStats::default();
+        let mut last_id = String::new();
+        loop {
+            let rows: Vec<(String, String, String)> = {
+                let statement_text = "SELECT u.id, u.data, m.parsed_time FROM unknown u JOIN main_data m ON m.id = u.id WHERE u.id > ?1 ORDER BY u.id LIMIT ?2";
+                let mut statement = conn.prepare_cached(statement_text)?;
+                let rows = statement.query_map((&last_id, REPROCESS_PAGE_SIZE), |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })?;
+                rows.collect::<Result<_, _>>()?
+            };
+            let Some((id, _, _)) = rows.last() else {
+                break;
+            };
+            last_id = id.clone();
+
+            let tx = conn.transaction()?;
+            for (id, raw, parsed_time) in rows {
+                stats.scanned += 1;
+                let received_at = DateTime::parse_from_rfc3339(&parsed_time)?.with_timezone(&Utc);
+                let Some(line) = reprocess_line(Uuid::parse_str(&id)?, &raw, received_at) else {
+                    continue;
+                };
+                tx.execute("DELETE FROM main_data WHERE id = ?1", [&id])?;
+                // The packet was counted as unknown when it was first stored
+                tx.execute(
+                    "UPDATE stations SET unknown_count = unknown_count - 1 WHERE callsign = ?1",
+                    [&line.from],
+                )?;
+                Self::insert_line(&tx, &line)?;
+                stats.decoded += 1;
+            }
+            tx.commit()?;
+        }
+        Ok(stats)
+    }
+
+    /// Remove rows past their retention period and thin out old positions.
+    ///
+    /// Rows are deleted in chunks of `PRUNE_BATCH_SIZE`, so the connection
+    /// is released to the ingest tasks between statements.
+    pub fn prune(&self, policy: &RetentionPolicy) -> Result<PruneStats> {
+        let mut stats = PruneStats::default();
+        for retention in &policy.retain {
+            let cutoff = retention::cutoff(retention.max_age)
+                .format("%+")
+                .to_string();
+            loop {
+                let conn = self.conn.lock().unwrap();
+                let deleted = if retention.table == "paths" {
+                    let statement_text = "DELETE FROM paths WHERE rowid IN (SELECT rowid FROM paths WHERE received_at < ?1 LIMIT ?2)";
+                    conn.execute(statement_text, (&cutoff, PRUNE_BATCH_SIZE))?
+                } else {
+                    let statement_text = "DELETE FROM main_data WHERE id IN (SELECT id FROM main_data WHERE type = ?1 AND parsed_time < ?2 LIMIT ?3)";
+                    conn.execute(
+                        statement_text,
+                        (&retention.table, &cutoff, PRUNE_BATCH_SIZE),
+                    )?
+                };
+                drop(conn);
+                debug!(
+                    "[SqliteDb::prune] {} expired from {}",
+                    deleted, retention.table
+                );
+                stats.expired += deleted as u64;
+                if (deleted as i64) < PRUNE_BATCH_SIZE {
+                    break;
+                }
+            }
+        }
+
+        if let Some(downsample) = &policy.downsample {
+            stats.downsampled += self.downsample(downsample)?;
+        }
+
+        // Paths of packets which were removed above
+        let cutoff = retention::cutoff(ORPHAN_PATH_GRACE)
+            .format("%+")
+            .to_string();
+        let statement_text = "DELETE FROM paths WHERE rowid IN (SELECT rowid FROM paths WHERE received_at < ?1 AND NOT EXISTS (SELECT 1 FROM main_data m WHERE m.id = paths.id) LIMIT ?2)";
+        loop {
+            let conn = self.conn.lock().unwrap();
+            let deleted = conn.execute(statement_text, (&cutoff, PRUNE_BATCH_SIZE))?;
+            drop(conn);
+            stats.expired += deleted as u64;
+            if (deleted as i64) < PRUNE_BATCH_SIZE {
+                break;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Keep one position per station and interval, ranking the positions one
+    /// window at a time from where the last run stopped
+    fn downsample(&self, downsample: &Downsample) -> Result<u64> {
+        let interval = downsample.interval_secs();
+        let until = downsample.until();
+        let mut start: i64 = {
+            let conn = self.conn.lock().unwrap();
             let statement_text =
-                "INSERT INTO main_data (id, `from`, via, type, `parsed_time`) VALUES (?1, ?2, ?3, ?4, ?5)";
+                "SELECT downsampled_until FROM downsample_state WHERE interval_seconds = ?1";
             let mut statement = conn.prepare_cached(statement_text)?;
-            let _ = statement.execute((
-                record_uuid.hyphenated().to_string(),
-                from,
-                via,
-                type_info,
-                parsed_time.clone(),
-            ))?;
+            let mut rows = statement.query_map([interval], |row| row.get(0))?;
+            rows.next().transpose()?.unwrap_or(0)
+        };
+
+        let mut downsampled = 0;
+        while start < until {
+            let conn = self.conn.lock().unwrap();
+            // Skip ahead to the next position, instead of stepping through
+            // gaps one window at a time
+            let statement_text =
+                "SELECT CAST(strftime('%s', MIN(parsed_time)) AS INTEGER) FROM main_data
+                WHERE type IN ('position', 'mic_e') AND parsed_time >= ?1 AND parsed_time < ?2";
+            let next: Option<i64> = conn.query_row(
+                statement_text,
+                (sqlite_time(start), sqlite_time(until)),
+                |row| row.get(0),
+            )?;
+            drop(conn);
+            let window_start = match next {
+                Some(next) => downsample.interval_start(next),
+                None => until,
+            };
+            let window_end = downsample.window_end(window_start, until);
+
+            let statement_text = "DELETE FROM main_data WHERE id IN (
+                SELECT id FROM (
+                    SELECT id, ROW_NUMBER() OVER (
+                        PARTITION BY `from`, CAST(strftime('%s', parsed_time) AS INTEGER) / ?3
+                        ORDER BY parsed_time, id
+                    ) AS rn
+                    FROM main_data WHERE type IN ('position', 'mic_e') AND parsed_time >= ?1 AND parsed_time < ?2
+                ) WHERE rn > 1 LIMIT ?4
+            )";
+            loop {
+                let conn = self.conn.lock().unwrap();
+                let deleted = conn.execute(
+                    statement_text,
+                    (
+                        sqlite_time(window_start),
+                        sqlite_time(window_end),
+                        interval,
+                        PRUNE_BATCH_SIZE,
+                    ),
+                )?;
+                drop(conn);
+                debug!("[SqliteDb::prune] {} downsampled", deleted);
+                downsampled += deleted as u64;
+                if (deleted as i64) < PRUNE_BATCH_SIZE {
+                    break;
+                }
+            }
+
+            let conn = self.conn.lock().unwrap();
+            let statement_text = "INSERT INTO downsample_state (interval_seconds, downsampled_until) VALUES (?1, ?2)
+                ON CONFLICT (interval_seconds) DO UPDATE SET downsampled_until = excluded.downsampled_until";
+            conn.execute(statement_text, (interval, window_end))?;
+            start = window_end;
         }
+        Ok(downsampled)
+    }
+
+    /// Refresh the query planner statistics and give free pages back to the
+    /// file system
+    pub fn optimize(&self) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute_batch("PRAGMA optimize; VACUUM;")?;
         Ok(())
     }
 
-    pub fn create_db(&self) -> Result<()> {
+    /// Current schema version, `EMPTY_SCHEMA` for a new database.
+    ///
+    /// Databases created before versioning was introduced have our tables but
+    /// no `schema_version` table, and are reported as version 1.
+    pub fn schema_version(&self) -> Result<i64> {
         let conn_handle = Arc::clone(&self.conn);
         let conn = conn_handle.lock().unwrap();
-        debug!("[SqliteDb::create_db] Got connection lock");
+        Self::schema_version_locked(&conn)
+    }
+
+    fn schema_version_locked(conn: &Connection) -> Result<i64> {
+        let table_exists = |name: &str| -> Result<bool> {
+            let count: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+                [name],
+                |row| row.get(0),
+            )?;
+            Ok(count > 0)
+        };
+        if table_exists("schema_version")? {
+            let version: Option<i64> =
+                conn.query_row("SELECT MAX(version) FROM schema_version", (), |row| {
+                    row.get(0)
+                })?;
+            Ok(version.unwrap_or(migrations::EMPTY_SCHEMA))
+        } else if table_exists("main_data")? {
+            Ok(1)
+        } else {
+            Ok(migrations::EMPTY_SCHEMA)
+        }
+    }
+
+    /// Apply every pending migration, each in its own transaction.
+    ///
+    /// Returns the versions which were applied.
+    pub fn migrate(&self) -> Result<Vec<i64>> {
+        let conn_handle = Arc::clone(&self.conn);
+        let mut conn = conn_handle.lock().unwrap();
+        let current = Self::schema_version_locked(&conn)?;
+        migrations::check_not_newer(current, MIGRATIONS)?;
+        debug!("[SqliteDb::migrate] Schema is at version {}", current);
 
-        // Create the message table
         conn.execute(
+            "CREATE TABLE IF NOT EXISTS schema_version (
+                version             INTEGER PRIMARY KEY,
+                description         TEXT NOT NULL,
+                applied_time        TEXT NOT NULL
+            )",
+            (), // empty list of parameters.
+        )?;
+        if current > migrations::EMPTY_SCHEMA {
+            // Record the implicit baseline of a pre-versioning database
+            for migration in MIGRATIONS.iter().filter(|x| x.version <= current) {
+                conn.execute(
+                    "INSERT OR IGNORE INTO schema_version (version, description, applied_time) VALUES (?1, ?2, ?3)",
+                    (migration.version, migration.description, Utc::now().format("%+").to_string()),
+                )?;
+            }
+        }
+
+        // Rebuilding a table must not cascade into the tables referencing it,
+        // and the pragma has no effect inside a transaction
+        conn.pragma_update(None, "foreign_keys", "OFF")?;
+        let applied = Self::apply_migrations(&mut conn, current);
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        applied
+    }
+
+    /// Apply each pending migration in its own transaction
+    fn apply_migrations(conn: &mut Connection, current: i64) -> Result<Vec<i64>> {
+        let mut applied = Vec::new();
+        for migration in migrations::pending(MIGRATIONS, current) {
+            let tx = conn.transaction()?;
+            for statement in migration.statements {
+                tx.execute_batch(statement)?;
+            }
+            if tx.prepare("PRAGMA foreign_key_check")?.exists(())? {
+                return Err(anyhow!(
+                    "Migration {} left rows violating a foreign key",
+                    migration.version
+                ));
+            }
+            tx.execute(
+                "INSERT INTO schema_version (version, description, applied_time) VALUES (?1, ?2, ?3)",
+                (migration.version, migration.description, Utc::now().format("%+").to_string()),
+            )?;
+            tx.commit()?;
+            debug!(
+                "[SqliteDb::migrate] Applied migration {}: {}",
+                migration.version, migration.description
+            );
+            applied.push(migration.version);
+        }
+        Ok(applied)
+    }
+}
+
+/// Schema migrations, applied in order of `version`
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Initial schema",
+        statements: &[
+            // Create the message table
             "CREATE TABLE messages (
                 `id`        TEXT PRIMARY KEY,
                 `to`        TEXT NOT NULL,
@@ -180,12 +787,7 @@ impl SqliteDb {
                 text      TEXT NOT NULL,
                 msg_id    INTEGER
             )",
-            (), // empty list of parameters.
-        )?;
-        debug!("[SqliteDb::create_db] Created `messages` table");
-
-        // Create the position table
-        conn.execute(
+            // Create the position table
             "CREATE TABLE position (
                 id                  TEXT PRIMARY KEY,
                 `to`                  TEXT NOT NULL,
@@ -199,24 +801,14 @@ impl SqliteDb {
                 comment             TEXT NOT NULL,
                 cst                 TEXT NOT NULL
             )",
-            (), // empty list of parameters.
-        )?;
-        debug!("[SqliteDb::create_db] Created `position` table");
-
-        // Create the Status table
-        conn.execute(
+            // Create the Status table
             "CREATE TABLE status (
                 id                  TEXT PRIMARY KEY,
                 `to`                  TEXT NOT NULL,
                 timestamp           TEXT,
                 comment             TEXT NOT NULL
             )",
-            (), // empty list of parameters.
-        )?;
-        debug!("[SqliteDb::create_db] Created `status` table");
-
-        // Create the MicE table
-        conn.execute(
+            // Create the MicE table
             "CREATE TABLE MicE (
                 id                  TEXT PRIMARY KEY,
                 latitude            REAL NOT NULL,
@@ -230,37 +822,13 @@ impl SqliteDb {
                 comment             TEXT NOT NULL,
                 current             INTEGER NOT NULL
             )",
-            (), // empty list of parameters.
-        )?;
-        debug!("[SqliteDb::create_db] Created `MicE` table");
-
-        // Create the Type Lookup table
-        conn.execute(
+            // Create and populate the Type Lookup table
             "CREATE TABLE `type` (
                 id                  TEXT PRIMARY KEY,
                 `table`             TEXT NOT NULL
             )",
-            (), // empty list of parameters.
-        )?;
-        debug!("[SqliteDb::create_db] Created `type` table");
-
-        // Populate the Type Lookup table
-        {
-            let tables = vec![(1, "messages"), (2, "position"), (3, "status"), (4, "MicE")];
-            debug!(
-                "[SqliteDb::create_db] prepared records to insert into `type` table: {:?}",
-                &tables
-            );
-            let mut type_statement =
-                conn.prepare_cached("INSERT INTO `type` (id, `table`) VALUES (?1, ?2)")?;
-            for table in tables {
-                type_statement.execute(table)?;
-            }
-            debug!("[SqliteDb::create_db] Completed building `type` table");
-        }
-
-        // Create the main lookup table
-        conn.execute(
+            "INSERT INTO `type` (id, `table`) VALUES (1, 'messages'), (2, 'position'), (3, 'status'), (4, 'MicE')",
+            // Create the main lookup table
             "CREATE TABLE main_data (
                 id                  TEXT PRIMARY KEY,
                 `from`              TEXT NOT NULL,
@@ -268,10 +836,353 @@ impl SqliteDb {
                 type                INTEGER NOT NULL,
                 `parsed_time`       TEXT
             )",
-            (), // empty list of parameters.
-        )?;
-        debug!("[SqliteDb::create_db] Created `main_data` table");
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "Foreign keys, indexes, packet type enum and stations table",
+        statements: &[
+            "CREATE TABLE stations (
+                callsign            TEXT PRIMARY KEY,
+                first_seen          TEXT NOT NULL
+            )",
+            "INSERT INTO stations (callsign, first_seen)
+                SELECT `from`, MIN(COALESCE(parsed_time, '1970-01-01T00:00:00+00:00'))
+                FROM main_data GROUP BY `from`",
+            // SQLite can not add constraints to existing tables, so every table
+            // is rebuilt; rows without a `main_data` row are dropped on the way
+            "CREATE TABLE main_data_v2 (
+                id                  TEXT PRIMARY KEY,
+                `from`              TEXT NOT NULL REFERENCES stations (callsign),
+                via                 TEXT NOT NULL,
+                type                TEXT NOT NULL CHECK (type IN ('message', 'position', 'status', 'mic_e', 'unknown')),
+                `parsed_time`       TEXT NOT NULL
+            )",
+            "INSERT INTO main_data_v2 (id, `from`, via, type, `parsed_time`)
+                SELECT id, `from`, via,
+                    CASE type WHEN 1 THEN 'message' WHEN 2 THEN 'position' WHEN 3 THEN 'status' WHEN 4 THEN 'mic_e' ELSE 'unknown' END,
+                    COALESCE(parsed_time, '1970-01-01T00:00:00+00:00')
+                FROM main_data",
+            "DROP TABLE main_data",
+            "ALTER TABLE main_data_v2 RENAME TO main_data",
+            "CREATE INDEX main_data_from_time_idx ON main_data (`from`, `parsed_time`)",
+            "CREATE INDEX main_data_time_idx ON main_data (`parsed_time`)",
+            "CREATE INDEX main_data_type_time_idx ON main_data (type, `parsed_time`)",
+            "CREATE TABLE messages_v2 (
+                `id`        TEXT PRIMARY KEY REFERENCES main_data (id) ON DELETE CASCADE,
+                `to`        TEXT NOT NULL,
+                addressee TEXT NOT NULL,
+                text      TEXT NOT NULL,
+                msg_id    INTEGER
+            )",
+            "INSERT INTO messages_v2 SELECT * FROM messages WHERE id IN (SELECT id FROM main_data)",
+            "DROP TABLE messages",
+            "ALTER TABLE messages_v2 RENAME TO messages",
+            "CREATE INDEX messages_addressee_idx ON messages (addressee)",
+            "CREATE TABLE position_v2 (
+                id                  TEXT PRIMARY KEY REFERENCES main_data (id) ON DELETE CASCADE,
+                `to`                  TEXT NOT NULL,
+                timestamp           TEXT,
+                messaging_supported INTEGER NOT NULL,
+                latitude            REAL NOT NULL,
+                longitude           REAL NOT NULL,
+                precision           REAL NOT NULL,
+                symbol_table        TEXT NOT NULL,
+                symbol_code         TEXT NOT NULL,
+                comment             TEXT NOT NULL,
+                cst                 TEXT NOT NULL
+            )",
+            "INSERT INTO position_v2 SELECT * FROM position WHERE id IN (SELECT id FROM main_data)",
+            "DROP TABLE position",
+            "ALTER TABLE position_v2 RENAME TO position",
+            "CREATE INDEX position_lat_lon_idx ON position (latitude, longitude)",
+            "CREATE TABLE status_v2 (
+                id                  TEXT PRIMARY KEY REFERENCES main_data (id) ON DELETE CASCADE,
+                `to`                  TEXT NOT NULL,
+                timestamp           TEXT,
+                comment             TEXT NOT NULL
+            )",
+            "INSERT INTO status_v2 SELECT * FROM status WHERE id IN (SELECT id FROM main_data)",
+            "DROP TABLE status",
+            "ALTER TABLE status_v2 RENAME TO status",
+            "CREATE TABLE MicE_v2 (
+                id                  TEXT PRIMARY KEY REFERENCES main_data (id) ON DELETE CASCADE,
+                latitude            REAL NOT NULL,
+                longitude           REAL NOT NULL,
+                precision           REAL NOT NULL,
+                message             TEXT NOT NULL,
+                speed               INTEGER NOT NULL,
+                course              INTEGER NOT NULL,
+                symbol_table        TEXT NOT NULL,
+                symbol_code         TEXT NOT NULL,
+                comment             TEXT NOT NULL,
+                current             INTEGER NOT NULL
+            )",
+            "INSERT INTO MicE_v2 SELECT * FROM MicE WHERE id IN (SELECT id FROM main_data)",
+            "DROP TABLE MicE",
+            "ALTER TABLE MicE_v2 RENAME TO MicE",
+            "CREATE INDEX MicE_lat_lon_idx ON MicE (latitude, longitude)",
+            // The packet type is now stored by name
+            "DROP TABLE `type`",
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "Store undecoded packets",
+        statements: &["CREATE TABLE `unknown` (
+                id                  TEXT PRIMARY KEY REFERENCES main_data (id) ON DELETE CASCADE,
+                data                TEXT NOT NULL,
+                error               TEXT
+            )"],
+    },
+    Migration {
+        version: 4,
+        description: "Paths of suppressed duplicate packets",
+        statements: &[
+            // No foreign key: the first copy may still be queued in another
+            // worker when its duplicates are written
+            "CREATE TABLE `paths` (
+                id                  TEXT NOT NULL,
+                via                 TEXT NOT NULL,
+                received_at         TEXT NOT NULL
+            )",
+            "CREATE INDEX paths_id_idx ON paths (id)",
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "Station state: last position, path, device and packet counts",
+        statements: &[
+            "ALTER TABLE stations ADD COLUMN last_heard TEXT NOT NULL DEFAULT ''",
+            "ALTER TABLE stations ADD COLUMN last_via TEXT NOT NULL DEFAULT ''",
+            "ALTER TABLE stations ADD COLUMN last_position_time TEXT",
+            "ALTER TABLE stations ADD COLUMN latitude REAL",
+            "ALTER TABLE stations ADD COLUMN longitude REAL",
+            "ALTER TABLE stations ADD COLUMN symbol_table TEXT",
+            "ALTER TABLE stations ADD COLUMN symbol_code TEXT",
+            "ALTER TABLE stations ADD COLUMN comment TEXT",
+            "ALTER TABLE stations ADD COLUMN device TEXT",
+            "ALTER TABLE stations ADD COLUMN position_count INTEGER NOT NULL DEFAULT 0",
+            "ALTER TABLE stations ADD COLUMN message_count INTEGER NOT NULL DEFAULT 0",
+            "ALTER TABLE stations ADD COLUMN status_count INTEGER NOT NULL DEFAULT 0",
+            "ALTER TABLE stations ADD COLUMN mic_e_count INTEGER NOT NULL DEFAULT 0",
+            "ALTER TABLE stations ADD COLUMN unknown_count INTEGER NOT NULL DEFAULT 0",
+            // Backfill from the packets stored so far
+            "UPDATE stations SET
+                last_heard = c.last_heard,
+                position_count = c.position_count,
+                message_count = c.message_count,
+                status_count = c.status_count,
+                mic_e_count = c.mic_e_count,
+                unknown_count = c.unknown_count
+            FROM (
+                SELECT `from`, max(parsed_time) AS last_heard,
+                    sum(type = 'position') AS position_count,
+                    sum(type = 'message') AS message_count,
+                    sum(type = 'status') AS status_count,
+                    sum(type = 'mic_e') AS mic_e_count,
+                    sum(type = 'unknown') AS unknown_count
+                FROM main_data GROUP BY `from`
+            ) c WHERE c.`from` = stations.callsign",
+            "UPDATE stations SET last_heard = first_seen WHERE last_heard = ''",
+            "UPDATE stations SET last_via = l.via
+            FROM (
+                SELECT `from`, via, ROW_NUMBER() OVER (PARTITION BY `from` ORDER BY parsed_time DESC) AS rn
+                FROM main_data
+            ) l WHERE l.rn = 1 AND l.`from` = stations.callsign",
+            "UPDATE stations SET
+                last_position_time = p.parsed_time,
+                latitude = p.latitude,
+                longitude = p.longitude,
+                symbol_table = p.symbol_table,
+                symbol_code = p.symbol_code,
+                comment = p.comment
+            FROM (
+                SELECT *, ROW_NUMBER() OVER (PARTITION BY `from` ORDER BY parsed_time DESC) AS rn FROM (
+                    SELECT m.`from`, m.parsed_time, x.latitude, x.longitude, x.symbol_table, x.symbol_code, x.comment
+                        FROM main_data m JOIN position x ON x.id = m.id
+                    UNION ALL
+                    SELECT m.`from`, m.parsed_time, x.latitude, x.longitude, x.symbol_table, x.symbol_code, x.comment
+                        FROM main_data m JOIN MicE x ON x.id = m.id
+                )
+            ) p WHERE p.rn = 1 AND p.`from` = stations.callsign",
+            "UPDATE stations SET device = d.`to`
+            FROM (
+                SELECT *, ROW_NUMBER() OVER (PARTITION BY `from` ORDER BY parsed_time DESC) AS rn FROM (
+                    SELECT m.`from`, m.parsed_time, x.`to` FROM main_data m JOIN position x ON x.id = m.id
+                    UNION ALL
+                    SELECT m.`from`, m.parsed_time, x.`to` FROM main_data m JOIN messages x ON x.id = m.id
+                    UNION ALL
+                    SELECT m.`from`, m.parsed_time, x.`to` FROM main_data m JOIN status x ON x.id = m.id
+                )
+            ) d WHERE d.rn = 1 AND d.`from` = stations.callsign",
+            "CREATE INDEX stations_last_heard_idx ON stations (last_heard)",
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "Lines without a source callsign",
+        statements: &[
+            "CREATE TABLE main_data_v6 (
+                id                  TEXT PRIMARY KEY,
+                `from`              TEXT REFERENCES stations (callsign),
+                via                 TEXT NOT NULL,
+                type                TEXT NOT NULL CHECK (type IN ('message', 'position', 'status', 'mic_e', 'unknown')),
+                `parsed_time`       TEXT NOT NULL
+            )",
+            "INSERT INTO main_data_v6 (id, `from`, via, type, `parsed_time`)
+                SELECT id, NULLIF(`from`, ''), via, type, `parsed_time` FROM main_data",
+            "DROP TABLE main_data",
+            "ALTER TABLE main_data_v6 RENAME TO main_data",
+            "CREATE INDEX main_data_from_time_idx ON main_data (`from`, `parsed_time`)",
+            "CREATE INDEX main_data_time_idx ON main_data (`parsed_time`)",
+            "CREATE INDEX main_data_type_time_idx ON main_data (type, `parsed_time`)",
+            "DELETE FROM stations WHERE callsign = ''",
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "Downsampling progress",
+        statements: &["CREATE TABLE downsample_state (
+                interval_seconds    INTEGER PRIMARY KEY,
+                -- Unix time
+                downsampled_until   INTEGER NOT NULL
+            )"],
+    },
+];
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::parse_line_or_unknown;
+
+    fn temp_db() -> (SqliteDb, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("k0hax-sqlite-{}.db", Uuid::new_v4()));
+        (SqliteDb::new(path.to_str().unwrap()), path)
+    }
+
+    fn count(db: &SqliteDb, statement_text: &str) -> i64 {
+        let conn = db.conn.lock().unwrap();
+        conn.query_row(statement_text, (), |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn line_without_a_source_is_stored() {
+        let (db, path) = temp_db();
+        db.migrate().unwrap();
+        let line = parse_line_or_unknown("garbage without header");
+        assert_eq!(line.source(), None);
+        db.insert_aprs_line(&line).unwrap();
+        assert_eq!(
+            count(&db, "SELECT count(*) FROM main_data WHERE `from` IS NULL"),
+            1
+        );
+        assert_eq!(count(&db, "SELECT count(*) FROM unknown"), 1);
+        assert_eq!(count(&db, "SELECT count(*) FROM stations"), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn most_recently_heard_stations_are_listed_up_to_the_limit() {
+        let (db, path) = temp_db();
+        db.migrate().unwrap();
+        let now = Utc::now().timestamp();
+        for (callsign, age) in [("K1ABC", 30), ("K2ABC", 10), ("K3ABC", 20)] {
+            let mut line = parse_line_or_unknown(&format!("{}>APRS:>status", callsign));
+            line.received_at = DateTime::from_timestamp(now - age, 0).unwrap();
+            db.insert_aprs_line(&line).unwrap();
+        }
+        let since = DateTime::from_timestamp(now - 60, 0).unwrap();
+        let callsigns: Vec<String> = db
+            .stations_heard_since(since, 2)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.callsign)
+            .collect();
+        assert_eq!(callsigns, ["K2ABC", "K3ABC"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rebuilding_main_data_keeps_the_packets_referencing_it() {
+        let (db, path) = temp_db();
+        {
+            let conn = db.conn.lock().unwrap();
+            conn.execute_batch(
+                "CREATE TABLE schema_version (
+                    version             INTEGER PRIMARY KEY,
+                    description         TEXT NOT NULL,
+                    applied_time        TEXT NOT NULL
+                )",
+            )
+            .unwrap();
+            for migration in MIGRATIONS.iter().filter(|x| x.version < 6) {
+                for statement in migration.statements {
+                    conn.execute_batch(statement).unwrap();
+                }
+                conn.execute(
+                    "INSERT INTO schema_version (version, description, applied_time) VALUES (?1, ?2, '')",
+                    (migration.version, migration.description),
+                )
+                .unwrap();
+            }
+        }
+        db.insert_aprs_line(&parse_line_or_unknown(
+            "N0CALL>APRS:!4903.50N/07201.75W-Test",
+        ))
+        .unwrap();
+
+        assert_eq!(db.migrate().unwrap(), vec![6, 7]);
+        assert_eq!(count(&db, "SELECT count(*) FROM main_data"), 1);
+        assert_eq!(count(&db, "SELECT count(*) FROM position"), 1);
+        assert_eq!(
+            count(
+                &db,
+                "SELECT position_count FROM stations WHERE callsign = 'N0CALL'"
+            ),
+            1
+        );
+        // The pragma is restored once the migrations are applied
+        assert_eq!(count(&db, "PRAGMA foreign_keys"), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn downsampling_resumes_where_it_stopped() {
+        let (db, path) = temp_db();
+        db.migrate().unwrap();
+        let downsample = Downsample {
+            older_than: std::time::Duration::from_secs(24 * 60 * 60),
+            interval: std::time::Duration::from_secs(600),
+        };
+        let policy = RetentionPolicy {
+            retain: Vec::new(),
+            downsample: Some(downsample),
+        };
+        let start = downsample.interval_start(Utc::now().timestamp() - 3 * 24 * 60 * 60);
+        for offset in [10, 20, 30, 700, 2 * 24 * 60 * 60] {
+            let mut line = parse_line_or_unknown("N0CALL>APRS:!4903.50N/07201.75W-Test");
+            line.received_at = DateTime::from_timestamp(start + offset, 0).unwrap();
+            db.insert_aprs_line(&line).unwrap();
+        }
+
+        assert_eq!(db.prune(&policy).unwrap().downsampled, 2);
+        assert_eq!(count(&db, "SELECT count(*) FROM position"), 3);
+        assert_eq!(
+            count(
+                &db,
+                "SELECT downsampled_until FROM downsample_state WHERE interval_seconds = 600"
+            ),
+            downsample.until()
+        );
+
+        // Later positions of an interval which was already thinned are left
+        let mut line = parse_line_or_unknown("N0CALL>APRS:!4903.50N/07201.75W-Test");
+        line.received_at = DateTime::from_timestamp(start + 40, 0).unwrap();
+        db.insert_aprs_line(&line).unwrap();
+        assert_eq!(db.prune(&policy).unwrap().downsampled, 0);
+        assert_eq!(count(&db, "SELECT count(*) FROM position"), 4);
+        std::fs::remove_file(path).unwrap();
     }
 }
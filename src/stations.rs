@@ -0,0 +1,225 @@
+use crate::data::*;
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Number of packets of each type heard from a station
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PacketCounts {
+    pub position: u64,
+    pub message: u64,
+    pub status: u64,
+    pub mic_e: u64,
+    pub unknown: u64,
+}
+
+impl PacketCounts {
+    fn add(&mut self, data: &ParsedAprsData) {
+        match data {
+            ParsedAprsData::Position(_) => self.position += 1,
+            ParsedAprsData::Message(_) => self.message += 1,
+            ParsedAprsData::Status(_) => self.status += 1,
+            ParsedAprsData::MicE(_) => self.mic_e += 1,
+            ParsedAprsData::Unknown(_) => self.unknown += 1,
+        }
+    }
+}
+
+/// Latest position report of a station
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LastPosition {
+    pub time: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub symbol_table: char,
+    pub symbol_code: char,
+    pub comment: String,
+}
+
+/// A row of the `stations` table: what is known about a station as of the
+/// last packet heard from it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StationState {
+    pub callsign: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_heard: DateTime<Utc>,
+    /// Path of the last packet heard
+    pub last_via: Vec<String>,
+    pub last_position: Option<LastPosition>,
+    /// Destination of the last packet which had one. Senders put their
+    /// software or radio model there (the "tocall").
+    pub device: Option<String>,
+    pub packets: PacketCounts,
+}
+
+/// Columns of a `stations` row as read back by the backends, in the order
+/// callsign, first_seen, last_heard, last_via, last_position_time, latitude,
+/// longitude, symbol_table, symbol_code, comment, device and the position,
+/// message, status, mic_e and unknown counts. Times are RFC 3339.
+pub type StationColumns = (
+    String,
+    String,
+    String,
+    String,
+    Option<String>,
+    Option<f64>,
+    Option<f64>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    i64,
+    i64,
+    i64,
+    i64,
+    i64,
+);
+
+impl StationState {
+    pub fn from_columns(columns: StationColumns) -> Result<StationState> {
+        let (
+            callsign,
+            first_seen,
+            last_heard,
+            last_via,
+            last_position_time,
+            latitude,
+            longitude,
+            symbol_table,
+            symbol_code,
+            comment,
+            device,
+            position,
+            message,
+            status,
+            mic_e,
+            unknown,
+        ) = columns;
+        let last_position = match (last_position_time, latitude, longitude) {
+            (Some(time), Some(latitude), Some(longitude)) => Some(LastPosition {
+                time: parse_time(&time)?,
+                latitude,
+                longitude,
+                symbol_table: first_char(symbol_table),
+                symbol_code: first_char(symbol_code),
+                comment: comment.unwrap_or_default(),
+            }),
+            _ => None,
+        };
+        Ok(StationState {
+            callsign,
+            first_seen: parse_time(&first_seen)?,
+            last_heard: parse_time(&last_heard)?,
+            last_via: last_via
+                .split(", ")
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_string())
+                .collect(),
+            last_position,
+            device,
+            packets: PacketCounts {
+                position: position.max(0) as u64,
+                message: message.max(0) as u64,
+                status: status.max(0) as u64,
+                mic_e: mic_e.max(0) as u64,
+                unknown: unknown.max(0) as u64,
+            },
+        })
+    }
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(value)?.with_timezone(&Utc))
+}
+
+fn first_char(value: Option<String>) -> char {
+    value.and_then(|x| x.chars().next()).unwrap_or(' ')
+}
+
+/// Changes a batch of lines makes to the state of one station, merged into
+/// the `stations` table by each backend's upsert
+#[derive(Debug, Clone, PartialEq)]
+pub struct StationUpdate {
+    pub callsign: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_heard: DateTime<Utc>,
+    pub last_via: Vec<String>,
+    pub last_position: Option<LastPosition>,
+    pub device: Option<String>,
+    /// Packets to add to the stored counts
+    pub packets: PacketCounts,
+}
+
+impl StationUpdate {
+    /// Fold lines into one update per station, ordered by callsign so
+    /// concurrent writers lock the rows in the same order. Lines without a
+    /// source callsign, such as undecodable ones lacking a `>`, are stored
+    /// without belonging to a station.
+    pub fn from_lines(lines: &[ParsedLine]) -> Vec<StationUpdate> {
+        let mut updates: BTreeMap<&str, StationUpdate> = BTreeMap::new();
+        for line in lines.iter().filter(|x| x.source().is_some()) {
+            let update = updates.entry(&line.from).or_insert_with(|| StationUpdate {
+                callsign: line.from.clone(),
+                first_seen: line.received_at,
+                last_heard: line.received_at,
+                last_via: line.via.clone(),
+                last_position: None,
+                device: None,
+                packets: PacketCounts::default(),
+            });
+            update.packets.add(&line.data);
+            update.first_seen = update.first_seen.min(line.received_at);
+            let device = destination(&line.data).map(|x| x.to_string());
+            if line.received_at >= update.last_heard {
+                update.last_heard = line.received_at;
+                update.last_via = line.via.clone();
+                update.device = device.or(update.device.take());
+            } else if update.device.is_none() {
+                update.device = device;
+            }
+            if let Some(position) = last_position(line) {
+                if update
+                    .last_position
+                    .as_ref()
+                    .is_none_or(|x| position.time >= x.time)
+                {
+                    update.last_position = Some(position);
+                }
+            }
+        }
+        updates.into_values().collect()
+    }
+}
+
+/// Destination callsign of a packet, when it is not used to encode data
+fn destination(data: &ParsedAprsData) -> Option<&str> {
+    match data {
+        ParsedAprsData::Position(x) => Some(&x.to),
+        ParsedAprsData::Message(x) => Some(&x.to),
+        ParsedAprsData::Status(x) => Some(&x.to),
+        ParsedAprsData::MicE(_) | ParsedAprsData::Unknown(_) => None,
+    }
+}
+
+fn last_position(line: &ParsedLine) -> Option<LastPosition> {
+    match &line.data {
+        ParsedAprsData::Position(x) => Some(LastPosition {
+            time: line.received_at,
+            latitude: x.latitude,
+            longitude: x.longitude,
+            symbol_table: x.symbol_table,
+            symbol_code: x.symbol_code,
+            comment: x.comment.clone(),
+        }),
+        ParsedAprsData::MicE(x) => Some(LastPosition {
+            time: line.received_at,
+            latitude: x.latitude,
+            longitude: x.longitude,
+            symbol_table: x.symbol_table,
+            symbol_code: x.symbol_code,
+            comment: x.comment.clone(),
+        }),
+        _ => None,
+    }
+}
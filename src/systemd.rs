@@ -0,0 +1,75 @@
+use log::warn;
+use sd_notify::NotifyState;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Whether `ready` was sent, so a reload does not announce readiness early
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Whether the daemon runs as a systemd service of `Type=notify`, such as
+///
+/// ```ini
+/// [Service]
+/// Type=notify
+/// ExecStart=/usr/bin/k0hax-aprs -c /etc/k0hax-aprs/config.toml N0CALL postgres localhost aprs
+/// ExecReload=kill -HUP $MAINPID
+/// WatchdogSec=5min
+/// Restart=on-failure
+/// ```
+///
+/// Without it the other functions do nothing.
+pub fn enabled() -> bool {
+    std::env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+fn notify(states: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, states) {
+        warn!("Could not notify systemd: {}", e);
+    }
+}
+
+/// Tell systemd the daemon is logged in and its sinks are open
+pub fn ready() {
+    READY.store(true, Ordering::Relaxed);
+    notify(&[NotifyState::Ready]);
+}
+
+/// Tell systemd a reload has started, until `reloaded`
+pub fn reloading() {
+    match NotifyState::monotonic_usec_now() {
+        Ok(now) => notify(&[NotifyState::Reloading, now]),
+        Err(_) => notify(&[NotifyState::Reloading]),
+    }
+}
+
+/// Tell systemd a reload has finished
+pub fn reloaded() {
+    if READY.load(Ordering::Relaxed) {
+        notify(&[NotifyState::Ready]);
+    }
+}
+
+/// Tell systemd the daemon is shutting down
+pub fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Show a line of text in `systemctl status`
+pub fn status(text: &str) {
+    notify(&[NotifyState::Status(text)]);
+}
+
+/// Time after which systemd restarts the service unless `watchdog` is
+/// called, set by `WatchdogSec=`
+pub fn watchdog_timeout() -> Option<Duration> {
+    let mut usec = 0;
+    match sd_notify::watchdog_enabled(false, &mut usec) {
+        true if usec > 0 => Some(Duration::from_micros(usec)),
+        _ => None,
+    }
+}
+
+/// Tell systemd the daemon is still working
+pub fn watchdog() {
+    notify(&[NotifyState::Watchdog]);
+}
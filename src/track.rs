@@ -0,0 +1,357 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Mean earth radius used for great circle distances
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+/// A position report of a station, at the time it was received
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Fix {
+    pub time: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// Number of rejected fixes in a row which agree with each other, after
+/// which the fixes they disagree with are taken to be the outliers instead
+pub const REANCHOR_AFTER: usize = 3;
+
+/// Tuning of `build_track`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackOptions {
+    /// Silence after which the next fix starts a new segment
+    pub max_gap: Duration,
+    /// Fixes which would need a higher speed from the previous fix are
+    /// rejected as outliers, in km/h
+    pub max_speed_kmh: f64,
+    /// Distance from the start of a stop within which the station counts as
+    /// stationary, in meters
+    pub stop_radius_m: f64,
+    /// Minimum time a station has to stay within `stop_radius_m` for a stop
+    pub min_stop_duration: Duration,
+}
+
+impl Default for TrackOptions {
+    fn default() -> Self {
+        TrackOptions {
+            max_gap: Duration::from_secs(30 * 60),
+            max_speed_kmh: 1000.0,
+            stop_radius_m: 50.0,
+            min_stop_duration: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// A period in which the station did not move
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Stop {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// Fixes without a gap longer than `TrackOptions::max_gap` between them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub fixes: Vec<Fix>,
+    pub distance_m: f64,
+    /// Highest speed between two consecutive fixes, in km/h
+    pub max_speed_kmh: f64,
+    /// Distance over the time from the first to the last fix, in km/h
+    pub avg_speed_kmh: f64,
+    pub stops: Vec<Stop>,
+}
+
+impl Segment {
+    fn new(fixes: Vec<Fix>, options: &TrackOptions) -> Segment {
+        let mut distance_m = 0.0;
+        let mut max_speed_kmh: f64 = 0.0;
+        for pair in fixes.windows(2) {
+            let distance = distance_m_between(&pair[0], &pair[1]);
+            distance_m += distance;
+            if let Some(speed) = speed_kmh(distance, &pair[0], &pair[1]) {
+                max_speed_kmh = max_speed_kmh.max(speed);
+            }
+        }
+        let avg_speed_kmh = match (fixes.first(), fixes.last()) {
+            (Some(first), Some(last)) => speed_kmh(distance_m, first, last).unwrap_or(0.0),
+            _ => 0.0,
+        };
+        let stops = find_stops(&fixes, options);
+        Segment {
+            fixes,
+            distance_m,
+            max_speed_kmh,
+            avg_speed_kmh,
+            stops,
+        }
+    }
+
+    pub fn start(&self) -> Option<DateTime<Utc>> {
+        self.fixes.first().map(|x| x.time)
+    }
+
+    pub fn end(&self) -> Option<DateTime<Utc>> {
+        self.fixes.last().map(|x| x.time)
+    }
+}
+
+/// The movements of a station over a time range
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Track {
+    pub segments: Vec<Segment>,
+    pub distance_m: f64,
+    pub max_speed_kmh: f64,
+    /// Distance over the time spent in segments, in km/h
+    pub avg_speed_kmh: f64,
+    /// Fixes dropped as impossible jumps
+    pub rejected: usize,
+}
+
+/// Group time ordered fixes into segments.
+///
+/// A fix is rejected when reaching it from the last accepted fix would need
+/// more than `max_speed_kmh`, or when it repeats the time of that fix at
+/// another place. Once `REANCHOR_AFTER` rejected fixes in a row agree with
+/// each other, the accepted fixes they cannot be reached from are rejected
+/// instead, so a bad first fix such as 0,0 does not hide the real ones. A
+/// gap longer than `max_gap` starts a new segment.
+pub fn build_track(fixes: &[Fix], options: &TrackOptions) -> Track {
+    let mut segments = Vec::new();
+    let mut current: Vec<Fix> = Vec::new();
+    // Rejected fixes in a row which agree with each other
+    let mut pending: Vec<Fix> = Vec::new();
+    let mut rejected = 0;
+    for fix in fixes {
+        if let Some(last) = current.last() {
+            let elapsed = fix.time.signed_duration_since(last.time).to_std();
+            match elapsed {
+                Ok(elapsed) if elapsed > options.max_gap => {
+                    segments.push(Segment::new(std::mem::take(&mut current), options));
+                    pending.clear();
+                }
+                Ok(_) if plausible(last, fix, options) => pending.clear(),
+                Ok(_) => {
+                    rejected += 1;
+                    if pending.last().is_some_and(|x| !plausible(x, fix, options)) {
+                        pending.clear();
+                    }
+                    pending.push(*fix);
+                    if pending.len() < REANCHOR_AFTER {
+                        continue;
+                    }
+                    while current
+                        .last()
+                        .is_some_and(|x| !plausible(x, &pending[0], options))
+                    {
+                        current.pop();
+                        rejected += 1;
+                    }
+                    let gap = current
+                        .last()
+                        .and_then(|x| pending[0].time.signed_duration_since(x.time).to_std().ok());
+                    if gap.is_some_and(|x| x > options.max_gap) {
+                        segments.push(Segment::new(std::mem::take(&mut current), options));
+                    }
+                    rejected -= pending.len();
+                    current.append(&mut pending);
+                    continue;
+                }
+                // Out of order
+                Err(_) => {
+                    rejected += 1;
+                    continue;
+                }
+            }
+        }
+        current.push(*fix);
+    }
+    if !current.is_empty() {
+        segments.push(Segment::new(current, options));
+    }
+
+    let distance_m: f64 = segments.iter().map(|x| x.distance_m).sum();
+    let seconds: f64 = segments
+        .iter()
+        .filter_map(|x| Some(x.end()?.signed_duration_since(x.start()?)))
+        .map(|x| x.num_milliseconds() as f64 / 1000.0)
+        .sum();
+    Track {
+        max_speed_kmh: segments.iter().map(|x| x.max_speed_kmh).fold(0.0, f64::max),
+        avg_speed_kmh: if seconds > 0.0 {
+            distance_m / seconds * 3.6
+        } else {
+            0.0
+        },
+        distance_m,
+        segments,
+        rejected,
+    }
+}
+
+/// Whether a station can have moved from one fix to the next
+fn plausible(from: &Fix, to: &Fix, options: &TrackOptions) -> bool {
+    let distance = distance_m_between(from, to);
+    match speed_kmh(distance, from, to) {
+        Some(speed) => speed <= options.max_speed_kmh,
+        None => distance <= options.stop_radius_m,
+    }
+}
+
+/// Great circle distance between two fixes
+pub fn distance_m_between(a: &Fix, b: &Fix) -> f64 {
+    let (lat_a, lat_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.longitude - a.longitude).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().min(1.0).asin()
+}
+
+/// Speed needed to cover `distance_m` between the times of two fixes, `None`
+/// when no time passed
+fn speed_kmh(distance_m: f64, a: &Fix, b: &Fix) -> Option<f64> {
+    let milliseconds = b.time.signed_duration_since(a.time).num_milliseconds();
+    if milliseconds <= 0 {
+        return None;
+    }
+    Some(distance_m / (milliseconds as f64 / 1000.0) * 3.6)
+}
+
+/// Runs of fixes which stay within `stop_radius_m` of their first fix for at
+/// least `min_stop_duration`
+fn find_stops(fixes: &[Fix], options: &TrackOptions) -> Vec<Stop> {
+    let mut stops = Vec::new();
+    let mut start = 0;
+    while start < fixes.len() {
+        let anchor = &fixes[start];
+        let mut end = start;
+        while end + 1 < fixes.len()
+            && distance_m_between(anchor, &fixes[end + 1]) <= options.stop_radius_m
+        {
+            end += 1;
+        }
+        let stayed = fixes[end].time.signed_duration_since(anchor.time).to_std();
+        if matches!(stayed, Ok(x) if x >= options.min_stop_duration) {
+            stops.push(Stop {
+                start: anchor.time,
+                end: fixes[end].time,
+                latitude: anchor.latitude,
+                longitude: anchor.longitude,
+            });
+        }
+        start = end + 1;
+    }
+    stops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fix `minutes` into the track, `km` east of 45N 122W
+    fn fix(minutes: i64, km: f64) -> Fix {
+        Fix {
+            time: Utc.timestamp_opt(1_700_000_000 + minutes * 60, 0).unwrap(),
+            latitude: 45.0,
+            longitude: -122.0 + km / (111.195 * 45f64.to_radians().cos()),
+        }
+    }
+
+    fn null_island(minutes: i64) -> Fix {
+        Fix {
+            latitude: 0.0,
+            longitude: 0.0,
+            ..fix(minutes, 0.0)
+        }
+    }
+
+    #[test]
+    fn distance_of_one_degree_of_latitude() {
+        let a = Fix {
+            latitude: 45.0,
+            ..fix(0, 0.0)
+        };
+        let b = Fix {
+            latitude: 46.0,
+            ..a
+        };
+        assert!((distance_m_between(&a, &b) - 111_195.0).abs() < 10.0);
+    }
+
+    #[test]
+    fn steady_movement_is_one_segment() {
+        let fixes: Vec<Fix> = (0..10).map(|x| fix(x, x as f64)).collect();
+        let track = build_track(&fixes, &TrackOptions::default());
+        assert_eq!(track.segments.len(), 1);
+        assert_eq!(track.rejected, 0);
+        assert!((track.distance_m - 9000.0).abs() < 10.0);
+        assert!((track.avg_speed_kmh - 60.0).abs() < 0.1);
+        assert!((track.max_speed_kmh - 60.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn gap_starts_a_new_segment() {
+        let fixes = [fix(0, 0.0), fix(1, 1.0), fix(60, 2.0), fix(61, 3.0)];
+        let track = build_track(&fixes, &TrackOptions::default());
+        assert_eq!(track.segments.len(), 2);
+        assert_eq!(track.segments[1].start(), Some(fixes[2].time));
+        // The jump across the gap is not counted
+        assert!((track.distance_m - 2000.0).abs() < 10.0);
+    }
+
+    #[test]
+    fn jump_in_the_middle_is_rejected() {
+        let fixes = [fix(0, 0.0), fix(1, 1.0), fix(2, 500.0), fix(3, 3.0)];
+        let track = build_track(&fixes, &TrackOptions::default());
+        assert_eq!(track.rejected, 1);
+        assert_eq!(track.segments[0].fixes, vec![fixes[0], fixes[1], fixes[3]]);
+    }
+
+    #[test]
+    fn out_of_order_and_same_time_elsewhere_are_rejected() {
+        let fixes = [fix(5, 0.0), fix(4, 0.1), fix(5, 2.0), fix(6, 1.0)];
+        let track = build_track(&fixes, &TrackOptions::default());
+        assert_eq!(track.rejected, 2);
+        assert_eq!(track.segments[0].fixes, vec![fixes[0], fixes[3]]);
+    }
+
+    #[test]
+    fn bad_first_fix_is_replaced_by_the_real_ones() {
+        let mut fixes = vec![null_island(0)];
+        fixes.extend((1..10).map(|x| fix(x, x as f64)));
+        let track = build_track(&fixes, &TrackOptions::default());
+        assert_eq!(track.segments.len(), 1);
+        assert_eq!(track.segments[0].fixes, fixes[1..]);
+        assert_eq!(track.rejected, 1);
+    }
+
+    #[test]
+    fn scattered_outliers_do_not_reanchor() {
+        // Rejected fixes which disagree with each other are all outliers
+        let fixes = [
+            fix(0, 0.0),
+            fix(1, 1.0),
+            null_island(2),
+            fix(3, 900.0),
+            fix(4, -900.0),
+            fix(5, 5.0),
+        ];
+        let track = build_track(&fixes, &TrackOptions::default());
+        assert_eq!(track.rejected, 3);
+        assert_eq!(track.segments[0].fixes, vec![fixes[0], fixes[1], fixes[5]]);
+    }
+
+    #[test]
+    fn stop_is_found() {
+        let mut fixes: Vec<Fix> = (0..3).map(|x| fix(x, x as f64)).collect();
+        fixes.extend((3..10).map(|x| fix(x, 2.01)));
+        fixes.push(fix(11, 4.0));
+        let track = build_track(&fixes, &TrackOptions::default());
+        let stops = &track.segments[0].stops;
+        assert_eq!(stops.len(), 1);
+        assert_eq!(stops[0].start, fixes[2].time);
+        assert_eq!(stops[0].end, fixes[9].time);
+    }
+}
@@ -1,8 +1,10 @@
 use crate::data::*;
 use anyhow::Result;
 use aprs_parser::{AprsMessage, AprsPacket};
+use chrono::prelude::*;
 use log::info;
 use std::error::Error;
+use uuid::Uuid;
 
 /// Generate an APRS-IS passcode from a given Call Sign
 pub fn generate_passcode(callsign: &str) -> Option<String> {
@@ -17,7 +19,7 @@ pub fn generate_passcode(callsign: &str) -> Option<String> {
     let mut high: bool = true;
 
     for c in base_call.chars() {
-        if high == true {
+        if high {
             passcode ^= (c as u16) << 8;
             high = false;
         } else {
@@ -26,7 +28,7 @@ pub fn generate_passcode(callsign: &str) -> Option<String> {
         }
     }
 
-    return Some(passcode.to_string());
+    Some(passcode.to_string())
 }
 
 pub fn parse_line(data: &str) -> Result<ParsedLine, Box<dyn Error>> {
@@ -34,8 +36,8 @@ pub fn parse_line(data: &str) -> Result<ParsedLine, Box<dyn Error>> {
     let mut via_strings: Vec<String> = Vec::new();
     for cs in result.via {
         via_strings.push(match cs {
-            aprs_parser::Via::Callsign(x, _heard) => format!("{}", x),
-            aprs_parser::Via::QConstruct(x) => format!("{}", x.as_textual()),
+            aprs_parser::Via::Callsign(x, _heard) => x.to_string(),
+            aprs_parser::Via::QConstruct(x) => x.as_textual().to_string(),
         });
     }
     let result_data: ParsedAprsData = match ParsedAprsData::from(result.data) {
@@ -43,33 +45,100 @@ pub fn parse_line(data: &str) -> Result<ParsedLine, Box<dyn Error>> {
         ParsedAprsData::Message(x) => ParsedAprsData::Message(x),
         ParsedAprsData::Status(x) => ParsedAprsData::Status(x),
         ParsedAprsData::MicE(x) => ParsedAprsData::MicE(x),
-        ParsedAprsData::Unknown(x) => ParsedAprsData::Unknown(format!("{}", data)),
+        ParsedAprsData::Unknown(x) => ParsedAprsData::Unknown(ParsedAprsUnknown {
+            raw: data.to_string(),
+            error: x.error,
+        }),
     };
     Ok(ParsedLine {
+        id: Uuid::new_v4(),
+        received_at: Utc::now(),
         from: result.from.to_string(),
         via: via_strings,
-        data: result_data.into(),
+        data: result_data,
     })
 }
 
+/// Parse a line, keeping lines which fail to decode as `ParsedAprsData::Unknown`
+pub fn parse_line_or_unknown(data: &str) -> ParsedLine {
+    match parse_line(data) {
+        Ok(x) => x,
+        Err(e) => ParsedLine::undecoded(data, e.to_string()),
+    }
+}
+
+/// One line summary of a packet of any type, as printed by `print_parsed`
+pub fn format_parsed(data: &ParsedLine) -> String {
+    let via_string: String = format!("[via: {}]", data.via.join(", "));
+    let (to, text) = match &data.data {
+        ParsedAprsData::Message(x) => (x.addressee.clone(), x.text.clone()),
+        ParsedAprsData::Position(x) => (
+            x.to.clone(),
+            format!(
+                "{:.5}, {:.5} {}{} {}",
+                x.latitude, x.longitude, x.symbol_table, x.symbol_code, x.comment
+            ),
+        ),
+        ParsedAprsData::Status(x) => (x.to.clone(), format!(">{}", x.comment)),
+        ParsedAprsData::MicE(x) => (
+            "MIC-E".to_string(),
+            format!(
+                "{:.5}, {:.5} {}{} {} kn {}° {}",
+                x.latitude,
+                x.longitude,
+                x.symbol_table,
+                x.symbol_code,
+                x.speed,
+                x.course,
+                x.comment
+            ),
+        ),
+        ParsedAprsData::Unknown(x) => ("?".to_string(), format!("{} ({})", x.raw, x.error)),
+    };
+    let from_string: String = format!("[{}]->[{}]", data.from, to);
+    format!("{0: <30} {1: <50}: {2:}", from_string, via_string, text)
+}
+
 pub fn print_parsed(data: &ParsedLine) -> Result<(), Box<dyn Error>> {
-    match &data.data {
-        ParsedAprsData::Message(x) => {
-            let via_string: String = data
-                .via
-                .iter()
-                .map(|y| y.to_string() + ", ")
-                .collect::<String>();
-            let via_string: String = via_string.trim_end_matches(", ").to_string();
-            let via_string: String = format!("[via: {}]", via_string);
-            let from_string: String = format!("[{}]->[{}]", data.from, x.addressee);
-            println!("{0: <30} {1: <50}: {2:}", from_string, via_string, x.text);
-        }
-        _ => {
-            return Ok(());
+    println!("{}", format_parsed(data));
+    Ok(())
+}
+
+/// Match a callsign against a pattern where `*` matches any run of
+/// characters and `?` a single one, ignoring case
+pub fn callsign_matches(pattern: &str, callsign: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_uppercase().chars().collect();
+    let callsign: Vec<char> = callsign.to_uppercase().chars().collect();
+    let (mut p, mut c) = (0, 0);
+    // Position of the last `*` and of the callsign character it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    while c < callsign.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == callsign[c]) {
+            p += 1;
+            c += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, c));
+            p += 1;
+        } else if let Some((star_p, star_c)) = star {
+            // Let the `*` swallow one more character and try again
+            p = star_p + 1;
+            c = star_c + 1;
+            star = Some((star_p, star_c + 1));
+        } else {
+            return false;
         }
     }
-    Ok(())
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+/// Longest wait between reconnection attempts
+pub const MAX_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Wait before the next reconnection attempt after a number of consecutive
+/// failures, doubling from one second up to `MAX_RECONNECT_DELAY`
+pub fn reconnect_delay(failures: u64) -> std::time::Duration {
+    let seconds = 1 << failures.saturating_sub(1).min(6);
+    std::time::Duration::from_secs(seconds).min(MAX_RECONNECT_DELAY)
 }
 
 pub fn print_line(data: &str) -> Result<(), Box<dyn Error>> {
@@ -98,24 +167,21 @@ pub fn print_messages(data: &str) -> Result<(), Box<dyn Error>> {
     let result = AprsPacket::decode_textual(data.as_bytes())?;
     let callsign = result.from;
     let data = result.data;
-    match data {
-        #[allow(unused_variables)]
-        aprs_parser::AprsData::Message(AprsMessage {
-            to: destination,
-            addressee: addressee_bytes,
-            text: text_bytes,
-            id,
-        }) => {
-            let addressee =
-                std::str::from_utf8(&addressee_bytes).unwrap_or_else(|_| "<ERROR PARSING UTF8>");
-            let text = std::str::from_utf8(&text_bytes).unwrap_or_else(|_| "<ERROR PARSING UTF8>");
-            let fmt = format!(
-                "[{}]->[{}] [{}]: {}",
-                callsign, destination, addressee, text
-            );
-            info!("{}", fmt);
-        }
-        _ => {}
+    #[allow(unused_variables)]
+    if let aprs_parser::AprsData::Message(AprsMessage {
+        to: destination,
+        addressee: addressee_bytes,
+        text: text_bytes,
+        id,
+    }) = data
+    {
+        let addressee = std::str::from_utf8(&addressee_bytes).unwrap_or("<ERROR PARSING UTF8>");
+        let text = std::str::from_utf8(&text_bytes).unwrap_or("<ERROR PARSING UTF8>");
+        let fmt = format!(
+            "[{}]->[{}] [{}]: {}",
+            callsign, destination, addressee, text
+        );
+        info!("{}", fmt);
     };
     Ok(())
 }
@@ -0,0 +1,160 @@
+use libk0hax_aprs::client::AprsClient;
+use libk0hax_aprs::mock::{LoginReply, MockOptions, MockServer, Step};
+use libk0hax_aprs::server::{AprsIsServer, ServerOptions};
+use std::time::Duration;
+
+const POSITION: &str = "K1ABC>APRS,TCPIP*,qAC,T2MOCK:!4530.00N/12230.00W-test";
+const STATUS: &str = "N0XYZ>APRS,TCPIP*,qAC,T2MOCK:>status";
+const WAIT: Duration = Duration::from_secs(5);
+
+async fn connect(server: &MockServer, callsign: &str) -> AprsClient {
+    let addr = server.addr();
+    AprsClient::new(&addr.ip().to_string(), addr.port(), callsign)
+        .await
+        .unwrap()
+}
+
+async fn read(client: &AprsClient) -> Result<String, String> {
+    match tokio::time::timeout(WAIT, client.read_raw_line()).await {
+        Ok(x) => x.map_err(|e| e.to_string()),
+        Err(_) => panic!("no line within {:?}", WAIT),
+    }
+}
+
+#[tokio::test]
+async fn login_is_verified_by_passcode() {
+    let server = MockServer::start(MockOptions::default()).await.unwrap();
+    let client = connect(&server, "N0CALL").await;
+    let login = client.login();
+    assert_eq!(login.callsign, "N0CALL");
+    assert!(login.verified);
+    assert_eq!(login.server.as_deref(), Some("T2MOCK"));
+    assert_eq!(server.lines(0), vec!["user N0CALL pass 13023"]);
+}
+
+#[tokio::test]
+async fn login_response_is_configurable() {
+    let server = MockServer::start(MockOptions {
+        login: LoginReply::Fixed(false),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    assert!(!connect(&server, "N0CALL").await.login().verified);
+
+    let server = MockServer::start(MockOptions {
+        login: LoginReply::Line("# logresp N0CALL verified".to_string()),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let login = connect(&server, "N0CALL").await.login();
+    assert!(login.verified);
+    assert_eq!(login.server, None);
+}
+
+#[tokio::test]
+async fn unreachable_server_is_an_error() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    assert!(
+        AprsClient::new(&addr.ip().to_string(), addr.port(), "N0CALL")
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn packets_and_keepalives_are_read() {
+    let server = MockServer::start(MockOptions {
+        scripts: vec![vec![
+            Step::Line(POSITION.to_string()),
+            Step::Keepalive,
+            Step::Line(STATUS.to_string()),
+        ]],
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let client = connect(&server, "N0CALL").await;
+    assert_eq!(read(&client).await.unwrap(), POSITION);
+    assert!(read(&client).await.unwrap_err().contains("Server Comment"));
+    assert_eq!(read(&client).await.unwrap(), STATUS);
+    assert_eq!(client.reconnects(), 0);
+}
+
+#[tokio::test]
+async fn client_reconnects_after_disconnect() {
+    let server = MockServer::start(MockOptions {
+        scripts: vec![
+            vec![Step::Line(POSITION.to_string()), Step::Disconnect],
+            vec![Step::Line(STATUS.to_string())],
+        ],
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let client = connect(&server, "N0CALL").await;
+    assert_eq!(read(&client).await.unwrap(), POSITION);
+    // The lost connection is reported once it is established again
+    assert!(read(&client).await.is_err());
+    assert_eq!(client.reconnects(), 1);
+    assert_eq!(server.connections(), 2);
+    assert_eq!(read(&client).await.unwrap(), STATUS);
+    assert_eq!(server.lines(1), vec!["user N0CALL pass 13023"]);
+}
+
+#[tokio::test]
+async fn sent_lines_are_recorded() {
+    let server = MockServer::start(MockOptions {
+        scripts: vec![vec![Step::Expect(1), Step::Line(STATUS.to_string())]],
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let client = connect(&server, "N0CALL").await;
+    client
+        .send_line("N0CALL>APRS,TCPIP*:>transmitted")
+        .await
+        .unwrap();
+    // Only sent once the mock has the packet
+    assert_eq!(read(&client).await.unwrap(), STATUS);
+    let received = server.wait_for_lines(2, WAIT).await;
+    assert_eq!(received[1].connection, 0);
+    assert_eq!(received[1].line, "N0CALL>APRS,TCPIP*:>transmitted");
+}
+
+#[tokio::test]
+async fn server_applies_filters_and_passes_uploads_on() {
+    let (server, mut uploads) = AprsIsServer::new(ServerOptions {
+        name: "T2LOCAL".to_string(),
+        buffer: 16,
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server.clone().serve(listener));
+    let client = AprsClient::new(&addr.ip().to_string(), addr.port(), "N0CALL")
+        .await
+        .unwrap();
+    assert!(client.login().verified);
+    assert_eq!(client.login().server.as_deref(), Some("T2LOCAL"));
+
+    client.send_line("#filter b/K1ABC").await.unwrap();
+    let reply = read(&client).await.unwrap_err();
+    assert!(reply.contains("# filter b/K1ABC active"), "{}", reply);
+    for raw in [STATUS, POSITION] {
+        server.publish(&libk0hax_aprs::parse_line_or_unknown(raw), raw);
+    }
+    assert_eq!(read(&client).await.unwrap(), POSITION);
+
+    client
+        .send_line("N0CALL>APRS,TCPIP*:>transmitted")
+        .await
+        .unwrap();
+    let upload = tokio::time::timeout(WAIT, uploads.recv()).await.unwrap();
+    assert_eq!(
+        upload.as_deref(),
+        Some("N0CALL>APRS,TCPIP*,qAC,T2LOCAL:>transmitted")
+    );
+}